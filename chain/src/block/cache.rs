@@ -9,6 +9,7 @@ pub mod test;
 
 use std::cmp::Ordering;
 use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::iter;
 use std::ops::ControlFlow;
 
 use nakamoto_common::bitcoin;
@@ -22,10 +23,9 @@ use nakamoto_common::bitcoin::util::uint::Uint256;
 use nakamoto_common::block::tree::{self, BlockReader, BlockTree, Branch, Error, ImportResult};
 use nakamoto_common::block::{
     self,
-    iter::Iter,
     store::Store,
     time::{self, Clock},
-    Bits, BlockTime, Height, Work,
+    Bits, BlockTime, Height, Retarget, Work,
 };
 use nakamoto_common::nonempty::NonEmpty;
 
@@ -70,6 +70,11 @@ pub struct BlockCache<S: Store> {
     orphans: HashMap<BlockHash, BlockHeader>,
     checkpoints: BTreeMap<Height, BlockHash>,
     params: Params,
+    trusted: bool,
+    /// Number of headers pruned after the genesis.
+    pruned: Height,
+    /// Total proof-of-work of the active chain, including pruned blocks.
+    work: Work,
     store: S,
 }
 
@@ -102,7 +107,10 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             headers,
             orphans,
             params,
+            trusted: false,
             checkpoints,
+            pruned: 0,
+            work: genesis.work(),
             store,
         })
     }
@@ -117,6 +125,30 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         Self::new(store, params, checkpoints)?.load()
     }
 
+    /// Use the given difficulty adjustment policy to validate headers, instead of the one
+    /// derived from the consensus parameters. Useful for custom chains.
+    pub fn with_retarget(mut self, retarget: Retarget) -> Self {
+        self.params.no_pow_retargeting = !retarget.adjust;
+        self.params.allow_min_difficulty_blocks = retarget.allow_min_difficulty;
+        self
+    }
+
+    /// Trust the difficulty target and proof-of-work of imported headers, instead of
+    /// validating them. Headers must still connect to the chain, match checkpoints, and
+    /// have valid timestamps.
+    ///
+    /// This is only safe if all headers come from a trusted source, since anyone can
+    /// otherwise produce a longer chain at no cost.
+    pub fn with_trusted_pow(mut self, trusted: bool) -> Self {
+        self.trusted = trusted;
+        self
+    }
+
+    /// Get the difficulty adjustment policy used to validate headers.
+    pub fn retarget(&self) -> Retarget {
+        Retarget::from(&self.params)
+    }
+
     /// Load the block headers from the store, into the cache.
     pub fn load(self) -> Result<Self, Error> {
         self.load_with(|_| ControlFlow::Continue(()))
@@ -132,20 +164,42 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             let (height, header) = result?;
             let hash = header.block_hash();
 
-            self.extend_chain(height, hash, header);
+            if height > self.height() + 1 {
+                // The headers before this one were pruned, so it doesn't connect to the genesis.
+                assert!(self.chain.tail.is_empty());
+
+                self.pruned = height - 1;
+                self.work = self.work + self.store.pruned_work() + header.work();
+                self.headers.insert(hash, height);
+                self.chain.push(CachedBlock {
+                    height,
+                    hash,
+                    header,
+                });
+            } else {
+                self.extend_chain(height, hash, header);
+            }
 
             if progress(height).is_break() {
                 return Err(Error::Interrupted);
             }
         }
 
-        let length = self.store.len()?;
-        assert_eq!(length, self.chain.len());
-        assert_eq!(length, self.headers.len());
+        assert_eq!(self.store.height()?, self.height());
+        assert_eq!(self.chain.len(), self.headers.len());
 
         Ok(self)
     }
 
+    /// Get a block of the active chain by height. Returns `None` if the block was pruned.
+    fn block(&self, height: Height) -> Option<&CachedBlock> {
+        match height {
+            0 => Some(&self.chain.head),
+            h if h <= self.pruned => None,
+            h => self.chain.tail.get((h - self.pruned - 1) as usize),
+        }
+    }
+
     /// Iterate over a range of blocks.
     ///
     /// # Errors
@@ -158,10 +212,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             "BlockCache::range: range start must not be greater than range end"
         );
 
-        self.chain
-            .iter()
-            .skip(range.start as usize)
-            .take((range.end - range.start) as usize)
+        range.filter_map(move |height| self.block(height))
     }
 
     /// Get the median time past for the blocks leading up to the given height.
@@ -178,12 +229,15 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         let start = height.saturating_sub(time::MEDIAN_TIME_SPAN);
         let end = height;
 
-        for (i, blk) in self.range(start..end).enumerate() {
-            times[i] = blk.time;
+        let mut count = 0;
+        for blk in self.range(start..end) {
+            times[count] = blk.time;
+            count += 1;
         }
 
-        // Gracefully handle the case where `height` < `MEDIUM_TIME_SPAN`.
-        let available = &mut times[0..(end - start) as usize];
+        // Gracefully handle the case where `height` < `MEDIUM_TIME_SPAN`, or where some of
+        // the blocks were pruned.
+        let available = &mut times[0..count];
 
         available.sort_unstable();
         available[available.len() / 2]
@@ -213,25 +267,28 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         // Validate that the block's PoW is valid against its difficulty target, and
         // is greater than the minimum allowed for this network.
         //
-        // We do this because it's cheap to verify and prevents flooding attacks.
-        let target = header.target();
-        match header.validate_pow(&target) {
-            Ok(_) => {
-                let limit = self.params.pow_limit;
-                if target > limit {
-                    return Err(Error::InvalidBlockTarget(target, limit));
+        // We do this because it's cheap to verify and prevents flooding attacks. Trusted
+        // headers are exempt.
+        if !self.trusted {
+            let target = header.target();
+            match header.validate_pow(&target) {
+                Ok(_) => {
+                    let limit = self.params.pow_limit;
+                    if target > limit {
+                        return Err(Error::InvalidBlockTarget(target, limit));
+                    }
                 }
+                Err(bitcoin::util::Error::BlockBadProofOfWork) => {
+                    return Err(Error::InvalidBlockPoW);
+                }
+                Err(bitcoin::util::Error::BlockBadTarget) => unreachable! {
+                    // The only way to get a 'bad target' error is to pass a different target
+                    // than the one specified in the header.
+                },
+                Err(_) => unreachable! {
+                    // We've handled all possible errors above.
+                },
             }
-            Err(bitcoin::util::Error::BlockBadProofOfWork) => {
-                return Err(Error::InvalidBlockPoW);
-            }
-            Err(bitcoin::util::Error::BlockBadTarget) => unreachable! {
-                // The only way to get a 'bad target' error is to pass a different target
-                // than the one specified in the header.
-            },
-            Err(_) => unreachable! {
-                // We've handled all possible errors above.
-            },
         }
 
         if let Some(height) = self.headers.get(&header.prev_blockhash) {
@@ -379,6 +436,11 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         if let Some((fork_height, fork_header)) = self.get_block(&cursor) {
             assert!(!headers.is_empty());
 
+            // Branches forking off below the pruned blocks can't be activated.
+            if fork_height < self.pruned {
+                return None;
+            }
+
             return Some(Candidate {
                 tip,
                 fork_height,
@@ -419,29 +481,8 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
     ) -> Result<(), Error> {
         assert_eq!(tip.hash, header.prev_blockhash);
 
-        let compact_target = if self.params.allow_min_difficulty_blocks
-            && (tip.height + 1) % self.params.difficulty_adjustment_interval() != 0
-        {
-            if header.time > tip.time + self.params.pow_target_spacing as BlockTime * 2 {
-                block::pow_limit_bits(&self.params.network)
-            } else {
-                self.next_min_difficulty_target(&self.params)
-            }
-        } else {
-            self.next_difficulty_target(tip.height, tip.time, tip.target(), &self.params)
-        };
-
-        let target = BlockHeader::u256_from_compact_target(compact_target);
-
-        match header.validate_pow(&target) {
-            Err(bitcoin::util::Error::BlockBadProofOfWork) => {
-                return Err(Error::InvalidBlockPoW);
-            }
-            Err(bitcoin::util::Error::BlockBadTarget) => {
-                return Err(Error::InvalidBlockTarget(header.target(), target));
-            }
-            Err(_) => unreachable!(),
-            Ok(_) => {}
+        if !self.trusted {
+            self.validate_target(tip, header)?;
         }
 
         // Validate against block checkpoints.
@@ -458,7 +499,16 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         // A timestamp is accepted as valid if it is greater than the median timestamp of
         // the previous MEDIAN_TIME_SPAN blocks, and less than the network-adjusted
         // time + MAX_FUTURE_BLOCK_TIME.
-        if header.time <= self.median_time_past(height) {
+        //
+        // Headers below the highest known checkpoint must eventually connect to it, so we
+        // skip the median time past check for them, as it's comparatively expensive.
+        let below_checkpoint = self
+            .checkpoints
+            .keys()
+            .next_back()
+            .map_or(false, |h| height < *h);
+
+        if !below_checkpoint && header.time <= self.median_time_past(height) {
             return Err(Error::InvalidBlockTime(header.time, Ordering::Less));
         }
         if header.time > clock.block_time() + time::MAX_FUTURE_BLOCK_TIME {
@@ -468,11 +518,45 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         Ok(())
     }
 
+    /// Validate the difficulty target and proof-of-work of a header extending the given tip.
+    fn validate_target(&self, tip: &CachedBlock, header: &BlockHeader) -> Result<(), Error> {
+        let compact_target = self.next_target(tip, header);
+        let target = BlockHeader::u256_from_compact_target(compact_target);
+
+        match header.validate_pow(&target) {
+            Err(bitcoin::util::Error::BlockBadProofOfWork) => Err(Error::InvalidBlockPoW),
+            Err(bitcoin::util::Error::BlockBadTarget) => {
+                Err(Error::InvalidBlockTarget(header.target(), target))
+            }
+            Err(_) => unreachable!(),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    /// Get the expected difficulty target of a header extending the given tip, according
+    /// to our difficulty adjustment policy.
+    fn next_target(&self, tip: &CachedBlock, header: &BlockHeader) -> Bits {
+        let retarget = self.retarget();
+        let boundary = (tip.height + 1) % self.params.difficulty_adjustment_interval() == 0;
+
+        if retarget.allow_min_difficulty && !boundary {
+            if header.time > tip.time + self.params.pow_target_spacing as BlockTime * 2 {
+                BlockHeader::compact_target_from_u256(&self.params.pow_limit)
+            } else {
+                self.next_min_difficulty_target(&self.params)
+            }
+        } else if !retarget.adjust {
+            tip.bits
+        } else {
+            self.next_difficulty_target(tip.height, tip.time, tip.target(), &self.params)
+        }
+    }
+
     /// Get the next minimum-difficulty target. Only valid in testnet and regtest networks.
     fn next_min_difficulty_target(&self, params: &Params) -> Bits {
         assert!(params.allow_min_difficulty_blocks);
 
-        let pow_limit_bits = block::pow_limit_bits(&params.network);
+        let pow_limit_bits = BlockHeader::compact_target_from_u256(&params.pow_limit);
 
         for (height, header) in self.iter().rev() {
             if header.bits != pow_limit_bits
@@ -485,12 +569,23 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
     }
 
     /// Rollback active chain to the given height. Returns the list of rolled-back headers.
-    fn rollback(&mut self, height: Height) -> Result<Vec<(Height, BlockHeader)>, Error> {
+    fn truncate(&mut self, height: Height) -> Result<Vec<(Height, BlockHeader)>, Error> {
         let mut stale = Vec::new();
 
-        for (block, height) in self.chain.tail.drain(height as usize..).zip(height + 1..) {
+        assert!(
+            self.pruned == 0 || height > self.pruned,
+            "BlockCache::rollback: can't rollback to a pruned block"
+        );
+
+        for (block, height) in self
+            .chain
+            .tail
+            .drain((height - self.pruned) as usize..)
+            .zip(height + 1..)
+        {
             stale.push((height, block.header));
 
+            self.work = self.work - block.header.work();
             self.headers.remove(&block.hash);
             self.orphans.insert(block.hash, block.header);
         }
@@ -501,7 +596,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
 
     /// Activate a fork candidate. Returns the list of rolled-back (stale) headers.
     fn switch_to_fork(&mut self, branch: &Candidate) -> Result<Vec<(Height, BlockHeader)>, Error> {
-        let stale = self.rollback(branch.fork_height)?;
+        let stale = self.truncate(branch.fork_height)?;
 
         for (i, header) in branch.headers.iter().enumerate() {
             self.extend_chain(
@@ -521,6 +616,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
 
         self.headers.insert(hash, height);
         self.orphans.remove(&hash);
+        self.work = self.work + header.work();
         self.chain.push(CachedBlock {
             height,
             hash,
@@ -530,7 +626,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
 
     /// Get the blocks starting from the given height.
     fn chain_suffix(&self, height: Height) -> &[CachedBlock] {
-        &self.chain.tail[height as usize..]
+        &self.chain.tail[(height - self.pruned) as usize..]
     }
 }
 
@@ -618,6 +714,49 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
             Ok(ImportResult::TipUnchanged)
         }
     }
+
+    /// Prune the active chain. A difficulty adjustment interval's worth of blocks is always
+    /// kept, which is enough to validate new blocks, and deeper than any re-org.
+    fn prune(&mut self, below: Height) -> Result<Height, Error> {
+        let below = below.min(
+            self.height()
+                .saturating_sub(self.params.difficulty_adjustment_interval()),
+        );
+        // Stores need at least two headers to be pruned, see `Store::prune`.
+        if below <= self.pruned.max(1) + 1 {
+            return Ok(self.pruned + 1);
+        }
+        let pruned = (below - self.pruned - 1) as usize;
+        let work = self.store.pruned_work() + Branch(&self.chain.tail[..pruned]).work();
+
+        self.store.prune(below, work)?;
+
+        for block in self.chain.tail.drain(..pruned) {
+            self.headers.remove(&block.hash);
+        }
+        self.pruned = below - 1;
+
+        Ok(below)
+    }
+
+    /// Rollback the active chain. Unlike with re-orgs, the rolled-back blocks aren't kept
+    /// around as a fork, since they may be re-imported.
+    fn rollback(&mut self, height: Height) -> Result<Vec<(Height, BlockHeader)>, Error> {
+        if height >= self.height() {
+            return Ok(vec![]);
+        }
+        if self.pruned > 0 && height <= self.pruned {
+            return Err(Error::InvalidBlockHeight(height));
+        }
+        let mut stale = self.truncate(height)?;
+
+        for (_, header) in &stale {
+            self.orphans.remove(&header.block_hash());
+        }
+        stale.reverse();
+
+        Ok(stale)
+    }
 }
 
 impl<S: Store<Header = BlockHeader>> BlockReader for BlockCache<S> {
@@ -625,13 +764,13 @@ impl<S: Store<Header = BlockHeader>> BlockReader for BlockCache<S> {
     fn get_block(&self, hash: &BlockHash) -> Option<(Height, &BlockHeader)> {
         self.headers
             .get(hash)
-            .and_then(|height| self.chain.get(*height as usize))
+            .and_then(|height| self.block(*height))
             .map(|blk| (blk.height, &blk.header))
     }
 
-    /// Get a block by height.
+    /// Get a block by height. Returns `None` if the block was pruned.
     fn get_block_by_height(&self, height: Height) -> Option<&BlockHeader> {
-        self.chain.get(height as usize).map(|b| &b.header)
+        self.block(height).map(|b| &b.header)
     }
 
     /// Find a branch.
@@ -666,23 +805,21 @@ impl<S: Store<Header = BlockHeader>> BlockReader for BlockCache<S> {
         &self.chain.first().header
     }
 
-    /// Iterate over the longest chain, starting from genesis.
+    /// Iterate over the longest chain, starting from genesis. Pruned blocks are skipped.
     fn iter<'a>(&'a self) -> Box<dyn DoubleEndedIterator<Item = (Height, BlockHeader)> + 'a> {
-        Box::new(Iter::new(&self.chain).map(|(i, h)| (i, h.header)))
+        Box::new(
+            iter::once(&self.chain.head)
+                .chain(self.chain.tail.iter())
+                .map(|b| (b.height, b.header)),
+        )
     }
 
-    /// Iterate over a range of blocks.
+    /// Iterate over a range of blocks. Pruned blocks are skipped.
     fn range<'a>(
         &'a self,
         range: std::ops::Range<Height>,
     ) -> Box<dyn Iterator<Item = (Height, BlockHash)> + 'a> {
-        Box::new(
-            self.chain
-                .iter()
-                .map(|block| (block.height, block.hash))
-                .skip(range.start as usize)
-                .take((range.end - range.start) as usize),
-        )
+        Box::new(BlockCache::range(self, range).map(|block| (block.height, block.hash)))
     }
 
     /// Return the height of the longest chain.
@@ -690,6 +827,17 @@ impl<S: Store<Header = BlockHeader>> BlockReader for BlockCache<S> {
         self.chain.last().height
     }
 
+    /// Get the total proof-of-work of the active chain. This is kept up to date as blocks
+    /// are connected and disconnected.
+    fn chain_work(&self) -> Work {
+        self.work
+    }
+
+    /// Get the height of the last pruned block.
+    fn pruned(&self) -> Height {
+        self.pruned
+    }
+
     /// Get the height of the last checkpoint block.
     fn last_checkpoint(&self) -> Height {
         let height = self.height();
@@ -700,6 +848,9 @@ impl<S: Store<Header = BlockHeader>> BlockReader for BlockCache<S> {
             .map(|(h, _)| *h)
             .find(|h| *h <= height)
             .unwrap_or(0)
+            // Blocks can't be re-organized past the pruned ones, so the first block kept
+            // acts as a checkpoint.
+            .max(if self.pruned > 0 { self.pruned + 1 } else { 0 })
     }
 
     /// Known block checkpoints.
@@ -767,20 +918,27 @@ impl<S: Store<Header = BlockHeader>> BlockReader for BlockCache<S> {
     ///
     /// *Panics* if the given starting height is out of bounds.
     ///
-    fn locator_hashes(&self, from: Height) -> Vec<BlockHash> {
+    fn locator_hashes_with(&self, from: Height, density: usize) -> Vec<BlockHash> {
         let mut hashes = Vec::new();
 
         assert!(from <= self.height());
 
         let last_checkpoint = self.last_checkpoint();
 
-        for height in block::locators_indexes(from).into_iter() {
+        for height in block::locators_indexes_with(from, density).into_iter() {
             if height < last_checkpoint {
                 // Don't go past the latest checkpoint. We never want to accept a fork
                 // older than our last checkpoint.
                 break;
             }
-            if let Some(blk) = self.chain.get(height as usize) {
+            if let Some(blk) = self.block(height) {
+                hashes.push(blk.hash);
+            }
+        }
+        // Always anchor the locator at the last checkpoint, so that peers can find a common
+        // ancestor without us having to go further back.
+        if let Some(blk) = self.block(last_checkpoint) {
+            if hashes.last() != Some(&blk.hash) {
                 hashes.push(blk.hash);
             }
         }
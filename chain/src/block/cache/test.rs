@@ -3,7 +3,7 @@ use super::BlockCache;
 use nakamoto_common::bitcoin_hashes::Hash;
 use nakamoto_common::block::time::{AdjustedTime, Clock, LocalTime};
 use nakamoto_common::block::tree::{BlockReader, BlockTree, Error, ImportResult};
-use nakamoto_common::block::{BlockTime, Height, Target};
+use nakamoto_common::block::{BlockTime, Height, Retarget, Target};
 use nakamoto_common::nonempty::NonEmpty;
 
 use nakamoto_test::assert_matches;
@@ -81,6 +81,10 @@ impl BlockTree for HeightCache {
     fn extend_tip<C>(&mut self, _header: BlockHeader, _context: &C) -> Result<ImportResult, Error> {
         unimplemented!()
     }
+
+    fn rollback(&mut self, _height: Height) -> Result<Vec<(Height, BlockHeader)>, Error> {
+        unimplemented!()
+    }
 }
 
 impl BlockReader for HeightCache {
@@ -134,7 +138,7 @@ impl BlockReader for HeightCache {
         unimplemented!()
     }
 
-    fn locator_hashes(&self, _from: Height) -> Vec<BlockHash> {
+    fn locator_hashes_with(&self, _from: Height, _density: usize) -> Vec<BlockHash> {
         unimplemented!()
     }
 }
@@ -527,6 +531,134 @@ fn test_bitcoin_difficulty() {
     }
 }
 
+// Test that the difficulty adjustment policy is applied at the adjustment boundary.
+#[test]
+fn test_cache_retarget_policy() {
+    let network = bitcoin::Network::Regtest;
+    // Adjust the difficulty every ten blocks.
+    let params = Params {
+        pow_target_timespan: 10 * TARGET_SPACING as u64,
+        ..Params::new(network)
+    };
+    let interval = params.difficulty_adjustment_interval();
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+
+    let next = |prev: &BlockHeader, bits| {
+        let mut header = BlockHeader {
+            version: 1,
+            time: prev.time + 60,
+            bits,
+            merkle_root: TxMerkleNode::all_zeros(),
+            prev_blockhash: prev.block_hash(),
+            nonce: 0,
+        };
+        block::solve(&mut header);
+        header
+    };
+    // Start from a higher difficulty than the proof-of-work limit, so that the adjusted
+    // target can be computed without overflowing.
+    let genesis = BlockHeader {
+        bits: 0x1f0fffff,
+        ..constants::genesis_block(network).header
+    };
+
+    // Mine up to the adjustment boundary, ten times faster than the target spacing.
+    let mut headers = vec![next(&genesis, genesis.bits)];
+    for _ in 2..interval {
+        let prev = headers.last().unwrap();
+        headers.push(next(prev, prev.bits));
+    }
+    let tip = *headers.last().unwrap();
+    let unchanged = next(&tip, tip.bits);
+    // A quarter of the previous target, since the interval was mined in less than a
+    // quarter of the target timespan.
+    let adjusted = next(&tip, 0x1f03ffff);
+
+    // On regtest, the target is never adjusted.
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut cache = BlockCache::from(store, params.clone(), &[]).unwrap();
+    assert_eq!(
+        cache.retarget(),
+        Retarget {
+            adjust: false,
+            allow_min_difficulty: true
+        }
+    );
+    cache.import_blocks(headers.iter().cloned(), &ctx).unwrap();
+    assert_eq!(cache.height(), interval - 1);
+    assert_matches!(
+        cache.import_block(adjusted, &ctx),
+        Err(Error::InvalidBlockTarget(..))
+    );
+    assert_matches!(
+        cache.import_block(unchanged, &ctx),
+        Ok(ImportResult::TipChanged(..))
+    );
+
+    // With strict retargeting, as on mainnet, the target is adjusted at the boundary.
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .with_retarget(Retarget::STRICT);
+    assert_eq!(cache.retarget(), Retarget::STRICT);
+    cache.import_blocks(headers.iter().cloned(), &ctx).unwrap();
+    assert_eq!(cache.height(), interval - 1);
+    assert_matches!(
+        cache.import_block(unchanged, &ctx),
+        Err(Error::InvalidBlockTarget(..))
+    );
+    assert_matches!(
+        cache.import_block(adjusted, &ctx),
+        Ok(ImportResult::TipChanged(..))
+    );
+}
+
+#[test]
+fn test_cache_trusted_pow() {
+    let network = bitcoin::Network::Bitcoin;
+    let params = Params::new(network);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let genesis = constants::genesis_block(network).header;
+
+    // A header that connects to genesis, but isn't mined.
+    let header = BlockHeader {
+        version: 1,
+        time: genesis.time + TARGET_SPACING,
+        bits: genesis.bits,
+        merkle_root: TxMerkleNode::all_zeros(),
+        prev_blockhash: genesis.block_hash(),
+        nonce: 0,
+    };
+    assert!(header.validate_pow(&header.target()).is_err());
+
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut cache = BlockCache::from(store, params.clone(), &[]).unwrap();
+    assert_matches!(
+        cache.import_block(header, &ctx),
+        Err(Error::InvalidBlockPoW)
+    );
+
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .with_trusted_pow(true);
+    assert_matches!(
+        cache.import_block(header, &ctx),
+        Ok(ImportResult::TipChanged(..))
+    );
+
+    // Headers must still be valid otherwise.
+    let header = BlockHeader {
+        prev_blockhash: header.block_hash(),
+        time: genesis.time,
+        ..header
+    };
+    assert_matches!(
+        cache.import_block(header, &ctx),
+        Err(Error::InvalidBlockTime(_, std::cmp::Ordering::Less))
+    );
+}
+
 // Test that we're correctly loading headers from the header store.
 #[test]
 fn test_from_store() {
@@ -1130,6 +1262,104 @@ fn test_cache_import_with_checkpoints() {
         .expect("Correct checkpoints cause no error");
 }
 
+#[test]
+fn test_cache_locator_hashes_with_checkpoints() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let g = &mut fastrand::Rng::new();
+
+    let tree = Tree::new(genesis);
+    let mut chain = vec![tree.next(g)];
+    for _ in 1..20 {
+        let next = chain.last().unwrap().next(g);
+        chain.push(next);
+    }
+    let (a1, a3, a20) = (&chain[0], &chain[2], chain.last().unwrap());
+
+    let mut cache = BlockCache::from(store, params, &[(3, a3.hash)]).unwrap();
+    cache.import_blocks(tree.branch([a1, a20]), &ctx).unwrap();
+
+    let locators = cache.locator_hashes(cache.height());
+
+    assert_eq!(locators.first(), Some(&a20.hash));
+    assert_eq!(
+        locators.last(),
+        Some(&a3.hash),
+        "The locator is anchored at the last checkpoint"
+    );
+    assert!(!locators.contains(&genesis.block_hash()));
+}
+
+#[test]
+fn test_cache_prune() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let interval = params.difficulty_adjustment_interval();
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let tmp = tempfile::tempdir().unwrap();
+    let path = tmp.path().join("headers.db");
+    let store = store::File::create(&path, genesis).unwrap();
+    let g = &mut fastrand::Rng::new();
+
+    let tree = Tree::new(genesis);
+    let mut chain = vec![tree.next(g)];
+    for _ in 1..interval + 100 {
+        let next = chain.last().unwrap().next(g);
+        chain.push(next);
+    }
+    let (a1, a50, a100, tip) = (&chain[0], &chain[49], &chain[99], chain.last().unwrap());
+
+    let mut cache = BlockCache::from(store, params.clone(), &[]).unwrap();
+    cache.import_blocks(tree.branch([a1, tip]), &ctx).unwrap();
+
+    let work = cache.chain_work();
+
+    // Enough blocks are always kept to validate new blocks.
+    assert_eq!(cache.prune(interval + 50).unwrap(), 100);
+    assert_eq!(cache.prune(80).unwrap(), 100, "pruned blocks stay pruned");
+
+    for mut cache in [
+        cache,
+        BlockCache::from(store::File::open(&path, genesis).unwrap(), params, &[]).unwrap(),
+    ] {
+        assert_eq!(cache.height(), interval + 100);
+        assert_eq!(cache.tip().0, tip.hash);
+        assert_eq!(cache.genesis(), &genesis);
+        assert_eq!(cache.chain_work(), work, "pruned work is kept");
+        assert_eq!(cache.get_block_by_height(99), None);
+        assert_eq!(cache.get_block_by_height(100), Some(&a100.block()));
+        assert_eq!(cache.get_block(&a50.hash), None);
+        assert_eq!(
+            cache.iter().map(|(h, _)| h).take(2).collect::<Vec<_>>(),
+            vec![0, 100]
+        );
+
+        // The first block kept acts as a checkpoint.
+        assert_eq!(cache.last_checkpoint(), 100);
+        assert_eq!(
+            cache.locator_hashes(cache.height()).last(),
+            Some(&a100.hash)
+        );
+
+        // Forks below the pruned blocks are rejected.
+        let fork = a50.next(g);
+        assert_matches!(
+            cache.import_blocks(iter::once(fork.block()), &ctx),
+            Ok(ImportResult::TipUnchanged)
+        );
+
+        // The chain can still be extended.
+        assert_matches!(
+            cache.import_blocks(iter::once(tip.next(g).block()), &ctx),
+            Ok(ImportResult::TipChanged(..))
+        );
+    }
+}
+
 #[test]
 fn test_cache_import_invalid_fork() {
     let network = bitcoin::Network::Regtest;
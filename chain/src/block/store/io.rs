@@ -4,12 +4,12 @@ use std::io::{self, Read, Seek, Write};
 use std::iter;
 use std::marker::PhantomData;
 use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use nakamoto_common::bitcoin::consensus::encode::{Decodable, Encodable};
 
 use nakamoto_common::block::store::{Error, Store};
-use nakamoto_common::block::Height;
+use nakamoto_common::block::{Height, Work};
 
 /// Append a block to the end of the stream.
 fn put<H: Sized + Encodable, S: Seek + Write, I: Iterator<Item = H>>(
@@ -36,6 +36,35 @@ fn get<H: Decodable, S: Seek + Read>(mut stream: S, ix: u64) -> Result<H, Error>
     H::consensus_decode(&mut buf.as_slice()).map_err(Error::from)
 }
 
+/// Get the number of pruned records at the start of the stream, and their total work.
+/// Pruned records always form a prefix of the stream: the first one holds the work, and the
+/// others are all zeros.
+fn pruned<S: Seek + Read>(mut stream: S, size: usize) -> Result<(u64, Work), Error> {
+    let len = stream.seek(io::SeekFrom::End(0))? / size as u64;
+    let mut buf = vec![0; size];
+    let (mut lo, mut hi) = (1, len);
+
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+
+        stream.seek(io::SeekFrom::Start(mid * size as u64))?;
+        stream.read_exact(&mut buf)?;
+
+        if buf.iter().all(|b| *b == 0) {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    // Since at least two records are pruned, a non-zero second record means nothing is.
+    if lo < 2 {
+        return Ok((0, Work::default()));
+    }
+    let work = get(&mut stream, 0)?;
+
+    Ok((lo, work))
+}
+
 /// An iterator over block headers in a file.
 #[derive(Debug)]
 pub struct Iter<H> {
@@ -69,32 +98,56 @@ impl<H: Decodable> Iterator for Iter<H> {
 }
 
 /// A `Store` backed by a single file.
+///
+/// Pruned headers are replaced by a hole at the start of the file, so that the offsets of the
+/// remaining headers don't change. On most file systems, holes don't take up disk space.
 #[derive(Debug)]
 pub struct File<H> {
     file: fs::File,
+    path: PathBuf,
     genesis: H,
+    /// Number of headers pruned after the genesis.
+    pruned: Height,
+    /// Total work of the pruned headers.
+    pruned_work: Work,
 }
 
 impl<H> File<H> {
     /// Open a new file store from the given path and genesis header.
-    pub fn open<P: AsRef<Path>>(path: P, genesis: H) -> io::Result<Self> {
-        fs::OpenOptions::new()
+    pub fn open<P: AsRef<Path>>(path: P, genesis: H) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = fs::OpenOptions::new()
             .create(true)
             .read(true)
             .append(true)
-            .open(path)
-            .map(|file| Self { file, genesis })
+            .open(&path)?;
+        let (pruned, pruned_work) = self::pruned(&mut file, mem::size_of::<H>())?;
+
+        Ok(Self {
+            file,
+            path,
+            genesis,
+            pruned,
+            pruned_work,
+        })
     }
 
     /// Create a new file store at the given path, with the provided genesis header.
     pub fn create<P: AsRef<Path>>(path: P, genesis: H) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
         let file = fs::OpenOptions::new()
             .create_new(true)
             .read(true)
             .append(true)
-            .open(path)?;
-
-        Ok(Self { file, genesis })
+            .open(&path)?;
+
+        Ok(Self {
+            file,
+            path,
+            genesis,
+            pruned: 0,
+            pruned_work: Work::default(),
+        })
     }
 }
 
@@ -114,6 +167,9 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
     /// Get the block at the given height. Returns `io::ErrorKind::UnexpectedEof` if
     /// the height is not found.
     fn get(&self, height: Height) -> Result<H, Error> {
+        if height > 0 && height <= self.pruned {
+            return Err(Error::Pruned(height));
+        }
         if let Some(ix) = height.checked_sub(1) {
             // Clone so this function doesn't have to take a `&mut self`.
             let mut file = self.file.try_clone()?;
@@ -128,6 +184,7 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
     fn rollback(&mut self, height: Height) -> Result<(), Error> {
         let size = mem::size_of::<H>();
 
+        self.pruned = self.pruned.min(height);
         self.file
             .set_len((height) * size as u64)
             .map_err(Error::from)
@@ -143,7 +200,7 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
         // Clone so this function doesn't have to take a `&mut self`.
         match self.file.try_clone() {
             Ok(file) => Box::new(iter::once(Ok((0, self.genesis))).chain(Iter {
-                height: 1,
+                height: self.pruned + 1,
                 file,
                 _phantom: PhantomData,
             })),
@@ -190,6 +247,54 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
 
         Ok(())
     }
+
+    /// Drop the headers below the given height, by copying the remaining headers to a new
+    /// file, after a hole of the same size as the dropped headers. The total work of the
+    /// dropped headers is written at the start of the hole.
+    fn prune(&mut self, below: Height, work: Work) -> Result<(), Error> {
+        let size = mem::size_of::<H>() as u64;
+        let pruned = below.saturating_sub(1).min(self.height()?);
+
+        if pruned <= self.pruned {
+            return Ok(());
+        }
+        assert!(
+            pruned >= 2,
+            "File::prune: at least two headers must be pruned"
+        );
+        assert!(size as usize >= mem::size_of::<Work>());
+
+        let tmp = self.path.with_extension("prune");
+        let mut file = self.file.try_clone()?;
+        let mut pruned_file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp)?;
+
+        pruned_file.set_len(pruned * size)?;
+        work.consensus_encode(&mut pruned_file)?;
+        pruned_file.seek(io::SeekFrom::End(0))?;
+        file.seek(io::SeekFrom::Start(pruned * size))?;
+        io::copy(&mut file, &mut pruned_file)?;
+        pruned_file.sync_all()?;
+
+        fs::rename(&tmp, &self.path)?;
+
+        self.file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.pruned = pruned;
+        self.pruned_work = work;
+
+        Ok(())
+    }
+
+    /// Get the total work of the pruned headers.
+    fn pruned_work(&self) -> Work {
+        self.pruned_work
+    }
 }
 
 #[cfg(test)]
@@ -198,6 +303,7 @@ mod test {
 
     use nakamoto_common::bitcoin::TxMerkleNode;
     use nakamoto_common::bitcoin_hashes::Hash;
+    use nakamoto_common::block::tree::Branch;
     use nakamoto_common::block::BlockHash;
 
     use super::{Error, File, Height, Store};
@@ -344,6 +450,42 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_prune() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("headers.db");
+        let genesis = store("genesis.db").genesis;
+        let mut store = File::open(&path, genesis).unwrap();
+
+        let header = BlockHeader {
+            version: 1,
+            prev_blockhash: genesis.block_hash(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            bits: 0x2ffffff,
+            time: 1842918273,
+            nonce: 0,
+        };
+        let headers = (0..32)
+            .map(|i| BlockHeader { nonce: i, ..header })
+            .collect::<Vec<_>>();
+
+        let work = Branch(&headers[..16]).work();
+
+        store.put(headers.iter().cloned()).unwrap();
+        store.prune(17, work).unwrap();
+
+        for store in [store, File::open(&path, genesis).unwrap()] {
+            assert_eq!(store.len().unwrap(), 33, "pruning doesn't change heights");
+            assert_eq!(store.pruned_work(), work);
+            assert_eq!(store.get(0).unwrap(), genesis);
+            assert!(matches!(store.get(16), Err(Error::Pruned(16))));
+            assert_eq!(store.get(17).unwrap(), headers[16]);
+
+            let heights = store.iter().map(|r| r.unwrap().0).collect::<Vec<_>>();
+            assert_eq!(heights, iter::once(0).chain(17..=32).collect::<Vec<_>>());
+        }
+    }
+
     #[test]
     fn test_corrupt_file() {
         let mut store = store("headers.db");
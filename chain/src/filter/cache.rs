@@ -126,6 +126,22 @@ impl<S> FilterCache<S> {
                 return Err(store::Error::Interrupted);
             }
         }
+        self.verify_checkpoints(network.filter_checkpoints())
+    }
+
+    /// Verify the filter header chain against known checkpoints. Returns the height of the
+    /// first mismatching checkpoint on error.
+    pub fn verify_checkpoints(
+        &self,
+        checkpoints: impl IntoIterator<Item = (Height, FilterHeader)>,
+    ) -> Result<(), store::Error> {
+        for (height, checkpoint) in checkpoints {
+            if let Some(stored) = self.headers.get(height as usize) {
+                if stored.header != checkpoint {
+                    return Err(store::Error::Checkpoint(height));
+                }
+            }
+        }
         Ok(())
     }
 }
@@ -184,3 +200,42 @@ impl<S: Store<Header = StoredHeader>> Filters for FilterCache<S> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use nakamoto_common::block::filter::FilterHeader;
+    use nakamoto_test::block::gen;
+
+    use super::*;
+    use crate::block::store::memory::Memory;
+
+    #[test]
+    fn test_verify_checkpoints() {
+        let network = Network::Regtest;
+        let mut rng = fastrand::Rng::new();
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis.clone(), 20, &mut rng);
+        let fork = gen::blockchain(genesis, 20, &mut rng);
+        let cfheaders =
+            gen::cfheaders_from_blocks(FilterHeader::genesis(network), chain.tail.iter());
+        let forked = gen::cfheaders_from_blocks(FilterHeader::genesis(network), fork.tail.iter());
+
+        let mut cache = FilterCache::load(Memory::genesis(network)).unwrap();
+        cache.import_headers(cfheaders.clone()).unwrap();
+        cache.verify(network).unwrap();
+
+        // Checkpoints are indexed by height, and the genesis is at height zero.
+        let (_, checkpoint) = cfheaders[9];
+        cache.verify_checkpoints([(10, checkpoint)]).unwrap();
+
+        // Checkpoints above the tip are ignored.
+        let (_, mismatch) = forked[9];
+        cache.verify_checkpoints([(21, mismatch)]).unwrap();
+
+        // A mismatching filter header is rejected.
+        assert!(matches!(
+            cache.verify_checkpoints([(10, checkpoint), (15, mismatch)]),
+            Err(store::Error::Checkpoint(15))
+        ));
+    }
+}
@@ -4,6 +4,7 @@ use thiserror::Error;
 
 pub use nakamoto_common::block::filter::{BlockFilter, FilterHash, FilterHeader, Filters};
 pub use nakamoto_common::block::store::Store;
+use nakamoto_common::block::Height;
 
 pub type File = crate::store::io::File<FilterHeader>;
 pub type Memory = crate::store::memory::Memory<FilterHeader>;
@@ -13,6 +14,8 @@ pub type Memory = crate::store::memory::Memory<FilterHeader>;
 pub enum Error {
     #[error("filter store is corrupted")]
     Integrity,
+    #[error("filter header at height {0} doesn't match checkpoint")]
+    Checkpoint(Height),
     #[error("the operation was interrupted")]
     Interrupted,
 }
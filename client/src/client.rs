@@ -8,33 +8,41 @@ use std::net;
 use std::ops::ControlFlow;
 use std::ops::RangeInclusive;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::{self, SystemTime};
 
 pub use crossbeam_channel as chan;
 
 use nakamoto_chain::block::{store, Block};
 use nakamoto_chain::filter;
-use nakamoto_chain::filter::cache::FilterCache;
+use nakamoto_chain::filter::cache::{FilterCache, Filters as _};
 use nakamoto_chain::{block::cache::BlockCache, filter::BlockFilter};
 
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::message::NetworkMessage;
 use nakamoto_common::bitcoin::network::Address;
+use nakamoto_common::bitcoin::Txid;
 use nakamoto_common::block::store::{Genesis as _, Store as _};
 use nakamoto_common::block::time::{AdjustedTime, RefClock};
 use nakamoto_common::block::tree::{self, BlockReader, ImportResult};
-use nakamoto_common::block::{BlockHash, BlockHeader, Height, Transaction};
+use nakamoto_common::block::{BlockHash, BlockHeader, Height, Transaction, Work};
 use nakamoto_common::nonempty::NonEmpty;
 use nakamoto_common::p2p::peer::{Source, Store as _};
 
+pub use nakamoto_common::block::Retarget;
 pub use nakamoto_common::network::{Network, Services};
-pub use nakamoto_common::p2p::Domain;
+pub use nakamoto_common::p2p::{Cidr, Domain};
 
 use nakamoto_p2p::fsm;
 
 pub use nakamoto_net::event;
-pub use nakamoto_net::{Reactor, Waker};
-pub use nakamoto_p2p::fsm::{Command, CommandError, ConnDirection, Hooks, Limits, Peer};
+pub use nakamoto_net::{Keepalive, Reactor, ReactorConfig, Waker};
+pub use nakamoto_p2p::fsm::{
+    AddrStats, Capabilities, Command, CommandError, ConnDirection, GetAddrLimits, Health, Hooks,
+    InFlight, Latencies, Limits, Metrics, Peer, Percentiles, Request, SyncStatus,
+};
 
 pub use crate::error::Error;
 pub use crate::event::{Event, Loading};
@@ -43,6 +51,18 @@ pub use crate::peer;
 pub use crate::service::Service;
 pub use crate::spv;
 
+use crate::dns;
+use crate::journal::{self, Journal};
+
+/// Interval, in headers, between two [`Loading`] progress events of the same kind.
+pub const LOADING_EVENT_INTERVAL: Height = 1000;
+
+/// Whether a [`Loading`] progress event should be emitted for the given height.
+/// The last height is always reported, so that consumers see the phase complete.
+fn is_progress(height: Height, total: Height) -> bool {
+    height % LOADING_EVENT_INTERVAL == 0 || height == total
+}
+
 /// Client configuration.
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -52,18 +72,164 @@ pub struct Config {
     pub domains: Vec<Domain>,
     /// Peers to connect to instead of using the peer discovery mechanism.
     pub connect: Vec<net::SocketAddr>,
+    /// Peers to connect to exclusively. If non-empty, the client never dials any other
+    /// peer, and DNS seeding is disabled. Inbound connections are governed by `listen`.
+    pub connect_only: Vec<net::SocketAddr>,
+    /// Peers to fail over between, in order of preference, eg. one's own full nodes. Only
+    /// one of them is connected to at a time. When it disconnects, the next one is tried,
+    /// which is reported with [`Event::PeerFailedOver`]. DNS seeding is disabled if this
+    /// is non-empty.
+    pub failover: Vec<net::SocketAddr>,
     /// Client listen addresses.
     pub listen: Vec<net::SocketAddr>,
     /// Client home path, where runtime data is stored, eg. block headers and filters.
     pub root: PathBuf,
-    /// User agent string.
-    pub user_agent: &'static str,
+    /// User agent string, sent to peers during the handshake. It must be at most
+    /// [`fsm::MAX_USER_AGENT_LENGTH`] bytes long, and not contain control characters.
+    pub user_agent: String,
     /// Client hooks.
     pub hooks: Hooks,
     /// Services offered by this node.
     pub services: ServiceFlags,
-    /// Configured limits.
+    /// Address ranges allowed to connect to us. If empty, any address that isn't denied
+    /// is allowed.
+    pub inbound_allow: Vec<Cidr>,
+    /// Address ranges denied from connecting to us. Takes precedence over
+    /// [`Config::inbound_allow`].
+    pub inbound_deny: Vec<Cidr>,
+    /// Services required from outbound peers. Peers that don't advertise all of these
+    /// services are disconnected during the handshake, unless they are whitelisted.
+    pub required_services: ServiceFlags,
+    /// Minimum protocol version of peers. Older peers are disconnected during the handshake,
+    /// whether or not they have the required services.
+    pub min_protocol_version: u32,
+    /// Configured limits. Setting [`Limits::max_outbound_peers`] to zero runs the client in
+    /// inbound-only mode, in which DNS seeds aren't queried either.
     pub limits: Limits,
+    /// Time to wait for an outbound connection to be established, before giving up.
+    /// Failed attempts are reported with [`Event::PeerConnectionFailed`].
+    pub connect_timeout: time::Duration,
+    /// Time to wait for a connected peer to complete the handshake, before disconnecting it.
+    pub handshake_timeout: time::Duration,
+    /// Rotate a random outbound peer at this interval, for a peer in a network group we're
+    /// not connected to, to make long-lived eclipse attacks harder. If not set, peers are
+    /// only rotated with [`handle::Handle::rotate_peer`].
+    pub rotate_interval: Option<time::Duration>,
+    /// Time to wait for a block requested from a peer, eg. after a filter match, before
+    /// requesting it from another peer. Slow peers are then avoided for block requests.
+    /// The wait grows with each attempt, so that large blocks can still be downloaded
+    /// over slow links.
+    pub block_download_timeout: time::Duration,
+    /// Block header checkpoints, as height and block hash pairs. Headers below the last
+    /// checkpoint are only lightly validated, and header sync resumes from it. If not set,
+    /// the checkpoints of the configured network are used.
+    pub checkpoints: Option<Vec<(Height, BlockHash)>>,
+    /// Maximum ratio of compact filters from a single peer that may match the watchlist.
+    /// Peers whose filters match more often are reported with [`Event::SuspiciousMatchRate`].
+    pub max_filter_match_rate: f64,
+    /// Number of compact filters processed from a peer before its match rate is checked.
+    pub filter_match_rate_sample_size: u64,
+    /// Number of peers each compact filter is fetched from, and cross-checked between.
+    /// Peers sending conflicting filters are disconnected, which is reported with
+    /// [`Event::FilterConflict`]. Higher values protect against peers hiding transactions
+    /// from the client, at the cost of bandwidth.
+    pub filter_redundancy: usize,
+    /// Fall back to BIP 37 bloom filters and merkle blocks with peers that don't serve
+    /// compact block filters. Disabled by default, since bloom filters reveal the watched
+    /// scripts to the peers they are loaded on.
+    pub bloom_fallback: bool,
+    /// Only sync block headers, without downloading compact block filters. Useful for
+    /// applications that only follow the chain tip. In this mode, [`Event::Synced`] is
+    /// emitted when the header chain is synced.
+    pub headers_only: bool,
+    /// Prune block headers deeper than this below the tip from the header store, once their
+    /// filters are processed, to bound disk usage. Pruned headers can't be rescanned.
+    /// If not set, all headers are kept.
+    pub prune_depth: Option<Height>,
+    /// Use BIP 152 compact block relay with peers that support it. New blocks are then
+    /// announced to the client as soon as they are found, and requested blocks can be
+    /// reconstructed from known transactions.
+    pub compact_blocks: bool,
+    /// Probability of downloading a block whose compact filter doesn't match the watchlist,
+    /// as a decoy. This makes it harder for peers to tell which blocks the client is
+    /// interested in, at the cost of bandwidth. Decoy blocks are not reported as matches.
+    pub filter_decoy_rate: f64,
+    /// Maximum number of headers in a `headers` message, sent or expected from peers.
+    pub max_message_headers: usize,
+    /// Maximum number of filter headers requested from a peer at once.
+    pub max_message_cfheaders: usize,
+    /// Maximum number of filter header requests inflight at once. Above one, filter headers
+    /// are synced from checkpoints, in parallel.
+    pub max_cfheaders_requests: usize,
+    /// Serve cached compact filters and filter header checkpoints to inbound peers. This is
+    /// best-effort, since only filters in the cache can be served; compact filters support
+    /// is therefore not advertised, and peers must be configured to request filters from
+    /// the client, eg. in a private network.
+    pub serve_filters: bool,
+    /// Serve block headers to peers that request them, once synced. Requests are
+    /// rate-limited per peer.
+    pub serve_headers: bool,
+    /// Maximum number of header requests, and of filter requests, served to a single peer
+    /// per minute. Requests beyond this are ignored.
+    pub serve_budget: usize,
+    /// Number of consecutive block hashes in block locators, before stepping back
+    /// exponentially.
+    pub locator_density: usize,
+    /// Minimum proof-of-work of the header chain, below which the client doesn't consider
+    /// itself synced. Defaults to [`Network::minimum_chain_work`] if not set.
+    pub minimum_chain_work: Option<Work>,
+    /// Seed for the random number generator used in peer selection, nonces and other
+    /// protocol decisions. Setting it makes the client's behavior reproducible given the same
+    /// inputs, which is useful for debugging. If not set, the generator is seeded from entropy.
+    pub rng_seed: Option<u64>,
+    /// DNS seeds used to bootstrap the address book when it is empty, and to refill it when
+    /// it is exhausted. If not set, the network's default seeds are used.
+    pub dns_seeds: Vec<String>,
+    /// Query DNS seeds when the address book is empty or exhausted. Outcomes are reported
+    /// with [`Event::DnsSeedResolved`] and [`Event::DnsSeedFailed`]. Disabling this avoids
+    /// leaking the client's use of Bitcoin to DNS resolvers, eg. when connecting over Tor.
+    pub dns_seeding: bool,
+    /// Thresholds for asking peers for addresses, to keep the address book fresh.
+    pub getaddr: GetAddrLimits,
+    /// File in which the fee estimates of recent blocks are saved, to be restored on startup
+    /// if they are less than [`crate::fees::MAX_HISTORY_AGE`] old. This avoids having no fee
+    /// estimates right after a restart. If not set, fee estimates are not saved.
+    pub fee_history: Option<PathBuf>,
+    /// File to which every client [`Event`] is appended, with its sequence number, so that
+    /// events can be replayed after a restart. See [`crate::journal`]. If not set, events
+    /// aren't logged.
+    pub event_log: Option<PathBuf>,
+    /// When to flush the event log to disk. By default, it's flushed in batches of
+    /// [`journal::DEFAULT_SYNC_EVENTS`] events. Set to [`journal::SyncPolicy::Always`] not to
+    /// lose any events on a crash, at the cost of performance.
+    pub event_log_sync: journal::SyncPolicy,
+    /// Sample the fee rates of unconfirmed transactions announced by peers, and blend them
+    /// with block-based fee estimates, to better reflect current conditions. This costs
+    /// bandwidth, since peers then relay transactions to the client.
+    pub mempool_fees: bool,
+    /// Minimum fee rate, in satoshis/vByte, of transactions peers should relay to the
+    /// client when [`Config::mempool_fees`] is set. If not set, it's derived from the fee
+    /// rates of recent blocks.
+    pub fee_filter: Option<fsm::fees::FeeRate>,
+    /// Trust the difficulty and proof-of-work of block headers instead of validating them,
+    /// to save CPU time during the initial sync on constrained hardware. Headers must still
+    /// connect, and match checkpoints.
+    ///
+    /// **This is only safe if all headers come from a trusted peer**, eg. one's own full
+    /// node over a secure channel, since any peer could otherwise feed the client an
+    /// invalid chain. For this reason, it requires [`Config::connect_only`] or
+    /// [`Config::failover`] to be set, and [`Config::listen`] to be empty.
+    pub trusted_headers: bool,
+    /// Maximum number of blocks of the active chain that a re-org may revert. Forks deeper
+    /// than this are refused, even if they have more work, and the peers proposing them are
+    /// disconnected. See [`Event::DeepReorgDetected`](crate::Event::DeepReorgDetected).
+    pub max_reorg_depth: Height,
+    /// Time to wait for a DNS seed to respond, before moving on to the next one.
+    pub dns_timeout: time::Duration,
+    /// Difficulty adjustment policy used to validate block headers. If not set, it's
+    /// derived from the network's consensus parameters. Useful for custom chains, eg. a
+    /// regtest network with difficulty adjustment.
+    pub retarget: Option<Retarget>,
 }
 
 impl Config {
@@ -96,48 +262,59 @@ impl Default for Config {
         Self {
             network: Network::default(),
             connect: Vec::new(),
+            connect_only: Vec::new(),
+            failover: Vec::new(),
             domains: Domain::all(),
             listen: vec![([0, 0, 0, 0], 0).into()],
             root: PathBuf::from(env::var("HOME").unwrap_or_default()),
-            user_agent: fsm::USER_AGENT,
+            user_agent: fsm::USER_AGENT.to_owned(),
             hooks: Hooks::default(),
             limits: Limits::default(),
             services: ServiceFlags::NONE,
+            required_services: fsm::Config::default().required_services,
+            min_protocol_version: fsm::Config::default().min_protocol_version,
+            inbound_allow: Vec::new(),
+            inbound_deny: Vec::new(),
+            connect_timeout: fsm::Config::default().connect_timeout.into(),
+            handshake_timeout: fsm::Config::default().handshake_timeout.into(),
+            rotate_interval: None,
+            block_download_timeout: fsm::Config::default().block_download_timeout.into(),
+            checkpoints: None,
+            max_filter_match_rate: fsm::Config::default().max_filter_match_rate,
+            filter_match_rate_sample_size: fsm::Config::default().filter_match_rate_sample_size,
+            filter_redundancy: fsm::Config::default().filter_redundancy,
+            bloom_fallback: false,
+            headers_only: false,
+            prune_depth: None,
+            compact_blocks: false,
+            filter_decoy_rate: 0.,
+            max_message_headers: fsm::Config::default().max_message_headers,
+            max_message_cfheaders: fsm::Config::default().max_message_cfheaders,
+            max_cfheaders_requests: fsm::Config::default().max_cfheaders_requests,
+            serve_filters: false,
+            serve_headers: fsm::Config::default().serve_headers,
+            serve_budget: fsm::Config::default().serve_budget,
+            locator_density: fsm::Config::default().locator_density,
+            minimum_chain_work: None,
+            rng_seed: None,
+            dns_seeds: Vec::new(),
+            dns_seeding: true,
+            getaddr: GetAddrLimits::default(),
+            fee_history: None,
+            event_log: None,
+            event_log_sync: journal::SyncPolicy::default(),
+            mempool_fees: false,
+            fee_filter: None,
+            trusted_headers: false,
+            max_reorg_depth: fsm::Config::default().max_reorg_depth,
+            dns_timeout: dns::DNS_TIMEOUT,
+            retarget: None,
         }
     }
 }
 
 /// The client's event publisher.
-pub struct Publisher<E> {
-    publishers: Vec<Box<dyn nakamoto_net::Publisher<E>>>,
-}
-
-impl<E> Publisher<E> {
-    /// Register a publisher.
-    pub fn register(mut self, publisher: impl nakamoto_net::Publisher<E> + 'static) -> Self {
-        self.publishers.push(Box::new(publisher));
-        self
-    }
-}
-
-impl<E> Default for Publisher<E> {
-    fn default() -> Self {
-        Self {
-            publishers: Vec::new(),
-        }
-    }
-}
-
-impl<E> nakamoto_net::Publisher<E> for Publisher<E>
-where
-    E: Clone,
-{
-    fn publish(&mut self, e: E) {
-        for p in self.publishers.iter_mut() {
-            p.publish(e.clone());
-        }
-    }
-}
+pub type Publisher<E> = event::Publishers<E>;
 
 /// A light-client process.
 pub struct Client<R: Reactor> {
@@ -147,11 +324,15 @@ pub struct Client<R: Reactor> {
     blocks: event::Subscriber<(Block, Height)>,
     filters: event::Subscriber<(BlockFilter, BlockHash, Height)>,
     loading: event::Emitter<Loading>,
-    subscriber: event::Subscriber<Event>,
+    subscriber: event::Subscriber<(u64, Event)>,
     shutdown: chan::Sender<()>,
     listening: chan::Receiver<net::SocketAddr>,
+    listen_addrs: Arc<Mutex<Vec<net::SocketAddr>>>,
     seeds: Vec<net::SocketAddr>,
     publisher: Publisher<fsm::Event>,
+    journal: Arc<Mutex<Option<Journal>>>,
+    seq: Arc<AtomicU64>,
+    published: Arc<AtomicU64>,
 
     reactor: R,
 }
@@ -162,6 +343,11 @@ where
 {
     /// Create a new client.
     pub fn new() -> Result<Self, Error> {
+        Self::with_reactor_config(ReactorConfig::default())
+    }
+
+    /// Create a new client, with the given socket options for peer connections.
+    pub fn with_reactor_config(config: ReactorConfig) -> Result<Self, Error> {
         let (handle, commands) = chan::unbounded::<Command>();
         let (event_pub, events) = event::broadcast(|e, p| p.emit(e));
         let (blocks_pub, blocks) = event::broadcast(|e, p| {
@@ -185,9 +371,45 @@ where
                 p.emit((filter, block_hash, height));
             }
         });
-        let (publisher, subscriber) = event::broadcast({
+        // State machine events are counted as they are mapped, so that client events can be
+        // matched against a state snapshot. See [`fsm::Snapshot::events`].
+        let published = Arc::new(AtomicU64::new(0));
+        let (publisher, mapped) = event::broadcast({
             let mut spv = spv::Mapper::new();
-            move |e, p| spv.process(e, p)
+            let published = published.clone();
+
+            move |e, p| {
+                published.fetch_add(1, Ordering::SeqCst);
+                spv.process(e, p)
+            }
+        });
+
+        // Client events are stamped with a sequence number before they are published.
+        let seq = Arc::new(AtomicU64::new(0));
+        let sequenced = event::Emitter::default();
+        let subscriber = sequenced.subscriber();
+
+        mapped.subscribe_fn({
+            let seq = seq.clone();
+            move |e| {
+                sequenced.emit((seq.fetch_add(1, Ordering::SeqCst), e.clone()));
+                true
+            }
+        });
+
+        // Nb. The event log subscribes first, so that events are logged before they reach
+        // any other subscriber.
+        let journal: Arc<Mutex<Option<Journal>>> = Arc::default();
+        subscriber.subscribe_fn({
+            let journal = journal.clone();
+            move |(seq, e)| {
+                if let Some(journal) = journal.lock().unwrap().as_mut() {
+                    if let Err(err) = journal.append(*seq, e) {
+                        log::error!(target: "client", "Failed to append event #{} to event log: {}", seq, err);
+                    }
+                }
+                true
+            }
         });
 
         let publisher = Publisher::default()
@@ -199,8 +421,8 @@ where
         let seeds = Vec::new();
         let loading = event::Emitter::default();
         let (shutdown, shutdown_recv) = chan::bounded(1);
-        let (listening_send, listening) = chan::bounded(1);
-        let reactor = R::new(shutdown_recv, listening_send)?;
+        let (listening_send, listening) = chan::unbounded();
+        let reactor = R::new(shutdown_recv, listening_send, config)?;
 
         Ok(Self {
             events,
@@ -212,9 +434,13 @@ where
             filters,
             subscriber,
             publisher,
+            journal,
+            seq,
+            published,
             seeds,
             shutdown,
             listening,
+            listen_addrs: Arc::default(),
         })
     }
 
@@ -227,8 +453,38 @@ where
         Ok(())
     }
 
+    /// Publish the client's protocol events to the given publisher, in addition to the
+    /// client's own subscribers. Can be called multiple times, eg. to send events to a
+    /// logger and to a metrics collector. Must be called before the client is run.
+    ///
+    /// Nb. Each publisher receives its own clone of every event. See [`event::Publishers`].
+    pub fn publish_to(&mut self, publisher: impl event::Publisher<fsm::Event> + 'static) {
+        self.publisher.add(publisher);
+    }
+
     /// Start the client process. This function is meant to be run in its own thread.
-    pub fn run(mut self, config: Config) -> Result<(), Error> {
+    pub fn run(mut self, config: Config) -> Result<(), Error>
+    where
+        R::Waker: 'static,
+    {
+        fsm::validate_user_agent(&config.user_agent)?;
+
+        if config.trusted_headers {
+            if (config.connect_only.is_empty() && config.failover.is_empty())
+                || !config.listen.is_empty()
+            {
+                return Err(fsm::ConfigError::UntrustedHeaderSource.into());
+            }
+            log::warn!(target: "client", "Header proof-of-work is trusted and won't be validated");
+        }
+        if config.limits.is_inbound_only()
+            && !(config.connect.is_empty()
+                && config.connect_only.is_empty()
+                && config.failover.is_empty())
+        {
+            return Err(fsm::ConfigError::ConnectInboundOnly.into());
+        }
+
         let home = config.root.join(".nakamoto");
         let network = config.network;
         let dir = home.join(network.as_str());
@@ -236,6 +492,18 @@ where
 
         fs::create_dir_all(&dir)?;
 
+        if let Some(path) = &config.event_log {
+            let journal = Journal::open(path, config.event_log_sync)?;
+
+            log::info!(
+                target: "client",
+                "Logging events to {:?}, starting at #{}", path, journal.next_seq()
+            );
+            // Sequence numbers carry on from the last logged event.
+            self.seq.store(journal.next_seq(), Ordering::SeqCst);
+            *self.journal.lock().unwrap() = Some(journal);
+        }
+
         let genesis = network.genesis();
         let params = network.params();
 
@@ -264,16 +532,18 @@ where
         };
 
         let local_time = SystemTime::now().into();
-        let checkpoints = network.checkpoints().collect::<Vec<_>>();
+        let checkpoints = config
+            .checkpoints
+            .clone()
+            .unwrap_or_else(|| network.checkpoints().collect());
         let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
-        let rng = fastrand::Rng::new();
-
-        log::info!(target: "client", "Loading block headers from store..");
-
-        let cache = BlockCache::new(store, params, &checkpoints)?.load_with(|height| {
-            self.loading.emit(Loading::BlockHeaderLoaded { height });
-            ControlFlow::Continue(())
-        })?;
+        let rng = match config.rng_seed {
+            Some(seed) => {
+                log::info!(target: "client", "Using RNG seed {}", seed);
+                fastrand::Rng::with_seed(seed)
+            }
+            None => fastrand::Rng::new(),
+        };
 
         log::info!(target: "client", "Initializing block filters..");
 
@@ -298,21 +568,66 @@ where
             }
             Err(err) => return Err(err.into()),
         };
+        let total_headers = store.height()?;
+        let total_filter_headers = cfheaders_store.height()?;
+
+        self.loading.emit(Loading::Started {
+            total_headers,
+            total_filter_headers,
+        });
+
+        log::info!(target: "client", "Loading block headers from store..");
+
+        let mut cache =
+            BlockCache::new(store, params, &checkpoints)?.with_trusted_pow(config.trusted_headers);
+
+        if let Some(retarget) = config.retarget {
+            log::info!(target: "client", "Using custom difficulty adjustment policy: {:?}", retarget);
+            cache = cache.with_retarget(retarget);
+        }
+        let cache = cache.load_with(|height| {
+            if is_progress(height, total_headers) {
+                self.loading.emit(Loading::BlockHeaderLoaded { height });
+            }
+            ControlFlow::Continue(())
+        })?;
+
         log::info!(target: "client", "Loading filter headers from store..");
 
-        let filters = FilterCache::load_with(cfheaders_store, |height| {
-            self.loading.emit(Loading::FilterHeaderLoaded { height });
+        let mut filters = FilterCache::load_with(cfheaders_store, |height| {
+            if is_progress(height, total_filter_headers) {
+                self.loading.emit(Loading::FilterHeaderLoaded { height });
+            }
             ControlFlow::Continue(())
         })?;
         log::info!(target: "client", "Verifying filter headers..");
 
-        filters.verify_with(network, |height| {
-            self.loading.emit(Loading::FilterHeaderVerified { height });
+        // Verify store integrity.
+        match filters.verify_with(network, |height| {
+            if is_progress(height, total_filter_headers) {
+                self.loading.emit(Loading::FilterHeaderVerified { height });
+            }
             ControlFlow::Continue(())
-        })?; // Verify store integrity.
-
-        // Loading is done, close all channels.
-        self.loading.close();
+        }) {
+            Err(filter::store::Error::Checkpoint(height)) => {
+                // Re-sync filter headers from the last checkpoint that matched.
+                let good = network
+                    .filter_checkpoints()
+                    .map(|(h, _)| h)
+                    .take_while(|h| *h < height)
+                    .last()
+                    .unwrap_or(0);
+
+                log::warn!(
+                    target: "client",
+                    "Filter header mismatch with checkpoint at height {}, rolling back to height {}..",
+                    height,
+                    good
+                );
+                filters.rollback(good)?;
+            }
+            result => result?,
+        }
 
         log::info!(target: "client", "Loading peer addresses..");
 
@@ -345,17 +660,65 @@ where
 
         log::trace!(target: "client", "{:#?}", peers);
 
-        if config.connect.is_empty() && peers.is_empty() {
-            log::info!(target: "client", "Address book is empty. Trying DNS seeds..");
-            peers.seed(
-                network.seeds().iter().map(|s| (*s, network.port())),
-                Source::Dns,
-            )?;
-            peers.flush()?;
-
-            log::info!(target: "client", "{} seeds added to address book", peers.len());
+        let dns_seeding = config.connect.is_empty()
+            && config.connect_only.is_empty()
+            && config.failover.is_empty()
+            && config.dns_seeding
+            && !config.limits.is_inbound_only();
+
+        if dns_seeding {
+            let seeds: Vec<String> = if config.dns_seeds.is_empty() {
+                let seeds = network.seeds();
+
+                log::info!(target: "client", "No DNS seeds configured, using {} default seed(s)", seeds.len());
+                self.loading
+                    .emit(Loading::DefaultDnsSeeds { seeds: seeds.len() });
+
+                seeds.iter().map(|s| s.to_string()).collect()
+            } else {
+                config.dns_seeds.clone()
+            };
+
+            if peers.is_empty() {
+                log::info!(target: "client", "Address book is empty. Trying DNS seeds..");
+
+                // Nb. The addresses are stored right away, so that they are available when
+                // the client starts, while the outcomes are passed on to the state machine,
+                // to be reported as events. If none of the seeds resolve, there is no one
+                // to connect to, so we bail.
+                let addrs = dns::resolve_with(
+                    &seeds,
+                    network.port(),
+                    config.dns_timeout,
+                    &rng,
+                    |seed, result| {
+                        self.handle
+                            .send(Command::DnsSeedResolved {
+                                seed: seed.to_owned(),
+                                result: result.map(<[_]>::to_vec).map_err(|e| e.to_string()),
+                            })
+                            .ok();
+                    },
+                )?;
+
+                peers.seed(addrs.into_iter(), Source::Dns)?;
+                peers.flush()?;
+
+                log::info!(target: "client", "{} seeds added to address book", peers.len());
+            }
+            self.events.subscribe_fn(reseed(
+                seeds,
+                network.port(),
+                config.dns_timeout,
+                fastrand::Rng::with_seed(rng.u64(..)),
+                self.handle.clone(),
+                self.reactor.waker(),
+            ));
         }
 
+        // Loading is done, close all channels.
+        self.loading.close();
+
         self.reactor.run(
             &listen,
             Service::new(cache, filters, peers, RefClock::from(clock), rng, config),
@@ -391,10 +754,64 @@ where
             subscriber: self.subscriber.clone(),
             shutdown: self.shutdown.clone(),
             listening: self.listening.clone(),
+            listen_addrs: self.listen_addrs.clone(),
+            journal: self.journal.clone(),
+            published: self.published.clone(),
         }
     }
 }
 
+/// Returns an event subscription that queries the given DNS seeds in the background when
+/// the address book is exhausted, at most once every [`dns::RESEED_INTERVAL`]. The outcomes
+/// are sent to the state machine, which adds the addresses to the address book.
+fn reseed<W: Waker + 'static>(
+    seeds: Vec<String>,
+    port: u16,
+    timeout: time::Duration,
+    rng: fastrand::Rng,
+    commands: chan::Sender<Command>,
+    waker: W,
+) -> impl FnMut(&fsm::Event) -> bool + Send + 'static {
+    let mut last: Option<time::Instant> = None;
+
+    move |event| {
+        if !matches!(
+            event,
+            fsm::Event::Address(fsm::AddressEvent::AddressBookExhausted)
+        ) {
+            return true;
+        }
+        if last.map_or(false, |t| t.elapsed() < dns::RESEED_INTERVAL) {
+            return true;
+        }
+        last = Some(time::Instant::now());
+
+        log::info!(target: "client", "Address book exhausted. Trying DNS seeds..");
+
+        let seeds = seeds.clone();
+        let rng = fastrand::Rng::with_seed(rng.u64(..));
+        let commands = commands.clone();
+        let waker = waker.clone();
+
+        thread::Builder::new()
+            .name(String::from("dns"))
+            .spawn(move || {
+                // Nb. Failures are reported per seed, as events.
+                dns::resolve_with(&seeds, port, timeout, &rng, |seed, result| {
+                    let cmd = Command::DnsSeedResolved {
+                        seed: seed.to_owned(),
+                        result: result.map(<[_]>::to_vec).map_err(|e| e.to_string()),
+                    };
+                    if commands.send(cmd).is_ok() {
+                        waker.wake().ok();
+                    }
+                })
+                .ok();
+            })
+            .is_ok()
+    }
+}
+
 /// An instance of [`handle::Handle`] for [`Client`].
 pub struct Handle<W: Waker> {
     commands: chan::Sender<Command>,
@@ -402,11 +819,14 @@ pub struct Handle<W: Waker> {
     blocks: event::Subscriber<(Block, Height)>,
     filters: event::Subscriber<(BlockFilter, BlockHash, Height)>,
     loading: event::Subscriber<Loading>,
-    subscriber: event::Subscriber<Event>,
+    subscriber: event::Subscriber<(u64, Event)>,
     waker: W,
     timeout: time::Duration,
     shutdown: chan::Sender<()>,
     listening: chan::Receiver<net::SocketAddr>,
+    listen_addrs: Arc<Mutex<Vec<net::SocketAddr>>>,
+    journal: Arc<Mutex<Option<Journal>>>,
+    published: Arc<AtomicU64>,
 }
 
 impl<W: Waker> Clone for Handle<W> {
@@ -422,14 +842,50 @@ impl<W: Waker> Clone for Handle<W> {
             waker: self.waker.clone(),
             shutdown: self.shutdown.clone(),
             listening: self.listening.clone(),
+            listen_addrs: self.listen_addrs.clone(),
+            journal: self.journal.clone(),
+            published: self.published.clone(),
         }
     }
 }
 
 impl<W: Waker> Handle<W> {
     /// Wait for node to start listening for incoming connections.
+    ///
+    /// Returns the next bound listening address. When listening on multiple addresses,
+    /// this can be called once for each of them, after which it returns an error.
     pub fn listening(&mut self) -> Result<net::SocketAddr, handle::Error> {
-        Ok(self.listening.recv_timeout(self.timeout)?)
+        let addr = self.listening.recv_timeout(self.timeout)?;
+        self.listen_addrs.lock().unwrap().push(addr);
+
+        Ok(addr)
+    }
+
+    /// Get the addresses the node is listening on, once they are all bound. If port `0`
+    /// was requested, the port assigned by the operating system is returned.
+    ///
+    /// Blocks until the node is running, or the handle timeout elapses.
+    pub fn listen_addrs(&self) -> Result<Vec<net::SocketAddr>, handle::Error> {
+        let deadline = time::Instant::now() + self.timeout;
+        let mut addrs = self.listen_addrs.lock().unwrap();
+
+        // The reactor closes the channel once all listening sockets are bound.
+        loop {
+            match self.listening.recv_deadline(deadline) {
+                Ok(addr) => addrs.push(addr),
+                Err(chan::RecvTimeoutError::Disconnected) => return Ok(addrs.clone()),
+                Err(chan::RecvTimeoutError::Timeout) => return Err(handle::Error::Timeout),
+            }
+        }
+    }
+
+    /// Discard the entries of the event log before the given sequence number, eg. once the
+    /// events were processed. Does nothing if [`Config::event_log`] isn't set.
+    pub fn compact_event_log(&self, seq: u64) -> io::Result<()> {
+        match self.journal.lock().unwrap().as_mut() {
+            Some(journal) => journal.compact(seq),
+            None => Ok(()),
+        }
     }
 
     /// Set the timeout for operations that wait on the network.
@@ -473,6 +929,13 @@ impl<W: Waker> handle::Handle for Handle<W> {
         Ok(receive.recv()?)
     }
 
+    fn get_sync_status(&self) -> Result<SyncStatus, handle::Error> {
+        let (transmit, receive) = chan::bounded::<SyncStatus>(1);
+        self.command(Command::GetSyncStatus(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
     fn query_tree(
         &self,
         query: impl Fn(&dyn BlockReader) + Send + Sync + 'static,
@@ -524,9 +987,69 @@ impl<W: Waker> handle::Handle for Handle<W> {
     }
 
     fn subscribe(&self) -> chan::Receiver<Event> {
+        self.subscriber.subscribe_with(|(_, e)| Some(e.clone()))
+    }
+
+    fn subscribe_sequenced(&self) -> chan::Receiver<(u64, Event)> {
         self.subscriber.subscribe()
     }
 
+    fn subscribe_from_snapshot(&self) -> Result<chan::Receiver<Event>, handle::Error> {
+        let (sender, receiver) = chan::unbounded();
+        // Until the snapshot is taken, received events are buffered along with the number of
+        // state machine events published up to and including the one they were mapped from.
+        // Once it's taken, this holds the number of state machine events it reflects.
+        let pending = Arc::new(Mutex::new(Ok(Vec::new())));
+
+        self.subscriber.subscribe_fn({
+            let pending = pending.clone();
+            let published = self.published.clone();
+            let sender = sender.clone();
+
+            move |(_, e)| {
+                let n = published.load(Ordering::SeqCst);
+
+                match &mut *pending.lock().unwrap() {
+                    Ok(events) => {
+                        events.push((n, e.clone()));
+                        true
+                    }
+                    // Events that are reflected in the snapshot are skipped.
+                    Err(seen) if n <= *seen => true,
+                    Err(_) => sender.send(e.clone()).is_ok(),
+                }
+            }
+        });
+
+        let snapshot = self.get_snapshot();
+        // Nb. Live events are held back until the snapshot and pending events are sent.
+        let mut pending = pending.lock().unwrap();
+        // Nb. If the snapshot couldn't be taken, the receiver is dropped, and the subscription
+        // with it on the next event.
+        let seen = snapshot.as_ref().map_or(0, |s| s.events);
+        let events = std::mem::replace(&mut *pending, Err(seen)).unwrap_or_default();
+        let snapshot = snapshot?;
+
+        sender
+            .send(Event::Snapshot {
+                tip: snapshot.tip,
+                filter_tip: snapshot.filter_tip,
+                peers: snapshot.peers,
+            })
+            .ok();
+        for (_, e) in events.into_iter().filter(|(n, _)| *n > seen) {
+            sender.send(e).ok();
+        }
+        Ok(receiver)
+    }
+
+    fn watch_tx(&self, txid: Txid) -> chan::Receiver<spv::TxStatus> {
+        self.subscriber.subscribe_with(move |(_, e)| match e {
+            Event::TxStatusChanged { txid: t, status } if *t == txid => Some(status.clone()),
+            _ => None,
+        })
+    }
+
     fn loading(&self) -> chan::Receiver<Loading> {
         self.loading.subscribe()
     }
@@ -0,0 +1,99 @@
+//! DNS seed resolution, used to bootstrap the address book.
+use std::io;
+use std::net::{self, ToSocketAddrs as _};
+use std::thread;
+use std::time;
+
+use crossbeam_channel as chan;
+
+use nakamoto_net::error::Error;
+
+/// Default time to wait for a DNS seed to respond.
+pub const DNS_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+/// Number of addresses after which we stop querying DNS seeds.
+pub const TARGET_ADDRESSES: usize = 128;
+/// Minimum time between two lookups triggered by an exhausted address book.
+pub const RESEED_INTERVAL: time::Duration = time::Duration::from_secs(60 * 10);
+
+/// Resolve DNS seeds to peer addresses on the given port.
+///
+/// Seeds are queried one at a time, starting at a random seed and rotating through the
+/// others, until [`TARGET_ADDRESSES`] addresses are found or all seeds were queried. Seeds
+/// that fail to resolve or don't respond within the timeout are skipped. If all of them
+/// fail, the last error is returned.
+pub fn resolve(
+    seeds: &[String],
+    port: u16,
+    timeout: time::Duration,
+    rng: &fastrand::Rng,
+) -> Result<Vec<net::SocketAddr>, Error> {
+    resolve_with(seeds, port, timeout, rng, |_, _| {})
+}
+
+/// Like [`resolve`], but calls the given function with the outcome of each seed lookup:
+/// the addresses returned, or the error.
+pub fn resolve_with(
+    seeds: &[String],
+    port: u16,
+    timeout: time::Duration,
+    rng: &fastrand::Rng,
+    mut on_lookup: impl FnMut(&str, Result<&[net::SocketAddr], &Error>),
+) -> Result<Vec<net::SocketAddr>, Error> {
+    let mut addrs = Vec::new();
+    let mut error = None;
+    let mut success = false;
+
+    if seeds.is_empty() {
+        return Ok(addrs);
+    }
+    let start = rng.usize(..seeds.len());
+
+    for seed in seeds.iter().cycle().skip(start).take(seeds.len()) {
+        match lookup(seed, port, timeout) {
+            Ok(resolved) => {
+                log::debug!(target: "client", "DNS seed {} returned {} address(es)", seed, resolved.len());
+                on_lookup(seed, Ok(&resolved));
+                addrs.extend(resolved);
+                success = true;
+            }
+            Err(err) => {
+                log::warn!(target: "client", "DNS seed {} failed to resolve: {}", seed, err);
+                on_lookup(seed, Err(&err));
+                error = Some(err);
+            }
+        }
+        if addrs.len() >= TARGET_ADDRESSES {
+            break;
+        }
+    }
+    match error {
+        Some(err) if !success => Err(err),
+        _ => Ok(addrs),
+    }
+}
+
+/// Resolve a single host, giving up after the timeout. Since the system resolver is
+/// blocking, the lookup runs on its own thread, which is left to finish on its own if it
+/// times out.
+fn lookup(host: &str, port: u16, timeout: time::Duration) -> Result<Vec<net::SocketAddr>, Error> {
+    let (tx, rx) = chan::bounded(1);
+    let name = host.to_owned();
+
+    thread::Builder::new()
+        .name(format!("dns:{}", name))
+        .spawn(move || {
+            let result = (name.as_str(), port)
+                .to_socket_addrs()
+                .map(|addrs| addrs.collect::<Vec<_>>());
+            tx.send(result).ok();
+        })
+        .and_then(|_| {
+            rx.recv_timeout(timeout).unwrap_or_else(|_| {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "lookup timed out"))
+            })
+        })
+        .map_err(|err| Error::Resolve {
+            host: host.to_owned(),
+            err,
+        })
+}
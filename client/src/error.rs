@@ -31,9 +31,15 @@ pub enum Error {
     /// An error coming from the filter store.
     #[error(transparent)]
     FilterStore(#[from] chain::filter::store::Error),
+    /// An error coming from the filter cache.
+    #[error(transparent)]
+    FilterCache(#[from] chain::filter::cache::Error),
     /// An error coming from the peer store.
     #[error("error loading peers: {0}")]
     PeerStore(io::Error),
+    /// An invalid configuration.
+    #[error("invalid configuration: {0}")]
+    Config(#[from] p2p::fsm::ConfigError),
     /// A communication channel error.
     #[error("command channel disconnected")]
     Channel,
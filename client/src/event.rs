@@ -4,7 +4,8 @@ use std::io;
 use std::sync::Arc;
 
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
-use nakamoto_common::bitcoin::{Transaction, Txid};
+use nakamoto_common::bitcoin::{OutPoint, Transaction, Txid};
+use nakamoto_common::block::time::LocalDuration;
 use nakamoto_common::block::{BlockHash, BlockHeader, Height};
 use nakamoto_net::DisconnectReason;
 use nakamoto_p2p::fsm;
@@ -15,30 +16,55 @@ use crate::spv::TxStatus;
 
 /// Event emitted by the client during the "loading" phase.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
 pub enum Loading {
+    /// Loading has started. This is the first event emitted, and gives the number
+    /// of headers that will be loaded, so that progress can be computed.
+    Started {
+        /// Number of block headers in the store, excluding genesis.
+        total_headers: Height,
+        /// Number of filter headers in the store, excluding genesis.
+        total_filter_headers: Height,
+    },
     /// A block header was loaded from the store.
-    /// This event only fires during startup.
+    /// This event only fires during startup, every [`crate::LOADING_EVENT_INTERVAL`] headers.
     BlockHeaderLoaded {
         /// Height of loaded block.
         height: Height,
     },
     /// A filter header was loaded from the store.
-    /// This event only fires during startup.
+    /// This event only fires during startup, every [`crate::LOADING_EVENT_INTERVAL`] headers.
     FilterHeaderLoaded {
         /// Height of loaded filter header.
         height: Height,
     },
     /// A filter header was verified.
-    /// This event only fires during startup.
+    /// This event only fires during startup, every [`crate::LOADING_EVENT_INTERVAL`] headers.
     FilterHeaderVerified {
         /// Height of verified filter header.
         height: Height,
     },
+    /// No DNS seeds were configured, so the network's default seeds are queried.
+    DefaultDnsSeeds {
+        /// Number of default seeds.
+        seeds: usize,
+    },
 }
 
 impl fmt::Display for Loading {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Self::Started {
+                total_headers,
+                total_filter_headers,
+            } => {
+                write!(
+                    fmt,
+                    "loading {} block header(s) and {} filter header(s)",
+                    total_headers, total_filter_headers
+                )
+            }
             Self::BlockHeaderLoaded { height } => {
                 write!(fmt, "block header #{} loaded", height)
             }
@@ -48,12 +74,25 @@ impl fmt::Display for Loading {
             Self::FilterHeaderVerified { height } => {
                 write!(fmt, "filter header #{} verified", height)
             }
+            Self::DefaultDnsSeeds { seeds } => {
+                write!(
+                    fmt,
+                    "no DNS seeds configured, using {} default seed(s)",
+                    seeds
+                )
+            }
         }
     }
 }
 
 /// Event emitted by the client, after the "loading" phase is over.
+///
+/// With the `serde` feature enabled, events can be serialized and deserialized. Connection
+/// errors and disconnect reasons are encoded as their messages, and are decoded as I/O errors
+/// carrying these messages, so their original kind is lost.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
 pub enum Event {
     /// Ready to process peer events and start receiving commands.
     /// Note that this isn't necessarily the first event emitted.
@@ -63,6 +102,16 @@ pub enum Event {
         /// The tip of the filter header chain.
         filter_tip: Height,
     },
+    /// Snapshot of the client's state, emitted first to subscribers that catch up from a
+    /// snapshot. See [`crate::handle::Handle::subscribe_from_snapshot`].
+    Snapshot {
+        /// The tip of the block header chain.
+        tip: Height,
+        /// The tip of the filter header chain.
+        filter_tip: Height,
+        /// Negotiated peers.
+        peers: Vec<PeerId>,
+    },
     /// Peer connected. This is fired when the physical TCP/IP connection
     /// is established. Use [`Event::PeerNegotiated`] to know when the P2P handshake
     /// has completed.
@@ -70,6 +119,7 @@ pub enum Event {
         /// Peer address.
         addr: PeerId,
         /// Connection link.
+        #[cfg_attr(feature = "serde", serde(with = "ser::link"))]
         link: ConnDirection,
     },
     /// Peer disconnected after successful connection.
@@ -77,6 +127,7 @@ pub enum Event {
         /// Peer address.
         addr: PeerId,
         /// Reason for disconnection.
+        #[cfg_attr(feature = "serde", serde(with = "ser::reason"))]
         reason: DisconnectReason<fsm::DisconnectReason>,
     },
     /// Connection was never established and timed out or failed.
@@ -84,15 +135,52 @@ pub enum Event {
         /// Peer address.
         addr: PeerId,
         /// Connection error.
+        #[cfg_attr(feature = "serde", serde(with = "ser::error"))]
         error: Arc<io::Error>,
     },
+    /// The current failover peer was lost or couldn't be reached, and the next one in the
+    /// list is being tried. See [`crate::Config::failover`].
+    PeerFailedOver {
+        /// Peer that was given up on.
+        from: PeerId,
+        /// Peer being tried next.
+        to: PeerId,
+    },
+    /// Our outbound peers lack diversity: they are all in the same network group, or their
+    /// addresses were all learned from the same peer. This may mean that we are being
+    /// eclipsed, ie. that an attacker controls all our connections to the network.
+    PossibleEclipse {
+        /// Number of outbound peers.
+        peers: usize,
+        /// Number of distinct network groups among outbound peers.
+        groups: usize,
+        /// The peer all outbound peer addresses were learned from, if any.
+        source: Option<PeerId>,
+    },
+    /// A DNS seed was resolved, and its addresses added to the address book. Seeds are
+    /// queried on startup if the address book is empty, and whenever it is exhausted.
+    DnsSeedResolved {
+        /// The seed host name.
+        seed: String,
+        /// Number of addresses returned.
+        addresses: usize,
+    },
+    /// A DNS seed failed to resolve or timed out.
+    DnsSeedFailed {
+        /// The seed host name.
+        seed: String,
+        /// The resolution error.
+        error: String,
+    },
     /// Peer handshake completed. The peer connection is fully functional from this point.
     PeerNegotiated {
         /// Peer address.
         addr: PeerId,
         /// Connection link.
+        #[cfg_attr(feature = "serde", serde(with = "ser::link"))]
         link: ConnDirection,
         /// Peer services.
+        #[cfg_attr(feature = "serde", serde(with = "ser::services"))]
         services: ServiceFlags,
         /// Peer height.
         height: Height,
@@ -100,6 +188,19 @@ pub enum Event {
         user_agent: String,
         /// Negotiated protocol version.
         version: u32,
+        /// Whether the peer relays transactions, as advertised in its `version` message.
+        relay: bool,
+        /// Offset in seconds between the timestamp reported by the peer and our local
+        /// time. A positive offset means the peer's clock is ahead of ours.
+        time_offset: i64,
+    },
+    /// Our local clock is too far off from the time reported by our peers, and network
+    /// time can't be adjusted. This usually means the system clock is wrong, and can
+    /// prevent the client from syncing.
+    ClockSkewDetected {
+        /// Median offset of peer clocks relative to ours, in seconds. A positive offset
+        /// means our clock is behind.
+        offset: i64,
     },
     /// The best known height amongst connected peers has been updated.
     /// Note that there is no guarantee that this height really exists;
@@ -129,6 +230,25 @@ pub enum Event {
         /// Height of the block when it was part of the main chain.
         height: Height,
     },
+    /// A block is being downloaded, eg. because it matched one of the filters. This event
+    /// precedes [`Event::BlockMatched`] for blocks that are downloaded, which can take a while
+    /// for large blocks.
+    BlockDownloadStarted {
+        /// Block hash.
+        hash: BlockHash,
+        /// Block height.
+        height: Height,
+    },
+    /// A peer proposed a fork that would revert more blocks of the main chain than allowed,
+    /// and was disconnected. See [`crate::Config::max_reorg_depth`].
+    DeepReorgDetected {
+        /// Peer that proposed the fork.
+        peer: PeerId,
+        /// Number of blocks of the main chain that would have been reverted.
+        depth: Height,
+        /// Tip of the competing chain.
+        competing_tip: BlockHash,
+    },
     /// A block has matched one of the filters and is ready to be processed.
     /// This event usually precedes [`Event::TxStatusChanged`] events.
     BlockMatched {
@@ -141,6 +261,31 @@ pub enum Event {
         /// Transactions in this block.
         transactions: Vec<Transaction>,
     },
+    /// A merkle block was received from a peer that doesn't serve compact filters, and
+    /// matched the bloom filter loaded on it. See [`crate::Config::bloom_fallback`].
+    /// Matched transactions that are received are reported with
+    /// [`Event::TransactionMatched`].
+    MerkleBlockMatched {
+        /// Peer the merkle block was received from.
+        peer: PeerId,
+        /// Block hash.
+        hash: BlockHash,
+        /// Block height.
+        height: Height,
+        /// Transactions of the block that matched the bloom filter.
+        txids: Vec<Txid>,
+    },
+    /// A transaction matched by a merkle block was received.
+    TransactionMatched {
+        /// Peer the transaction was received from.
+        peer: PeerId,
+        /// Hash of the block the transaction was included in.
+        block: BlockHash,
+        /// Height of the block.
+        height: Height,
+        /// The matched transaction.
+        transaction: Transaction,
+    },
     /// Transaction fee rate estimated for a block.
     FeeEstimated {
         /// Block hash of the estimate.
@@ -148,6 +293,7 @@ pub enum Event {
         /// Block height of the estimate.
         height: Height,
         /// Fee estimate.
+        #[cfg_attr(feature = "serde", serde(with = "ser::fees"))]
         fees: FeeEstimate,
     },
     /// A filter was processed. If it matched any of the scripts in the watchlist,
@@ -163,6 +309,47 @@ pub enum Event {
         /// Whether or not this filter is valid.
         valid: bool,
     },
+    /// The filter header chain has caught up with the block header chain. This doesn't
+    /// mean the filters themselves were processed; see [`Event::Synced`] for that.
+    FilterHeadersSynced {
+        /// Height of the filter header chain.
+        height: Height,
+    },
+    /// A peer rejected one of our messages. Only some peers send these.
+    MessageRejected {
+        /// The rejecting peer.
+        peer: PeerId,
+        /// Command of the rejected message, eg. `tx`.
+        message: String,
+        /// The rejected transaction, if it's one we submitted.
+        txid: Option<Txid>,
+        /// Reject code, as defined by BIP 61.
+        code: u8,
+        /// Reason given by the peer.
+        reason: String,
+    },
+    /// Peers sent conflicting compact filters for the same block. The peers whose filter
+    /// doesn't match the filter header chain are disconnected.
+    /// See [`crate::Config::filter_redundancy`].
+    FilterConflict {
+        /// Block hash of the filter.
+        block: BlockHash,
+        /// Height of the filter.
+        height: Height,
+        /// Peers that sent the filter.
+        peers: Vec<PeerId>,
+    },
+    /// Compact filters from a peer matched the watchlist implausibly often, which may mean
+    /// the peer is sending bogus filters to learn which blocks the client is interested in.
+    /// See [`crate::Config::max_filter_match_rate`].
+    SuspiciousMatchRate {
+        /// The peer the filters were received from.
+        peer: PeerId,
+        /// Number of filters from this peer that were processed.
+        processed: u64,
+        /// Number of those filters that matched.
+        matched: u64,
+    },
     /// The status of a transaction has changed.
     TxStatusChanged {
         /// The Transaction ID.
@@ -170,6 +357,18 @@ pub enum Event {
         /// The new transaction status.
         status: TxStatus,
     },
+    /// A watched outpoint was spent in a block of the active chain.
+    /// See [`crate::handle::Handle::watch_outpoint`].
+    OutpointSpent {
+        /// The spent outpoint.
+        outpoint: OutPoint,
+        /// The spending transaction.
+        txid: Txid,
+        /// Hash of the block in which the outpoint was spent.
+        block: BlockHash,
+        /// Height of the block.
+        height: Height,
+    },
     /// Compact filters have been synced and processed up to this point and matching blocks have
     /// been fetched.
     ///
@@ -189,12 +388,35 @@ impl fmt::Display for Event {
             Self::Ready { .. } => {
                 write!(fmt, "ready to process events and commands")
             }
+            Self::Snapshot {
+                tip,
+                filter_tip,
+                peers,
+            } => write!(
+                fmt,
+                "snapshot at height {} (filter height {}) with {} peer(s)",
+                tip,
+                filter_tip,
+                peers.len()
+            ),
             Self::BlockConnected { hash, height, .. } => {
                 write!(fmt, "block {} connected at height {}", hash, height)
             }
             Self::BlockDisconnected { hash, height, .. } => {
                 write!(fmt, "block {} disconnected at height {}", hash, height)
             }
+            Self::BlockDownloadStarted { hash, height } => {
+                write!(fmt, "downloading block {} at height {}", hash, height)
+            }
+            Self::DeepReorgDetected {
+                peer,
+                depth,
+                competing_tip,
+            } => write!(
+                fmt,
+                "rejected fork with tip {} from {}, reverting {} block(s)",
+                competing_tip, peer, depth
+            ),
             Self::BlockMatched { hash, height, .. } => {
                 write!(
                     fmt,
@@ -202,6 +424,29 @@ impl fmt::Display for Event {
                     hash, height
                 )
             }
+            Self::MerkleBlockMatched {
+                peer,
+                hash,
+                height,
+                txids,
+            } => write!(
+                fmt,
+                "merkle block {} at height {} from {} matched {} transaction(s)",
+                hash,
+                height,
+                peer,
+                txids.len()
+            ),
+            Self::TransactionMatched {
+                transaction,
+                height,
+                ..
+            } => write!(
+                fmt,
+                "transaction {} matched in block at height {}",
+                transaction.txid(),
+                height
+            ),
             Self::FeeEstimated { fees, height, .. } => {
                 write!(
                     fmt,
@@ -218,9 +463,56 @@ impl fmt::Display for Event {
                     height, matched
                 )
             }
+            Self::FilterHeadersSynced { height } => {
+                write!(fmt, "filter headers synced up to height {}", height)
+            }
+            Self::MessageRejected {
+                peer,
+                message,
+                txid,
+                code,
+                reason,
+            } => {
+                if let Some(txid) = txid {
+                    write!(fmt, "transaction {} rejected by {}", txid, peer)?;
+                } else {
+                    write!(fmt, "`{}` message rejected by {}", message, peer)?;
+                }
+                write!(fmt, " (code = {:#04x}): {}", code, reason)
+            }
+            Self::FilterConflict {
+                block,
+                height,
+                peers,
+            } => write!(
+                fmt,
+                "conflicting filters for block {} at height {} received from {} peer(s)",
+                block,
+                height,
+                peers.len()
+            ),
+            Self::SuspiciousMatchRate {
+                peer,
+                processed,
+                matched,
+            } => write!(
+                fmt,
+                "{} out of {} filter(s) from peer {} matched, which is suspicious",
+                matched, processed, peer
+            ),
             Self::TxStatusChanged { txid, status } => {
                 write!(fmt, "transaction {} status changed: {}", txid, status)
             }
+            Self::OutpointSpent {
+                outpoint,
+                txid,
+                height,
+                ..
+            } => write!(
+                fmt,
+                "outpoint {} spent by transaction {} at height {}",
+                outpoint, txid, height
+            ),
             Self::Synced { height, .. } => write!(fmt, "filters synced up to height {}", height),
             Self::PeerConnected { addr, link } => {
                 write!(fmt, "peer {} connected ({:?})", &addr, link)
@@ -232,9 +524,44 @@ impl fmt::Display for Event {
                     &addr, error
                 )
             }
+            Self::PeerFailedOver { from, to } => {
+                write!(fmt, "failing over from peer {} to {}", from, to)
+            }
+            Self::PossibleEclipse {
+                peers,
+                groups,
+                source: Some(source),
+            } => {
+                write!(
+                    fmt,
+                    "possible eclipse: {} outbound peer(s) in {} network group(s), all learned from {}",
+                    peers, groups, source
+                )
+            }
+            Self::PossibleEclipse { peers, groups, .. } => {
+                write!(
+                    fmt,
+                    "possible eclipse: {} outbound peer(s) in {} network group(s)",
+                    peers, groups
+                )
+            }
+            Self::DnsSeedResolved { seed, addresses } => {
+                write!(fmt, "DNS seed {} returned {} address(es)", seed, addresses)
+            }
+            Self::DnsSeedFailed { seed, error } => {
+                write!(fmt, "DNS seed {} failed: {}", seed, error)
+            }
             Self::PeerHeightUpdated { height } => {
                 write!(fmt, "peer height updated to {}", height)
             }
+            Self::ClockSkewDetected { offset } => {
+                write!(
+                    fmt,
+                    "local clock is {} {} network time",
+                    LocalDuration::from_secs(offset.unsigned_abs()),
+                    if *offset > 0 { "behind" } else { "ahead of" }
+                )
+            }
             Self::PeerDisconnected { addr, reason } => {
                 write!(fmt, "disconnected from {} ({})", &addr, reason)
             }
@@ -251,3 +578,462 @@ impl fmt::Display for Event {
         }
     }
 }
+
+#[cfg(feature = "serde")]
+mod ser {
+    //! Serialization of event fields that don't implement [`serde::Serialize`] and
+    //! [`serde::Deserialize`].
+
+    pub mod link {
+        use nakamoto_p2p::fsm::ConnDirection;
+        use serde::{de, Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            link: &ConnDirection,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match link {
+                ConnDirection::Inbound => serializer.serialize_str("inbound"),
+                ConnDirection::Outbound => serializer.serialize_str("outbound"),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<ConnDirection, D::Error> {
+            match String::deserialize(deserializer)?.as_str() {
+                "inbound" => Ok(ConnDirection::Inbound),
+                "outbound" => Ok(ConnDirection::Outbound),
+                other => Err(de::Error::unknown_variant(other, &["inbound", "outbound"])),
+            }
+        }
+    }
+
+    pub mod services {
+        use nakamoto_common::bitcoin::network::constants::ServiceFlags;
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            services: &ServiceFlags,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.serialize_u64(services.to_u64())
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<ServiceFlags, D::Error> {
+            u64::deserialize(deserializer).map(ServiceFlags::from)
+        }
+    }
+
+    pub mod fees {
+        use nakamoto_p2p::fsm::fees::{FeeEstimate, FeeRate};
+        use serde::ser::{SerializeStruct, Serializer};
+        use serde::{Deserialize, Deserializer};
+
+        #[derive(Deserialize)]
+        struct Fees {
+            low: FeeRate,
+            median: FeeRate,
+            high: FeeRate,
+        }
+
+        pub fn serialize<S: Serializer>(
+            fees: &FeeEstimate,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            let mut s = serializer.serialize_struct("FeeEstimate", 3)?;
+
+            s.serialize_field("low", &fees.low)?;
+            s.serialize_field("median", &fees.median)?;
+            s.serialize_field("high", &fees.high)?;
+            s.end()
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<FeeEstimate, D::Error> {
+            let Fees { low, median, high } = Fees::deserialize(deserializer)?;
+
+            Ok(FeeEstimate { low, median, high })
+        }
+    }
+
+    pub mod error {
+        use std::io;
+        use std::sync::Arc;
+
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            error: &Arc<io::Error>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(error)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Arc<io::Error>, D::Error> {
+            let msg = String::deserialize(deserializer)?;
+
+            Ok(Arc::new(io::Error::new(io::ErrorKind::Other, msg)))
+        }
+    }
+
+    pub mod reason {
+        use nakamoto_net::DisconnectReason;
+        use nakamoto_p2p::fsm;
+        use serde::{Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            reason: &DisconnectReason<fsm::DisconnectReason>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(reason)
+        }
+
+        /// Disconnect reasons are decoded as connection errors, since only their message
+        /// is encoded.
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<DisconnectReason<fsm::DisconnectReason>, D::Error> {
+            super::error::deserialize(deserializer).map(DisconnectReason::ConnectionError)
+        }
+    }
+}
+
+impl Loading {
+    /// Convert to a JSON value.
+    pub fn to_json(&self) -> microserde::json::Value {
+        use microserde::json::{Number, Object, Value};
+
+        let mut obj = Object::new();
+        let (kind, height) = match self {
+            Self::Started {
+                total_headers,
+                total_filter_headers,
+            } => {
+                obj.insert("type".to_owned(), Value::String("started".to_owned()));
+                obj.insert(
+                    "total_headers".to_owned(),
+                    Value::Number(Number::U64(*total_headers)),
+                );
+                obj.insert(
+                    "total_filter_headers".to_owned(),
+                    Value::Number(Number::U64(*total_filter_headers)),
+                );
+                return Value::Object(obj);
+            }
+            Self::DefaultDnsSeeds { seeds } => {
+                obj.insert(
+                    "type".to_owned(),
+                    Value::String("default_dns_seeds".to_owned()),
+                );
+                obj.insert(
+                    "seeds".to_owned(),
+                    Value::Number(Number::U64(*seeds as u64)),
+                );
+                return Value::Object(obj);
+            }
+            Self::BlockHeaderLoaded { height } => ("block_header_loaded", height),
+            Self::FilterHeaderLoaded { height } => ("filter_header_loaded", height),
+            Self::FilterHeaderVerified { height } => ("filter_header_verified", height),
+        };
+        obj.insert("type".to_owned(), Value::String(kind.to_owned()));
+        obj.insert("height".to_owned(), Value::Number(Number::U64(*height)));
+
+        Value::Object(obj)
+    }
+}
+
+impl Event {
+    /// Convert to a JSON value.
+    ///
+    /// Every event is represented as an object with a `type` field naming the event, and
+    /// the event fields alongside it. Hashes are hex-encoded, heights are numbers, and
+    /// block headers and transactions are hex-encoded in their consensus serialization.
+    pub fn to_json(&self) -> microserde::json::Value {
+        use microserde::json::{Number, Object, Value};
+        use nakamoto_common::bitcoin::consensus::encode::serialize_hex;
+
+        fn string(s: impl ToString) -> Value {
+            Value::String(s.to_string())
+        }
+
+        fn number(n: impl Into<u64>) -> Value {
+            Value::Number(Number::U64(n.into()))
+        }
+
+        fn link(link: &ConnDirection) -> Value {
+            match link {
+                ConnDirection::Inbound => string("inbound"),
+                ConnDirection::Outbound => string("outbound"),
+            }
+        }
+
+        let mut obj = Object::new();
+        let kind = match self {
+            Self::Ready { tip, filter_tip } => {
+                obj.insert("tip".to_owned(), number(*tip));
+                obj.insert("filter_tip".to_owned(), number(*filter_tip));
+                "ready"
+            }
+            Self::Snapshot {
+                tip,
+                filter_tip,
+                peers,
+            } => {
+                obj.insert("tip".to_owned(), number(*tip));
+                obj.insert("filter_tip".to_owned(), number(*filter_tip));
+                obj.insert(
+                    "peers".to_owned(),
+                    Value::Array(peers.iter().map(string).collect()),
+                );
+                "snapshot"
+            }
+            Self::PeerConnected { addr, link: l } => {
+                obj.insert("addr".to_owned(), string(addr));
+                obj.insert("link".to_owned(), link(l));
+                "peer_connected"
+            }
+            Self::PeerDisconnected { addr, reason } => {
+                obj.insert("addr".to_owned(), string(addr));
+                obj.insert("reason".to_owned(), string(reason));
+                "peer_disconnected"
+            }
+            Self::PeerConnectionFailed { addr, error } => {
+                obj.insert("addr".to_owned(), string(addr));
+                obj.insert("error".to_owned(), string(error));
+                "peer_connection_failed"
+            }
+            Self::PeerFailedOver { from, to } => {
+                obj.insert("from".to_owned(), string(from));
+                obj.insert("to".to_owned(), string(to));
+                "peer_failed_over"
+            }
+            Self::PossibleEclipse {
+                peers,
+                groups,
+                source,
+            } => {
+                obj.insert("peers".to_owned(), number(*peers as u64));
+                obj.insert("groups".to_owned(), number(*groups as u64));
+                obj.insert("source".to_owned(), source.map_or(Value::Null, string));
+                "possible_eclipse"
+            }
+            Self::DnsSeedResolved { seed, addresses } => {
+                obj.insert("seed".to_owned(), string(seed));
+                obj.insert("addresses".to_owned(), number(*addresses as u64));
+                "dns_seed_resolved"
+            }
+            Self::DnsSeedFailed { seed, error } => {
+                obj.insert("seed".to_owned(), string(seed));
+                obj.insert("error".to_owned(), string(error));
+                "dns_seed_failed"
+            }
+            Self::PeerNegotiated {
+                addr,
+                link: l,
+                services,
+                height,
+                user_agent,
+                version,
+                relay,
+                time_offset,
+            } => {
+                obj.insert("addr".to_owned(), string(addr));
+                obj.insert("link".to_owned(), link(l));
+                obj.insert("services".to_owned(), number(services.to_u64()));
+                obj.insert("height".to_owned(), number(*height));
+                obj.insert("user_agent".to_owned(), string(user_agent));
+                obj.insert("version".to_owned(), number(*version));
+                obj.insert("relay".to_owned(), Value::Bool(*relay));
+                obj.insert(
+                    "time_offset".to_owned(),
+                    Value::Number(Number::I64(*time_offset)),
+                );
+                "peer_negotiated"
+            }
+            Self::PeerHeightUpdated { height } => {
+                obj.insert("height".to_owned(), number(*height));
+                "peer_height_updated"
+            }
+            Self::ClockSkewDetected { offset } => {
+                obj.insert("offset".to_owned(), Value::Number(Number::I64(*offset)));
+                "clock_skew_detected"
+            }
+            Self::BlockConnected {
+                header,
+                hash,
+                height,
+            } => {
+                obj.insert("hash".to_owned(), string(hash));
+                obj.insert("height".to_owned(), number(*height));
+                obj.insert("header".to_owned(), string(serialize_hex(header)));
+                "block_connected"
+            }
+            Self::BlockDisconnected {
+                header,
+                hash,
+                height,
+            } => {
+                obj.insert("hash".to_owned(), string(hash));
+                obj.insert("height".to_owned(), number(*height));
+                obj.insert("header".to_owned(), string(serialize_hex(header)));
+                "block_disconnected"
+            }
+            Self::BlockDownloadStarted { hash, height } => {
+                obj.insert("hash".to_owned(), string(hash));
+                obj.insert("height".to_owned(), number(*height));
+                "block_download_started"
+            }
+            Self::DeepReorgDetected {
+                peer,
+                depth,
+                competing_tip,
+            } => {
+                obj.insert("peer".to_owned(), string(peer));
+                obj.insert("depth".to_owned(), number(*depth));
+                obj.insert("competing_tip".to_owned(), string(competing_tip));
+                "deep_reorg_detected"
+            }
+            Self::BlockMatched {
+                hash,
+                header,
+                height,
+                transactions,
+            } => {
+                obj.insert("hash".to_owned(), string(hash));
+                obj.insert("height".to_owned(), number(*height));
+                obj.insert("header".to_owned(), string(serialize_hex(header)));
+                obj.insert(
+                    "transactions".to_owned(),
+                    Value::Array(
+                        transactions
+                            .iter()
+                            .map(|tx| string(serialize_hex(tx)))
+                            .collect(),
+                    ),
+                );
+                "block_matched"
+            }
+            Self::MerkleBlockMatched {
+                peer,
+                hash,
+                height,
+                txids,
+            } => {
+                obj.insert("peer".to_owned(), string(peer));
+                obj.insert("hash".to_owned(), string(hash));
+                obj.insert("height".to_owned(), number(*height));
+                obj.insert(
+                    "txids".to_owned(),
+                    Value::Array(txids.iter().map(string).collect()),
+                );
+                "merkle_block_matched"
+            }
+            Self::TransactionMatched {
+                peer,
+                block,
+                height,
+                transaction,
+            } => {
+                obj.insert("peer".to_owned(), string(peer));
+                obj.insert("block".to_owned(), string(block));
+                obj.insert("height".to_owned(), number(*height));
+                obj.insert("transaction".to_owned(), string(serialize_hex(transaction)));
+                "transaction_matched"
+            }
+            Self::FeeEstimated {
+                block,
+                height,
+                fees,
+            } => {
+                obj.insert("block".to_owned(), string(block));
+                obj.insert("height".to_owned(), number(*height));
+                obj.insert("fees".to_owned(), fees.to_json());
+                "fee_estimated"
+            }
+            Self::FilterProcessed {
+                block,
+                height,
+                matched,
+                valid,
+            } => {
+                obj.insert("block".to_owned(), string(block));
+                obj.insert("height".to_owned(), number(*height));
+                obj.insert("matched".to_owned(), Value::Bool(*matched));
+                obj.insert("valid".to_owned(), Value::Bool(*valid));
+                "filter_processed"
+            }
+            Self::FilterHeadersSynced { height } => {
+                obj.insert("height".to_owned(), number(*height));
+                "filter_headers_synced"
+            }
+            Self::MessageRejected {
+                peer,
+                message,
+                txid,
+                code,
+                reason,
+            } => {
+                obj.insert("peer".to_owned(), string(peer));
+                obj.insert("message".to_owned(), string(message));
+                obj.insert("txid".to_owned(), txid.map_or(Value::Null, string));
+                obj.insert("code".to_owned(), number(*code));
+                obj.insert("reason".to_owned(), string(reason));
+                "message_rejected"
+            }
+            Self::FilterConflict {
+                block,
+                height,
+                peers,
+            } => {
+                obj.insert("block".to_owned(), string(block));
+                obj.insert("height".to_owned(), number(*height));
+                obj.insert(
+                    "peers".to_owned(),
+                    Value::Array(peers.iter().map(string).collect()),
+                );
+                "filter_conflict"
+            }
+            Self::SuspiciousMatchRate {
+                peer,
+                processed,
+                matched,
+            } => {
+                obj.insert("peer".to_owned(), string(peer));
+                obj.insert("processed".to_owned(), number(*processed));
+                obj.insert("matched".to_owned(), number(*matched));
+                "suspicious_match_rate"
+            }
+            Self::TxStatusChanged { txid, status } => {
+                obj.insert("txid".to_owned(), string(txid));
+                obj.insert("status".to_owned(), status.to_json());
+                "tx_status_changed"
+            }
+            Self::OutpointSpent {
+                outpoint,
+                txid,
+                block,
+                height,
+            } => {
+                obj.insert("outpoint".to_owned(), string(outpoint));
+                obj.insert("txid".to_owned(), string(txid));
+                obj.insert("block".to_owned(), string(block));
+                obj.insert("height".to_owned(), number(*height));
+                "outpoint_spent"
+            }
+            Self::Synced { height, tip } => {
+                obj.insert("height".to_owned(), number(*height));
+                obj.insert("tip".to_owned(), number(*tip));
+                "synced"
+            }
+        };
+        obj.insert("type".to_owned(), string(kind));
+
+        Value::Object(obj)
+    }
+}
@@ -0,0 +1,131 @@
+//! Portable header chain files.
+//!
+//! A header chain file holds the block headers of a chain, excluding genesis, so that a
+//! new node can be bootstrapped without a full header sync. The file is laid out as
+//! follows, with integers in little-endian:
+//!
+//! | Field    | Size       | Description                                        |
+//! |----------|------------|----------------------------------------------------|
+//! | magic    | 4          | [`MAGIC`]                                          |
+//! | version  | 1          | [`VERSION`]                                        |
+//! | genesis  | 32         | Hash of the network's genesis block                |
+//! | count    | 8          | Number of headers                                  |
+//! | headers  | 80 * count | Consensus-encoded headers, starting at height 1    |
+//! | checksum | 32         | Double-SHA256 of all preceding bytes               |
+//!
+use std::io;
+
+use thiserror::Error;
+
+use nakamoto_common::bitcoin::consensus::encode::{self, Decodable, Encodable};
+use nakamoto_common::bitcoin::hashes::{sha256d, Hash};
+use nakamoto_common::block::{BlockHash, BlockHeader, Height};
+
+/// Header chain file magic bytes.
+pub const MAGIC: [u8; 4] = *b"NKHC";
+/// Header chain file format version.
+pub const VERSION: u8 = 1;
+
+/// Size of the file header, ie. everything before the block headers.
+const PREAMBLE_SIZE: usize = 4 + 1 + 32 + 8;
+/// Size of the checksum.
+const CHECKSUM_SIZE: usize = 32;
+
+/// An error reading or writing a header chain file.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// An I/O error.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// A decoding error.
+    #[error(transparent)]
+    Encoding(#[from] encode::Error),
+    /// The file is not a header chain file.
+    #[error("not a header chain file")]
+    InvalidMagic,
+    /// The file format version is not supported.
+    #[error("unsupported header chain file version {0}")]
+    UnsupportedVersion(u8),
+    /// The file checksum doesn't match its contents.
+    #[error("header chain file checksum mismatch")]
+    Checksum,
+    /// The file is for a different network.
+    #[error("header chain is for a different network, with genesis {0}")]
+    WrongNetwork(BlockHash),
+    /// One of the headers doesn't connect to the previous one, or has invalid proof-of-work.
+    #[error("invalid header at height {0}: {1}")]
+    InvalidHeader(Height, &'static str),
+}
+
+/// Write a header chain file for the network with the given genesis.
+pub fn write<W: io::Write>(
+    mut writer: W,
+    genesis: &BlockHash,
+    headers: &[BlockHeader],
+) -> Result<(), Error> {
+    let mut buf = Vec::with_capacity(PREAMBLE_SIZE + headers.len() * 80 + CHECKSUM_SIZE);
+
+    buf.extend(MAGIC);
+    buf.push(VERSION);
+    genesis.consensus_encode(&mut buf)?;
+    (headers.len() as u64).consensus_encode(&mut buf)?;
+
+    for header in headers {
+        header.consensus_encode(&mut buf)?;
+    }
+    let checksum = sha256d::Hash::hash(&buf);
+    buf.extend(checksum.into_inner());
+
+    writer.write_all(&buf)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Read a header chain file, checking that it is for the network with the given genesis.
+///
+/// Headers are checked to form a chain starting at genesis, with valid proof-of-work.
+/// Difficulty adjustments and checkpoints are not checked, since that requires the
+/// block tree.
+pub fn read<R: io::Read>(mut reader: R, genesis: &BlockHash) -> Result<Vec<BlockHeader>, Error> {
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf)?;
+
+    if buf.len() < PREAMBLE_SIZE + CHECKSUM_SIZE || buf[..4] != MAGIC {
+        return Err(Error::InvalidMagic);
+    }
+    let (data, checksum) = buf.split_at(buf.len() - CHECKSUM_SIZE);
+    if sha256d::Hash::hash(data).into_inner() != checksum {
+        return Err(Error::Checksum);
+    }
+    if data[4] != VERSION {
+        return Err(Error::UnsupportedVersion(data[4]));
+    }
+
+    let mut cursor = &data[5..];
+    let hash = BlockHash::consensus_decode(&mut cursor)?;
+    if &hash != genesis {
+        return Err(Error::WrongNetwork(hash));
+    }
+    let count = u64::consensus_decode(&mut cursor)?;
+    if cursor.len() % 80 != 0 || cursor.len() as u64 / 80 != count {
+        return Err(Error::InvalidHeader(count, "unexpected number of headers"));
+    }
+
+    let mut headers = Vec::with_capacity(count as usize);
+    let mut prev = *genesis;
+
+    for height in 1..=count {
+        let header = BlockHeader::consensus_decode(&mut cursor)?;
+
+        if header.prev_blockhash != prev {
+            return Err(Error::InvalidHeader(height, "header doesn't connect"));
+        }
+        prev = header
+            .validate_pow(&header.target())
+            .map_err(|_| Error::InvalidHeader(height, "invalid proof-of-work"))?;
+
+        headers.push(header);
+    }
+    Ok(headers)
+}
@@ -0,0 +1,68 @@
+//! Fee estimation history, saved across restarts.
+use std::io::Write as _;
+use std::path::Path;
+use std::{fs, io};
+
+pub use nakamoto_p2p::fsm::fees::{FeeEstimate, FeeHistory, MAX_HISTORY_AGE};
+
+/// Load a fee history from the given file. Returns [`None`] if the file doesn't exist.
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Option<FeeHistory>> {
+    let s = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let value =
+        microserde::json::from_str(&s).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+    let history =
+        FeeHistory::from_json(value).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+
+    Ok(Some(history))
+}
+
+/// Save a fee history to the given file, replacing its contents. The history is written
+/// to a temporary file first, which then atomically replaces the file.
+pub fn save<P: AsRef<Path>>(path: P, history: &FeeHistory) -> io::Result<()> {
+    let path = path.as_ref();
+    let tmp = path.with_extension("tmp");
+    let mut s = microserde::json::to_string(&history.to_json());
+    s.push('\n');
+
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(s.as_bytes())?;
+    file.sync_all()?;
+
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nakamoto_common::block::time::LocalTime;
+
+    #[test]
+    fn test_save_and_load() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("fees.json");
+
+        assert_eq!(load(&path).unwrap(), None);
+
+        let history = FeeHistory {
+            time: LocalTime::from_secs(1680000000),
+            estimates: vec![(
+                101,
+                FeeEstimate {
+                    low: 1,
+                    median: 12,
+                    high: 140,
+                },
+            )],
+        };
+        save(&path, &history).unwrap();
+        assert_eq!(load(&path).unwrap(), Some(history));
+        assert!(!path.with_extension("tmp").exists());
+
+        fs::write(&path, "{}").unwrap();
+        assert_eq!(load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
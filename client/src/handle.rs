@@ -1,24 +1,33 @@
 //! Node handles are created from nodes by users of the library, to communicate with the underlying
 //! protocol instance.
-use std::net;
-use std::ops::{RangeBounds, RangeInclusive};
+use std::ops::{Range, RangeBounds, RangeInclusive};
+use std::path::Path;
+use std::{fs, io, net, time};
 
 use crossbeam_channel as chan;
 use thiserror::Error;
 
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::Address;
-use nakamoto_common::bitcoin::Script;
+use nakamoto_common::bitcoin::{OutPoint, Script, Txid};
 
 use nakamoto_common::bitcoin::network::message::NetworkMessage;
 use nakamoto_common::block::filter::BlockFilter;
+use nakamoto_common::block::merkle::MerkleProof;
 use nakamoto_common::block::tree::{BlockReader, ImportResult};
-use nakamoto_common::block::{self, Block, BlockHash, BlockHeader, Height, Transaction};
+use nakamoto_common::block::{self, Block, BlockHash, BlockHeader, BlockTime, Height, Transaction};
 use nakamoto_common::nonempty::NonEmpty;
+use nakamoto_net::event;
+use nakamoto_p2p::fsm::fees::FeeEstimate;
 use nakamoto_p2p::fsm::ConnDirection;
-use nakamoto_p2p::fsm::{self, Command, CommandError, GetFiltersError, Peer};
+use nakamoto_p2p::fsm::{
+    self, AddrStats, Command, CommandError, GetFiltersError, Health, InFlight, Latencies, Limits,
+    Metrics, Peer, Snapshot, SyncStatus,
+};
 
 use crate::client::{Event, Loading};
+use crate::export;
+use crate::spv::TxStatus;
 
 /// An error resulting from a handle method.
 #[derive(Error, Debug)]
@@ -38,6 +47,12 @@ pub enum Error {
     /// An I/O error occured.
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    /// A header chain file could not be read or written.
+    #[error("header chain file: {0}")]
+    Export(#[from] export::Error),
+    /// A merkle proof could not be obtained for a transaction.
+    #[error("merkle proof: {0}")]
+    MerkleProof(&'static str),
 }
 
 impl From<chan::RecvError> for Error {
@@ -65,8 +80,142 @@ impl<T> From<chan::SendError<T>> for Error {
 pub trait Handle: Sized + Send + Sync + Clone {
     /// Get the tip of the chain.
     fn get_tip(&self) -> Result<(Height, BlockHeader), Error>;
+    /// Get the sync status of the node. Blocks until the node has finished loading its
+    /// block headers and filter headers from disk.
+    fn get_sync_status(&self) -> Result<SyncStatus, Error>;
+    /// Get the best block height advertised by connected peers, or `None` if there are no
+    /// peers. This is the height last reported with [`Event::PeerHeightUpdated`], and can be
+    /// used as the sync target by late subscribers. Note that there is no guarantee that
+    /// this height really exists; peers could report a bogus height.
+    fn best_peer_height(&self) -> Result<Option<Height>, Error> {
+        let (reply, receive) = chan::bounded(1);
+        self.command(Command::GetBestPeerHeight(reply))?;
+
+        Ok(receive.recv()?)
+    }
+    /// Get a snapshot of the node's state: its header and filter header tips, and its
+    /// negotiated peers.
+    fn get_snapshot(&self) -> Result<Snapshot, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetSnapshot(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+    /// Get a summary of the node's health: its sync status, number of outbound peers and
+    /// age of its tip. Useful for readiness probes, see [`Health::is_ready`].
+    fn get_health(&self) -> Result<Health, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetHealth(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+    /// Get a snapshot of the node's metrics, eg. peer counts, sync heights and traffic
+    /// totals, to be exported to a monitoring system.
+    fn get_metrics(&self) -> Result<Metrics, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetMetrics(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+    /// Get address book statistics, eg. to tell whether the address book is empty or
+    /// full of addresses that can't be connected to.
+    fn get_address_stats(&self) -> Result<AddrStats, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetAddressStats(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+    /// Ask a connected peer for its addresses, and return the addresses it sends, with the
+    /// time they were last seen. If the peer stops responding, the addresses received so
+    /// far are returned. Useful for crawling the network.
+    fn request_addresses(&self, addr: net::SocketAddr) -> Result<Vec<(BlockTime, Address)>, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::RequestAddresses(addr, transmit))?;
+
+        Ok(receive.recv()??)
+    }
+    /// Get the requests sent to peers that are still awaiting a response, with how long
+    /// they've been pending, longest first. Useful to diagnose stalled syncs.
+    fn get_inflight(&self) -> Result<Vec<InFlight>, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetInFlight(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+    /// Get the current fee estimate for a transaction to confirm within `target_blocks`
+    /// blocks, based on recently processed blocks. Returns [`None`] if there isn't enough
+    /// data yet.
+    fn get_fee_estimate(&self, target_blocks: usize) -> Result<Option<FeeEstimate>, Error> {
+        let (reply, receive) = chan::bounded(1);
+        self.command(Command::GetFeeEstimate {
+            target_blocks,
+            reply,
+        })?;
+
+        Ok(receive.recv()?)
+    }
+    /// Get the ping latency percentiles of each connected peer, and of all peers together.
+    /// Peers that haven't answered a ping yet are left out.
+    fn get_latencies(&self) -> Result<Latencies, Error> {
+        let (reply, receive) = chan::bounded(1);
+        self.command(Command::GetLatencies(reply))?;
+
+        Ok(receive.recv()?)
+    }
+    /// Drop block headers below the given height from the header store, to bound its size.
+    /// Returns the height of the first header kept after the genesis, which may be lower
+    /// than requested. See [`Command::PruneHeaders`].
+    fn prune_headers(&self, below: Height) -> Result<Result<Height, block::tree::Error>, Error> {
+        let (reply, receive) = chan::bounded(1);
+        self.command(Command::PruneHeaders { below, reply })?;
+
+        Ok(receive.recv()?)
+    }
+    /// Revert the blocks above the given height and sync them again, eg. to recover from
+    /// a corrupt chain state. Returns the new height of the active chain.
+    /// See [`Command::Resync`].
+    fn resync(&self, from: Height) -> Result<Result<Height, block::tree::Error>, Error> {
+        let (reply, receive) = chan::bounded(1);
+        self.command(Command::Resync { from, reply })?;
+
+        Ok(receive.recv()?)
+    }
     /// Get a full block from the network.
     fn get_block(&self, hash: &BlockHash) -> Result<(), Error>;
+    /// Get a proof that the given transaction is included in the given block of the active
+    /// chain. The block is downloaded from the network, and the proof is built from it and
+    /// verified against the header in our block tree.
+    fn get_merkle_proof(
+        &self,
+        txid: Txid,
+        hash: BlockHash,
+        timeout: time::Duration,
+    ) -> Result<MerkleProof, Error> {
+        let (transmit, receive) = chan::bounded(1);
+
+        self.query_tree(move |t| {
+            transmit
+                .send(t.get_block(&hash).map(|(_, header)| *header))
+                .ok();
+        })?;
+        let header = receive.recv()?.ok_or(Error::MerkleProof("unknown block"))?;
+
+        let blocks = self.blocks();
+        self.get_block(&hash)?;
+
+        let block = event::wait(
+            &blocks,
+            |(block, _)| (block.block_hash() == hash).then_some(block),
+            timeout,
+        )?;
+        let proof = MerkleProof::from_block(&block, &txid)
+            .ok_or(Error::MerkleProof("transaction not in block"))?;
+
+        if !proof.verify(&txid, &header) {
+            return Err(Error::MerkleProof("proof doesn't verify against header"));
+        }
+        Ok(proof)
+    }
     /// Get compact filters from the network.
     fn get_filters(&self, range: RangeInclusive<Height>) -> Result<(), Error>;
     /// Query the block tree using the given function. To return results from
@@ -75,6 +224,26 @@ pub trait Handle: Sized + Send + Sync + Clone {
         &self,
         query: impl Fn(&dyn BlockReader) + Send + Sync + 'static,
     ) -> Result<(), Error>;
+    /// Get the block headers of the active chain in the given height range, along with the
+    /// height of the chain tip at the time of the query.
+    ///
+    /// The headers are read from a single snapshot of the block tree, so they are always
+    /// consistent with each other and with the returned tip, even if a re-org happens
+    /// concurrently. Heights past the tip are not included in the result.
+    fn get_headers(&self, range: Range<Height>) -> Result<(Height, Vec<BlockHeader>), Error> {
+        let (transmit, receive) = chan::bounded(1);
+
+        self.query_tree(move |t| {
+            let height = t.height();
+            let headers = (range.start..range.end.min(height + 1))
+                .filter_map(|h| t.get_block_by_height(h).copied())
+                .collect::<Vec<_>>();
+
+            transmit.send((height, headers)).ok();
+        })?;
+
+        Ok(receive.recv()?)
+    }
     /// Find a branch from the active chain to the given (stale) block.
     ///
     /// See [BlockReader::find_branch](`nakamoto_common::block::tree::BlockReader::find_branch`).
@@ -86,6 +255,21 @@ pub trait Handle: Sized + Send + Sync + Clone {
     fn filters(&self) -> chan::Receiver<(BlockFilter, BlockHash, Height)>;
     /// Subscribe to SPV events.
     fn subscribe(&self) -> chan::Receiver<Event>;
+    /// Subscribe to SPV events, along with their sequence numbers. Sequence numbers increase
+    /// by one with every event, so that gaps can be detected. When an event log is
+    /// configured, they match the sequence numbers of the log, and carry on across restarts.
+    fn subscribe_sequenced(&self) -> chan::Receiver<(u64, Event)>;
+    /// Subscribe to SPV events, starting with an [`Event::Snapshot`] of the current state.
+    /// This lets late subscribers catch up without replaying past events.
+    ///
+    /// No event is missed between the snapshot and the events that follow it, and events
+    /// that are already reflected in the snapshot aren't received.
+    fn subscribe_from_snapshot(&self) -> Result<chan::Receiver<Event>, Error>;
+    /// Subscribe to the status changes of the given transaction.
+    ///
+    /// This is equivalent to filtering the [`Event::TxStatusChanged`] events of
+    /// [`Handle::subscribe`] by transaction ID.
+    fn watch_tx(&self, txid: Txid) -> chan::Receiver<TxStatus>;
     /// Subscribe to client loading events.
     fn loading(&self) -> chan::Receiver<Loading>;
     /// Send a command to the client.
@@ -124,6 +308,54 @@ pub trait Handle: Sized + Send + Sync + Clone {
 
         Ok(())
     }
+    /// Replace the watchlist with the provided scripts, in one step, so that there is no
+    /// window during which only part of the new watchlist is watched.
+    ///
+    /// If `rescan` is set, the range of the last rescan is scanned again with the new
+    /// watchlist, from its start height up to its end height, or without end if it had none.
+    /// Otherwise, only blocks that weren't scanned yet are matched against it.
+    fn set_watchlist(
+        &self,
+        watch: impl Iterator<Item = Script>,
+        rescan: bool,
+    ) -> Result<(), Error> {
+        self.command(Command::SetWatchlist {
+            watch: watch.collect(),
+            rescan,
+        })
+    }
+    /// Watch an outpoint, and get an [`Event::OutpointSpent`] when it is spent.
+    ///
+    /// Compact block filters only commit to the scripts of spent outputs, so the
+    /// outpoint's `scriptPubKey` must be provided. Like [`Handle::watch`], this won't
+    /// trigger a rescan of existing blocks.
+    fn watch_outpoint(&self, outpoint: OutPoint, script_pubkey: Script) -> Result<(), Error> {
+        self.command(Command::WatchOutpoint {
+            outpoint,
+            script_pubkey,
+        })?;
+
+        Ok(())
+    }
+    /// Set the probability of downloading blocks that don't match the watchlist, as decoys.
+    /// See [`crate::Config::filter_decoy_rate`].
+    fn set_filter_decoy_rate(&self, rate: f64) -> Result<(), Error> {
+        self.command(Command::SetFilterDecoyRate(rate))
+    }
+    /// Change the client's peer and cache limits at runtime. If the limits are lowered,
+    /// excess peers are disconnected.
+    fn set_limits(&self, limits: Limits) -> Result<(), Error> {
+        self.command(Command::SetLimits(limits))
+    }
+    /// Pause compact filter syncing, eg. when the application is in the background.
+    /// Peers stay connected and block headers continue to sync.
+    fn pause_filter_sync(&self) -> Result<(), Error> {
+        self.command(Command::PauseFilterSync)
+    }
+    /// Resume compact filter syncing after it was paused.
+    fn resume_filter_sync(&self) -> Result<(), Error> {
+        self.command(Command::ResumeFilterSync)
+    }
     /// Broadcast a message to peers matching the predicate.
     /// To only broadcast to outbound peers, use [`Peer::is_outbound`].
     fn broadcast(
@@ -138,6 +370,14 @@ pub trait Handle: Sized + Send + Sync + Clone {
     fn connect(&self, addr: net::SocketAddr) -> Result<ConnDirection, Error>;
     /// Disconnect from the designated peer address.
     fn disconnect(&self, addr: net::SocketAddr) -> Result<(), Error>;
+    /// Rotate a random outbound peer for a peer in another network group. Returns the
+    /// disconnected peer and the new one, if a peer was rotated. See [`Command::RotatePeer`].
+    fn rotate_peer(&self) -> Result<Option<(net::SocketAddr, net::SocketAddr)>, Error> {
+        let (reply, receive) = chan::bounded(1);
+        self.command(Command::RotatePeer(reply))?;
+
+        Ok(receive.recv()?)
+    }
     /// Submit a transaction to the network.
     ///
     /// Returns the peer(s) the transaction was announced to, or an error if no peers were found.
@@ -148,8 +388,44 @@ pub trait Handle: Sized + Send + Sync + Clone {
         &self,
         headers: Vec<BlockHeader>,
     ) -> Result<Result<ImportResult, block::tree::Error>, Error>;
+    /// Export the active header chain to a file, which can be imported by another node with
+    /// [`Handle::import_headers_from`]. Returns the height of the exported chain.
+    ///
+    /// See the [`export`] module for the file format.
+    fn export_headers(&self, path: &Path) -> Result<Height, Error> {
+        let (height, headers) = self.get_headers(0..Height::MAX)?;
+        let (genesis, headers) = headers.split_first().ok_or(Error::Disconnected)?;
+        let file = io::BufWriter::new(fs::File::create(path)?);
+
+        export::write(file, &genesis.block_hash(), headers)?;
+
+        Ok(height)
+    }
+    /// Import a header chain file created with [`Handle::export_headers`]. The file must be
+    /// for the node's network. The headers are fully validated on import.
+    fn import_headers_from(
+        &self,
+        path: &Path,
+    ) -> Result<Result<ImportResult, block::tree::Error>, Error> {
+        let (_, genesis) = self.get_headers(0..1)?;
+        let genesis = genesis.first().ok_or(Error::Disconnected)?.block_hash();
+        let file = io::BufReader::new(fs::File::open(path)?);
+        let headers = export::read(file, &genesis)?;
+
+        if headers.is_empty() {
+            return Ok(Ok(ImportResult::TipUnchanged));
+        }
+        self.import_headers(headers)
+    }
     /// Import peer addresses into the node's address book.
     fn import_addresses(&self, addrs: Vec<Address>) -> Result<(), Error>;
+    /// Add known peer addresses to the node's address book, as candidates for outbound
+    /// connections. The services of addresses that are already known are merged.
+    fn add_addresses(&self, addrs: Vec<(net::SocketAddr, ServiceFlags)>) -> Result<(), Error> {
+        self.command(Command::AddAddresses(addrs))?;
+
+        Ok(())
+    }
     /// Wait for the given predicate to be fulfilled.
     fn wait<F: FnMut(fsm::Event) -> Option<T>, T>(&self, f: F) -> Result<T, Error>;
     /// Wait for a given number of peers to be connected with the given services.
@@ -161,6 +437,26 @@ pub trait Handle: Sized + Send + Sync + Clone {
     /// Wait for the node's active chain to reach a certain height. The hash at that height
     /// is returned.
     fn wait_for_height(&self, h: Height) -> Result<BlockHash, Error>;
+    /// Wait for the node to be synced, ie. for filters and matching blocks to be processed
+    /// up to the tip of the header chain. Returns the synced height, or [`Error::Timeout`]
+    /// if the node isn't synced in time.
+    fn wait_synced(&self, timeout: time::Duration) -> Result<Height, Error> {
+        let events = self.subscribe();
+
+        if let SyncStatus::Synced { height } = self.get_sync_status()? {
+            return Ok(height);
+        }
+        let height = event::wait(
+            &events,
+            |e| match e {
+                Event::Synced { height, tip } if height == tip => Some(height),
+                _ => None,
+            },
+            timeout,
+        )?;
+
+        Ok(height)
+    }
     /// Listen on events.
     fn events(&self) -> chan::Receiver<fsm::Event>;
     /// Shutdown the node process.
@@ -0,0 +1,272 @@
+//! Append-only log of client events, used to replay events after a restart.
+//!
+//! Every event is written as a line of JSON, along with its sequence number:
+//!
+//! ```json
+//! {"event":{"type":"ready","tip":0,"filter_tip":0},"seq":0}
+//! ```
+//!
+//! Consumers that must not miss events keep track of the last sequence number they
+//! processed, and on startup, [`replay`] the log from there.
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use microserde::json::{Number, Object, Value};
+
+use crate::event::Event;
+
+/// Number of events after which the log is flushed to disk, by default.
+pub const DEFAULT_SYNC_EVENTS: usize = 64;
+
+/// When to flush the log to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// After every event. This is the safest, and slowest policy: since events are logged
+    /// as they happen, flushing every one of them slows the client down, eg. while syncing.
+    Always,
+    /// After the given number of events. Up to this many events may be lost on a crash.
+    Every(usize),
+    /// Let the operating system decide.
+    Never,
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        Self::Every(DEFAULT_SYNC_EVENTS)
+    }
+}
+
+/// A log entry.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Sequence number of the event. Sequence numbers increase by one with every event
+    /// emitted by the client.
+    pub seq: u64,
+    /// The event, as returned by [`Event::to_json`].
+    pub event: Value,
+}
+
+/// An append-only event log.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: fs::File,
+    sync: SyncPolicy,
+    /// Sequence number of the next event.
+    next: u64,
+    /// Number of events written since the last sync.
+    unsynced: usize,
+}
+
+impl Journal {
+    /// Open the log at the given path, creating it if necessary. An entry that was only
+    /// partially written, eg. due to a crash, is discarded.
+    pub fn open<P: AsRef<Path>>(path: P, sync: SyncPolicy) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let contents = fs::read_to_string(&path)?;
+        let (entries, len) = parse(&contents)?;
+
+        if len < contents.len() {
+            log::warn!(
+                target: "client",
+                "Discarding partial entry at the end of event log {:?}", path
+            );
+            file.set_len(len as u64)?;
+            file.sync_all()?;
+        }
+        let next = entries.last().map_or(0, |e| e.seq + 1);
+
+        Ok(Self {
+            path,
+            file,
+            sync,
+            next,
+            unsynced: 0,
+        })
+    }
+
+    /// Sequence number of the next event appended.
+    pub fn next_seq(&self) -> u64 {
+        self.next
+    }
+
+    /// Append an event to the log, with the given sequence number. Sequence numbers must
+    /// increase, but may skip numbers, eg. if an event couldn't be logged.
+    pub fn append(&mut self, seq: u64, event: &Event) -> io::Result<()> {
+        if seq < self.next {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "event sequence number is out of order",
+            ));
+        }
+        let mut line = to_string(seq, event.to_json());
+
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.next = seq + 1;
+        self.unsynced += 1;
+
+        match self.sync {
+            SyncPolicy::Always => self.sync()?,
+            SyncPolicy::Every(n) if self.unsynced >= n => self.sync()?,
+            SyncPolicy::Every(_) | SyncPolicy::Never => {}
+        }
+        Ok(())
+    }
+
+    /// Flush the log to disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+
+        Ok(())
+    }
+
+    /// Discard the entries before the given sequence number, eg. once they were processed.
+    /// The log is rewritten and atomically replaced. The last entry is always kept, for
+    /// sequence numbers to carry on when the log is reopened.
+    pub fn compact(&mut self, seq: u64) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+
+        for entry in replay(&self.path, seq.min(self.next.saturating_sub(1)))? {
+            let mut line = to_string(entry.seq, entry.event);
+
+            line.push('\n');
+            file.write_all(line.as_bytes())?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.file = fs::OpenOptions::new().append(true).open(&self.path)?;
+        self.unsynced = 0;
+
+        Ok(())
+    }
+}
+
+/// Read the entries of the log at the given path, starting at the given sequence number.
+/// Returns an empty list if the log doesn't exist.
+pub fn replay<P: AsRef<Path>>(path: P, from: u64) -> io::Result<Vec<Entry>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let (entries, _) = parse(&contents)?;
+
+    Ok(entries.into_iter().filter(|e| e.seq >= from).collect())
+}
+
+/// Serialize a log entry.
+fn to_string(seq: u64, event: Value) -> String {
+    let mut obj = Object::new();
+
+    obj.insert("seq".to_owned(), Value::Number(Number::U64(seq)));
+    obj.insert("event".to_owned(), event);
+
+    microserde::json::to_string(&Value::Object(obj))
+}
+
+/// Parse the complete entries of a log. Returns the entries and the length of the log they
+/// span, which is shorter than the log if the last entry is incomplete.
+fn parse(contents: &str) -> io::Result<(Vec<Entry>, usize)> {
+    let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+    let mut entries = Vec::new();
+    let mut len = 0;
+
+    for line in contents.split_inclusive('\n') {
+        let line = match line.strip_suffix('\n') {
+            Some(line) => line,
+            None => break,
+        };
+        let mut obj = match microserde::json::from_str::<Value>(line).map_err(|_| invalid())? {
+            Value::Object(obj) => obj,
+            _ => return Err(invalid()),
+        };
+        let seq = match obj.get("seq") {
+            Some(Value::Number(Number::U64(n))) => *n,
+            _ => return Err(invalid()),
+        };
+        let event = obj.remove("event").ok_or_else(invalid)?;
+
+        entries.push(Entry { seq, event });
+        len += line.len() + 1;
+    }
+    Ok((entries, len))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_append_and_replay() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("events.log");
+        let event = |tip| Event::Ready { tip, filter_tip: 0 };
+
+        assert!(replay(&path, 0).unwrap().is_empty());
+
+        let mut journal = Journal::open(&path, SyncPolicy::Every(2)).unwrap();
+        for tip in 0..3 {
+            journal.append(tip, &event(tip)).unwrap();
+        }
+        drop(journal);
+
+        let entries = replay(&path, 1).unwrap();
+        assert_eq!(
+            entries.iter().map(|e| e.seq).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            microserde::json::to_string(&entries[0].event),
+            microserde::json::to_string(&event(1).to_json())
+        );
+
+        // A partially written entry is discarded.
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"seq\":3,\"ev")
+            .unwrap();
+        let mut journal = Journal::open(&path, SyncPolicy::Always).unwrap();
+        assert_eq!(journal.next_seq(), 3);
+        assert!(journal.append(2, &event(2)).is_err());
+        journal.append(3, &event(3)).unwrap();
+
+        // Compacting keeps the sequence numbers.
+        journal.compact(2).unwrap();
+        journal.append(4, &event(4)).unwrap();
+        assert_eq!(
+            replay(&path, 0)
+                .unwrap()
+                .iter()
+                .map(|e| e.seq)
+                .collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+
+        // The last entry is kept.
+        journal.compact(9).unwrap();
+        assert_eq!(
+            replay(&path, 0)
+                .unwrap()
+                .iter()
+                .map(|e| e.seq)
+                .collect::<Vec<_>>(),
+            vec![4]
+        );
+        assert_eq!(
+            Journal::open(&path, SyncPolicy::Never).unwrap().next_seq(),
+            5
+        );
+    }
+}
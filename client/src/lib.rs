@@ -3,9 +3,13 @@
 #![allow(clippy::type_complexity)]
 #![deny(missing_docs, unsafe_code)]
 pub mod client;
+pub mod dns;
 pub mod error;
 pub mod event;
+pub mod export;
+pub mod fees;
 pub mod handle;
+pub mod journal;
 pub mod peer;
 pub mod service;
 pub mod spv;
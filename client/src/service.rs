@@ -2,22 +2,33 @@
 use std::borrow::{Borrow, Cow};
 use std::collections::HashMap;
 use std::net;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use nakamoto_chain::BlockTree;
 use nakamoto_common::bitcoin::consensus::Encodable;
-use nakamoto_common::block::time::{AdjustedClock, LocalTime};
+use nakamoto_common::block::time::{AdjustedClock, LocalDuration, LocalTime};
 use nakamoto_net::{ConnDirection, DisconnectReason, PeerProtocol, ReactorDispatch};
 use nakamoto_p2p as p2p;
 
 use crate::client::Config;
+use crate::fees;
 use crate::peer;
 use nakamoto_common::block::filter;
 
+/// Minimum time between saves of the fee history. The history is also saved when the
+/// service is dropped.
+pub const FEE_HISTORY_SAVE_INTERVAL: LocalDuration = LocalDuration::from_mins(10);
+
 /// Client service. Wraps a state machine and handles decoding and encoding of network messages.
 pub struct Service<T, F, P, C> {
     inboxes: HashMap<net::SocketAddr, p2p::stream::Decoder>,
     machine: p2p::StateMachine<T, F, P, C>,
+    fee_history: Option<PathBuf>,
+    /// Fee history that changed since it was last saved.
+    fee_history_unsaved: Option<fees::FeeHistory>,
+    /// Last time the fee history was saved.
+    fee_history_saved: LocalTime,
 }
 
 impl<T: BlockTree, F: filter::Filters, P: peer::Store, C: AdjustedClock<net::SocketAddr>>
@@ -32,8 +43,33 @@ impl<T: BlockTree, F: filter::Filters, P: peer::Store, C: AdjustedClock<net::Soc
         rng: fastrand::Rng,
         config: Config,
     ) -> Self {
+        let mut history = Vec::new();
+
+        if let Some(path) = &config.fee_history {
+            match fees::load(path) {
+                Ok(Some(h)) if h.is_fresh(clock.local_time()) => {
+                    log::info!(
+                        "Restoring {} fee estimate(s) from {:?}",
+                        h.estimates.len(),
+                        path
+                    );
+                    history = h.estimates;
+                }
+                Ok(Some(_)) => {
+                    log::info!("Fee history in {:?} is stale, ignoring", path);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    log::warn!("Failed to load fee history from {:?}: {}", path, err);
+                }
+            }
+        }
+
         Self {
             inboxes: HashMap::new(),
+            fee_history: config.fee_history,
+            fee_history_unsaved: None,
+            fee_history_saved: LocalTime::default(),
             machine: p2p::StateMachine::new(
                 tree,
                 filters,
@@ -42,12 +78,44 @@ impl<T: BlockTree, F: filter::Filters, P: peer::Store, C: AdjustedClock<net::Soc
                 rng,
                 p2p::Config {
                     network: config.network,
+                    params: config.network.params(),
                     domains: config.domains,
                     connect: config.connect,
+                    connect_only: config.connect_only,
+                    failover: config.failover,
                     user_agent: config.user_agent,
                     hooks: config.hooks,
                     limits: config.limits,
                     services: config.services,
+                    required_services: config.required_services,
+                    min_protocol_version: config.min_protocol_version,
+                    inbound_allow: config.inbound_allow,
+                    inbound_deny: config.inbound_deny,
+                    connect_timeout: config.connect_timeout.into(),
+                    handshake_timeout: config.handshake_timeout.into(),
+                    rotate_interval: config.rotate_interval.map(LocalDuration::from),
+                    block_download_timeout: config.block_download_timeout.into(),
+                    bloom_fallback: config.bloom_fallback,
+                    headers_only: config.headers_only,
+                    compact_blocks: config.compact_blocks,
+                    filter_decoy_rate: config.filter_decoy_rate,
+                    max_message_headers: config.max_message_headers,
+                    max_message_cfheaders: config.max_message_cfheaders,
+                    max_cfheaders_requests: config.max_cfheaders_requests,
+                    serve_filters: config.serve_filters,
+                    serve_headers: config.serve_headers,
+                    serve_budget: config.serve_budget,
+                    locator_density: config.locator_density,
+                    minimum_chain_work: config.minimum_chain_work,
+                    getaddr: config.getaddr,
+                    fee_history: history,
+                    mempool_fees: config.mempool_fees,
+                    fee_filter: config.fee_filter,
+                    prune_depth: config.prune_depth,
+                    max_filter_match_rate: config.max_filter_match_rate,
+                    filter_match_rate_sample_size: config.filter_match_rate_sample_size,
+                    filter_redundancy: config.filter_redundancy,
+                    max_reorg_depth: config.max_reorg_depth,
 
                     ..p2p::Config::default()
                 },
@@ -56,6 +124,43 @@ impl<T: BlockTree, F: filter::Filters, P: peer::Store, C: AdjustedClock<net::Soc
     }
 }
 
+impl<T, F, P, C> Service<T, F, P, C>
+where
+    T: BlockTree,
+    F: filter::Filters,
+    P: peer::Store,
+    C: AdjustedClock<net::SocketAddr>,
+{
+    /// Save the fee estimates of recent blocks, if configured, and they changed at least
+    /// [`FEE_HISTORY_SAVE_INTERVAL`] after they were last saved.
+    fn save_fee_history(&mut self, local_time: LocalTime) {
+        if local_time - self.fee_history_saved < FEE_HISTORY_SAVE_INTERVAL {
+            return;
+        }
+        if let Some(history) = self.fee_history_unsaved.take() {
+            self.fee_history_saved = local_time;
+            write_fee_history(self.fee_history.as_ref(), &history);
+        }
+    }
+}
+
+impl<T, F, P, C> Drop for Service<T, F, P, C> {
+    fn drop(&mut self) {
+        if let Some(history) = self.fee_history_unsaved.take() {
+            write_fee_history(self.fee_history.as_ref(), &history);
+        }
+    }
+}
+
+/// Save a fee history to the given path, if any.
+fn write_fee_history(path: Option<&PathBuf>, history: &fees::FeeHistory) {
+    if let Some(path) = path {
+        if let Err(err) = fees::save(path, history) {
+            log::warn!("Failed to save fee history to {:?}: {}", path, err);
+        }
+    }
+}
+
 impl<T, F, P, C> nakamoto_net::PeerService for Service<T, F, P, C>
 where
     T: BlockTree,
@@ -88,6 +193,7 @@ where
 
     fn tick(&mut self, local_time: LocalTime) {
         self.machine.tick(local_time);
+        self.save_fee_history(local_time);
     }
 
     fn on_timer(&mut self) {
@@ -96,6 +202,7 @@ where
 
     fn received(&mut self, addr: &net::SocketAddr, bytes: Cow<[u8]>) {
         if let Some(inbox) = self.inboxes.get_mut(addr) {
+            self.machine.record_bytes_received(bytes.len());
             inbox.input(bytes.borrow());
 
             loop {
@@ -103,7 +210,26 @@ where
                     Ok(Some(msg)) => self.machine.received(addr, Cow::Owned(msg)),
                     Ok(None) => break,
 
-                    Err(err) => {
+                    Err(p2p::stream::Error::Oversized {
+                        command,
+                        length,
+                        max,
+                    }) => {
+                        log::error!(
+                            "Oversized `{}` message of {} bytes (max = {}) received from {}",
+                            command,
+                            length,
+                            max,
+                            addr
+                        );
+                        self.machine.disconnect(
+                            *addr,
+                            p2p::DisconnectReason::PeerMisbehaving("oversized message"),
+                        );
+
+                        return;
+                    }
+                    Err(p2p::stream::Error::Decode(err)) => {
                         log::error!("Invalid message received from {}: {}", addr, err);
 
                         self.machine
@@ -142,7 +268,13 @@ where
     }
 }
 
-impl<T, F, P, C> Iterator for Service<T, F, P, C> {
+impl<T, F, P, C> Iterator for Service<T, F, P, C>
+where
+    T: BlockTree,
+    F: filter::Filters,
+    P: peer::Store,
+    C: AdjustedClock<net::SocketAddr>,
+{
     type Item = ReactorDispatch<Vec<u8>, p2p::Event, p2p::DisconnectReason>;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -153,13 +285,23 @@ impl<T, F, P, C> Iterator for Service<T, F, P, C> {
 
                 msg.consensus_encode(&mut buf)
                     .expect("writing to an in-memory buffer doesn't fail");
+                self.machine.record_bytes_sent(buf.len());
 
                 Some(ReactorDispatch::SendPeer(addr, buf))
             }
             Some(ReactorDispatch::NotifySubscribers(e)) => {
+                if let p2p::Event::Inventory(p2p::fsm::InventoryEvent::BlockProcessed {
+                    fees: Some(_),
+                    ..
+                }) = &e
+                {
+                    if self.fee_history.is_some() {
+                        self.fee_history_unsaved = Some(self.machine.fee_history());
+                    }
+                }
                 Some(ReactorDispatch::NotifySubscribers(e))
             }
-            Some(ReactorDispatch::ConnectPeer(a)) => Some(ReactorDispatch::ConnectPeer(a)),
+            Some(ReactorDispatch::ConnectPeer(a, t)) => Some(ReactorDispatch::ConnectPeer(a, t)),
             Some(ReactorDispatch::DisconnectPeer(a, r)) => {
                 Some(ReactorDispatch::DisconnectPeer(a, r))
             }
@@ -4,11 +4,12 @@
 #[cfg(test)]
 mod tests;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::{fmt, net};
 
-use nakamoto_common::bitcoin::{Block, Txid};
+use nakamoto_common::bitcoin::{Block, OutPoint, Txid};
 use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::p2p::peer::Source;
 use nakamoto_net::event::Emitter;
 use nakamoto_p2p as p2p;
 use p2p::fsm;
@@ -17,6 +18,8 @@ use crate::client::Event;
 
 /// Transaction status of a given transaction.
 #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "status", rename_all = "snake_case"))]
 pub enum TxStatus {
     /// This is the initial state of a transaction after it has been announced by the
     /// client.
@@ -75,6 +78,45 @@ impl fmt::Display for TxStatus {
     }
 }
 
+impl TxStatus {
+    /// Convert to a JSON value.
+    pub fn to_json(&self) -> microserde::json::Value {
+        use microserde::json::{Number, Object, Value};
+
+        let mut obj = Object::new();
+
+        match self {
+            Self::Unconfirmed => {
+                obj.insert("status".to_owned(), Value::String("unconfirmed".to_owned()));
+            }
+            Self::Acknowledged { peer } => {
+                obj.insert(
+                    "status".to_owned(),
+                    Value::String("acknowledged".to_owned()),
+                );
+                obj.insert("peer".to_owned(), Value::String(peer.to_string()));
+            }
+            Self::Confirmed { height, block } => {
+                obj.insert("status".to_owned(), Value::String("confirmed".to_owned()));
+                obj.insert("height".to_owned(), Value::Number(Number::U64(*height)));
+                obj.insert("block".to_owned(), Value::String(block.to_string()));
+            }
+            Self::Reverted => {
+                obj.insert("status".to_owned(), Value::String("reverted".to_owned()));
+            }
+            Self::Stale { replaced_by, block } => {
+                obj.insert("status".to_owned(), Value::String("stale".to_owned()));
+                obj.insert(
+                    "replaced_by".to_owned(),
+                    Value::String(replaced_by.to_string()),
+                );
+                obj.insert("block".to_owned(), Value::String(block.to_string()));
+            }
+        }
+        Value::Object(obj)
+    }
+}
+
 /// Event mapper for SPV and client events.
 /// Consumes protocol events and emits [`Event`].
 pub struct Mapper {
@@ -90,6 +132,12 @@ pub struct Mapper {
     block_height: Height,
     /// Filter heights that have been matched, and for which we are awaiting a block to process.
     pending: HashSet<Height>,
+    /// Inputs of transactions reverted by a re-org, and not confirmed again since, along with
+    /// our tip at the time. Used to detect transactions replaced by a conflicting transaction
+    /// in the new chain. Entries are pruned after [`fsm::TRANSACTION_PRUNE_DEPTH`] blocks.
+    reverted: HashMap<OutPoint, (Txid, Height)>,
+    /// Whether filters are disabled. If so, we are synced up to the header tip.
+    headers_only: bool,
 }
 
 impl Mapper {
@@ -100,6 +148,7 @@ impl Mapper {
         let filter_height = 0;
         let block_height = 0;
         let pending = HashSet::new();
+        let reverted = HashMap::new();
 
         Self {
             tip,
@@ -107,6 +156,8 @@ impl Mapper {
             filter_height,
             block_height,
             pending,
+            reverted,
+            headers_only: false,
         }
     }
 
@@ -116,8 +167,11 @@ impl Mapper {
             fsm::Event::Ready {
                 height,
                 filter_height,
+                headers_only,
                 ..
             } => {
+                self.headers_only = headers_only;
+
                 emitter.emit(Event::Ready {
                     tip: height,
                     filter_tip: filter_height,
@@ -129,6 +183,29 @@ impl Mapper {
             fsm::Event::Peer(fsm::PeerEvent::ConnectionFailed(addr, error)) => {
                 emitter.emit(Event::PeerConnectionFailed { addr, error });
             }
+            fsm::Event::Peer(fsm::PeerEvent::FailedOver { from, to }) => {
+                emitter.emit(Event::PeerFailedOver { from, to });
+            }
+            fsm::Event::Peer(fsm::PeerEvent::PossibleEclipse {
+                peers,
+                groups,
+                source,
+            }) => {
+                emitter.emit(Event::PossibleEclipse {
+                    peers,
+                    groups,
+                    source: match source {
+                        Some(Source::Peer(addr)) => Some(addr),
+                        _ => None,
+                    },
+                });
+            }
+            fsm::Event::Address(fsm::AddressEvent::DnsSeedResolved { seed, addresses }) => {
+                emitter.emit(Event::DnsSeedResolved { seed, addresses });
+            }
+            fsm::Event::Address(fsm::AddressEvent::DnsSeedFailed { seed, error }) => {
+                emitter.emit(Event::DnsSeedFailed { seed, error });
+            }
             fsm::Event::Peer(fsm::PeerEvent::Negotiated {
                 addr,
                 link,
@@ -136,6 +213,8 @@ impl Mapper {
                 user_agent,
                 height,
                 version,
+                relay,
+                time_offset,
             }) => {
                 emitter.emit(Event::PeerNegotiated {
                     addr,
@@ -144,18 +223,37 @@ impl Mapper {
                     user_agent,
                     height,
                     version,
+                    relay,
+                    time_offset,
                 });
             }
             fsm::Event::Peer(fsm::PeerEvent::Disconnected(addr, reason)) => {
                 emitter.emit(Event::PeerDisconnected { addr, reason });
             }
+            fsm::Event::ClockSkewDetected { offset } => {
+                emitter.emit(Event::ClockSkewDetected { offset });
+            }
             fsm::Event::Chain(fsm::ChainEvent::PeerHeightUpdated { height }) => {
                 emitter.emit(Event::PeerHeightUpdated { height });
             }
             fsm::Event::Chain(fsm::ChainEvent::Synced(_, height)) => {
                 self.tip = height;
+
+                if self.headers_only {
+                    // Without filters to process, we're synced as soon as the header
+                    // chain is. Nb. the tip may be lower than before, after a re-org.
+                    self.filter_height = height;
+                    self.block_height = self.block_height.min(height);
+                    self.sync_height = self.sync_height.min(height);
+                }
             }
             fsm::Event::Chain(fsm::ChainEvent::BlockConnected { header, height }) => {
+                // Reverted transactions that were neither confirmed again nor replaced
+                // by now were most likely dropped.
+                self.reverted.retain(|_, (_, tip)| {
+                    height.saturating_sub(*tip) <= fsm::TRANSACTION_PRUNE_DEPTH
+                });
+
                 emitter.emit(Event::BlockConnected {
                     header,
                     hash: header.block_hash(),
@@ -169,11 +267,24 @@ impl Mapper {
                     height,
                 });
             }
+            fsm::Event::Chain(fsm::ChainEvent::DeepReorgDetected {
+                peer,
+                depth,
+                competing_tip,
+            }) => {
+                emitter.emit(Event::DeepReorgDetected {
+                    peer,
+                    depth,
+                    competing_tip,
+                });
+            }
             fsm::Event::Inventory(fsm::InventoryEvent::BlockProcessed {
                 block,
                 height,
                 fees,
             }) => {
+                self.process_conflicts(&block, emitter);
+
                 let hash = self.process_block(block, height, emitter);
 
                 if let Some(fees) = fees {
@@ -184,22 +295,76 @@ impl Mapper {
                     });
                 }
             }
+            fsm::Event::Inventory(fsm::InventoryEvent::BlockDownloadStarted {
+                block,
+                height,
+                ..
+            }) => {
+                emitter.emit(Event::BlockDownloadStarted {
+                    hash: block,
+                    height,
+                });
+            }
             fsm::Event::Inventory(fsm::InventoryEvent::Confirmed {
                 transaction,
                 height,
                 block,
             }) => {
+                let txid = transaction.txid();
+
+                self.reverted.retain(|_, (t, _)| *t != txid);
+
                 emitter.emit(Event::TxStatusChanged {
-                    txid: transaction.txid(),
+                    txid,
                     status: TxStatus::Confirmed { height, block },
                 });
             }
+            fsm::Event::Inventory(fsm::InventoryEvent::Reverted { transaction }) => {
+                let txid = transaction.txid();
+
+                for input in &transaction.input {
+                    self.reverted
+                        .insert(input.previous_output, (txid, self.tip));
+                }
+                emitter.emit(Event::TxStatusChanged {
+                    txid,
+                    status: TxStatus::Reverted,
+                });
+            }
             fsm::Event::Inventory(fsm::InventoryEvent::Acknowledged { txid, peer }) => {
                 emitter.emit(Event::TxStatusChanged {
                     txid,
                     status: TxStatus::Acknowledged { peer },
                 });
             }
+            fsm::Event::Inventory(fsm::InventoryEvent::OutpointSpent {
+                outpoint,
+                txid,
+                block,
+                height,
+            }) => {
+                emitter.emit(Event::OutpointSpent {
+                    outpoint,
+                    txid,
+                    block,
+                    height,
+                });
+            }
+            fsm::Event::Inventory(fsm::InventoryEvent::MessageRejected {
+                peer,
+                message,
+                txid,
+                code,
+                reason,
+            }) => {
+                emitter.emit(Event::MessageRejected {
+                    peer,
+                    message,
+                    txid,
+                    code: code as u8,
+                    reason,
+                });
+            }
             fsm::Event::Filter(fsm::FilterEvent::RescanStarted { start, .. }) => {
                 self.pending.clear();
 
@@ -216,6 +381,60 @@ impl Mapper {
             }) => {
                 self.process_filter(block, height, matched, valid, emitter);
             }
+            fsm::Event::Filter(fsm::FilterEvent::Synced(height)) => {
+                emitter.emit(Event::FilterHeadersSynced { height });
+            }
+            fsm::Event::Filter(fsm::FilterEvent::SuspiciousMatchRate {
+                peer,
+                processed,
+                matched,
+            }) => {
+                emitter.emit(Event::SuspiciousMatchRate {
+                    peer,
+                    processed,
+                    matched,
+                });
+            }
+            fsm::Event::Filter(fsm::FilterEvent::FilterConflict {
+                height,
+                block_hash,
+                peers,
+            }) => {
+                emitter.emit(Event::FilterConflict {
+                    block: block_hash,
+                    height,
+                    peers,
+                });
+            }
+            fsm::Event::Bloom(fsm::BloomEvent::MerkleBlockProcessed {
+                peer,
+                height,
+                block,
+                matched,
+            }) if !matched.is_empty() => {
+                emitter.emit(Event::MerkleBlockMatched {
+                    peer,
+                    hash: block,
+                    height,
+                    txids: matched,
+                });
+            }
+            fsm::Event::Bloom(fsm::BloomEvent::TransactionMatched {
+                peer,
+                height,
+                block,
+                transaction,
+            }) => {
+                emitter.emit(Event::TransactionMatched {
+                    peer,
+                    block,
+                    height,
+                    transaction,
+                });
+            }
+            // Bloom filter loads only reflect the client's own watchlist, and are
+            // not surfaced.
+            fsm::Event::Bloom(fsm::BloomEvent::FilterLoaded { .. }) => {}
             _ => {}
         }
         assert!(
@@ -248,6 +467,35 @@ impl Mapper {
 
     // PRIVATE METHODS /////////////////////////////////////////////////////////
 
+    /// Check whether a processed block spends the inputs of a reverted transaction, in
+    /// which case the reverted transaction was replaced and is now stale.
+    fn process_conflicts(&mut self, block: &Block, emitter: &Emitter<Event>) {
+        if self.reverted.is_empty() {
+            return;
+        }
+        let hash = block.block_hash();
+
+        for tx in &block.txdata {
+            let replaced_by = tx.txid();
+
+            for input in &tx.input {
+                let txid = match self.reverted.remove(&input.previous_output) {
+                    Some((txid, _)) if txid != replaced_by => txid,
+                    _ => continue,
+                };
+                self.reverted.retain(|_, (t, _)| *t != txid);
+
+                emitter.emit(Event::TxStatusChanged {
+                    txid,
+                    status: TxStatus::Stale {
+                        replaced_by,
+                        block: hash,
+                    },
+                });
+            }
+        }
+    }
+
     // TODO: Instead of receiving the block, fetch it if matched.
     fn process_block(
         &mut self,
@@ -232,8 +232,236 @@ fn test_peer_negotiated() {
     assert_matches!(events.try_recv(), Ok(Event::PeerConnected { .. }));
     assert_matches!(
         events.try_recv(),
-        Ok(Event::PeerNegotiated { addr, height, user_agent, .. })
-        if addr == remote && height == 42 && user_agent == "?"
+        Ok(Event::PeerNegotiated { addr, height, user_agent, relay, .. })
+        if addr == remote && height == 42 && user_agent == "?" && !relay
+    );
+}
+
+#[test]
+fn test_headers_only_synced() {
+    let network = Network::Regtest;
+    let mut mock = mock::Client::new(network);
+    let client = mock.handle();
+    let subscriber = client.subscribe();
+    let hash = network.genesis_hash();
+
+    mock.subscriber.broadcast(fsm::Event::Ready {
+        height: 0,
+        filter_height: 0,
+        headers_only: true,
+        time: LocalTime::now(),
+    });
+    mock.subscriber
+        .broadcast(fsm::Event::Chain(fsm::ChainEvent::Synced(hash, 42)));
+
+    assert_matches!(subscriber.try_recv(), Ok(Event::Ready { .. }));
+    assert_matches!(
+        subscriber.try_recv(),
+        Ok(Event::Synced {
+            height: 42,
+            tip: 42
+        })
+    );
+}
+
+#[test]
+fn test_filter_headers_synced() {
+    let network = Network::Regtest;
+    let mut mock = mock::Client::new(network);
+    let client = mock.handle();
+    let subscriber = client.subscribe();
+
+    mock.subscriber
+        .broadcast(fsm::Event::Filter(fsm::FilterEvent::Synced(42)));
+
+    assert_matches!(
+        subscriber.try_recv(),
+        Ok(Event::FilterHeadersSynced { height: 42 })
+    );
+    assert!(
+        subscriber.try_recv().is_err(),
+        "filters aren't considered synced"
+    );
+}
+
+#[test]
+fn test_watch_tx() {
+    let network = Network::Regtest;
+    let mut mock = mock::Client::new(network);
+    let mut rng = fastrand::Rng::new();
+    let client = mock.handle();
+
+    let (tx, other) = (gen::transaction(&mut rng), gen::transaction(&mut rng));
+    let block = network.genesis_hash();
+    let statuses = client.watch_tx(tx.txid());
+
+    for transaction in [other, tx] {
+        mock.subscriber
+            .broadcast(fsm::Event::Inventory(fsm::InventoryEvent::Confirmed {
+                transaction,
+                height: 0,
+                block,
+            }));
+    }
+    assert_eq!(
+        statuses.try_recv(),
+        Ok(TxStatus::Confirmed { height: 0, block })
+    );
+    assert!(statuses.try_recv().is_err());
+}
+
+#[test]
+fn test_tx_replaced_after_reorg() {
+    let network = Network::Regtest;
+    let mut mock = mock::Client::new(network);
+    let mut rng = fastrand::Rng::new();
+    let client = mock.handle();
+    let subscriber = client.subscribe();
+
+    let outpoint = OutPoint::new(Txid::all_zeros(), 0);
+    let original = gen::transaction_with(outpoint, 100_000, &mut rng);
+    let replacement = gen::transaction_with(outpoint, 100_000, &mut rng);
+    let block = gen::block_with(
+        &network.genesis(),
+        vec![gen::coinbase(&mut rng), replacement.clone()],
+        &mut rng,
+    );
+
+    mock.subscriber
+        .broadcast(fsm::Event::Inventory(fsm::InventoryEvent::Reverted {
+            transaction: original.clone(),
+        }));
+    mock.subscriber
+        .broadcast(fsm::Event::Inventory(fsm::InventoryEvent::BlockProcessed {
+            block: block.clone(),
+            height: 1,
+            fees: None,
+        }));
+
+    assert_matches!(
+        subscriber.try_recv(),
+        Ok(Event::TxStatusChanged { txid, status: TxStatus::Reverted })
+        if txid == original.txid()
+    );
+    assert_matches!(
+        subscriber.try_recv(),
+        Ok(Event::TxStatusChanged {
+            txid,
+            status: TxStatus::Stale { replaced_by, block: b },
+        })
+        if txid == original.txid() && replaced_by == replacement.txid() && b == block.block_hash()
+    );
+}
+
+#[test]
+fn test_reverted_tx_pruned() {
+    let network = Network::Regtest;
+    let mut mock = mock::Client::new(network);
+    let mut rng = fastrand::Rng::new();
+    let client = mock.handle();
+    let subscriber = client.subscribe();
+
+    let outpoint = OutPoint::new(Txid::all_zeros(), 0);
+    let original = gen::transaction_with(outpoint, 100_000, &mut rng);
+    let replacement = gen::transaction_with(outpoint, 100_000, &mut rng);
+    let headers = gen::headers(
+        network.genesis(),
+        fsm::TRANSACTION_PRUNE_DEPTH + 1,
+        &mut rng,
+    );
+    let block = gen::block_with(
+        headers.last(),
+        vec![gen::coinbase(&mut rng), replacement],
+        &mut rng,
+    );
+
+    mock.subscriber
+        .broadcast(fsm::Event::Inventory(fsm::InventoryEvent::Reverted {
+            transaction: original.clone(),
+        }));
+
+    // The reverted transaction is neither confirmed again nor replaced for a while.
+    for (height, header) in headers.iter().enumerate().skip(1) {
+        mock.subscriber
+            .broadcast(fsm::Event::Chain(fsm::ChainEvent::BlockConnected {
+                header: *header,
+                height: height as Height,
+            }));
+    }
+    mock.subscriber
+        .broadcast(fsm::Event::Inventory(fsm::InventoryEvent::BlockProcessed {
+            block,
+            height: fsm::TRANSACTION_PRUNE_DEPTH + 2,
+            fees: None,
+        }));
+
+    assert_matches!(
+        subscriber.try_recv(),
+        Ok(Event::TxStatusChanged { txid, status: TxStatus::Reverted })
+        if txid == original.txid()
+    );
+    assert!(!subscriber.try_iter().any(|e| matches!(
+        e,
+        Event::TxStatusChanged {
+            status: TxStatus::Stale { .. },
+            ..
+        }
+    )));
+}
+
+#[test]
+fn test_bloom_events() {
+    let network = Network::Regtest;
+    let mut mock = mock::Client::new(network);
+    let client = mock.handle();
+    let subscriber = client.subscribe();
+    let mut rng = fastrand::Rng::new();
+    let peer: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+    let transaction = gen::transaction(&mut rng);
+    let txid = transaction.txid();
+    let block = gen::block(&network.genesis(), &mut rng).block_hash();
+
+    mock.subscriber
+        .broadcast(fsm::Event::Bloom(fsm::BloomEvent::FilterLoaded {
+            peer,
+            elements: 1,
+        }));
+    mock.subscriber
+        .broadcast(fsm::Event::Bloom(fsm::BloomEvent::MerkleBlockProcessed {
+            peer,
+            height: 7,
+            block,
+            matched: vec![],
+        }));
+    assert!(
+        subscriber.try_recv().is_err(),
+        "filter loads and unmatched merkle blocks aren't surfaced"
+    );
+
+    mock.subscriber
+        .broadcast(fsm::Event::Bloom(fsm::BloomEvent::MerkleBlockProcessed {
+            peer,
+            height: 7,
+            block,
+            matched: vec![txid],
+        }));
+    mock.subscriber
+        .broadcast(fsm::Event::Bloom(fsm::BloomEvent::TransactionMatched {
+            peer,
+            height: 7,
+            block,
+            transaction,
+        }));
+
+    assert_matches!(
+        subscriber.try_recv(),
+        Ok(Event::MerkleBlockMatched { height: 7, hash, txids, .. })
+        if hash == block && txids == vec![txid]
+    );
+    assert_matches!(
+        subscriber.try_recv(),
+        Ok(Event::TransactionMatched { height: 7, transaction, .. })
+        if transaction.txid() == txid
     );
 }
 
@@ -351,3 +579,25 @@ fn test_tx_status_ordering() {
             }
     );
 }
+
+#[test]
+fn test_suspicious_match_rate() {
+    let network = Network::Regtest;
+    let mut mock = mock::Client::new(network);
+    let client = mock.handle();
+    let subscriber = client.subscribe();
+    let peer: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+
+    mock.subscriber
+        .broadcast(fsm::Event::Filter(fsm::FilterEvent::SuspiciousMatchRate {
+            peer,
+            processed: 100,
+            matched: 100,
+        }));
+
+    assert_matches!(
+        subscriber.try_recv(),
+        Ok(Event::SuspiciousMatchRate { peer: p, processed: 100, matched: 100 })
+        if p == peer
+    );
+}
@@ -1,4 +1,6 @@
 #[cfg(test)]
+pub mod miner;
+#[cfg(test)]
 pub mod mock;
 
 use std::collections::HashMap;
@@ -37,7 +39,10 @@ fn network(
     let mut handles = Vec::new();
 
     for cfg in cfgs.iter().cloned() {
-        let checkpoints = cfg.network.checkpoints().collect::<Vec<_>>();
+        let checkpoints = cfg
+            .checkpoints
+            .clone()
+            .unwrap_or_else(|| cfg.network.checkpoints().collect());
         let genesis = cfg.network.genesis();
         let params = cfg.network.params();
 
@@ -86,6 +91,8 @@ fn test_full_sync() {
     let cfgs = vec![
         Config {
             services: ServiceFlags::NETWORK,
+            // The test chain doesn't have the mainnet minimum work.
+            minimum_chain_work: Some(Default::default()),
             ..Config::default()
         };
         3
@@ -115,6 +122,99 @@ fn test_full_sync() {
     }
 }
 
+#[test]
+fn test_local_miner() {
+    logger::init(log::Level::Debug);
+
+    let mut miner = miner::LocalMiner::new(Config::default()).unwrap();
+
+    assert_eq!(miner.mine(1).unwrap().0, 1);
+    let (height, hash) = miner.mine(8).unwrap();
+    assert_eq!(height, 9);
+    assert_eq!(miner.client().get_tip().unwrap().1.block_hash(), hash);
+
+    miner.shutdown().unwrap();
+}
+
+#[test]
+fn test_wait_synced() {
+    logger::init(log::Level::Debug);
+
+    let mut miner = miner::LocalMiner::new(Config {
+        headers_only: true,
+        ..Config::default()
+    })
+    .unwrap();
+
+    let (height, _) = miner.mine(4).unwrap();
+    let synced = miner.client().wait_synced(miner::MINE_TIMEOUT).unwrap();
+    assert_eq!(synced, height);
+
+    miner.shutdown().unwrap();
+}
+
+#[test]
+fn test_dns_resolve() {
+    let rng = fastrand::Rng::new();
+    let timeout = time::Duration::from_secs(1);
+
+    assert!(crate::dns::resolve(&[], 8333, timeout, &rng)
+        .unwrap()
+        .is_empty());
+
+    // Seeds that fail to resolve are skipped.
+    let seeds = vec!["localhost".to_owned(), "seed.invalid".to_owned()];
+    let mut outcomes = HashMap::new();
+    let addrs = crate::dns::resolve_with(&seeds, 8333, timeout, &rng, |seed, result| {
+        if let Err(err) = result {
+            assert!(
+                matches!(err, nakamoto_net::error::Error::Resolve { host, .. } if host == seed)
+            );
+        }
+        outcomes.insert(seed.to_owned(), result.ok().map(|addrs| addrs.len()));
+    })
+    .unwrap();
+
+    assert!(!addrs.is_empty());
+    assert_eq!(outcomes["localhost"], Some(addrs.len()));
+    assert_eq!(outcomes["seed.invalid"], None);
+    assert!(addrs
+        .iter()
+        .all(|a| a.ip().is_loopback() && a.port() == 8333));
+
+    // If all seeds fail, the last error is returned.
+    let seeds = vec!["seed.invalid".to_owned()];
+    assert!(matches!(
+        crate::dns::resolve(&seeds, 8333, timeout, &rng),
+        Err(nakamoto_net::error::Error::Resolve { host, .. }) if host == "seed.invalid"
+    ));
+}
+
+#[test]
+fn test_export_roundtrip() {
+    use crate::export;
+    use nakamoto_common::network::Network;
+
+    let genesis = Network::Mainnet.genesis_hash();
+    let headers = BITCOIN_HEADERS.tail.clone();
+    let mut file = Vec::new();
+
+    export::write(&mut file, &genesis, &headers).unwrap();
+    assert_eq!(export::read(file.as_slice(), &genesis).unwrap(), headers);
+
+    assert!(matches!(
+        export::read(file.as_slice(), &Network::Testnet.genesis_hash()),
+        Err(export::Error::WrongNetwork(h)) if h == genesis
+    ));
+
+    let last = file.len() - 1;
+    file[last] ^= 1;
+    assert!(matches!(
+        export::read(file.as_slice(), &genesis),
+        Err(export::Error::Checksum)
+    ));
+}
+
 #[test]
 fn test_wait_for_peers() {
     logger::init(log::Level::Debug);
@@ -137,6 +237,59 @@ fn test_wait_for_peers() {
     assert_eq!(peers.len(), nodes.len() - 1);
 }
 
+#[test]
+fn test_trusted_headers_config() {
+    let trusted: net::SocketAddr = ([127, 0, 0, 1], 8333).into();
+
+    // Headers can't be trusted if any peer may connect to us.
+    for cfg in [
+        Config {
+            trusted_headers: true,
+            listen: vec![],
+            ..Config::default()
+        },
+        Config {
+            trusted_headers: true,
+            connect_only: vec![trusted],
+            ..Config::default()
+        },
+    ] {
+        let client: Client<Reactor> = Client::new().unwrap();
+
+        assert!(matches!(
+            client.run(cfg),
+            Err(error::Error::Config(
+                fsm::ConfigError::UntrustedHeaderSource
+            ))
+        ));
+    }
+}
+
+#[test]
+fn test_connect_inbound_only_config() {
+    let peer: net::SocketAddr = ([127, 0, 0, 1], 8333).into();
+
+    for mut cfg in [
+        Config {
+            connect: vec![peer],
+            ..Config::default()
+        },
+        Config {
+            failover: vec![peer],
+            ..Config::default()
+        },
+    ] {
+        cfg.limits.max_outbound_peers = 0;
+
+        let client: Client<Reactor> = Client::new().unwrap();
+
+        assert!(matches!(
+            client.run(cfg),
+            Err(error::Error::Config(fsm::ConfigError::ConnectInboundOnly))
+        ));
+    }
+}
+
 #[test]
 fn test_send_handle() {
     let client: Client<Reactor> = Client::new().unwrap();
@@ -273,3 +426,254 @@ fn test_query_headers() {
     assert_eq!(header, BITCOIN_HEADERS.tail.first().cloned());
     assert!(found);
 }
+
+#[test]
+fn test_get_headers() {
+    let cfg = Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+    let store = store::Memory::new((genesis, BITCOIN_HEADERS.tail.clone()).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::load(store::Memory::default()).unwrap();
+
+    thread::spawn(|| {
+        let local_time = time::SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let rng = fastrand::Rng::new();
+
+        client.run_with(
+            vec![],
+            Service::new(cache, filters, HashMap::new(), clock, rng, cfg),
+        )
+    });
+
+    let tip = BITCOIN_HEADERS.tail.len() as Height;
+    let (height, headers) = handle.get_headers(1..4).unwrap();
+
+    assert_eq!(height, tip);
+    assert_eq!(headers, BITCOIN_HEADERS.tail[..3].to_vec());
+
+    // Heights past the tip are ignored.
+    let (_, headers) = handle.get_headers(tip - 1..tip + 8).unwrap();
+    assert_eq!(headers.len(), 2);
+    assert_eq!(headers.last(), BITCOIN_HEADERS.tail.last());
+}
+
+#[test]
+fn test_subscribe_from_snapshot() {
+    use crate::chan;
+
+    /// Holds up the reactor on the first event it publishes, until released.
+    struct Gate(Option<(chan::Sender<()>, chan::Receiver<()>)>);
+
+    impl event::Publisher<fsm::Event> for Gate {
+        fn publish(&mut self, _: fsm::Event) {
+            if let Some((blocked, release)) = self.0.take() {
+                blocked.send(()).ok();
+                release.recv().ok();
+            }
+        }
+    }
+
+    let cfg = Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let mut client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+    let store = store::Memory::new((genesis, vec![]).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::load(store::Memory::default()).unwrap();
+    let (blocked, wait_blocked) = chan::bounded(1);
+    let (release, wait_release) = chan::bounded(1);
+
+    client.publish_to(Gate(Some((blocked, wait_release))));
+
+    thread::spawn(|| {
+        let local_time = time::SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let rng = fastrand::Rng::new();
+
+        client.run_with(
+            vec![],
+            Service::new(cache, filters, HashMap::new(), clock, rng, cfg),
+        )
+    });
+
+    // The reactor is held up after the first initialization event, so that the following
+    // ones, eg. `Ready`, are emitted after we subscribe, but before the snapshot is taken.
+    wait_blocked.recv().unwrap();
+    thread::spawn(move || {
+        thread::sleep(time::Duration::from_millis(100));
+        release.send(()).unwrap();
+    });
+    let events = handle.subscribe_from_snapshot().unwrap();
+
+    assert!(matches!(
+        events.recv().unwrap(),
+        client::Event::Snapshot { tip: 0, .. }
+    ));
+    // Events that are reflected in the snapshot aren't received.
+    assert!(!events
+        .try_iter()
+        .any(|e| matches!(e, client::Event::Ready { .. })));
+}
+
+#[test]
+fn test_listen_addrs() {
+    let cfg = Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+    let store = store::Memory::new((genesis, vec![]).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::load(store::Memory::default()).unwrap();
+
+    thread::spawn(|| {
+        let local_time = time::SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let rng = fastrand::Rng::new();
+
+        client.run_with(
+            vec![([127, 0, 0, 1], 0).into(), ([127, 0, 0, 1], 0).into()],
+            Service::new(cache, filters, HashMap::new(), clock, rng, cfg),
+        )
+    });
+
+    let addrs = handle.listen_addrs().unwrap();
+    assert_eq!(addrs.len(), 2);
+    assert!(addrs.iter().all(|a| a.port() != 0));
+    assert_ne!(addrs[0], addrs[1]);
+
+    // Addresses are kept for subsequent calls, and shared between handles.
+    assert_eq!(handle.clone().listen_addrs().unwrap(), addrs);
+}
+
+#[test]
+fn test_oversized_message_disconnect() {
+    use std::borrow::Cow;
+
+    use nakamoto_common::bitcoin::consensus::encode;
+    use nakamoto_common::bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+    use nakamoto_net::{ConnDirection, PeerProtocol as _, ReactorDispatch};
+
+    let cfg = Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let magic = cfg.network.magic();
+    let store = store::Memory::new((genesis, vec![]).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::load(store::Memory::default()).unwrap();
+    let local_time = time::SystemTime::now().into();
+    let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+    let rng = fastrand::Rng::new();
+    let mut service = Service::new(cache, filters, HashMap::new(), clock, rng, cfg);
+
+    let addr: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+    let local_addr: net::SocketAddr = ([0, 0, 0, 0], 8333).into();
+
+    service.initialize(local_time);
+    service.connected(addr, &local_addr, ConnDirection::Inbound);
+    service.for_each(drop);
+
+    // A message header announcing a 4 GB payload, without the payload.
+    let mut header = encode::serialize(&RawNetworkMessage {
+        magic,
+        payload: NetworkMessage::Ping(0),
+    });
+    header.truncate(nakamoto_p2p::stream::MESSAGE_HEADER_SIZE);
+    header[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+
+    service.received(&addr, Cow::Owned(header));
+
+    assert!(service.any(|o| matches!(
+        o,
+        ReactorDispatch::DisconnectPeer(a, fsm::DisconnectReason::PeerMisbehaving("oversized message"))
+            if a == addr
+    )));
+}
+
+#[test]
+fn test_event_to_json() {
+    use microserde::json::{self, Number, Value};
+
+    let genesis = Config::default().network.genesis();
+    let hash = genesis.block_hash();
+    let event = client::Event::BlockConnected {
+        header: genesis,
+        hash,
+        height: 0,
+    };
+    let value = json::from_str::<Value>(&json::to_string(&event.to_json())).unwrap();
+    let obj = match value {
+        Value::Object(obj) => obj,
+        _ => panic!("events are encoded as objects"),
+    };
+
+    assert!(matches!(obj.get("type"), Some(Value::String(s)) if s == "block_connected"));
+    assert!(matches!(obj.get("hash"), Some(Value::String(s)) if s == &hash.to_string()));
+    assert!(matches!(obj.get("height"), Some(Value::Number(Number::U64(0)))));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_event_serde_round_trip() {
+    use std::io;
+    use std::sync::Arc;
+
+    use nakamoto_common::bitcoin::Txid;
+    use nakamoto_net::DisconnectReason;
+    use nakamoto_p2p::fsm::fees::FeeEstimate;
+
+    use crate::spv::TxStatus;
+
+    let genesis = Config::default().network.genesis();
+    let hash = genesis.block_hash();
+    let addr: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+    let events = vec![
+        client::Event::BlockConnected {
+            header: genesis,
+            hash,
+            height: 0,
+        },
+        client::Event::PeerConnected {
+            addr,
+            link: client::ConnDirection::Inbound,
+        },
+        client::Event::PeerConnectionFailed {
+            addr,
+            error: Arc::new(io::Error::from(io::ErrorKind::ConnectionRefused)),
+        },
+        client::Event::PeerDisconnected {
+            addr,
+            reason: DisconnectReason::OnDemand(fsm::DisconnectReason::PeerTimeout("ping")),
+        },
+        client::Event::FeeEstimated {
+            block: hash,
+            height: 0,
+            fees: FeeEstimate {
+                low: 1,
+                median: 4,
+                high: 16,
+            },
+        },
+        client::Event::TxStatusChanged {
+            txid: Txid::from_hash(genesis.merkle_root.as_hash()),
+            status: TxStatus::Confirmed {
+                height: 0,
+                block: hash,
+            },
+        },
+    ];
+
+    for event in events {
+        let json = serde_json::to_string(&event).unwrap();
+        let decoded = serde_json::from_str::<client::Event>(&json).unwrap();
+
+        // Events can't be compared directly, so we compare their encodings instead.
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+        assert_eq!(decoded.to_string(), event.to_string());
+    }
+}
@@ -0,0 +1,115 @@
+//! In-process miner for end-to-end tests.
+//!
+//! Runs a client connected to a "miner", which is another client running in the same
+//! process, on the regtest network parameters. The two talk over loopback sockets, like
+//! the nodes of [`super::network`]. This is not a `bitcoind` regtest node: "mining" a block
+//! generates a random block header on top of the miner's tip, and imports it on the miner,
+//! which then announces it to the client. The miner never has the block bodies, so this
+//! only covers header sync.
+//!
+//! Blocks are mined with [`LocalMiner::mine`], which only returns once the client has
+//! connected them.
+use std::net;
+use std::thread;
+use std::time;
+
+use nakamoto_common::bitcoin::network::constants::ServiceFlags;
+use nakamoto_common::block::{BlockHash, BlockHeader, Height};
+use nakamoto_common::network::{Network, Services};
+use nakamoto_net::event;
+use nakamoto_test::block::gen;
+
+use crate::client::{self, Config, Event};
+use crate::error;
+use crate::handle::{self, Handle as _};
+
+/// Time to wait for the client to connect mined blocks.
+pub const MINE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+type Node = (
+    client::Handle<nakamoto_net_poll::Waker>,
+    net::SocketAddr,
+    thread::JoinHandle<()>,
+);
+
+/// A client connected to an in-process miner.
+pub struct LocalMiner {
+    miner: Node,
+    client: Node,
+    tip: (Height, BlockHeader),
+    rng: fastrand::Rng,
+}
+
+impl LocalMiner {
+    /// Start a miner and a client with the given configuration, and wait for them to
+    /// be connected. The configuration's network is overwritten with regtest.
+    pub fn new(cfg: Config) -> Result<Self, error::Error> {
+        let network = Network::Regtest;
+        let miner = Config {
+            network,
+            services: ServiceFlags::NETWORK,
+            checkpoints: vec![],
+            ..Config::default()
+        };
+        let client = Config {
+            network,
+            checkpoints: vec![],
+            ..cfg
+        };
+        // Nb. The client is first, so that it connects to the miner as an outbound peer.
+        let mut nodes = super::network(&[client, miner])?.into_iter();
+        let (client, miner) = (nodes.next().unwrap(), nodes.next().unwrap());
+
+        client.0.wait_for_peers(1, Services::Chain)?;
+
+        Ok(Self {
+            miner,
+            client,
+            tip: (0, network.genesis()),
+            rng: fastrand::Rng::new(),
+        })
+    }
+
+    /// Get a handle to the client under test.
+    pub fn client(&self) -> &client::Handle<nakamoto_net_poll::Waker> {
+        &self.client.0
+    }
+
+    /// Mine `n` blocks on top of the miner's tip, and block until the client has connected
+    /// all of them. Returns the new tip.
+    pub fn mine(&mut self, n: usize) -> Result<(Height, BlockHash), error::Error> {
+        let events = self.client.0.subscribe();
+        let (mut height, mut tip) = self.tip;
+        let mut headers = Vec::with_capacity(n);
+
+        for _ in 0..n {
+            tip = gen::block(&tip, &mut self.rng).header;
+            height += 1;
+            headers.push(tip);
+        }
+        self.miner.0.import_headers(headers)??;
+        self.tip = (height, tip);
+
+        let hash = tip.block_hash();
+        event::wait(
+            &events,
+            |e| match e {
+                Event::BlockConnected { hash: h, .. } if h == hash => Some(()),
+                _ => None,
+            },
+            MINE_TIMEOUT,
+        )
+        .map_err(handle::Error::from)?;
+
+        Ok((height, hash))
+    }
+
+    /// Shutdown both nodes.
+    pub fn shutdown(self) -> Result<(), handle::Error> {
+        for (handle, _, thread) in [self.client, self.miner] {
+            handle.shutdown()?;
+            thread.join().ok();
+        }
+        Ok(())
+    }
+}
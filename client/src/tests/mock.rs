@@ -9,6 +9,7 @@ use nakamoto_chain::filter::BlockFilter;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
 use nakamoto_common::bitcoin::network::Address;
+use nakamoto_common::bitcoin::Txid;
 use nakamoto_common::block::filter::FilterHeader;
 use nakamoto_common::block::store::Genesis as _;
 use nakamoto_common::block::time::{AdjustedTime, LocalTime};
@@ -160,6 +161,10 @@ impl Handle for TestHandle {
         Ok(self.tip)
     }
 
+    fn get_sync_status(&self) -> Result<fsm::SyncStatus, handle::Error> {
+        unimplemented!()
+    }
+
     fn get_block(&self, hash: &BlockHash) -> Result<(), handle::Error> {
         self.command(Command::GetBlock(*hash))?;
 
@@ -192,6 +197,26 @@ impl Handle for TestHandle {
         self.subscriber.subscribe()
     }
 
+    fn subscribe_from_snapshot(&self) -> Result<chan::Receiver<Event>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn subscribe_sequenced(&self) -> chan::Receiver<(u64, Event)> {
+        let mut seq = 0;
+
+        self.subscriber.subscribe_with(move |e| {
+            seq += 1;
+            Some((seq - 1, e.clone()))
+        })
+    }
+
+    fn watch_tx(&self, txid: Txid) -> chan::Receiver<spv::TxStatus> {
+        self.subscriber.subscribe_with(move |e| match e {
+            Event::TxStatusChanged { txid: t, status } if *t == txid => Some(status.clone()),
+            _ => None,
+        })
+    }
+
     fn loading(&self) -> chan::Receiver<Loading> {
         self.loading.subscribe()
     }
@@ -3,6 +3,7 @@ pub mod checkpoints;
 pub mod filter;
 pub mod genesis;
 pub mod iter;
+pub mod merkle;
 pub mod store;
 pub mod time;
 pub mod tree;
@@ -26,6 +27,10 @@ pub type Height = u64;
 /// Block time (seconds since Epoch).
 pub type BlockTime = u32;
 
+/// Number of consecutive heights at the start of a block locator, before the step between
+/// heights starts doubling.
+pub const LOCATOR_DENSITY: usize = 8;
+
 /// Get the locator indexes starting from a given height, and going backwards, exponentially
 /// backing off.
 ///
@@ -38,13 +43,26 @@ pub type BlockTime = u32;
 ///     99, 98, 97, 96, 95, 94, 93, 92, 91, 89, 85, 77, 61, 29, 0
 /// ]);
 /// ```
-pub fn locators_indexes(mut from: Height) -> Vec<Height> {
+pub fn locators_indexes(from: Height) -> Vec<Height> {
+    locators_indexes_with(from, LOCATOR_DENSITY)
+}
+
+/// Like [`locators_indexes`], but with the given number of consecutive heights at the start
+/// of the locator. A lower density yields smaller locators on long chains.
+///
+/// ```
+/// use nakamoto_common::block;
+///
+/// assert_eq!(block::locators_indexes_with(8, 2), vec![8, 7, 6, 4, 0]);
+/// assert_eq!(block::locators_indexes_with(99, 8), block::locators_indexes(99));
+/// ```
+pub fn locators_indexes_with(mut from: Height, density: usize) -> Vec<Height> {
     let mut indexes = Vec::new();
     let mut step = 1;
 
     while from > 0 {
-        // For the first 8 blocks, don't skip any heights.
-        if indexes.len() >= 8 {
+        // For the first few blocks, don't skip any heights.
+        if indexes.len() >= density {
             step *= 2;
         }
         indexes.push(from as Height);
@@ -64,3 +82,31 @@ pub fn pow_limit_bits(network: &bitcoin::Network) -> Bits {
         bitcoin::Network::Signet => 0x1e0377ae,
     }
 }
+
+/// Difficulty adjustment ("retarget") policy of a chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retarget {
+    /// Whether the difficulty target is adjusted at the end of every difficulty adjustment
+    /// interval. If not, blocks keep the target of their parent, eg. on regtest.
+    pub adjust: bool,
+    /// Whether a block mined more than twice the target spacing after its parent may use the
+    /// minimum difficulty, eg. on testnet. Doesn't apply at adjustment boundaries.
+    pub allow_min_difficulty: bool,
+}
+
+impl Retarget {
+    /// Strict retargeting, as on mainnet.
+    pub const STRICT: Self = Self {
+        adjust: true,
+        allow_min_difficulty: false,
+    };
+}
+
+impl From<&bitcoin::consensus::params::Params> for Retarget {
+    fn from(params: &bitcoin::consensus::params::Params) -> Self {
+        Self {
+            adjust: !params.no_pow_retargeting,
+            allow_min_difficulty: params.allow_min_difficulty_blocks,
+        }
+    }
+}
@@ -1,4 +1,4 @@
-//! Checkpoints used to validate blocks at certain heights.
+//! Checkpoints used to validate blocks and filter headers at certain heights.
 
 #[rustfmt::skip]
 /// Mainnet checkpoints.
@@ -29,3 +29,15 @@ pub const REGTEST: &[(u64, &str)] = &[];
 
 /// Signet checkpoints.
 pub const SIGNET: &[(u64, &str)] = &[];
+
+/// Mainnet filter header checkpoints.
+pub const MAINNET_FILTER_HEADERS: &[(u64, &str)] = &[];
+
+/// Testnet filter header checkpoints.
+pub const TESTNET_FILTER_HEADERS: &[(u64, &str)] = &[];
+
+/// Regtest filter header checkpoints.
+pub const REGTEST_FILTER_HEADERS: &[(u64, &str)] = &[];
+
+/// Signet filter header checkpoints.
+pub const SIGNET_FILTER_HEADERS: &[(u64, &str)] = &[];
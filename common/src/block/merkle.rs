@@ -0,0 +1,195 @@
+//! Merkle inclusion proofs for transactions.
+use bitcoin::hash_types::{TxMerkleNode, Txid};
+use bitcoin::hashes::{Hash, HashEngine};
+
+use super::{Block, BlockHeader};
+
+/// Proof that a transaction is included in a block, in the form of a merkle branch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// Sibling hashes from the transaction up to, but not including, the merkle root.
+    pub branch: Vec<TxMerkleNode>,
+    /// Position of the transaction in the block.
+    pub position: u32,
+    /// Number of transactions in the block, ie. leaves of the merkle tree.
+    pub transactions: u32,
+}
+
+impl MerkleProof {
+    /// Build a proof of inclusion of the given transaction from a full block. Returns `None`
+    /// if the transaction isn't in the block.
+    pub fn from_block(block: &Block, txid: &Txid) -> Option<Self> {
+        let position = block.txdata.iter().position(|tx| tx.txid() == *txid)?;
+        let mut level = block
+            .txdata
+            .iter()
+            .map(|tx| TxMerkleNode::from_hash(tx.txid().as_hash()))
+            .collect::<Vec<_>>();
+        let mut branch = Vec::new();
+        let mut index = position;
+
+        while level.len() > 1 {
+            // If a level has an odd number of nodes, the last node is paired with itself.
+            let sibling = (index ^ 1).min(level.len() - 1);
+            branch.push(level[sibling]);
+
+            level = level
+                .chunks(2)
+                .map(|pair| parent(&pair[0], pair.last().unwrap_or(&pair[0])))
+                .collect();
+            index /= 2;
+        }
+
+        Some(Self {
+            branch,
+            position: position as u32,
+            transactions: block.txdata.len() as u32,
+        })
+    }
+
+    /// Compute the merkle root committed to by this proof, for the given transaction.
+    pub fn root(&self, txid: &Txid) -> TxMerkleNode {
+        let mut node = TxMerkleNode::from_hash(txid.as_hash());
+        let mut index = self.position;
+
+        for sibling in &self.branch {
+            node = if index & 1 == 1 {
+                parent(sibling, &node)
+            } else {
+                parent(&node, sibling)
+            };
+            index >>= 1;
+        }
+        node
+    }
+
+    /// Verify that the given transaction is included in the block with the given header.
+    ///
+    /// Since the last node of a level with an odd number of nodes is paired with itself,
+    /// the same root can be computed for positions past the last transaction. Proofs are
+    /// therefore checked against the shape of a tree with the given number of transactions:
+    /// the position must be one of its leaves, and a node may only be paired with itself
+    /// if it is the last node of its level.
+    pub fn verify(&self, txid: &Txid, header: &BlockHeader) -> bool {
+        if self.position >= self.transactions {
+            return false;
+        }
+        let mut node = TxMerkleNode::from_hash(txid.as_hash());
+        let mut index = self.position;
+        let mut width = self.transactions;
+        let mut branch = self.branch.iter();
+
+        while width > 1 {
+            let sibling = match branch.next() {
+                Some(sibling) => sibling,
+                None => return false,
+            };
+            let last = index == width - 1 && width % 2 == 1;
+
+            if last != (*sibling == node) {
+                return false;
+            }
+            node = if index & 1 == 1 {
+                parent(sibling, &node)
+            } else {
+                parent(&node, sibling)
+            };
+            index >>= 1;
+            width = (width + 1) / 2;
+        }
+        branch.next().is_none() && node == header.merkle_root
+    }
+}
+
+/// Compute the parent of two merkle tree nodes.
+fn parent(left: &TxMerkleNode, right: &TxMerkleNode) -> TxMerkleNode {
+    let mut engine = TxMerkleNode::engine();
+
+    engine.input(&left[..]);
+    engine.input(&right[..]);
+
+    TxMerkleNode::from_engine(engine)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants;
+    use bitcoin::{PackedLockTime, Transaction};
+
+    fn block(n: u32) -> Block {
+        let mut block = constants::genesis_block(bitcoin::Network::Regtest);
+
+        block.txdata = (0..n)
+            .map(|i| Transaction {
+                version: 1,
+                lock_time: PackedLockTime(i),
+                input: vec![],
+                output: vec![],
+            })
+            .collect();
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        block
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        for n in 1..=9 {
+            let block = block(n);
+
+            for (i, tx) in block.txdata.iter().enumerate() {
+                let txid = tx.txid();
+                let proof = MerkleProof::from_block(&block, &txid).unwrap();
+
+                assert_eq!(proof.position, i as u32);
+                assert!(proof.verify(&txid, &block.header), "n = {}, i = {}", n, i);
+            }
+        }
+    }
+
+    #[test]
+    fn test_merkle_proof_invalid() {
+        let block = block(5);
+        let other = block.txdata[1].txid();
+        let txid = block.txdata[2].txid();
+        let proof = MerkleProof::from_block(&block, &txid).unwrap();
+
+        assert!(MerkleProof::from_block(&block, &Txid::all_zeros()).is_none());
+        assert!(!proof.verify(&other, &block.header));
+        assert!(!MerkleProof {
+            position: 3,
+            ..proof.clone()
+        }
+        .verify(&txid, &block.header));
+        assert!(!MerkleProof {
+            position: proof.position + (1 << proof.branch.len()),
+            ..proof
+        }
+        .verify(&txid, &block.header));
+
+        // The last transaction is paired with itself, so its proof also computes the root
+        // for the position after it, which isn't a transaction of the block.
+        let last = block.txdata[4].txid();
+        let proof = MerkleProof::from_block(&block, &last).unwrap();
+        let phantom = MerkleProof {
+            position: 5,
+            transactions: 6,
+            ..proof.clone()
+        };
+        assert_eq!(phantom.root(&last), block.header.merkle_root);
+        assert!(!phantom.verify(&last, &block.header));
+        assert!(!MerkleProof {
+            position: 5,
+            ..proof.clone()
+        }
+        .verify(&last, &block.header));
+
+        // The branch must match the number of transactions.
+        assert!(!MerkleProof {
+            transactions: 9,
+            ..proof.clone()
+        }
+        .verify(&last, &block.header));
+        assert!(proof.verify(&last, &block.header));
+    }
+}
@@ -1,6 +1,6 @@
 //! Block header storage.
 #![allow(clippy::len_without_is_empty)]
-use crate::block::Height;
+use crate::block::{Height, Work};
 
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::consensus::encode;
@@ -26,6 +26,9 @@ pub enum Error {
     /// Operation was interrupted.
     #[error("the operation was interrupted")]
     Interrupted,
+    /// The header at the given height was pruned.
+    #[error("header at height {0} was pruned")]
+    Pruned(Height),
 }
 
 /// Represents an object (such as a header), that has a genesis.
@@ -93,4 +96,18 @@ pub trait Store {
     fn check(&self) -> Result<(), Error>;
     /// Heal data corruption.
     fn heal(&self) -> Result<(), Error>;
+    /// Drop the headers below the given height, except for the genesis. Dropped headers
+    /// are skipped when iterating, and can't be retrieved anymore. The total work of the
+    /// dropped headers is kept, and returned by [`Store::pruned_work`].
+    ///
+    /// At least two headers must be dropped the first time. Stores that don't support
+    /// pruning keep all headers.
+    fn prune(&mut self, below: Height, work: Work) -> Result<(), Error> {
+        let _ = (below, work);
+        Ok(())
+    }
+    /// Get the total work of the headers dropped by [`Store::prune`].
+    fn pruned_work(&self) -> Work {
+        Work::default()
+    }
 }
@@ -43,6 +43,9 @@ pub trait Clock: Clone {
 pub trait AdjustedClock<K>: Clock {
     /// Record a peer offset.
     fn record_offset(&mut self, source: K, sample: TimeOffset);
+    /// Median peer offset, if it is too large to adjust our time by.
+    /// This usually means that our local clock is wrong.
+    fn skew(&self) -> Option<TimeOffset>;
     /// Set the local time.
     fn set(&mut self, local_time: LocalTime);
 }
@@ -52,6 +55,10 @@ impl<K: Eq + Clone + Hash> AdjustedClock<K> for AdjustedTime<K> {
         AdjustedTime::record_offset(self, source, sample)
     }
 
+    fn skew(&self) -> Option<TimeOffset> {
+        AdjustedTime::skew(self)
+    }
+
     fn set(&mut self, local_time: LocalTime) {
         AdjustedTime::set_local_time(self, local_time)
     }
@@ -83,6 +90,10 @@ impl<K: Eq + Clone + Hash> AdjustedClock<K> for RefClock<AdjustedTime<K>> {
         self.inner.borrow_mut().record_offset(source, sample);
     }
 
+    fn skew(&self) -> Option<TimeOffset> {
+        self.inner.borrow().skew()
+    }
+
     fn set(&mut self, local_time: LocalTime) {
         self.inner.borrow_mut().set_local_time(local_time);
     }
@@ -139,6 +150,8 @@ pub struct AdjustedTime<K> {
     samples: Vec<TimeOffset>,
     /// Current time offset, based on our samples.
     offset: TimeOffset,
+    /// Median offset of our samples, if it exceeds the maximum time adjustment.
+    skew: Option<TimeOffset>,
     /// Last known local time.
     local_time: LocalTime,
 }
@@ -178,6 +191,7 @@ impl<K: Hash + Eq> AdjustedTime<K> {
             sources,
             samples,
             offset,
+            skew: None,
             local_time,
         }
     }
@@ -225,10 +239,11 @@ impl<K: Hash + Eq> AdjustedTime<K> {
             // Don't let other nodes change our time by more than a certain amount.
             if median_offset.abs() <= MAX_TIME_ADJUSTMENT {
                 self.offset = median_offset;
+                self.skew = None;
             } else {
-                // TODO: Check whether other nodes have times similar to ours, otherwise
-                // log a warning about our clock possibly being wrong.
+                // Most of our peers disagree with our clock, it's likely wrong.
                 self.offset = 0;
+                self.skew = Some(median_offset);
             }
             #[cfg(feature = "log")]
             log::debug!("Time offset adjusted to {} seconds", self.offset);
@@ -240,6 +255,12 @@ impl<K: Hash + Eq> AdjustedTime<K> {
         self.offset
     }
 
+    /// Get the median network time offset, if our local clock is too far off from
+    /// network time to be adjusted. See [`MAX_TIME_ADJUSTMENT`].
+    pub fn skew(&self) -> Option<TimeOffset> {
+        self.skew
+    }
+
     /// Get the network-adjusted time given a local time.
     pub fn from(&self, time: BlockTime) -> BlockTime {
         let adjustment = self.offset;
@@ -287,6 +308,51 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_local_duration_units() {
+        assert_eq!(LocalDuration::from_hours(1), LocalDuration::from_mins(60));
+        assert_eq!(LocalDuration::from_days(1), LocalDuration::from_hours(24));
+        assert_eq!(LocalDuration::from_weeks(2), LocalDuration::from_days(14));
+    }
+
+    #[test]
+    fn test_local_duration_arithmetic() {
+        let max = LocalDuration::MAX;
+        let one = LocalDuration::from_secs(1);
+
+        assert_eq!(max.checked_add(one), None);
+        assert_eq!(max.saturating_add(one), max);
+        assert_eq!(max.checked_mul(2), None);
+        assert_eq!(max.saturating_mul(2), max);
+        assert_eq!(LocalDuration::from_secs(0).checked_sub(one), None);
+        assert_eq!(
+            LocalDuration::from_secs(0).saturating_sub(one),
+            LocalDuration::from_secs(0)
+        );
+        assert_eq!(
+            LocalDuration::from_secs(3).checked_sub(one),
+            Some(LocalDuration::from_secs(2))
+        );
+        assert_eq!(
+            LocalDuration::BLOCK_INTERVAL.checked_mul(6),
+            Some(LocalDuration::from_hours(1))
+        );
+    }
+
+    #[test]
+    fn test_local_time_arithmetic() {
+        let epoch = LocalTime::default();
+        let time = LocalTime::from_secs(60);
+        let one = LocalDuration::from_secs(1);
+
+        assert_eq!(epoch.checked_sub(one), None);
+        assert_eq!(epoch.saturating_sub(one), epoch);
+        assert_eq!(time.checked_sub(one), Some(LocalTime::from_secs(59)));
+        assert_eq!(time.checked_add(one), Some(LocalTime::from_secs(61)));
+        assert_eq!(time.checked_add(LocalDuration::MAX), None);
+        assert!(time.saturating_add(LocalDuration::MAX) > time);
+    }
+
     #[test]
     fn test_adjusted_time() {
         let mut adjusted_time: AdjustedTime<SocketAddr> = AdjustedTime::default();
@@ -320,6 +386,7 @@ mod tests {
             0,
             "A too large time adjustment reverts back to 0",
         ); // samples = [0, 42, 47, 4201, 4201, 4201, 4201]
+        assert_eq!(adjusted_time.skew(), Some(MAX_TIME_ADJUSTMENT + 1));
     }
 
     #[test]
@@ -120,6 +120,21 @@ pub trait BlockTree: BlockReader {
         header: BlockHeader,
         context: &C,
     ) -> Result<ImportResult, Error>;
+    /// Drop the blocks of the active chain below the given height, except for the genesis,
+    /// to bound storage use. Fewer blocks may be dropped than asked for, so that new blocks
+    /// can still be validated, and re-orgs handled. The first block kept acts as a checkpoint.
+    ///
+    /// Returns the height of the first block kept after the genesis. Trees that don't support
+    /// pruning keep all blocks.
+    fn prune(&mut self, below: Height) -> Result<Height, Error> {
+        let _ = below;
+        Ok(1)
+    }
+    /// Roll the active chain back to the given height, discarding the blocks above it, so
+    /// that they can be imported again. Returns the discarded blocks, from the tip down.
+    ///
+    /// Fails if the block at the given height was pruned.
+    fn rollback(&mut self, height: Height) -> Result<Vec<(Height, BlockHeader)>, Error>;
 }
 
 /// Read block header state.
@@ -155,6 +170,16 @@ pub trait BlockReader {
     }
     /// Return the height of the longest chain.
     fn height(&self) -> Height;
+    /// Return the total proof-of-work of the longest chain, not including pruned blocks.
+    fn chain_work(&self) -> Work {
+        self.iter()
+            .fold(Work::default(), |work, (_, header)| work + header.work())
+    }
+    /// Return the height of the last pruned block, or zero if no blocks were pruned. The
+    /// blocks after the genesis, up to and including this height, can't be read.
+    fn pruned(&self) -> Height {
+        0
+    }
     /// Get the tip of the longest chain.
     fn tip(&self) -> (BlockHash, BlockHeader);
     /// Get the last block of the longest chain.
@@ -187,7 +212,12 @@ pub trait BlockReader {
         max_headers: usize,
     ) -> Vec<BlockHeader>;
     /// Get the locator hashes starting from the given height and going backwards.
-    fn locator_hashes(&self, from: Height) -> Vec<BlockHash>;
+    fn locator_hashes(&self, from: Height) -> Vec<BlockHash> {
+        self.locator_hashes_with(from, crate::block::LOCATOR_DENSITY)
+    }
+    /// Get the locator hashes starting from the given height and going backwards, with
+    /// `density` consecutive hashes before the step starts doubling.
+    fn locator_hashes_with(&self, from: Height, density: usize) -> Vec<BlockHash>;
     /// Get the next difficulty given a block height, time and bits.
     fn next_difficulty_target(
         &self,
@@ -2,13 +2,13 @@
 
 use bitcoin::blockdata::block::{Block, BlockHeader};
 use bitcoin::consensus::params::Params;
-use bitcoin::hash_types::BlockHash;
+use bitcoin::hash_types::{BlockHash, FilterHeader};
 use bitcoin::hashes::hex::FromHex;
 use bitcoin::network::constants::ServiceFlags;
 
 use bitcoin_hashes::sha256d;
 
-use crate::block::Height;
+use crate::block::{Height, Work};
 
 /// Peer services supported by nakamoto.
 #[derive(Debug, Copy, Clone)]
@@ -34,6 +34,53 @@ impl Default for Services {
     }
 }
 
+/// Descriptor of a custom network, eg. a private signet with its own challenge.
+#[derive(Debug, Clone)]
+pub struct CustomNetwork {
+    /// Short name of the network. Used for display, and to name the data directory.
+    pub name: &'static str,
+    /// Network magic number, used in message headers.
+    pub magic: u32,
+    /// Default listen port.
+    pub port: u16,
+    /// Genesis block.
+    pub genesis: Block,
+    /// Consensus parameters.
+    pub params: Params,
+    /// DNS seeds.
+    pub seeds: &'static [&'static str],
+}
+
+impl CustomNetwork {
+    /// Turn this descriptor into a [`Network`].
+    ///
+    /// The descriptor is leaked, so that [`Network`] can remain `Copy`. This is meant to
+    /// be called once, when the client is configured.
+    ///
+    /// ```
+    /// use nakamoto_common::bitcoin;
+    /// use nakamoto_common::bitcoin::consensus::params::Params;
+    /// use nakamoto_common::network::{CustomNetwork, Network};
+    ///
+    /// let network = CustomNetwork {
+    ///     name: "private",
+    ///     magic: 0xdeadbeef,
+    ///     port: 38334,
+    ///     genesis: Network::Signet.genesis_block(),
+    ///     params: Params::new(bitcoin::Network::Signet),
+    ///     seeds: &[],
+    /// }
+    /// .register();
+    ///
+    /// assert_eq!(network.magic(), 0xdeadbeef);
+    /// assert_eq!(network.as_str(), "private");
+    /// assert_eq!(network.genesis_hash(), Network::Signet.genesis_hash());
+    /// ```
+    pub fn register(self) -> Network {
+        Network::Custom(Box::leak(Box::new(self)))
+    }
+}
+
 /// Bitcoin peer network.
 #[derive(Debug, Copy, Clone)]
 pub enum Network {
@@ -45,6 +92,8 @@ pub enum Network {
     Regtest,
     /// Bitcoin signet.
     Signet,
+    /// Custom network, see [`CustomNetwork::register`].
+    Custom(&'static CustomNetwork),
 }
 
 impl Default for Network {
@@ -60,6 +109,7 @@ impl From<Network> for bitcoin::Network {
             Network::Testnet => Self::Testnet,
             Network::Regtest => Self::Regtest,
             Network::Signet => Self::Signet,
+            Network::Custom(custom) => custom.params.network,
         }
     }
 }
@@ -83,6 +133,7 @@ impl Network {
             Network::Testnet => 18333,
             Network::Regtest => 18334,
             Network::Signet => 38333,
+            Network::Custom(custom) => custom.port,
         }
     }
 
@@ -95,6 +146,7 @@ impl Network {
             Network::Testnet => checkpoints::TESTNET,
             Network::Regtest => checkpoints::REGTEST,
             Network::Signet => checkpoints::SIGNET,
+            Network::Custom(_) => &[],
         }
         .iter()
         .cloned()
@@ -106,6 +158,42 @@ impl Network {
         Box::new(iter)
     }
 
+    /// Compact filter header checkpoints.
+    pub fn filter_checkpoints(&self) -> Box<dyn Iterator<Item = (Height, FilterHeader)>> {
+        use crate::block::checkpoints;
+
+        let iter = match self {
+            Network::Mainnet => checkpoints::MAINNET_FILTER_HEADERS,
+            Network::Testnet => checkpoints::TESTNET_FILTER_HEADERS,
+            Network::Regtest => checkpoints::REGTEST_FILTER_HEADERS,
+            Network::Signet => checkpoints::SIGNET_FILTER_HEADERS,
+            Network::Custom(_) => &[],
+        }
+        .iter()
+        .cloned()
+        .map(|(height, header)| {
+            let header = FilterHeader::from_hex(header).unwrap();
+            (height, header)
+        });
+
+        Box::new(iter)
+    }
+
+    /// Minimum proof-of-work of the best chain. Header chains with less work are not
+    /// considered synced.
+    ///
+    /// On mainnet, this is a conservative lower bound, around the work of the chain at the
+    /// last checkpoint, so that it's reached soon after. No minimum is enforced on test
+    /// networks.
+    pub fn minimum_chain_work(&self) -> Work {
+        match self {
+            Network::Mainnet => Work::from_u64(1).unwrap() << 78,
+            Network::Testnet | Network::Regtest | Network::Signet | Network::Custom(_) => {
+                Work::default()
+            }
+        }
+    }
+
     /// Return the short string representation of this network.
     pub fn as_str(&self) -> &'static str {
         match self {
@@ -113,6 +201,7 @@ impl Network {
             Network::Testnet => "testnet",
             Network::Regtest => "regtest",
             Network::Signet => "signet",
+            Network::Custom(custom) => custom.name,
         }
     }
 
@@ -139,6 +228,7 @@ impl Network {
             ],
             Network::Regtest => &[], // No seeds
             Network::Signet => &["seed.signet.bitcoin.sprovoost.nl"],
+            Network::Custom(custom) => custom.seeds,
         }
     }
 }
@@ -162,7 +252,10 @@ impl Network {
     pub fn genesis_block(&self) -> Block {
         use bitcoin::blockdata::constants;
 
-        constants::genesis_block((*self).into())
+        match self {
+            Self::Custom(custom) => custom.genesis.clone(),
+            _ => constants::genesis_block((*self).into()),
+        }
     }
 
     /// Get the hash of the genesis block of this network.
@@ -175,6 +268,7 @@ impl Network {
             Self::Testnet => genesis::TESTNET,
             Self::Regtest => genesis::REGTEST,
             Self::Signet => genesis::SIGNET,
+            Self::Custom(custom) => return custom.genesis.block_hash(),
         };
         BlockHash::from_hash(
             sha256d::Hash::from_slice(hash)
@@ -184,11 +278,17 @@ impl Network {
 
     /// Get the consensus parameters for this network.
     pub fn params(&self) -> Params {
-        Params::new((*self).into())
+        match self {
+            Self::Custom(custom) => custom.params.clone(),
+            _ => Params::new((*self).into()),
+        }
     }
 
     /// Get the network magic number for this network.
     pub fn magic(&self) -> u32 {
-        bitcoin::Network::from(*self).magic()
+        match self {
+            Self::Custom(custom) => custom.magic,
+            _ => bitcoin::Network::from(*self).magic(),
+        }
     }
 }
@@ -1,5 +1,7 @@
 //! P2P-related types
-use std::net;
+use std::{fmt, net, str::FromStr};
+
+use thiserror::Error;
 
 pub mod peer;
 
@@ -26,3 +28,126 @@ impl Domain {
         }
     }
 }
+
+/// Error parsing a [`Cidr`].
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum CidrError {
+    /// The address part is invalid.
+    #[error("invalid address: {0}")]
+    Address(#[from] net::AddrParseError),
+    /// The prefix length is invalid, or too long for the address.
+    #[error("invalid prefix length")]
+    Prefix,
+}
+
+/// An IP address range in CIDR notation, eg. `10.0.0.0/8` or `2001:db8::/32`.
+/// An address without a prefix length denotes a single address.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: net::IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Create a new range. Returns `None` if the prefix is too long for the address.
+    pub fn new(addr: net::IpAddr, prefix: u8) -> Option<Self> {
+        let max = match addr {
+            net::IpAddr::V4(_) => 32,
+            net::IpAddr::V6(_) => 128,
+        };
+        if prefix > max {
+            return None;
+        }
+        Some(Self { addr, prefix })
+    }
+
+    /// Check whether the range contains the given address. IPv4-mapped IPv6 addresses
+    /// are matched against IPv4 ranges.
+    pub fn contains(&self, addr: &net::IpAddr) -> bool {
+        let addr = match addr {
+            net::IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(*addr, net::IpAddr::V4),
+            net::IpAddr::V4(_) => *addr,
+        };
+        match (self.addr, addr) {
+            (net::IpAddr::V4(range), net::IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (net::IpAddr::V6(range), net::IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((addr, prefix)) => {
+                let addr = addr.parse()?;
+                let prefix = prefix.parse().map_err(|_| CidrError::Prefix)?;
+
+                Self::new(addr, prefix).ok_or(CidrError::Prefix)
+            }
+            None => {
+                let addr: net::IpAddr = s.parse()?;
+                let prefix = if addr.is_ipv4() { 32 } else { 128 };
+
+                Ok(Self { addr, prefix })
+            }
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr_contains() {
+        let range: Cidr = "10.1.0.0/16".parse().unwrap();
+
+        assert!(range.contains(&[10, 1, 2, 3].into()));
+        assert!(!range.contains(&[10, 2, 0, 1].into()));
+        assert!(range.contains(&"::ffff:10.1.0.1".parse().unwrap()));
+
+        let range: Cidr = "2001:db8::/32".parse().unwrap();
+
+        assert!(range.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!range.contains(&"2001:db9::1".parse().unwrap()));
+        assert!(!range.contains(&[10, 1, 0, 1].into()));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&[192, 168, 1, 1].into()));
+
+        let single: Cidr = "192.168.1.1".parse().unwrap();
+        assert!(single.contains(&[192, 168, 1, 1].into()));
+        assert!(!single.contains(&[192, 168, 1, 2].into()));
+    }
+
+    #[test]
+    fn test_cidr_parse() {
+        assert_eq!("10.0.0.0/33".parse::<Cidr>(), Err(CidrError::Prefix));
+        assert_eq!("10.0.0.0/x".parse::<Cidr>(), Err(CidrError::Prefix));
+        assert!(matches!(
+            "10.0.0/8".parse::<Cidr>(),
+            Err(CidrError::Address(_))
+        ));
+        assert_eq!(
+            "2001:db8::/32".parse::<Cidr>().unwrap().to_string(),
+            "2001:db8::/32"
+        );
+    }
+}
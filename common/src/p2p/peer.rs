@@ -373,7 +373,7 @@ pub mod test {
             &mut self,
             _services: ServiceFlags,
         ) -> Box<dyn Iterator<Item = (Address, Source)> + '_> {
-            Box::new(std::collections::VecDeque::drain(self, ..))
+            Box::new(std::iter::from_fn(move || self.pop_front()))
         }
     }
 }
@@ -3,14 +3,14 @@ use crossbeam_channel as chan;
 
 use nakamoto_net::error::Error;
 use nakamoto_net::event::Publisher;
-use nakamoto_net::time::{LocalDuration, LocalTime};
-use nakamoto_net::{ConnDirection, PeerService};
+use nakamoto_net::time::{LocalDuration, LocalTime, MonotonicClock};
+use nakamoto_net::{ConnDirection, PeerService, ReactorConfig};
 use nakamoto_net::{DisconnectReason, PeerId, ReactorDispatch};
 
 use log::*;
 
 use std::borrow::Cow;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::io;
 use std::io::prelude::*;
@@ -18,7 +18,6 @@ use std::net;
 use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
 use std::time;
-use std::time::SystemTime;
 
 use crate::fallible;
 use crate::socket::Socket;
@@ -36,7 +35,7 @@ const READ_BUFFER_SIZE: usize = 1024 * 192;
 #[derive(Debug, PartialEq, Eq, Clone)]
 enum Source<Id: PeerId> {
     Peer(Id),
-    Listener,
+    Listener(net::SocketAddr),
     Waker,
 }
 
@@ -60,12 +59,17 @@ impl nakamoto_net::Waker for Waker {
 /// A single-threaded non-blocking reactor.
 pub struct Reactor<R: Write + Read, Id: PeerId = net::SocketAddr> {
     peers: HashMap<Id, Socket<R>>,
-    connecting: HashSet<Id>,
+    /// Outbound connections being established, with the time at which they are aborted.
+    connecting: HashMap<Id, LocalTime>,
     sources: popol::Sources<Source<Id>>,
     waker: Waker,
     timeouts: TimeoutManager<()>,
+    /// Steady clock used to tell the time. Insensitive to wall clock jumps.
+    clock: MonotonicClock,
     shutdown: chan::Receiver<()>,
-    listening: chan::Sender<net::SocketAddr>,
+    /// Bound listening addresses are reported here. Dropped once all listeners are bound.
+    listening: Option<chan::Sender<net::SocketAddr>>,
+    config: ReactorConfig,
 }
 
 /// The `R` parameter represents the underlying stream type, eg. `net::TcpStream`.
@@ -103,13 +107,15 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
     fn new(
         shutdown: chan::Receiver<()>,
         listening: chan::Sender<net::SocketAddr>,
+        config: ReactorConfig,
     ) -> Result<Self, io::Error> {
         let peers = HashMap::new();
 
         let mut sources = popol::Sources::new();
         let waker = Waker::new(&mut sources)?;
         let timeouts = TimeoutManager::new(LocalDuration::from_secs(1));
-        let connecting = HashSet::new();
+        let connecting = HashMap::new();
+        let clock = MonotonicClock::new();
 
         Ok(Self {
             peers,
@@ -117,8 +123,10 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
             sources,
             waker,
             timeouts,
+            clock,
             shutdown,
-            listening,
+            listening: Some(listening),
+            config,
         })
     }
 
@@ -130,24 +138,36 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
         mut publisher: impl Publisher<N>,
         commands: chan::Receiver<C>,
     ) -> Result<(), Error> {
-        let listener = if listen_addrs.is_empty() {
-            None
-        } else {
-            let listener = self::listen(listen_addrs)?;
-            let local_addr = listener.local_addr()?;
-
-            self.sources
-                .register(Source::Listener, &listener, popol::interest::READ);
-            self.listening.send(local_addr).ok();
+        // Listening sockets, keyed by their local address.
+        let mut listeners = HashMap::new();
+        // Closing the channel once we're done signals that all addresses were reported.
+        let listening = self.listening.take();
+
+        for addr in listen_addrs {
+            let listener = self::listen(addr).map_err(|err| Error::Bind { addr: *addr, err })?;
+            let local_addr = listener
+                .local_addr()
+                .map_err(|err| Error::Bind { addr: *addr, err })?;
+
+            self.sources.register(
+                Source::Listener(local_addr),
+                &listener,
+                popol::interest::READ,
+            );
+            // Nb. If port `0` was requested, this is the port that was actually bound.
+            if let Some(listening) = &listening {
+                listening.send(local_addr).ok();
+            }
 
             info!(target: "net", "Listening on {}", local_addr);
 
-            Some(listener)
-        };
+            listeners.insert(local_addr, listener);
+        }
+        drop(listening);
 
         info!(target: "net", "Initializing service..");
 
-        let local_time = SystemTime::now().into();
+        let local_time = self.clock.now();
         service.initialize(local_time);
 
         self.process(&mut service, &mut publisher, local_time);
@@ -160,7 +180,7 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
         loop {
             let timeout = self
                 .timeouts
-                .next(SystemTime::now())
+                .next(self.clock.now())
                 .unwrap_or(WAIT_TIMEOUT)
                 .into();
 
@@ -172,7 +192,7 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
             );
 
             let result = self.sources.wait_timeout(&mut events, timeout); // Blocking.
-            let local_time = SystemTime::now().into();
+            let local_time = self.clock.now();
 
             service.tick(local_time);
 
@@ -205,8 +225,8 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
                                     self.handle_readable(addr.clone(), &mut service);
                                 }
                             }
-                            Source::Listener => loop {
-                                if let Some(ref listener) = listener {
+                            Source::Listener(local_addr) => loop {
+                                if let Some(listener) = listeners.get(local_addr) {
                                     let (conn, socket_addr) = match listener.accept() {
                                         Ok((conn, socket_addr)) => (conn, socket_addr),
                                         Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
@@ -220,14 +240,40 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
                                     let addr = Id::from(socket_addr);
                                     trace!("{}: Accepting peer connection", socket_addr);
 
-                                    conn.set_nonblocking(true)?;
-
-                                    let local_addr = conn.local_addr()?;
+                                    if let Err(err) = conn.set_nonblocking(true) {
+                                        let err = Error::Accept {
+                                            addr: *local_addr,
+                                            err,
+                                        };
+                                        error!(target: "net", "{}: {}", socket_addr, err);
+                                        continue;
+                                    }
+
+                                    if let Err(err) = self::configure(
+                                        &socket2::SockRef::from(&conn),
+                                        &self.config,
+                                    ) {
+                                        warn!(target: "net", "{}: Failed to set socket options: {}", socket_addr, err);
+                                    }
+
+                                    let local_addr = match conn.local_addr() {
+                                        Ok(addr) => addr,
+                                        Err(err) => {
+                                            let err = Error::Accept {
+                                                addr: *local_addr,
+                                                err,
+                                            };
+                                            error!(target: "net", "{}: {}", socket_addr, err);
+                                            continue;
+                                        }
+                                    };
                                     let link = ConnDirection::Inbound;
 
                                     self.register_peer(addr.clone(), conn, link);
 
                                     service.connected(addr, &local_addr, link);
+                                } else {
+                                    break;
                                 }
                             },
                             Source::Waker => {
@@ -257,6 +303,7 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
 
                     if !timeouts.is_empty() {
                         timeouts.clear();
+                        self.abort_connecting(local_time, &mut service);
                         service.on_timer();
                     }
                 }
@@ -286,23 +333,45 @@ impl<Id: PeerId> Reactor<net::TcpStream, Id> {
         while let Some(out) = service.next() {
             match out {
                 ReactorDispatch::SendPeer(addr, bytes) => {
+                    let limit = self.config.max_outbound_queue_bytes;
+
                     if let Some(socket) = self.peers.get_mut(&addr) {
-                        if let Some(source) = self.sources.get_mut(&Source::Peer(addr)) {
+                        if let Some(source) = self.sources.get_mut(&Source::Peer(addr.clone())) {
                             socket.push(&bytes);
                             source.set(popol::interest::WRITE);
                         }
+                        // If the peer isn't reading fast enough, stop buffering data for it.
+                        if socket.queued() > limit {
+                            warn!(
+                                target: "net",
+                                "{}: Outbound queue exceeded {} bytes, disconnecting",
+                                addr.to_socket_addr(),
+                                limit
+                            );
+                            socket.disconnect().ok();
+
+                            self.unregister_peer(
+                                addr,
+                                DisconnectReason::ConnectionError(Arc::new(io::Error::new(
+                                    io::ErrorKind::Other,
+                                    "outbound queue limit exceeded",
+                                ))),
+                                service,
+                            );
+                        }
                     }
                 }
-                ReactorDispatch::ConnectPeer(addr) => {
+                ReactorDispatch::ConnectPeer(addr, timeout) => {
                     let socket_addr = addr.to_socket_addr();
                     trace!("Connecting to {}...", socket_addr);
 
-                    match self::dial(&socket_addr) {
+                    match self::dial(&socket_addr, &self.config) {
                         Ok(stream) => {
                             trace!("{:#?}", stream);
 
                             self.register_peer(addr.clone(), stream, ConnDirection::Outbound);
-                            self.connecting.insert(addr.clone());
+                            self.connecting.insert(addr.clone(), local_time + timeout);
+                            self.timeouts.register((), local_time + timeout);
 
                             service.attempted(&addr);
                         }
@@ -398,6 +467,31 @@ impl<Id: PeerId> Reactor<net::TcpStream, Id> {
         }
     }
 
+    /// Abort outbound connections that weren't established in time.
+    fn abort_connecting<S: PeerService<Id>>(&mut self, local_time: LocalTime, service: &mut S) {
+        let expired = self
+            .connecting
+            .iter()
+            .filter(|(_, deadline)| **deadline <= local_time)
+            .map(|(addr, _)| addr.clone())
+            .collect::<Vec<_>>();
+
+        for addr in expired {
+            if let Some(peer) = self.peers.get(&addr) {
+                trace!("{}: Connection attempt timed out", addr.to_socket_addr());
+
+                // Nb. Unregistering the peer drops the socket, which closes it.
+                peer.disconnect().ok();
+
+                self.unregister_peer(
+                    addr,
+                    DisconnectReason::DialError(Arc::new(io::Error::from(io::ErrorKind::TimedOut))),
+                    service,
+                );
+            }
+        }
+    }
+
     fn handle_writable<S: PeerService<Id>>(
         &mut self,
         addr: Id,
@@ -415,7 +509,7 @@ impl<Id: PeerId> Reactor<net::TcpStream, Id> {
         //
         // Since we perform a non-blocking connect, we're only really connected once the socket
         // is writable.
-        if self.connecting.remove(&addr) {
+        if self.connecting.remove(&addr).is_some() {
             let local_addr = socket.local_address()?;
 
             service.connected(addr.clone(), &local_addr, socket.link);
@@ -451,8 +545,22 @@ impl<Id: PeerId> Reactor<net::TcpStream, Id> {
     }
 }
 
+/// Apply the configured socket options to a peer connection.
+fn configure(sock: &socket2::Socket, config: &ReactorConfig) -> Result<(), io::Error> {
+    sock.set_nodelay(config.nodelay)?;
+
+    match config.keepalive {
+        Some(keepalive) => sock.set_tcp_keepalive(
+            &socket2::TcpKeepalive::new()
+                .with_time(keepalive.time)
+                .with_interval(keepalive.interval),
+        ),
+        None => sock.set_keepalive(false),
+    }
+}
+
 /// Connect to a peer given a remote address.
-fn dial(addr: &net::SocketAddr) -> Result<net::TcpStream, io::Error> {
+fn dial(addr: &net::SocketAddr, config: &ReactorConfig) -> Result<net::TcpStream, io::Error> {
     use socket2::{Domain, Socket, Type};
     fallible! { io::Error::from(io::ErrorKind::Other) };
 
@@ -466,6 +574,7 @@ fn dial(addr: &net::SocketAddr) -> Result<net::TcpStream, io::Error> {
     sock.set_read_timeout(Some(READ_TIMEOUT))?;
     sock.set_write_timeout(Some(WRITE_TIMEOUT))?;
     sock.set_nonblocking(true)?;
+    self::configure(&sock, config)?;
 
     match sock.connect(&(*addr).into()) {
         Ok(()) => {}
@@ -480,10 +589,163 @@ fn dial(addr: &net::SocketAddr) -> Result<net::TcpStream, io::Error> {
 }
 
 // Listen for connections on the given address.
-fn listen<A: net::ToSocketAddrs>(addr: A) -> Result<net::TcpListener, Error> {
-    let sock = net::TcpListener::bind(addr)?;
+//
+// IPv6 sockets are bound in IPv6-only mode, so that an IPv4 and an IPv6 socket can
+// listen on the same port, eg. when listening on both `0.0.0.0` and `[::]`.
+fn listen(addr: &net::SocketAddr) -> Result<net::TcpListener, io::Error> {
+    use socket2::{Domain, Socket, Type};
+
+    let domain = if addr.is_ipv4() {
+        Domain::IPV4
+    } else {
+        Domain::IPV6
+    };
+    let sock = Socket::new(domain, Type::STREAM, None)?;
 
+    if addr.is_ipv6() {
+        sock.set_only_v6(true)?;
+    }
+    sock.set_reuse_address(true)?;
     sock.set_nonblocking(true)?;
+    sock.bind(&(*addr).into())?;
+    sock.listen(128)?;
+
+    Ok(sock.into())
+}
 
-    Ok(sock)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nakamoto_net::{Keepalive, PeerProtocol};
+    use socket2::{Domain, Socket, Type};
+    use std::collections::VecDeque;
+
+    /// A service that sends the queued payloads to peers, and records disconnections.
+    #[derive(Default)]
+    struct Sender {
+        outbox: VecDeque<ReactorDispatch<Vec<u8>, (), String>>,
+        disconnected: Vec<(net::SocketAddr, DisconnectReason<String>)>,
+    }
+
+    impl Iterator for Sender {
+        type Item = ReactorDispatch<Vec<u8>, (), String>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.outbox.pop_front()
+        }
+    }
+
+    impl PeerProtocol for Sender {
+        type PeerMessage = [u8];
+        type Notification = ();
+        type DisconnectDemand = String;
+
+        fn received(&mut self, _addr: &net::SocketAddr, _msg: Cow<[u8]>) {}
+        fn attempted(&mut self, _addr: &net::SocketAddr) {}
+        fn connected(
+            &mut self,
+            _addr: net::SocketAddr,
+            _local: &net::SocketAddr,
+            _link: ConnDirection,
+        ) {
+        }
+        fn disconnected(&mut self, addr: &net::SocketAddr, reason: DisconnectReason<String>) {
+            self.disconnected.push((*addr, reason));
+        }
+        fn tick(&mut self, _local_time: LocalTime) {}
+        fn on_timer(&mut self) {}
+    }
+
+    impl PeerService for Sender {
+        type Command = ();
+
+        fn command_received(&mut self, _cmd: ()) {}
+    }
+
+    #[test]
+    fn test_outbound_queue_limit() {
+        let limit = 1024;
+        let (_shutdown, shutdown_recv) = chan::bounded(1);
+        let (listening, _) = chan::unbounded();
+        let mut reactor: Reactor<net::TcpStream> = nakamoto_net::Reactor::new(
+            shutdown_recv,
+            listening,
+            ReactorConfig {
+                max_outbound_queue_bytes: limit,
+                ..ReactorConfig::default()
+            },
+        )
+        .unwrap();
+        let (mut publisher, _events) = chan::unbounded::<()>();
+        let mut service = Sender::default();
+
+        // The remote peer accepts the connection, but never reads from it.
+        let listener = net::TcpListener::bind(([127, 0, 0, 1], 0)).unwrap();
+        let remote = listener.local_addr().unwrap();
+        let stream = net::TcpStream::connect(remote).unwrap();
+        let _conn = listener.accept().unwrap();
+
+        stream.set_nonblocking(true).unwrap();
+        reactor.register_peer(remote, stream, ConnDirection::Outbound);
+
+        // Data up to the limit is queued.
+        service
+            .outbox
+            .push_back(ReactorDispatch::SendPeer(remote, vec![0; limit]));
+        reactor.process(&mut service, &mut publisher, LocalTime::now());
+
+        assert!(service.disconnected.is_empty());
+        assert_eq!(reactor.peers[&remote].queued(), limit);
+
+        // Going over the limit disconnects the peer, and data sent to it afterwards is dropped.
+        service
+            .outbox
+            .push_back(ReactorDispatch::SendPeer(remote, vec![0; 1]));
+        service
+            .outbox
+            .push_back(ReactorDispatch::SendPeer(remote, vec![0; 1]));
+        reactor.process(&mut service, &mut publisher, LocalTime::now());
+
+        assert!(!reactor.peers.contains_key(&remote));
+        assert!(matches!(
+            &service.disconnected[..],
+            [(addr, DisconnectReason::ConnectionError(_))] if *addr == remote
+        ));
+    }
+
+    #[test]
+    fn test_configure() {
+        let sock = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        let keepalive = Keepalive {
+            time: time::Duration::from_secs(42),
+            interval: time::Duration::from_secs(7),
+        };
+
+        configure(
+            &sock,
+            &ReactorConfig {
+                nodelay: true,
+                keepalive: Some(keepalive),
+                ..ReactorConfig::default()
+            },
+        )
+        .unwrap();
+        assert!(sock.nodelay().unwrap());
+        assert!(sock.keepalive().unwrap());
+        assert_eq!(sock.keepalive_time().unwrap(), keepalive.time);
+        assert_eq!(sock.keepalive_interval().unwrap(), keepalive.interval);
+
+        // Both can be disabled.
+        configure(
+            &sock,
+            &ReactorConfig {
+                nodelay: false,
+                keepalive: None,
+                ..ReactorConfig::default()
+            },
+        )
+        .unwrap();
+        assert!(!sock.nodelay().unwrap());
+        assert!(!sock.keepalive().unwrap());
+    }
 }
@@ -44,6 +44,11 @@ impl<R: Read + Write> Socket<R> {
         self.raw.read(buf)
     }
 
+    /// Number of bytes waiting to be written to the socket.
+    pub fn queued(&self) -> usize {
+        self.buffer.len()
+    }
+
     pub fn push(&mut self, bytes: &[u8]) {
         self.buffer.extend_from_slice(bytes);
     }
@@ -2,6 +2,7 @@
 
 use std::fmt::Debug;
 use std::io;
+use std::net;
 
 use crossbeam_channel as crossbeam;
 
@@ -10,6 +11,36 @@ use thiserror::Error;
 /// An error occuring in peer-to-peer networking code.
 #[derive(Error, Debug)]
 pub enum Error {
+    /// Failed to bind or listen on a local address.
+    #[error("failed to listen on {addr}: {err}")]
+    Bind {
+        /// Address we tried to listen on.
+        addr: net::SocketAddr,
+        /// Underlying error.
+        #[source]
+        err: io::Error,
+    },
+
+    /// Failed to set up a connection accepted on a listening socket.
+    #[error("failed to accept connection on {addr}: {err}")]
+    Accept {
+        /// Local address of the listening socket.
+        addr: net::SocketAddr,
+        /// Underlying error.
+        #[source]
+        err: io::Error,
+    },
+
+    /// Failed to resolve a host name to socket addresses.
+    #[error("failed to resolve {host}: {err}")]
+    Resolve {
+        /// Host name that was looked up.
+        host: String,
+        /// Underlying error.
+        #[source]
+        err: io::Error,
+    },
+
     /// An I/O error.
     #[error("i/o error: {0}")]
     Io(#[from] io::Error),
@@ -19,6 +50,19 @@ pub enum Error {
     Channel(Box<dyn std::error::Error + Send + Sync + 'static>),
 }
 
+impl Error {
+    /// Get the underlying I/O error, if any.
+    pub fn io(&self) -> Option<&io::Error> {
+        match self {
+            Self::Bind { err, .. } | Self::Accept { err, .. } | Self::Resolve { err, .. } => {
+                Some(err)
+            }
+            Self::Io(err) => Some(err),
+            Self::Channel(_) => None,
+        }
+    }
+}
+
 impl<T: Debug + Send + Sync + 'static> From<crossbeam::SendError<T>> for Error {
     fn from(err: crossbeam::SendError<T>) -> Self {
         Self::Channel(Box::new(err))
@@ -24,10 +24,13 @@ impl<E, T: Clone> Broadcast<E, T> {
     }
 }
 
+/// A subscription to events of type `T`. Returns `false` if the subscriber is gone.
+type Subscription<T> = Box<dyn FnMut(&T) -> bool + Send>;
+
 /// Publishes events to subscribers.
 #[derive(Clone)]
 pub struct Emitter<T> {
-    subscribers: Arc<Mutex<Vec<chan::Sender<T>>>>,
+    subscribers: Arc<Mutex<Vec<Subscription<T>>>>,
 }
 
 impl<T> Default for Emitter<T> {
@@ -41,10 +44,7 @@ impl<T> Default for Emitter<T> {
 impl<T: Clone> Emitter<T> {
     /// Emit an event to all subscribers and drop subscribers who can't receive it.
     pub fn emit(&self, event: T) {
-        self.subscribers
-            .lock()
-            .unwrap()
-            .retain(|s| s.try_send(event.clone()).is_ok());
+        self.subscribers.lock().unwrap().retain_mut(|s| s(&event));
     }
 
     /// Drop all subscribers.
@@ -63,15 +63,36 @@ impl<T: Clone> Emitter<T> {
 /// Subscribes to events.
 #[derive(Clone)]
 pub struct Subscriber<T> {
-    subscribers: Arc<Mutex<Vec<chan::Sender<T>>>>,
+    subscribers: Arc<Mutex<Vec<Subscription<T>>>>,
 }
 
-impl<T: Clone> Subscriber<T> {
+impl<T: Clone + Send + 'static> Subscriber<T> {
     /// Add a subscription to receive broadcast events.
     pub fn subscribe(&self) -> chan::Receiver<T> {
+        self.subscribe_with(|e| Some(e.clone()))
+    }
+
+    /// Add a subscription that calls the given function with every broadcast event, in the
+    /// thread broadcasting it. The subscription is dropped once the function returns `false`.
+    pub fn subscribe_fn(&self, f: impl FnMut(&T) -> bool + Send + 'static) {
+        self.subscribers.lock().unwrap().push(Box::new(f));
+    }
+
+    /// Add a subscription to receive the broadcast events for which the given function
+    /// returns something.
+    ///
+    /// Nb. The subscription is only dropped when an event is sent to it after its
+    /// receiver was dropped.
+    pub fn subscribe_with<U: Send + 'static>(
+        &self,
+        mut f: impl FnMut(&T) -> Option<U> + Send + 'static,
+    ) -> chan::Receiver<U> {
         let (sender, receiver) = chan::unbounded();
         let mut subs = self.subscribers.lock().unwrap();
-        subs.push(sender);
+        subs.push(Box::new(move |e| match f(e) {
+            Some(u) => sender.try_send(u).is_ok(),
+            None => true,
+        }));
 
         receiver
     }
@@ -138,3 +159,65 @@ impl<E, T: Clone + Send + Sync> Publisher<E> for Broadcast<E, T> {
         self.broadcast(event)
     }
 }
+
+/// Publishes events to several publishers, in the order they were registered. This can be
+/// used to send events to independent sinks, eg. a logger, a metrics collector and an
+/// application channel.
+///
+/// Nb. Every publisher except the last receives its own clone of each event, so the cost of
+/// publishing grows with the number of publishers and the size of events. Events that are
+/// expensive to clone are best wrapped in an [`Arc`].
+pub struct Publishers<E> {
+    publishers: Vec<Box<dyn Publisher<E>>>,
+}
+
+impl<E> Publishers<E> {
+    /// Register a publisher.
+    pub fn register(mut self, publisher: impl Publisher<E> + 'static) -> Self {
+        self.add(publisher);
+        self
+    }
+
+    /// Add a publisher.
+    pub fn add(&mut self, publisher: impl Publisher<E> + 'static) {
+        self.publishers.push(Box::new(publisher));
+    }
+
+    /// Number of registered publishers.
+    pub fn len(&self) -> usize {
+        self.publishers.len()
+    }
+
+    /// Check whether there are no registered publishers.
+    pub fn is_empty(&self) -> bool {
+        self.publishers.is_empty()
+    }
+}
+
+impl<E> Default for Publishers<E> {
+    fn default() -> Self {
+        Self {
+            publishers: Vec::new(),
+        }
+    }
+}
+
+impl<E: Clone> Publisher<E> for Publishers<E> {
+    /// Publish an event to all publishers.
+    fn publish(&mut self, event: E) {
+        if let Some((last, rest)) = self.publishers.split_last_mut() {
+            for p in rest {
+                p.publish(event.clone());
+            }
+            last.publish(event);
+        }
+    }
+}
+
+impl<E: Send> Publisher<E> for chan::Sender<E> {
+    /// Send an event on the channel. Events are dropped if the receiver is gone, or if the
+    /// channel is bounded and full.
+    fn publish(&mut self, event: E) {
+        self.try_send(event).ok();
+    }
+}
@@ -42,8 +42,9 @@ impl ConnDirection {
 pub enum ReactorDispatch<M, N, D, Id: PeerId = net::SocketAddr> {
     /// There are some bytes ready to be sent to a peer.
     SendPeer(Id, M),
-    /// Connect to a peer.
-    ConnectPeer(Id),
+    /// Connect to a peer, giving up if the connection isn't established within
+    /// the given time.
+    ConnectPeer(Id, LocalDuration),
     /// Disconnect from a peer.
     DisconnectPeer(Id, D),
     /// Ask for a single timer-based wakeup.
@@ -213,16 +214,67 @@ pub trait Waker: Send + Sync + Clone {
     fn wake(&self) -> io::Result<()>;
 }
 
+/// TCP keepalive parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Time a connection must be idle before keepalive probes are sent.
+    pub time: std::time::Duration,
+    /// Time between keepalive probes.
+    pub interval: std::time::Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            time: std::time::Duration::from_secs(60),
+            interval: std::time::Duration::from_secs(10),
+        }
+    }
+}
+
+/// Default limit on the data queued for sending to a single peer.
+pub const MAX_OUTBOUND_QUEUE_BYTES: usize = 32 * 1024 * 1024;
+
+/// Socket options applied by a reactor to peer connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReactorConfig {
+    /// Disable Nagle's algorithm (`TCP_NODELAY`), so that small messages such as pings and
+    /// inventory announcements are sent right away instead of being buffered. Enabled by
+    /// default. Disabling it saves a little bandwidth on constrained links, at the cost
+    /// of latency.
+    pub nodelay: bool,
+    /// TCP keepalive parameters, used to detect dead connections. By default, probes are
+    /// sent after a minute of inactivity, every ten seconds. If `None`, keepalive is
+    /// disabled, and dead connections are only detected by protocol pings.
+    pub keepalive: Option<Keepalive>,
+    /// Maximum number of bytes queued for sending to a single peer. Peers that don't read
+    /// fast enough for their queue to stay under this limit are disconnected with
+    /// [`DisconnectReason::ConnectionError`], which bounds the memory a slow peer can use.
+    pub max_outbound_queue_bytes: usize,
+}
+
+impl Default for ReactorConfig {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: Some(Keepalive::default()),
+            max_outbound_queue_bytes: MAX_OUTBOUND_QUEUE_BYTES,
+        }
+    }
+}
+
 /// Any network reactor that can drive the light-client service.
 pub trait Reactor<Id: PeerId = net::SocketAddr> {
     /// The type of [`Waker`] this reactor provides.
     type Waker: Waker;
 
     /// Create a new reactor, initializing it with a publisher for service events,
-    /// a channel to receive commands, and a channel to shut it down.
+    /// a channel to receive commands, a channel to shut it down, and the socket options
+    /// to apply to peer connections.
     fn new(
         shutdown: chan::Receiver<()>,
         listening: chan::Sender<net::SocketAddr>,
+        config: ReactorConfig,
     ) -> Result<Self, io::Error>
     where
         Self: Sized;
@@ -231,7 +283,9 @@ pub trait Reactor<Id: PeerId = net::SocketAddr> {
     ///
     /// # Arguments
     ///
-    /// - `listen_addrs`: list of IP sockets to bind to;
+    /// - `listen_addrs`: list of IP sockets to bind to. Every bound address, including the
+    ///   actual port when port `0` is requested, is sent on the `listening` channel, which
+    ///   is then closed;
     /// - `service`: a concrete network protocol implementation to run in the
     ///   reactor event loop;
     /// - `notification_publisher`: a concrete implementation of multiple subscribers single
@@ -240,6 +294,10 @@ pub trait Reactor<Id: PeerId = net::SocketAddr> {
     ///   by the network `service` business logic;
     /// - `commands_receiver`: the receiver part of the channel with the user thread
     ///   used to process commands from outside of the event loop.
+    ///
+    /// Fails with [`error::Error::Bind`] if one of the listen addresses can't be bound.
+    /// Inbound connections that can't be set up are logged as [`error::Error::Accept`]
+    /// and dropped, without stopping the reactor.
     fn run<N, C>(
         &mut self,
         listen_addrs: &[net::SocketAddr],
@@ -142,6 +142,28 @@ impl<M: Clone, D: Clone> Inbox<M, D> {
     }
 }
 
+/// Returned by [`Simulation::run_until_converged`] when peers fail to converge.
+#[derive(Debug, Clone)]
+pub struct Diverged<K> {
+    /// Number of simulation steps taken.
+    pub steps: usize,
+    /// The state of each peer when the simulation was stopped.
+    pub peers: Vec<(net::SocketAddr, K)>,
+}
+
+impl<K: fmt::Debug> fmt::Display for Diverged<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peers failed to converge after {} step(s):", self.steps)?;
+
+        for (addr, state) in &self.peers {
+            write!(f, " {} = {:?};", addr, state)?;
+        }
+        Ok(())
+    }
+}
+
+impl<K: fmt::Debug> std::error::Error for Diverged<K> {}
+
 /// Simulation options.
 #[derive(Debug, Clone)]
 pub struct Options {
@@ -289,6 +311,42 @@ where
         }
     }
 
+    /// Run the simulation until all peers agree on the state returned by `state`, eg. their
+    /// chain tip, or until `max_steps` inputs have been processed. Convergence is checked
+    /// before every step, so peers that agree from the start converge immediately.
+    ///
+    /// Returns the number of steps taken, or the diverging peer states if the peers didn't
+    /// converge.
+    pub fn run_until_converged<'a, P: Peer<T>, K: PartialEq + fmt::Debug>(
+        &mut self,
+        peers: impl IntoIterator<Item = &'a mut P>,
+        max_steps: usize,
+        state: impl Fn(&T) -> K,
+    ) -> Result<usize, Diverged<K>> {
+        let mut nodes: BTreeMap<_, _> = peers.into_iter().map(|p| (p.addr().ip(), p)).collect();
+        let mut steps = 0;
+        let mut done = false;
+
+        loop {
+            let states = nodes
+                .values()
+                .map(|p| (p.addr(), state(&***p)))
+                .collect::<Vec<_>>();
+
+            if states.windows(2).all(|w| w[0].1 == w[1].1) {
+                return Ok(steps);
+            }
+            if done || steps == max_steps {
+                return Err(Diverged {
+                    steps,
+                    peers: states,
+                });
+            }
+            done = !self.step_(&mut nodes);
+            steps += 1;
+        }
+    }
+
     /// Process one scheduled input from the inbox, using the provided peers.
     /// This function should be called until it returns `false`, or some desired state is reached.
     /// Returns `true` if there are more messages to process.
@@ -472,7 +530,7 @@ where
                     },
                 );
             }
-            ReactorDispatch::ConnectPeer(remote) => {
+            ReactorDispatch::ConnectPeer(remote, _) => {
                 assert!(remote.ip() != node, "self-connections are not allowed");
 
                 // Create an ephemeral sockaddr for the connecting (local) node.
@@ -1,5 +1,5 @@
 use std::sync::atomic;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 /// Local time.
 ///
@@ -73,6 +73,93 @@ impl LocalTime {
     pub fn elapse(&mut self, duration: LocalDuration) {
         self.millis += duration.as_millis()
     }
+
+    /// Add a duration to this time. Returns `None` on overflow.
+    pub fn checked_add(&self, duration: LocalDuration) -> Option<LocalTime> {
+        self.millis
+            .checked_add(duration.0)
+            .map(|millis| LocalTime { millis })
+    }
+
+    /// Substract a duration from this time. Returns `None` on underflow.
+    pub fn checked_sub(&self, duration: LocalDuration) -> Option<LocalTime> {
+        self.millis
+            .checked_sub(duration.0)
+            .map(|millis| LocalTime { millis })
+    }
+
+    /// Add a duration to this time, saturating at the maximum time.
+    pub fn saturating_add(&self, duration: LocalDuration) -> LocalTime {
+        LocalTime {
+            millis: self.millis.saturating_add(duration.0),
+        }
+    }
+
+    /// Substract a duration from this time, saturating at Epoch.
+    pub fn saturating_sub(&self, duration: LocalDuration) -> LocalTime {
+        LocalTime {
+            millis: self.millis.saturating_sub(duration.0),
+        }
+    }
+}
+
+/// Monotonic clock source.
+///
+/// The clock is anchored to the system (wall) clock when created, and from then on advances
+/// using a steady source, ie. [`Instant`]. This means that if the wall clock jumps backward,
+/// eg. because of an NTP step, the time returned by the clock keeps moving forward.
+///
+/// Since the steady source may not advance while the system is suspended, the clock is
+/// re-anchored whenever the wall clock is found to be *ahead* of it. Hence the returned
+/// time stays close to wall time, which is required for comparisons with block times.
+///
+/// ```
+/// use nakamoto_net::time::MonotonicClock;
+///
+/// let mut clock = MonotonicClock::new();
+/// let a = clock.now();
+/// let b = clock.now();
+///
+/// assert!(b >= a);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct MonotonicClock {
+    /// Local time at the instant the clock was (re-)anchored.
+    anchor: LocalTime,
+    /// Instant at which the clock was (re-)anchored.
+    instant: Instant,
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MonotonicClock {
+    /// Create a new clock, anchored to the current system time.
+    pub fn new() -> Self {
+        Self {
+            anchor: LocalTime::from(SystemTime::now()),
+            instant: Instant::now(),
+        }
+    }
+
+    /// Get the current local time. Never returns a time earlier than a previously
+    /// returned time.
+    pub fn now(&mut self) -> LocalTime {
+        let steady = self.anchor + LocalDuration::from(self.instant.elapsed());
+        let system = LocalTime::from(SystemTime::now());
+
+        if system > steady {
+            self.anchor = system;
+            self.instant = Instant::now();
+
+            system
+        } else {
+            steady
+        }
+    }
 }
 
 /// Convert a `SystemTime` into a local time.
@@ -136,6 +223,21 @@ impl LocalDuration {
         Self::from_secs(mins * 60)
     }
 
+    /// Create a new duration from whole hours.
+    pub const fn from_hours(hours: u64) -> Self {
+        Self::from_mins(hours * 60)
+    }
+
+    /// Create a new duration from whole days.
+    pub const fn from_days(days: u64) -> Self {
+        Self::from_hours(days * 24)
+    }
+
+    /// Create a new duration from whole weeks.
+    pub const fn from_weeks(weeks: u64) -> Self {
+        Self::from_days(weeks * 7)
+    }
+
     /// Construct a new duration from milliseconds.
     pub const fn from_millis(millis: u128) -> Self {
         Self(millis)
@@ -155,6 +257,45 @@ impl LocalDuration {
     pub const fn as_millis(&self) -> u128 {
         self.0
     }
+
+    /// Add two durations. Returns `None` on overflow.
+    pub const fn checked_add(&self, other: LocalDuration) -> Option<LocalDuration> {
+        match self.0.checked_add(other.0) {
+            Some(millis) => Some(Self(millis)),
+            None => None,
+        }
+    }
+
+    /// Substract two durations. Returns `None` on underflow.
+    pub const fn checked_sub(&self, other: LocalDuration) -> Option<LocalDuration> {
+        match self.0.checked_sub(other.0) {
+            Some(millis) => Some(Self(millis)),
+            None => None,
+        }
+    }
+
+    /// Multiply a duration by a scalar. Returns `None` on overflow.
+    pub const fn checked_mul(&self, other: u64) -> Option<LocalDuration> {
+        match self.0.checked_mul(other as u128) {
+            Some(millis) => Some(Self(millis)),
+            None => None,
+        }
+    }
+
+    /// Add two durations, saturating at [`LocalDuration::MAX`].
+    pub const fn saturating_add(&self, other: LocalDuration) -> LocalDuration {
+        Self(self.0.saturating_add(other.0))
+    }
+
+    /// Substract two durations, saturating at zero.
+    pub const fn saturating_sub(&self, other: LocalDuration) -> LocalDuration {
+        Self(self.0.saturating_sub(other.0))
+    }
+
+    /// Multiply a duration by a scalar, saturating at [`LocalDuration::MAX`].
+    pub const fn saturating_mul(&self, other: u64) -> LocalDuration {
+        Self(self.0.saturating_mul(other as u128))
+    }
 }
 
 impl std::fmt::Display for LocalDuration {
@@ -227,6 +368,12 @@ impl std::ops::Mul<u64> for LocalDuration {
     }
 }
 
+impl From<std::time::Duration> for LocalDuration {
+    fn from(other: std::time::Duration) -> Self {
+        Self(other.as_millis())
+    }
+}
+
 impl From<LocalDuration> for std::time::Duration {
     fn from(other: LocalDuration) -> Self {
         std::time::Duration::from_millis(other.0 as u64)
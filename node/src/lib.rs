@@ -14,16 +14,18 @@ pub mod logger;
 type Reactor = nakamoto_net_poll::Reactor<net::TcpStream>;
 
 /// Run the light-client. Takes an initial list of peers to connect to, a list of listen addresses,
-/// the client root and the Bitcoin network to connect to.
+/// the client root and the Bitcoin network to connect to. In inbound-only mode, no outbound
+/// connections are made, and the client only serves peers connecting to it. Peers to connect
+/// to can't be given in that mode, and are rejected with a configuration error.
 pub fn run(
     connect: &[net::SocketAddr],
     listen: &[net::SocketAddr],
     root: Option<PathBuf>,
     domains: &[Domain],
     network: Network,
+    inbound_only: bool,
 ) -> Result<(), Error> {
     let mut cfg = Config {
-        network,
         connect: connect.to_vec(),
         domains: domains.to_vec(),
         listen: if listen.is_empty() {
@@ -31,12 +33,14 @@ pub fn run(
         } else {
             listen.to_vec()
         },
-        ..Config::default()
+        ..Config::new(network)
     };
     if let Some(path) = root {
         cfg.root = path;
     }
-    if !connect.is_empty() {
+    if inbound_only {
+        cfg.limits.max_outbound_peers = 0;
+    } else if !connect.is_empty() {
         cfg.limits.max_outbound_peers = connect.len();
     }
 
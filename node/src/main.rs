@@ -36,6 +36,10 @@ pub struct Options {
     /// root directory for nakamoto files (default: ~)
     #[argh(option)]
     pub root: Option<PathBuf>,
+
+    /// only accept inbound connections; can't be used with --connect (default: false)
+    #[argh(switch)]
+    pub inbound_only: bool,
 }
 
 impl Options {
@@ -65,7 +69,14 @@ fn main() {
         vec![Domain::IPV4, Domain::IPV6]
     };
 
-    if let Err(e) = nakamoto_node::run(&opts.connect, &opts.listen, opts.root, &domains, network) {
+    if let Err(e) = nakamoto_node::run(
+        &opts.connect,
+        &opts.listen,
+        opts.root,
+        &domains,
+        network,
+        opts.inbound_only,
+    ) {
         log::error!(target: "node", "Exiting: {}", e);
         std::process::exit(1);
     }
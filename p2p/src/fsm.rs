@@ -10,17 +10,22 @@ pub mod output;
 
 // Sub-protocols.
 mod addrmgr;
+mod bloommgr;
 mod cbfmgr;
+mod cmpctmgr;
 mod invmgr;
 mod peermgr;
 mod pingmgr;
+mod ratelimit;
 mod syncmgr;
 
 #[cfg(test)]
 mod tests;
 
 use addrmgr::AddressManager;
+use bloommgr::BloomManager;
 use cbfmgr::FilterManager;
+use cmpctmgr::CompactBlockManager;
 use invmgr::InventoryManager;
 use output::Outbox;
 use peermgr::PeerManager;
@@ -28,10 +33,17 @@ use pingmgr::PingManager;
 use syncmgr::SyncManager;
 
 pub use addrmgr::Event as AddressEvent;
+pub use addrmgr::{AddrStats, GetAddrLimits};
+pub use bloommgr::Event as BloomEvent;
 pub use cbfmgr::Event as FilterEvent;
+pub use cmpctmgr::Event as CompactBlockEvent;
 pub use invmgr::Event as InventoryEvent;
+pub use invmgr::TRANSACTION_PRUNE_DEPTH;
+pub use peermgr::Capabilities;
 pub use peermgr::Event as PeerEvent;
 pub use pingmgr::Event as PingEvent;
+pub use pingmgr::{Latencies, Percentiles};
+pub use ratelimit::SERVE_BUDGET_PERIOD;
 pub use syncmgr::Event as ChainEvent;
 
 use crate::stream;
@@ -53,20 +65,21 @@ use nakamoto_common::bitcoin::consensus::params::Params;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
 use nakamoto_common::bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
+use nakamoto_common::bitcoin::network::message_compact_blocks::{BlockTxn, CmpctBlock};
 use nakamoto_common::bitcoin::network::message_filter::GetCFilters;
 use nakamoto_common::bitcoin::network::message_network::VersionMessage;
 use nakamoto_common::bitcoin::network::Address;
-use nakamoto_common::bitcoin::Script;
+use nakamoto_common::bitcoin::{OutPoint, Script};
 use nakamoto_common::block::filter::Filters;
 use nakamoto_common::block::time::AdjustedClock;
-use nakamoto_common::block::time::{LocalDuration, LocalTime};
+use nakamoto_common::block::time::{LocalDuration, LocalTime, TimeOffset};
 use nakamoto_common::block::tree::{self, BlockReader, BlockTree, ImportResult};
-use nakamoto_common::block::{BlockHash, Height};
-use nakamoto_common::block::{BlockTime, Transaction};
+use nakamoto_common::block::{Block, BlockTime, Transaction};
+use nakamoto_common::block::{BlockHash, Height, Work, LOCATOR_DENSITY};
 use nakamoto_common::network;
 use nakamoto_common::nonempty::NonEmpty;
 use nakamoto_common::p2p::peer::AddressSource;
-use nakamoto_common::p2p::{peer, Domain};
+use nakamoto_common::p2p::{peer, Cidr, Domain};
 use nakamoto_net as traits;
 
 use thiserror::Error;
@@ -78,9 +91,14 @@ pub const PROTOCOL_VERSION: u32 = 70016;
 pub const MIN_PROTOCOL_VERSION: u32 = 70012;
 /// User agent included in `version` messages.
 pub const USER_AGENT: &str = "/nakamoto:0.3.0/";
+/// Maximum length of a user agent, in bytes. Longer user agents are rejected by peers.
+pub const MAX_USER_AGENT_LENGTH: usize = 256;
 
 /// Starting size of peer inbox buffer.
 const INBOX_BUFFER_SIZE: usize = 1024 * 64;
+/// Number of blocks the chain must grow by before headers are pruned again. Pruning rewrites
+/// the header store, so it is done in batches.
+const PRUNE_INTERVAL: Height = 2016;
 
 /// Block locators. Consists of starting hashes and a stop hash.
 type Locators = (Vec<BlockHash>, BlockHash);
@@ -140,6 +158,16 @@ pub enum DisconnectReason {
     SelfConnection,
     /// Inbound connection limit reached.
     ConnectionLimit,
+    /// Inbound connection from a denied address range.
+    ConnectionDenied,
+    /// Inbound peer was evicted to make room for a new inbound peer.
+    PeerEvicted,
+    /// Outbound peer was rotated out, for a peer in another network group.
+    PeerRotated,
+    /// Connection limits were lowered, and this peer was in excess.
+    ConnectionLimitLowered,
+    /// Outbound connection attempt timed out.
+    ConnectionTimeout,
     /// Error trying to decode incoming message.
     DecodeError(Arc<encode::Error>),
     /// Peer was forced to disconnect by external command.
@@ -154,7 +182,13 @@ impl DisconnectReason {
     pub fn is_transient(&self) -> bool {
         matches!(
             self,
-            Self::ConnectionLimit | Self::PeerTimeout(_) | Self::PeerHeight(_)
+            Self::ConnectionLimit
+                | Self::PeerEvicted
+                | Self::PeerRotated
+                | Self::ConnectionLimitLowered
+                | Self::ConnectionTimeout
+                | Self::PeerTimeout(_)
+                | Self::PeerHeight(_)
         )
     }
 }
@@ -163,14 +197,23 @@ impl fmt::Display for DisconnectReason {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::PeerMisbehaving(reason) => write!(f, "peer misbehaving: {}", reason),
-            Self::PeerProtocolVersion(_) => write!(f, "peer protocol version mismatch"),
-            Self::PeerServices(_) => write!(f, "peer doesn't have the required services"),
+            Self::PeerProtocolVersion(version) => {
+                write!(f, "peer protocol version {} is not supported", version)
+            }
+            Self::PeerServices(s) => {
+                write!(f, "peer doesn't have the required services ({})", s)
+            }
             Self::PeerHeight(_) => write!(f, "peer is too far behind"),
             Self::PeerMagic(magic) => write!(f, "received message with invalid magic: {}", magic),
             Self::PeerTimeout(s) => write!(f, "peer timed out: {:?}", s),
             Self::PeerDropped => write!(f, "peer dropped"),
             Self::SelfConnection => write!(f, "detected self-connection"),
             Self::ConnectionLimit => write!(f, "inbound connection limit reached"),
+            Self::ConnectionDenied => write!(f, "inbound connection denied"),
+            Self::PeerEvicted => write!(f, "peer evicted to make room for another peer"),
+            Self::PeerRotated => write!(f, "peer rotated out for network diversity"),
+            Self::ConnectionLimitLowered => write!(f, "connection limit was lowered"),
+            Self::ConnectionTimeout => write!(f, "connection attempt timed out"),
             Self::DecodeError(err) => write!(f, "message decode error: {}", err),
             Self::Command => write!(f, "received external command"),
             Self::Other(reason) => write!(f, "{}", reason),
@@ -197,6 +240,16 @@ pub struct Peer {
     pub user_agent: String,
     /// Whether this peer relays transactions.
     pub relay: bool,
+    /// Protocol version negotiated with this peer.
+    pub version: u32,
+    /// Protocol features supported by this peer.
+    pub capabilities: Capabilities,
+    /// Number of block headers received from this peer that extended our chain.
+    pub headers: usize,
+    /// Number of valid compact filters received from this peer.
+    pub filters: usize,
+    /// Number of requested blocks received from this peer.
+    pub blocks: usize,
 }
 
 impl Peer {
@@ -217,6 +270,11 @@ impl From<(&peermgr::PeerInfo, &peermgr::Connection)> for Peer {
             services: peer.services,
             user_agent: peer.user_agent.clone(),
             relay: peer.relay,
+            version: peer.version,
+            capabilities: peer.capabilities(),
+            headers: 0,
+            filters: 0,
+            blocks: 0,
         }
     }
 }
@@ -230,6 +288,38 @@ pub enum Command {
     GetPeers(ServiceFlags, chan::Sender<Vec<Peer>>),
     /// Get the tip of the active chain.
     GetTip(chan::Sender<(Height, BlockHeader)>),
+    /// Get the sync status.
+    GetSyncStatus(chan::Sender<SyncStatus>),
+    /// Get a snapshot of the node's state.
+    GetSnapshot(chan::Sender<Snapshot>),
+    /// Get the best block height advertised by our peers, if we have any. The height is
+    /// reported by peers and isn't verified.
+    GetBestPeerHeight(chan::Sender<Option<Height>>),
+    /// Get a summary of the node's health.
+    GetHealth(chan::Sender<Health>),
+    /// Get a snapshot of the node's metrics.
+    GetMetrics(chan::Sender<Metrics>),
+    /// Get address book statistics.
+    GetAddressStats(chan::Sender<AddrStats>),
+    /// Ask a connected peer for its addresses, and reply with the addresses it sends. Replies
+    /// with what was received so far if the peer stops responding. See
+    /// [`AddressManager::request_addresses_from`].
+    RequestAddresses(
+        PeerId,
+        chan::Sender<Result<Vec<(BlockTime, Address)>, CommandError>>,
+    ),
+    /// Get the requests sent to peers that are awaiting a response, longest pending first.
+    GetInFlight(chan::Sender<Vec<InFlight>>),
+    /// Get the current fee estimate for confirmation within the given number of blocks.
+    /// Replies with [`None`] if there isn't enough data to estimate fees.
+    GetFeeEstimate {
+        /// Number of blocks within which a transaction should confirm.
+        target_blocks: usize,
+        /// Reply channel.
+        reply: chan::Sender<Option<fees::FeeEstimate>>,
+    },
+    /// Get the ping latency percentiles of each peer, and of all peers together.
+    GetLatencies(chan::Sender<Latencies>),
     /// Get a block from the active chain.
     GetBlock(BlockHash),
     /// Get block filters.
@@ -251,6 +341,40 @@ pub enum Command {
         /// Scripts to watch.
         watch: Vec<Script>,
     },
+    /// Replace the watchlist with the provided scripts, in one step. Watched transactions
+    /// and outpoints are kept.
+    ///
+    /// If `rescan` is set, the range of the last rescan is scanned again with the new
+    /// watchlist: from its start height up to its end height, or with no end if it had
+    /// none. Otherwise, only blocks that weren't scanned yet are matched against it.
+    SetWatchlist {
+        /// Scripts to watch.
+        watch: Vec<Script>,
+        /// Whether to rescan the range of the last rescan.
+        rescan: bool,
+    },
+    /// Watch an outpoint for spends.
+    ///
+    /// BIP 158 basic filters don't commit to outpoints: for every input, they commit to the
+    /// `scriptPubKey` of the output being spent. The outpoint's script is therefore added to
+    /// the watchlist, and matching blocks are checked for a transaction spending the outpoint.
+    /// Since other outputs may share the same script, matching blocks don't necessarily
+    /// spend the outpoint.
+    WatchOutpoint {
+        /// Outpoint to watch.
+        outpoint: OutPoint,
+        /// Script of the output being watched.
+        script_pubkey: Script,
+    },
+    /// Set the probability of downloading blocks that don't match the watchlist, as decoys.
+    SetFilterDecoyRate(f64),
+    /// Change the peer and cache limits. Excess peers are disconnected.
+    SetLimits(Limits),
+    /// Stop requesting compact filters and filter headers. Peers stay connected and
+    /// block header sync continues.
+    PauseFilterSync,
+    /// Resume filter syncing from where it was paused.
+    ResumeFilterSync,
     /// Broadcast to peers matching the predicate.
     Broadcast(NetworkMessage, fn(Peer) -> bool, chan::Sender<Vec<PeerId>>),
     /// Send a message to a random peer.
@@ -261,13 +385,52 @@ pub enum Command {
     Connect(net::SocketAddr),
     /// Disconnect from a peer.
     Disconnect(net::SocketAddr),
+    /// Rotate a random outbound peer for a peer in a network group we're not connected to.
+    /// Peers we're syncing from aren't rotated. Replies with the disconnected peer and the
+    /// new one, or [`None`] if no peer could be rotated.
+    RotatePeer(chan::Sender<Option<(PeerId, PeerId)>>),
     /// Import headers directly into the block store.
     ImportHeaders(
         Vec<BlockHeader>,
         chan::Sender<Result<ImportResult, tree::Error>>,
     ),
+    /// Drop block headers below the given height from the block store, to bound its size.
+    /// Headers whose filters weren't processed yet are kept, as well as enough headers to
+    /// validate new ones and handle re-orgs. The first header kept acts as a checkpoint.
+    ///
+    /// Replies with the height of the first header kept after the genesis. Pruned headers
+    /// can't be rescanned or served to peers anymore.
+    PruneHeaders {
+        /// Height below which headers are dropped.
+        below: Height,
+        /// Reply channel.
+        reply: chan::Sender<Result<Height, tree::Error>>,
+    },
+    /// Revert the blocks of the active chain above the given height, along with their
+    /// filters, and sync them again from peers. This is a recovery tool, eg. if the local
+    /// chain state is suspected to be corrupt.
+    ///
+    /// Replies with the new height of the active chain, which is unchanged if the given height
+    /// is above it. Fails if the block at the given height was pruned.
+    Resync {
+        /// Height of the last block to keep.
+        from: Height,
+        /// Reply channel.
+        reply: chan::Sender<Result<Height, tree::Error>>,
+    },
     /// Import addresses into the address book.
     ImportAddresses(Vec<Address>),
+    /// Add known addresses to the address book, with their services. Unlike addresses
+    /// to connect to, these are only candidates for outbound connections.
+    AddAddresses(Vec<(net::SocketAddr, ServiceFlags)>),
+    /// Record the outcome of a DNS seed lookup, which is done outside the state machine.
+    /// Resolved addresses are added to the address book.
+    DnsSeedResolved {
+        /// The seed host name.
+        seed: String,
+        /// The addresses returned, or the resolution error.
+        result: Result<Vec<net::SocketAddr>, String>,
+    },
     /// Submit a transaction to the network.
     SubmitTransaction(
         Transaction,
@@ -281,6 +444,18 @@ impl fmt::Debug for Command {
             Self::GetBlockByHeight(height, _) => write!(f, "GetBlockByHeight({})", height),
             Self::GetPeers(flags, _) => write!(f, "GetPeers({})", flags),
             Self::GetTip(_) => write!(f, "GetTip"),
+            Self::GetSyncStatus(_) => write!(f, "GetSyncStatus"),
+            Self::GetSnapshot(_) => write!(f, "GetSnapshot"),
+            Self::GetBestPeerHeight(_) => write!(f, "GetBestPeerHeight"),
+            Self::GetHealth(_) => write!(f, "GetHealth"),
+            Self::GetMetrics(_) => write!(f, "GetMetrics"),
+            Self::GetAddressStats(_) => write!(f, "GetAddressStats"),
+            Self::RequestAddresses(addr, _) => write!(f, "RequestAddresses({})", addr),
+            Self::GetInFlight(_) => write!(f, "GetInFlight"),
+            Self::GetFeeEstimate { target_blocks, .. } => {
+                write!(f, "GetFeeEstimate({})", target_blocks)
+            }
+            Self::GetLatencies(_) => write!(f, "GetLatencies"),
             Self::GetBlock(hash) => write!(f, "GetBlock({})", hash),
             Self::GetFilters(range, _) => write!(f, "GetFilters({:?})", range),
             Self::Rescan { from, to, watch } => {
@@ -289,13 +464,33 @@ impl fmt::Debug for Command {
             Self::Watch { watch } => {
                 write!(f, "Watch({:?})", watch)
             }
+            Self::SetWatchlist { watch, rescan } => {
+                write!(f, "SetWatchlist({:?}, {})", watch, rescan)
+            }
+            Self::WatchOutpoint {
+                outpoint,
+                script_pubkey,
+            } => {
+                write!(f, "WatchOutpoint({}, {:?})", outpoint, script_pubkey)
+            }
+            Self::SetFilterDecoyRate(rate) => write!(f, "SetFilterDecoyRate({})", rate),
+            Self::SetLimits(limits) => write!(f, "SetLimits({:?})", limits),
+            Self::PauseFilterSync => write!(f, "PauseFilterSync"),
+            Self::ResumeFilterSync => write!(f, "ResumeFilterSync"),
             Self::Broadcast(msg, _, _) => write!(f, "Broadcast({})", msg.cmd()),
             Self::Query(msg, _) => write!(f, "Query({})", msg.cmd()),
             Self::QueryTree(_) => write!(f, "QueryTree"),
             Self::Connect(addr) => write!(f, "Connect({})", addr),
             Self::Disconnect(addr) => write!(f, "Disconnect({})", addr),
+            Self::RotatePeer(_) => write!(f, "RotatePeer"),
             Self::ImportHeaders(_headers, _) => write!(f, "ImportHeaders(..)"),
+            Self::PruneHeaders { below, .. } => write!(f, "PruneHeaders({})", below),
+            Self::Resync { from, .. } => write!(f, "Resync({})", from),
             Self::ImportAddresses(addrs) => write!(f, "ImportAddresses({:?})", addrs),
+            Self::AddAddresses(addrs) => write!(f, "AddAddresses({:?})", addrs),
+            Self::DnsSeedResolved { seed, result } => {
+                write!(f, "DnsSeedResolved({}, {:?})", seed, result)
+            }
             Self::SubmitTransaction(tx, _) => write!(f, "SubmitTransaction({:?})", tx),
         }
     }
@@ -311,6 +506,159 @@ pub enum CommandError {
 
 pub use cbfmgr::GetFiltersError;
 
+/// An invalid state machine configuration.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The user agent is longer than [`MAX_USER_AGENT_LENGTH`].
+    #[error(
+        "user agent is {0} bytes long, the maximum is {}",
+        MAX_USER_AGENT_LENGTH
+    )]
+    UserAgentTooLong(usize),
+    /// The user agent contains control characters.
+    #[error("user agent contains control characters")]
+    UserAgentInvalid,
+    /// Header proof-of-work is trusted, but headers may come from untrusted peers.
+    #[error("trusted headers require connecting exclusively to trusted peers, without listening")]
+    UntrustedHeaderSource,
+    /// Peers to connect to were given, but outbound connections are disabled.
+    #[error("peers to connect to were given, but the client is inbound-only")]
+    ConnectInboundOnly,
+}
+
+/// Check that a user agent is fit to be sent in our `version` messages.
+pub fn validate_user_agent(user_agent: &str) -> Result<(), ConfigError> {
+    if user_agent.len() > MAX_USER_AGENT_LENGTH {
+        return Err(ConfigError::UserAgentTooLong(user_agent.len()));
+    }
+    if user_agent.chars().any(char::is_control) {
+        return Err(ConfigError::UserAgentInvalid);
+    }
+    Ok(())
+}
+
+/// Sync status, aggregated over the sub-protocols.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncStatus {
+    /// Syncing block headers.
+    Headers {
+        /// Height of our header chain.
+        current: Height,
+        /// Best height known to our peers.
+        target: Height,
+    },
+    /// Block headers are synced. Syncing filter headers, or scanning filters.
+    Filters {
+        /// Height up to which filters are synced.
+        current: Height,
+        /// Height of our header chain.
+        target: Height,
+    },
+    /// Synced up to the given height.
+    Synced {
+        /// Height of our header chain.
+        height: Height,
+    },
+}
+
+/// Summary of the node's health, eg. for readiness probes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Health {
+    /// Sync status.
+    pub sync: SyncStatus,
+    /// Number of negotiated outbound peers.
+    pub peers: usize,
+    /// Time elapsed since the timestamp of our best block header.
+    pub tip_age: LocalDuration,
+}
+
+impl Health {
+    /// Check whether the node is synced, with at least `min_peers` outbound peers,
+    /// and a tip no older than `max_tip_age`.
+    pub fn is_ready(&self, min_peers: usize, max_tip_age: LocalDuration) -> bool {
+        matches!(self.sync, SyncStatus::Synced { .. })
+            && self.peers >= min_peers
+            && self.tip_age <= max_tip_age
+    }
+}
+
+/// Snapshot of the node's state, eg. for late event subscribers to catch up from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Height of the block header chain.
+    pub tip: Height,
+    /// Height of the filter header chain.
+    pub filter_tip: Height,
+    /// Negotiated peers.
+    pub peers: Vec<PeerId>,
+    /// Number of events emitted before the snapshot was taken. These events are reflected
+    /// in the snapshot, later ones aren't.
+    pub events: u64,
+}
+
+/// Snapshot of the node's metrics, eg. for exporting to a monitoring system.
+///
+/// Counters are totals since the state machine was started.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Metrics {
+    /// Number of negotiated outbound peers.
+    pub outbound_peers: usize,
+    /// Number of negotiated inbound peers.
+    pub inbound_peers: usize,
+    /// Height of the block header chain.
+    pub height: Height,
+    /// Height of the filter header chain.
+    pub filter_height: Height,
+    /// Number of bytes received from peers.
+    pub bytes_received: u64,
+    /// Number of bytes sent to peers.
+    pub bytes_sent: u64,
+    /// Number of chain re-orgs.
+    pub reorgs: u64,
+    /// Number of blocks matched by compact filters, not including decoys.
+    pub blocks_matched: u64,
+}
+
+/// A request sent to a peer, that we're awaiting a response to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InFlight {
+    /// Peer the request was sent to.
+    pub peer: PeerId,
+    /// The request.
+    pub request: Request,
+    /// Time elapsed since the request was sent.
+    pub age: LocalDuration,
+}
+
+/// A request sent to a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Block headers, with the given locator hashes.
+    GetHeaders {
+        /// Locator hashes.
+        locators: Vec<BlockHash>,
+    },
+    /// Compact filter headers.
+    GetCFHeaders {
+        /// Height of the first filter header requested.
+        start_height: Height,
+        /// Hash of the block of the last filter header requested.
+        stop_hash: BlockHash,
+    },
+    /// Compact filters.
+    GetCFilters {
+        /// Height of the first filter requested.
+        start_height: Height,
+        /// Hash of the block of the last filter requested.
+        stop_hash: BlockHash,
+    },
+    /// A block, with `getdata`.
+    GetBlock {
+        /// Block hash.
+        hash: BlockHash,
+    },
+}
+
 /// Holds functions that are used to hook into or alter protocol behavior.
 #[derive(Clone)]
 pub struct Hooks {
@@ -321,10 +669,13 @@ pub struct Hooks {
     /// Called when a `version` message is received.
     /// If an error is returned, the peer is dropped, and the error is logged.
     pub on_version: Arc<dyn Fn(PeerId, VersionMessage) -> Result<(), &'static str> + Send + Sync>,
-    /// Called when a `getcfilters` message is received.
+    /// Called when a `getcfilters` message is received, unless we serve filters ourselves.
+    /// See [`Config::serve_filters`].
     pub on_getcfilters: Arc<dyn Fn(PeerId, GetCFilters, &Outbox) + Send + Sync>,
     /// Called when a `getdata` message is received.
     pub on_getdata: Arc<dyn Fn(PeerId, Vec<Inventory>, &Outbox) + Send + Sync>,
+    /// Chooses the outbound peers to connect to. Defaults to [`peermgr::RandomSelector`].
+    pub select_peer: Arc<dyn peermgr::Selector>,
 }
 
 impl Default for Hooks {
@@ -334,6 +685,7 @@ impl Default for Hooks {
             on_version: Arc::new(|_, _| Ok(())),
             on_getcfilters: Arc::new(|_, _, _| {}),
             on_getdata: Arc::new(|_, _, _| {}),
+            select_peer: Arc::new(peermgr::RandomSelector),
         }
     }
 }
@@ -356,6 +708,9 @@ pub struct StateMachine<T, F, P, C> {
     network: network::Network,
     /// Peer message inboxes.
     inbox: HashMap<PeerId, stream::Decoder>,
+    /// Tracing spans of connected peers, under which their messages are handled.
+    #[cfg(feature = "tracing")]
+    spans: HashMap<PeerId, tracing::Span>,
     /// Peer address manager.
     addrmgr: AddressManager<P, Outbox, C>,
     /// Blockchain synchronization manager.
@@ -364,12 +719,26 @@ pub struct StateMachine<T, F, P, C> {
     pingmgr: PingManager<Outbox, C>,
     /// CBF (Compact Block Filter) manager.
     cbfmgr: FilterManager<F, Outbox, C>,
+    /// Bloom filter manager. Only used if the BIP 37 fallback is enabled.
+    bloommgr: BloomManager<Outbox, C>,
+    /// Compact block manager. Only used if compact block relay is enabled.
+    cmpctmgr: CompactBlockManager<Outbox>,
     /// Peer manager.
     peermgr: PeerManager<Outbox, C>,
     /// Inventory manager.
     invmgr: InventoryManager<Outbox, C>,
+    /// Whether compact block filters are disabled, and only block headers are synced.
+    headers_only: bool,
+    /// Depth below the tip past which block headers are pruned.
+    prune_depth: Option<Height>,
+    /// Height of the active chain when headers were last pruned.
+    last_prune: Height,
     /// Network-adjusted clock.
     clock: C,
+    /// Last detected clock skew, in seconds.
+    clock_skew: Option<TimeOffset>,
+    /// Metric counters. Gauges are filled in when a snapshot is requested.
+    metrics: Metrics,
     /// Last time a "tick" was triggered.
     #[allow(dead_code)]
     last_tick: LocalTime,
@@ -384,12 +753,26 @@ pub struct StateMachine<T, F, P, C> {
 /// Configured limits.
 #[derive(Debug, Clone)]
 pub struct Limits {
-    /// Target outbound peer connections.
+    /// Target outbound peer connections. If set to zero, the node runs in inbound-only
+    /// mode: addresses from the address book and [`Config::connect`] are never dialed, and
+    /// no addresses are requested from peers. Inbound peers are still accepted and served.
+    /// Peers in [`Config::connect_only`] and [`Config::failover`] aren't allowed either.
     pub max_outbound_peers: usize,
     /// Maximum inbound peer connections.
     pub max_inbound_peers: usize,
     /// Size in bytes of the compact filter cache.
     pub filter_cache_size: usize,
+    /// Maximum number of filters in the compact filter cache. If not set, only the size in
+    /// bytes is limited. Evicted filters are fetched again from peers if needed.
+    pub filter_cache_len: Option<usize>,
+}
+
+impl Limits {
+    /// Whether outbound connections to the address book are disabled. See
+    /// [`Limits::max_outbound_peers`].
+    pub fn is_inbound_only(&self) -> bool {
+        self.max_outbound_peers == 0
+    }
 }
 
 impl Default for Limits {
@@ -398,6 +781,7 @@ impl Default for Limits {
             max_outbound_peers: peermgr::TARGET_OUTBOUND_PEERS,
             max_inbound_peers: peermgr::MAX_INBOUND_PEERS,
             filter_cache_size: cbfmgr::DEFAULT_FILTER_CACHE_SIZE,
+            filter_cache_len: None,
         }
     }
 }
@@ -409,43 +793,183 @@ pub struct Config {
     pub network: network::Network,
     /// Peers to connect to.
     pub connect: Vec<net::SocketAddr>,
+    /// Peers to connect to exclusively. If non-empty, no other outbound connections
+    /// are made, and these peers are reconnected to if they disconnect.
+    pub connect_only: Vec<net::SocketAddr>,
+    /// Peers to fail over between, in order of preference. If non-empty, only one of them
+    /// is connected to at a time, and no other outbound connections are made. See
+    /// [`peermgr::Config::failover`].
+    pub failover: Vec<net::SocketAddr>,
     /// Supported communication domains.
     pub domains: Vec<Domain>,
     /// Services offered by our peer.
     pub services: ServiceFlags,
-    /// Required peer services.
+    /// Services required from outbound peers. Peers lacking any of these are
+    /// disconnected during the handshake, unless whitelisted.
     pub required_services: ServiceFlags,
     /// Peer whitelist. Peers in this list are trusted by default.
     pub whitelist: Whitelist,
+    /// Address ranges allowed to connect to us. If empty, any address that isn't denied
+    /// is allowed.
+    pub inbound_allow: Vec<Cidr>,
+    /// Address ranges denied from connecting to us. Takes precedence over
+    /// [`Config::inbound_allow`].
+    pub inbound_deny: Vec<Cidr>,
     /// Consensus parameters.
     pub params: Params,
     /// Our protocol version.
     pub protocol_version: u32,
-    /// Our user agent.
-    pub user_agent: &'static str,
+    /// Minimum protocol version of peers, eg. to only connect to peers recent enough to
+    /// support features we rely on. Peers with an older version are disconnected during the
+    /// handshake. Can't be lower than [`MIN_PROTOCOL_VERSION`].
+    pub min_protocol_version: u32,
+    /// Our user agent, sent in `version` messages. See [`validate_user_agent`].
+    pub user_agent: String,
     /// Ping timeout, after which remotes are disconnected.
     pub ping_timeout: LocalDuration,
+    /// Maximum number of ping latencies recorded per peer. See
+    /// [`pingmgr::Config::max_recorded_latencies`].
+    pub max_recorded_latencies: usize,
+    /// Time to wait for an outbound connection to be established, before giving up.
+    pub connect_timeout: LocalDuration,
+    /// Time to wait for a connected peer to complete the handshake, before disconnecting it.
+    /// Should leave room for slow but honest peers.
+    pub handshake_timeout: LocalDuration,
+    /// Time to wait for a requested block, before requesting it from another peer. The
+    /// wait grows with each attempt, to accommodate large blocks over slow links.
+    pub block_download_timeout: LocalDuration,
+    /// Base time to wait before reconnecting to a persistent peer. Doubles with every
+    /// failed attempt.
+    pub retry_min_wait: LocalDuration,
+    /// Maximum time to wait before reconnecting to a persistent peer, not including jitter.
+    pub retry_max_wait: LocalDuration,
+    /// Fall back to BIP 37 bloom filters and merkle blocks with peers that don't serve
+    /// compact block filters. Bloom filters reveal the watched scripts to peers.
+    pub bloom_fallback: bool,
+    /// Only sync block headers. Compact block filters aren't requested from peers, and
+    /// peers aren't preferred based on whether they serve them.
+    pub headers_only: bool,
+    /// Use BIP 152 compact block relay with peers that support it, so that new blocks are
+    /// announced to us with their header, and blocks can be reconstructed from transactions
+    /// we already know of.
+    pub compact_blocks: bool,
+    /// Probability of downloading the block of a compact filter that doesn't match the
+    /// watchlist, as a decoy. Trades bandwidth for privacy.
+    pub filter_decoy_rate: f64,
+    /// Maximum number of headers in a `headers` message, sent or expected. Peers that cap
+    /// their responses lower are still synced from. Can't exceed the protocol maximum
+    /// of 2000.
+    pub max_message_headers: usize,
+    /// Maximum number of filter headers requested in a single `getcfheaders` message. Can't
+    /// exceed the protocol maximum of 2000.
+    pub max_message_cfheaders: usize,
+    /// Maximum number of `getcfheaders` requests inflight at once. Above one, filter header
+    /// checkpoints are requested from peers, and filter headers between checkpoints are
+    /// fetched in parallel.
+    pub max_cfheaders_requests: usize,
+    /// Serve compact filters from our filter cache, and filter header checkpoints, to inbound
+    /// peers. Serving is best-effort: requests for filters that aren't cached are ignored.
+    /// For this reason, `NODE_COMPACT_FILTERS` isn't advertised, since it promises to serve
+    /// all filters; peers must know to request filters from us.
+    pub serve_filters: bool,
+    /// Answer `getheaders` requests from peers out of our header store, once synced. Each
+    /// peer is limited to a number of requests per minute.
+    pub serve_headers: bool,
+    /// Maximum number of `getheaders` requests, and of filter requests, served to a single
+    /// peer per [`SERVE_BUDGET_PERIOD`]. Requests beyond this are ignored.
+    pub serve_budget: usize,
+    /// Number of consecutive block hashes in our block locators, before stepping back
+    /// exponentially. Lower values make for smaller `getheaders` messages on long chains.
+    pub locator_density: usize,
+    /// Maximum number of blocks of the active chain that a re-org may revert. Peers proposing
+    /// deeper re-orgs are disconnected. The fork's work isn't taken into account, so a deeper
+    /// fork is refused even if it has more work than the active chain.
+    pub max_reorg_depth: Height,
+    /// Minimum proof-of-work of the best chain, below which we aren't considered synced.
+    /// If not set, the network's default is used.
+    pub minimum_chain_work: Option<Work>,
+    /// Maximum ratio of compact filters from a single peer matching the watchlist, before
+    /// the peer is reported as suspicious.
+    pub max_filter_match_rate: f64,
+    /// Number of compact filters processed from a peer before its match rate is evaluated.
+    pub filter_match_rate_sample_size: u64,
+    /// Number of peers each compact filter is fetched from. Filters are only processed once
+    /// all of them have sent it, and peers sending conflicting filters are disconnected.
+    /// Capped at the number of peers we're connected to.
+    pub filter_redundancy: usize,
     /// State machine event hooks.
     pub hooks: Hooks,
     /// Configured limits.
     pub limits: Limits,
+    /// Thresholds for asking peers for addresses.
+    pub getaddr: GetAddrLimits,
+    /// Fee estimates of recently processed blocks, eg. saved on a previous run, used to
+    /// estimate fees until enough new blocks are processed.
+    pub fee_history: Vec<(Height, fees::FeeEstimate)>,
+    /// Sample the fee rates of unconfirmed transactions announced by peers, and blend them
+    /// with block fee estimates. This asks peers to relay transactions to us, and downloads
+    /// some of them, at a cost in bandwidth.
+    pub mempool_fees: bool,
+    /// Minimum fee rate of transactions peers should announce to us, sent in BIP 133
+    /// `feefilter` messages when [`Config::mempool_fees`] is set. If not set, the lowest fee
+    /// rate included in recent blocks is used.
+    pub fee_filter: Option<fees::FeeRate>,
+    /// Prune block headers deeper than this below the tip, once their filters are processed.
+    /// See [`Command::PruneHeaders`]. If not set, all headers are kept.
+    pub prune_depth: Option<Height>,
+    /// Rotate an outbound peer at this interval. See [`Command::RotatePeer`]. If not set,
+    /// peers are only rotated on request.
+    pub rotate_interval: Option<LocalDuration>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             network: network::Network::default(),
-            params: Params::new(network::Network::default().into()),
+            params: network::Network::default().params(),
             connect: Vec::new(),
+            connect_only: Vec::new(),
+            failover: Vec::new(),
             domains: Domain::all(),
             services: ServiceFlags::NONE,
             required_services: ServiceFlags::NETWORK,
             whitelist: Whitelist::default(),
+            inbound_allow: Vec::new(),
+            inbound_deny: Vec::new(),
             protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
             ping_timeout: pingmgr::PING_TIMEOUT,
-            user_agent: USER_AGENT,
+            max_recorded_latencies: pingmgr::MAX_RECORDED_LATENCIES,
+            connect_timeout: peermgr::CONNECTION_TIMEOUT,
+            handshake_timeout: peermgr::HANDSHAKE_TIMEOUT,
+            block_download_timeout: invmgr::REQUEST_TIMEOUT,
+            retry_min_wait: peermgr::RETRY_MIN_WAIT,
+            retry_max_wait: peermgr::RETRY_MAX_WAIT,
+            bloom_fallback: false,
+            headers_only: false,
+            compact_blocks: false,
+            filter_decoy_rate: 0.,
+            max_message_headers: syncmgr::MAX_MESSAGE_HEADERS,
+            max_message_cfheaders: cbfmgr::MAX_MESSAGE_CFHEADERS,
+            max_cfheaders_requests: 1,
+            serve_filters: false,
+            serve_headers: true,
+            serve_budget: ratelimit::DEFAULT_SERVE_BUDGET,
+            locator_density: LOCATOR_DENSITY,
+            max_reorg_depth: syncmgr::MAX_REORG_DEPTH,
+            minimum_chain_work: None,
+            user_agent: USER_AGENT.to_owned(),
+            max_filter_match_rate: cbfmgr::DEFAULT_MAX_MATCH_RATE,
+            filter_match_rate_sample_size: cbfmgr::DEFAULT_MATCH_RATE_SAMPLE_SIZE,
+            filter_redundancy: 1,
             hooks: Hooks::default(),
             limits: Limits::default(),
+            getaddr: GetAddrLimits::default(),
+            fee_history: Vec::new(),
+            mempool_fees: false,
+            fee_filter: None,
+            prune_depth: None,
+            rotate_interval: None,
         }
     }
 }
@@ -453,7 +977,7 @@ impl Default for Config {
 impl Config {
     /// Construct a new configuration.
     pub fn from(network: network::Network, connect: Vec<net::SocketAddr>) -> Self {
-        let params = Params::new(network.into());
+        let params = network.params();
 
         Self {
             network,
@@ -497,34 +1021,91 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
         let Config {
             network,
             connect,
+            connect_only,
+            failover,
             domains,
             services,
             whitelist,
+            inbound_allow,
+            inbound_deny,
             protocol_version,
+            min_protocol_version,
             ping_timeout,
+            max_recorded_latencies,
+            connect_timeout,
+            handshake_timeout,
+            block_download_timeout,
+            retry_min_wait,
+            retry_max_wait,
+            bloom_fallback,
+            headers_only,
+            compact_blocks,
+            filter_decoy_rate,
+            max_message_headers,
+            max_message_cfheaders,
+            max_cfheaders_requests,
+            serve_filters,
+            serve_headers,
+            serve_budget,
+            locator_density,
+            max_reorg_depth,
+            minimum_chain_work,
+            max_filter_match_rate,
+            filter_match_rate_sample_size,
+            filter_redundancy,
             user_agent,
             required_services,
             params,
             hooks,
             limits,
+            getaddr,
+            fee_history,
+            mempool_fees,
+            fee_filter,
+            prune_depth,
+            rotate_interval,
         } = config;
 
         let outbox = Outbox::new(network, protocol_version);
         let inbox = HashMap::new();
         let syncmgr = SyncManager::new(
             syncmgr::Config {
-                max_message_headers: syncmgr::MAX_MESSAGE_HEADERS,
+                max_message_headers: max_message_headers.min(syncmgr::MAX_MESSAGE_HEADERS),
+                locator_density,
                 request_timeout: syncmgr::REQUEST_TIMEOUT,
+                max_reorg_depth,
+                minimum_chain_work: minimum_chain_work
+                    .unwrap_or_else(|| network.minimum_chain_work()),
                 params,
+                serve_headers,
+                serve_budget,
+            },
+            rng.clone(),
+            outbox.clone(),
+            clock.clone(),
+        );
+        let pingmgr = PingManager::new(
+            pingmgr::Config {
+                ping_timeout,
+                max_recorded_latencies,
             },
             rng.clone(),
             outbox.clone(),
             clock.clone(),
         );
-        let pingmgr = PingManager::new(ping_timeout, rng.clone(), outbox.clone(), clock.clone());
         let cbfmgr = FilterManager::new(
             cbfmgr::Config {
                 filter_cache_size: limits.filter_cache_size,
+                filter_cache_len: limits.filter_cache_len,
+                decoy_rate: filter_decoy_rate.clamp(0., 1.),
+                max_message_cfheaders: max_message_cfheaders.min(cbfmgr::MAX_MESSAGE_CFHEADERS),
+                max_cfheaders_requests,
+                serve_filters,
+                checkpoints: network.filter_checkpoints().collect(),
+                max_match_rate: max_filter_match_rate,
+                match_rate_sample_size: filter_match_rate_sample_size.max(1),
+                filter_redundancy: filter_redundancy.max(1),
+                serve_budget,
                 ..cbfmgr::Config::default()
             },
             rng.clone(),
@@ -532,20 +1113,52 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
             outbox.clone(),
             clock.clone(),
         );
+        let bloommgr = BloomManager::new(
+            bloommgr::Config {
+                enabled: bloom_fallback,
+                ..bloommgr::Config::default()
+            },
+            rng.clone(),
+            outbox.clone(),
+            clock.clone(),
+        );
+        let cmpctmgr = CompactBlockManager::new(
+            cmpctmgr::Config {
+                enabled: compact_blocks,
+            },
+            rng.clone(),
+            outbox.clone(),
+        );
         let peermgr = PeerManager::new(
             peermgr::Config {
                 protocol_version: PROTOCOL_VERSION,
+                min_protocol_version: min_protocol_version.max(MIN_PROTOCOL_VERSION),
                 whitelist,
-                persistent: connect,
+                persistent: connect
+                    .into_iter()
+                    .chain(connect_only.iter().cloned())
+                    .collect(),
+                exclusive: !connect_only.is_empty(),
+                failover,
                 domains: domains.clone(),
                 target_outbound_peers: limits.max_outbound_peers,
                 max_inbound_peers: limits.max_inbound_peers,
-                retry_max_wait: LocalDuration::from_mins(60),
-                retry_min_wait: LocalDuration::from_secs(1),
+                inbound_allow,
+                inbound_deny,
+                retry_max_wait,
+                retry_min_wait,
+                connect_timeout,
+                handshake_timeout,
                 required_services,
-                preferred_services: syncmgr::REQUIRED_SERVICES | cbfmgr::REQUIRED_SERVICES,
+                preferred_services: if headers_only {
+                    syncmgr::REQUIRED_SERVICES
+                } else {
+                    syncmgr::REQUIRED_SERVICES | cbfmgr::REQUIRED_SERVICES
+                },
                 services,
                 user_agent,
+                relay: mempool_fees,
+                rotate_interval,
             },
             rng.clone(),
             hooks.clone(),
@@ -556,25 +1169,45 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
             addrmgr::Config {
                 required_services,
                 domains,
+                getaddr,
+                discovery: !limits.is_inbound_only(),
             },
             rng.clone(),
             peers,
             outbox.clone(),
             clock.clone(),
         );
-        let invmgr = InventoryManager::new(rng.clone(), outbox.clone(), clock.clone());
+        let mut invmgr = InventoryManager::new(rng.clone(), outbox.clone(), clock.clone());
+        invmgr.restore_fee_history(fee_history, tree.height());
+        invmgr.set_block_timeout(block_download_timeout);
+
+        if mempool_fees {
+            invmgr.track_mempool_fees();
+        }
+        if let Some(rate) = fee_filter {
+            invmgr.set_fee_filter(rate);
+        }
 
         Self {
             tree,
             network,
             clock,
+            clock_skew: None,
             inbox,
+            #[cfg(feature = "tracing")]
+            spans: HashMap::new(),
             addrmgr,
             syncmgr,
             pingmgr,
             cbfmgr,
+            bloommgr,
+            cmpctmgr,
             peermgr,
             invmgr,
+            headers_only,
+            prune_depth,
+            last_prune: 0,
+            metrics: Metrics::default(),
             last_tick: LocalTime::default(),
             rng,
             outbox,
@@ -582,12 +1215,39 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
         }
     }
 
+    /// Record bytes received from peers, before decoding.
+    pub fn record_bytes_received(&mut self, count: usize) {
+        self.metrics.bytes_received += count as u64;
+    }
+
+    /// Record bytes sent to peers, after encoding.
+    pub fn record_bytes_sent(&mut self, count: usize) {
+        self.metrics.bytes_sent += count as u64;
+    }
+
+    /// Get the fee estimates of recently processed blocks, to be saved and restored on
+    /// the next run with [`Config::fee_history`].
+    pub fn fee_history(&self) -> fees::FeeHistory {
+        fees::FeeHistory {
+            time: self.clock.local_time(),
+            estimates: self.invmgr.fee_history(),
+        }
+    }
+
     /// Disconnect a peer.
     pub fn disconnect(&mut self, addr: PeerId, reason: DisconnectReason) {
-        // TODO: Trigger disconnection everywhere, as if peer disconnected. This
-        // avoids being in a state where we know a peer is about to get disconnected,
-        // but we still process messages from it as normal.
-
+        // Drop the peer's state in all sub-protocols right away, as if the peer had
+        // disconnected. This avoids being in a state where we know a peer is about to get
+        // disconnected, but we still process messages from it as normal. The address and
+        // peer managers are notified once the disconnection is complete.
+        if self.peermgr.is_connected(&addr) {
+            self.cbfmgr.peer_disconnected(&addr);
+            self.bloommgr.peer_disconnected(&addr);
+            self.cmpctmgr.peer_disconnected(&addr);
+            self.syncmgr.peer_disconnected(&addr);
+            self.pingmgr.peer_disconnected(&addr);
+            self.invmgr.peer_disconnected(&addr);
+        }
         self.peermgr.disconnect(addr, reason);
     }
 
@@ -612,6 +1272,169 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
         peers
     }
 
+    /// Prune block headers below the given height, keeping those whose filters weren't
+    /// processed yet.
+    fn prune_headers(&mut self, below: Height) -> Result<Height, tree::Error> {
+        let below = if self.headers_only {
+            below
+        } else {
+            let rescan = &self.cbfmgr.rescan;
+            let processed = if rescan.active {
+                rescan.current
+            } else {
+                Height::MAX
+            };
+            below.min(processed).min(self.cbfmgr.filters.height())
+        };
+        self.tree.prune(below)
+    }
+
+    /// Revert the active chain down to the given height, and sync it again.
+    fn resync(&mut self, from: Height) -> Result<Height, tree::Error> {
+        let reverted = self.syncmgr.rollback(from, &mut self.tree)?;
+
+        if !reverted.is_empty() {
+            self.blocks_reverted(reverted);
+
+            if !self.headers_only {
+                self.cbfmgr.sync(&self.tree);
+            }
+            self.bloommgr.sync(&self.tree);
+        }
+        Ok(self.tree.height())
+    }
+
+    /// Roll the sub-protocols back after blocks of the active chain were reverted.
+    /// The reverted blocks are ordered from the tip down to the oldest ancestor.
+    fn blocks_reverted(&mut self, reverted: Vec<(Height, BlockHeader)>) {
+        if let Some((height, _)) = reverted.last() {
+            // The height we need to rollback to, ie. the tip of our new chain
+            // and the tallest block we are keeping.
+            let fork_height = height - 1;
+            self.cbfmgr.rollback(fork_height).unwrap();
+            self.bloommgr.rollback(fork_height);
+
+            for (height, _) in reverted {
+                for tx in self.invmgr.block_reverted(height) {
+                    self.cbfmgr.watch_transaction(&tx);
+                }
+            }
+        }
+    }
+
+    /// Rotate a random outbound peer, except the ones we're syncing headers or filters from.
+    fn rotate_peer(&mut self) -> Option<(PeerId, PeerId)> {
+        let keep = self
+            .syncmgr
+            .inflight()
+            .chain(self.cbfmgr.inflight())
+            .map(|req| req.peer)
+            .collect::<Vec<_>>();
+
+        self.peermgr.rotate(&keep, &mut self.addrmgr)
+    }
+
+    /// Get the current sync status.
+    fn sync_status(&self) -> SyncStatus {
+        let height = self.tree.height();
+
+        match self.syncmgr.best_height() {
+            Some(best) if height >= best => {}
+            best => {
+                return SyncStatus::Headers {
+                    current: height,
+                    target: best.unwrap_or(height),
+                }
+            }
+        }
+        if self.headers_only {
+            return SyncStatus::Synced { height };
+        }
+
+        let filter_height = self.cbfmgr.filters.height();
+        if filter_height < height {
+            return SyncStatus::Filters {
+                current: filter_height,
+                target: height,
+            };
+        }
+
+        let rescan = &self.cbfmgr.rescan;
+        if rescan.active && !rescan.watch.is_empty() {
+            let end = rescan.end.map_or(height, |end| end.min(height));
+
+            // Nb. The rescan's current height is the next height to be processed.
+            if rescan.current <= end {
+                return SyncStatus::Filters {
+                    current: rescan.current.saturating_sub(1),
+                    target: end,
+                };
+            }
+        }
+        SyncStatus::Synced { height }
+    }
+
+    /// Check whether our clock is too far off from network time, and notify
+    /// subscribers if it has just become so.
+    fn check_clock_skew(&mut self) {
+        let skew = self.clock.skew();
+
+        if skew == self.clock_skew {
+            return;
+        }
+        self.clock_skew = skew;
+
+        if let Some(offset) = skew {
+            warn!(
+                target: "p2p",
+                "Local clock is {} {} network time, please check your system clock",
+                LocalDuration::from_secs(offset.unsigned_abs()),
+                if offset > 0 { "behind" } else { "ahead of" }
+            );
+            self.outbox.event(Event::ClockSkewDetected { offset });
+        }
+    }
+
+    /// Import headers received from a peer, and update the sub-protocols if our tip changed.
+    fn received_headers(&mut self, addr: PeerId, headers: Vec<BlockHeader>) {
+        match self
+            .syncmgr
+            .received_headers(&addr, headers, &self.clock, &mut self.tree)
+        {
+            Err(e) => log::error!("Error receiving headers: {}", e),
+            Ok(ImportResult::TipChanged(_, _, _, reverted, _)) => {
+                self.peermgr.record_useful(&addr);
+
+                if !reverted.is_empty() {
+                    self.metrics.reorgs += 1;
+                }
+                self.blocks_reverted(reverted);
+
+                // Trigger a filter sync, since we're going to have to catch up on the
+                // new block header(s). This is not required, but reduces latency.
+                //
+                // In the case of a re-org, this will trigger a re-download of the
+                // missing headers after the rollback.
+                if !self.headers_only {
+                    self.cbfmgr.sync(&self.tree);
+                }
+                self.bloommgr.sync(&self.tree);
+            }
+            _ => {}
+        }
+    }
+
+    /// Process a block received from a peer, either in full or reconstructed from a
+    /// compact block.
+    fn received_block(&mut self, addr: PeerId, block: Block) {
+        if self.invmgr.remaining.contains_key(&block.block_hash()) {
+            self.peermgr.record_useful(&addr);
+        }
+        for confirmed in self.invmgr.received_block(&addr, block, &self.tree) {
+            self.cbfmgr.unwatch_transaction(&confirmed);
+        }
+    }
+
     /// Send a message to a random outbound peer. Returns the peer id.
     fn query<Q>(&mut self, msg: NetworkMessage, f: Q) -> Option<PeerId>
     where
@@ -666,7 +1489,14 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
                     .peers()
                     .filter(|(p, _)| p.is_negotiated())
                     .filter(|(p, _)| p.services.has(services))
-                    .map(Peer::from)
+                    .map(|p| {
+                        let mut peer = Peer::from(p);
+
+                        peer.headers = self.syncmgr.headers_received(&peer.addr);
+                        peer.filters = self.cbfmgr.filters_received(&peer.addr);
+                        peer.blocks = self.invmgr.blocks_received(&peer.addr);
+                        peer
+                    })
                     .collect::<Vec<Peer>>();
 
                 reply.send(peers).ok();
@@ -678,6 +1508,9 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
             Command::Disconnect(addr) => {
                 self.disconnect(addr, DisconnectReason::Command);
             }
+            Command::RotatePeer(reply) => {
+                reply.send(self.rotate_peer()).ok();
+            }
             Command::Query(msg, reply) => {
                 reply.send(self.query(msg, |_| true)).ok();
             }
@@ -699,6 +1532,12 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
                     }
                 }
             }
+            Command::PruneHeaders { below, reply } => {
+                reply.send(self.prune_headers(below)).ok();
+            }
+            Command::Resync { from, reply } => {
+                reply.send(self.resync(from)).ok();
+            }
             Command::ImportAddresses(addrs) => {
                 self.addrmgr.insert(
                     // Nb. For imported addresses, the time last active is not relevant.
@@ -706,14 +1545,99 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
                     peer::Source::Imported,
                 );
             }
+            Command::AddAddresses(addrs) => {
+                self.addrmgr.add(addrs);
+            }
+            Command::DnsSeedResolved { seed, result } => {
+                self.addrmgr.dns_seed_resolved(seed, result);
+            }
             Command::GetTip(reply) => {
                 let (_, header) = self.tree.tip();
                 let height = self.tree.height();
 
                 reply.send((height, header)).ok();
             }
+            Command::GetSyncStatus(reply) => {
+                reply.send(self.sync_status()).ok();
+            }
+            Command::GetSnapshot(reply) => {
+                let snapshot = Snapshot {
+                    tip: self.tree.height(),
+                    filter_tip: self.cbfmgr.filters.height(),
+                    peers: self
+                        .peermgr
+                        .peers()
+                        .filter(|(p, _)| p.is_negotiated())
+                        .map(|(_, conn)| conn.socket.addr)
+                        .collect(),
+                    events: self.outbox.events(),
+                };
+                reply.send(snapshot).ok();
+            }
+            Command::GetBestPeerHeight(reply) => {
+                reply.send(self.syncmgr.best_height()).ok();
+            }
+            Command::GetHealth(reply) => {
+                let (_, tip) = self.tree.tip();
+                let health = Health {
+                    sync: self.sync_status(),
+                    peers: self.peermgr.negotiated(ConnDirection::Outbound).count(),
+                    tip_age: self.clock.local_time() - LocalTime::from_block_time(tip.time),
+                };
+                reply.send(health).ok();
+            }
+            Command::GetMetrics(reply) => {
+                let metrics = Metrics {
+                    outbound_peers: self.peermgr.negotiated(ConnDirection::Outbound).count(),
+                    inbound_peers: self.peermgr.negotiated(ConnDirection::Inbound).count(),
+                    height: self.tree.height(),
+                    filter_height: self.cbfmgr.filters.height(),
+                    blocks_matched: self.cbfmgr.blocks_matched(),
+                    ..self.metrics
+                };
+                reply.send(metrics).ok();
+            }
+            Command::GetAddressStats(reply) => {
+                reply.send(self.addrmgr.stats()).ok();
+            }
+            Command::RequestAddresses(addr, reply) => {
+                let negotiated = self
+                    .peermgr
+                    .peers()
+                    .any(|(p, c)| p.is_negotiated() && c.socket.addr == addr);
+
+                if negotiated {
+                    self.addrmgr.request_addresses_from(addr, reply);
+                } else {
+                    reply.send(Err(CommandError::NotConnected)).ok();
+                }
+            }
+            Command::GetInFlight(reply) => {
+                let mut inflight = self
+                    .syncmgr
+                    .inflight()
+                    .chain(self.cbfmgr.inflight())
+                    .chain(self.invmgr.inflight())
+                    .collect::<Vec<_>>();
+                inflight.sort_by(|a, b| b.age.cmp(&a.age));
+
+                reply.send(inflight).ok();
+            }
+            Command::GetFeeEstimate {
+                target_blocks,
+                reply,
+            } => {
+                reply.send(self.invmgr.fee_estimate(target_blocks)).ok();
+            }
+            Command::GetLatencies(reply) => {
+                reply.send(self.pingmgr.latencies()).ok();
+            }
             Command::GetFilters(range, reply) => {
-                let result = self.cbfmgr.get_cfilters(range, &self.tree);
+                let result = if self.headers_only {
+                    Err(GetFiltersError::Disabled)
+                } else {
+                    self.cbfmgr.get_cfilters(range, &self.tree)
+                };
                 reply.send(result).ok();
             }
             Command::GetBlock(hash) => {
@@ -739,13 +1663,72 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
             }
             Command::Rescan { from, to, watch } => {
                 // A rescan with a new watch list may return matches on cached filters.
+                if self.bloommgr.config.enabled {
+                    self.bloommgr.rescan(from, to, watch.clone(), &self.tree);
+                }
+                if self.headers_only {
+                    return;
+                }
                 for (_, hash) in self.cbfmgr.rescan(from, to, watch, &self.tree) {
                     self.invmgr.get_block(hash);
                 }
             }
             Command::Watch { watch } => {
+                if self.bloommgr.config.enabled {
+                    self.bloommgr.watch(watch.clone());
+                }
                 self.cbfmgr.watch(watch);
             }
+            Command::SetWatchlist { watch, rescan } => {
+                if self.bloommgr.config.enabled {
+                    let last = &self.cbfmgr.rescan;
+
+                    self.bloommgr.set_watchlist(watch.clone());
+
+                    // Nb. Unbounded rescans never complete, so a rescan without an end is
+                    // still active.
+                    if rescan && (last.active || last.end.is_some()) {
+                        self.bloommgr.rescan(
+                            Bound::Included(last.start),
+                            last.end.map_or(Bound::Unbounded, Bound::Included),
+                            vec![],
+                            &self.tree,
+                        );
+                    }
+                }
+                for (_, hash) in self.cbfmgr.set_watchlist(watch, rescan, &self.tree) {
+                    self.invmgr.get_block(hash);
+                }
+            }
+            Command::WatchOutpoint {
+                outpoint,
+                script_pubkey,
+            } => {
+                if self.bloommgr.config.enabled {
+                    self.bloommgr.watch(vec![script_pubkey.clone()]);
+                }
+                self.cbfmgr.watch(vec![script_pubkey]);
+                self.invmgr.watch_outpoint(outpoint);
+            }
+            Command::SetFilterDecoyRate(rate) => {
+                self.cbfmgr.set_decoy_rate(rate);
+            }
+            Command::SetLimits(limits) => {
+                self.addrmgr.set_discovery(!limits.is_inbound_only());
+                self.peermgr.set_limits(
+                    limits.max_outbound_peers,
+                    limits.max_inbound_peers,
+                    &mut self.addrmgr,
+                );
+                self.cbfmgr.set_cache_size(limits.filter_cache_size);
+                self.cbfmgr.set_cache_len(limits.filter_cache_len);
+            }
+            Command::PauseFilterSync => {
+                self.cbfmgr.pause();
+            }
+            Command::ResumeFilterSync => {
+                self.cbfmgr.resume(&self.tree);
+            }
         }
     }
 }
@@ -763,10 +1746,14 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
         self.addrmgr.initialize();
         self.syncmgr.initialize(&self.tree);
         self.peermgr.initialize(&mut self.addrmgr);
-        self.cbfmgr.initialize(&self.tree);
+        if !self.headers_only {
+            self.cbfmgr.initialize(&self.tree);
+        }
+        self.bloommgr.initialize(&self.tree);
         self.outbox.event(Event::Ready {
             height: self.tree.height(),
             filter_height: self.cbfmgr.filters.height(),
+            headers_only: self.headers_only,
             time,
         });
     }
@@ -786,6 +1773,11 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
             return;
         }
 
+        #[cfg(feature = "tracing")]
+        let _peer = self.spans.get(&addr).map(|span| span.clone().entered());
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("message", cmd = ?cmd).entered();
+
         debug!(target: "p2p", "Received {:?} from {}", cmd, addr);
 
         if let Err(err) = (self.hooks.on_message)(addr, &msg.payload, &self.outbox) {
@@ -807,28 +1799,42 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
             NetworkMessage::Verack => {
                 if let Some((peer, conn)) = self.peermgr.received_verack(&addr, now) {
                     self.clock.record_offset(conn.socket.addr, peer.time_offset);
+                    self.check_clock_skew();
                     self.addrmgr
                         .peer_negotiated(&addr, peer.services, conn.link);
                     self.pingmgr.peer_negotiated(conn.socket.addr);
-                    self.cbfmgr.peer_negotiated(
+                    if !self.headers_only {
+                        self.cbfmgr.peer_negotiated(
+                            conn.socket.clone(),
+                            peer.height,
+                            peer.services,
+                            conn.link,
+                            peer.persistent,
+                            &self.tree,
+                        );
+                    }
+                    self.syncmgr.peer_negotiated(
                         conn.socket.clone(),
                         peer.height,
                         peer.services,
+                        // Prefer peers that don't serve filters for header sync, unless
+                        // we don't sync filters at all.
+                        self.headers_only || !peer.services.has(cbfmgr::REQUIRED_SERVICES),
                         conn.link,
-                        peer.persistent,
                         &self.tree,
                     );
-                    self.syncmgr.peer_negotiated(
+                    self.bloommgr
+                        .peer_negotiated(conn.socket.clone(), peer.services, conn.link);
+                    self.cmpctmgr.peer_negotiated(
                         conn.socket.clone(),
-                        peer.height,
                         peer.services,
-                        !peer.services.has(cbfmgr::REQUIRED_SERVICES),
+                        peer.version,
                         conn.link,
-                        &self.tree,
                     );
                     self.invmgr.peer_negotiated(
                         conn.socket,
                         peer.services,
+                        peer.version,
                         peer.relay,
                         peer.wtxidrelay,
                     );
@@ -841,39 +1847,14 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
             }
             NetworkMessage::Pong(nonce) => {
                 if self.pingmgr.received_pong(addr, nonce, now) {
+                    if let Some(latency) = self.pingmgr.latency(&addr) {
+                        self.peermgr.record_latency(&addr, latency);
+                    }
                     self.addrmgr.peer_active(addr);
                 }
             }
             NetworkMessage::Headers(headers) => {
-                match self
-                    .syncmgr
-                    .received_headers(&addr, headers, &self.clock, &mut self.tree)
-                {
-                    Err(e) => log::error!("Error receiving headers: {}", e),
-                    Ok(ImportResult::TipChanged(_, _, _, reverted, _)) => {
-                        // Nb. the reverted blocks are ordered from the tip down to
-                        // the oldest ancestor.
-                        if let Some((height, _)) = reverted.last() {
-                            // The height we need to rollback to, ie. the tip of our new chain
-                            // and the tallest block we are keeping.
-                            let fork_height = height - 1;
-                            self.cbfmgr.rollback(fork_height).unwrap();
-
-                            for (height, _) in reverted {
-                                for tx in self.invmgr.block_reverted(height) {
-                                    self.cbfmgr.watch_transaction(&tx);
-                                }
-                            }
-                        }
-                        // Trigger a filter sync, since we're going to have to catch up on the
-                        // new block header(s). This is not required, but reduces latency.
-                        //
-                        // In the case of a re-org, this will trigger a re-download of the
-                        // missing headers after the rollback.
-                        self.cbfmgr.sync(&self.tree);
-                    }
-                    _ => {}
-                }
+                self.received_headers(addr, headers);
             }
             NetworkMessage::GetHeaders(GetHeadersMessage {
                 locator_hashes,
@@ -884,11 +1865,77 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
                     .received_getheaders(&addr, (locator_hashes, stop_hash), &self.tree);
             }
             NetworkMessage::Block(block) => {
-                for confirmed in self.invmgr.received_block(&addr, block, &self.tree) {
-                    self.cbfmgr.unwatch_transaction(&confirmed);
+                self.received_block(addr, block);
+            }
+            NetworkMessage::CmpctBlock(CmpctBlock { compact_block }) => {
+                let header = compact_block.header;
+                let hash = header.block_hash();
+
+                // The block header is processed like any other header announcement.
+                self.received_headers(addr, vec![header]);
+
+                let wanted = self.invmgr.remaining.contains_key(&hash);
+                match self.cmpctmgr.received_cmpctblock(
+                    &addr,
+                    compact_block,
+                    self.invmgr.mempool.values(),
+                    wanted,
+                ) {
+                    Ok(Some(block)) => self.received_block(addr, block),
+                    Ok(None) => {}
+                    Err(cmpctmgr::Error::InvalidMessage { reason, .. }) => {
+                        self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
+                    }
+                    Err(err) => {
+                        debug!(target: "p2p", "Error receiving compact block: {}", err);
+                    }
+                }
+            }
+            NetworkMessage::BlockTxn(BlockTxn { transactions }) => {
+                match self.cmpctmgr.received_blocktxn(&addr, transactions) {
+                    Ok(Some(block)) => self.received_block(addr, block),
+                    Ok(None) => {}
+                    Err(cmpctmgr::Error::InvalidMessage { reason, .. }) => {
+                        self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
+                    }
+                    Err(err) => {
+                        debug!(target: "p2p", "Error receiving block transactions: {}", err);
+                    }
                 }
             }
+            NetworkMessage::SendCmpct(_) | NetworkMessage::GetBlockTxn(_) => {
+                // We don't serve blocks, so we never announce compact blocks to peers.
+            }
+            NetworkMessage::MerkleBlock(merkle_block) => {
+                match self
+                    .bloommgr
+                    .received_merkleblock(&addr, merkle_block, &self.tree)
+                {
+                    Err(bloommgr::Error::InvalidMessage { reason, .. }) => {
+                        self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
+                    }
+                    Err(err) => {
+                        debug!(target: "p2p", "Error receiving merkle block: {}", err);
+                    }
+                    Ok(_) => {}
+                }
+            }
+            NetworkMessage::Tx(tx) => {
+                self.invmgr.received_tx(&addr, &tx);
+                self.bloommgr.received_tx(&addr, tx);
+            }
+            NetworkMessage::FeeFilter(rate) => {
+                self.invmgr.received_feefilter(&addr, rate);
+            }
+            NetworkMessage::NotFound(inventory) => {
+                self.bloommgr.received_notfound(&addr, &inventory);
+                self.invmgr.received_notfound(&addr, inventory);
+            }
+            NetworkMessage::Reject(msg) => {
+                self.invmgr.received_reject(&addr, msg);
+            }
             NetworkMessage::Inv(inventory) => {
+                self.invmgr.received_inv(addr, &inventory);
                 self.syncmgr.received_inv(addr, inventory, &self.tree);
                 // TODO: invmgr: Update block availability for this peer.
             }
@@ -903,6 +1950,17 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
                     Ok(_) => {}
                 }
             }
+            NetworkMessage::CFCheckpt(msg) => {
+                match self.cbfmgr.received_cfcheckpt(&addr, msg, &self.tree) {
+                    Err(cbfmgr::Error::InvalidMessage { reason, .. }) => {
+                        self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
+                    }
+                    Err(err) => {
+                        log::warn!(target: "p2p", "Error receiving filter checkpoints: {}", err);
+                    }
+                    Ok(()) => {}
+                }
+            }
             NetworkMessage::GetCFHeaders(msg) => {
                 match self.cbfmgr.received_getcfheaders(&addr, msg, &self.tree) {
                     Err(cbfmgr::Error::InvalidMessage { reason, .. }) => {
@@ -914,6 +1972,8 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
             NetworkMessage::CFilter(msg) => {
                 match self.cbfmgr.received_cfilter(&addr, msg, &self.tree) {
                     Ok(matches) => {
+                        self.peermgr.record_useful(&addr);
+
                         for (_, hash) in matches {
                             self.invmgr.get_block(hash);
                         }
@@ -924,9 +1984,36 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
                     Err(cbfmgr::Error::Ignored { .. } | cbfmgr::Error::Filters { .. }) => {}
                 }
             }
+            NetworkMessage::GetCFilters(msg) if self.cbfmgr.is_serving() => {
+                // Filters are only served to inbound peers.
+                if self.peermgr.is_inbound(&addr) {
+                    match self.cbfmgr.received_getcfilters(&addr, msg, &self.tree) {
+                        Err(cbfmgr::Error::InvalidMessage { reason, .. }) => {
+                            self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
+                        }
+                        Err(err) => {
+                            debug!(target: "p2p", "Not serving filters: {}", err);
+                        }
+                        Ok(()) => {}
+                    }
+                }
+            }
             NetworkMessage::GetCFilters(msg) => {
                 (*self.hooks.on_getcfilters)(addr, msg, &self.outbox);
             }
+            NetworkMessage::GetCFCheckpt(msg) => {
+                if self.peermgr.is_inbound(&addr) {
+                    match self.cbfmgr.received_getcfcheckpt(&addr, msg, &self.tree) {
+                        Err(cbfmgr::Error::InvalidMessage { reason, .. }) => {
+                            self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
+                        }
+                        Err(err) => {
+                            debug!(target: "p2p", "Not serving filter checkpoints: {}", err);
+                        }
+                        Ok(()) => {}
+                    }
+                }
+            }
             NetworkMessage::Addr(addrs) => {
                 self.addrmgr.received_addr(addr, addrs);
                 // TODO: Tick the peer manager, because we may have new addresses to connect to.
@@ -941,6 +2028,9 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
             NetworkMessage::WtxidRelay => {
                 self.peermgr.received_wtxidrelay(&addr);
             }
+            NetworkMessage::SendAddrV2 => {
+                self.peermgr.received_sendaddrv2(&addr);
+            }
             NetworkMessage::SendHeaders => {
                 // We adhere to `sendheaders` by default.
             }
@@ -968,6 +2058,15 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
     ) {
         let height = self.tree.height();
 
+        #[cfg(feature = "tracing")]
+        let _peer = {
+            let span = tracing::debug_span!("peer", addr = %addr, ?link);
+            self.spans.insert(addr, span.clone());
+            span.entered()
+        };
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("connected", height).entered();
+
         self.addrmgr.record_local_address(*local_addr);
         self.addrmgr.peer_connected(&addr);
         self.peermgr.peer_connected(addr, *local_addr, link, height);
@@ -980,7 +2079,14 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
         addr: &net::SocketAddr,
         reason: nakamoto_net::DisconnectReason<DisconnectReason>,
     ) {
+        #[cfg(feature = "tracing")]
+        let _peer = self.spans.remove(addr).map(|span| span.entered());
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("disconnected", %reason).entered();
+
         self.cbfmgr.peer_disconnected(addr);
+        self.bloommgr.peer_disconnected(addr);
+        self.cmpctmgr.peer_disconnected(addr);
         self.syncmgr.peer_disconnected(addr);
         self.addrmgr.peer_disconnected(addr, reason.clone());
         self.pingmgr.peer_disconnected(addr);
@@ -1003,7 +2109,24 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
         self.pingmgr.received_wake();
         self.addrmgr.received_wake();
         self.peermgr.received_wake(&mut self.addrmgr);
-        self.cbfmgr.received_wake(&self.tree);
+        if !self.headers_only {
+            self.cbfmgr.received_wake(&self.tree);
+        }
+        self.bloommgr.received_wake();
+        if self.peermgr.is_rotation_due() || self.addrmgr.refill() {
+            self.rotate_peer();
+        }
+        if let Some(depth) = self.prune_depth {
+            let height = self.tree.height();
+
+            if height >= self.last_prune + PRUNE_INTERVAL {
+                self.last_prune = height;
+
+                if let Err(err) = self.prune_headers(height.saturating_sub(depth)) {
+                    log::error!(target: "p2p", "Failed to prune block headers: {}", err);
+                }
+            }
+        }
 
         #[cfg(not(test))]
         let local_time = self.clock.local_time();
@@ -2,8 +2,11 @@
 //! The peer-to-peer address manager.
 //!
 #![warn(missing_docs)]
+use std::collections::VecDeque;
 use std::net;
 
+use crossbeam_channel as chan;
+
 use nakamoto_common::bitcoin::network::address::Address;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 
@@ -16,7 +19,7 @@ use nakamoto_common::p2p::Domain;
 use nakamoto_net::DisconnectReason;
 
 use super::output::{Wakeup, Wire};
-use super::ConnDirection;
+use super::{CommandError, ConnDirection};
 
 /// Time to wait until a request times out.
 pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
@@ -27,6 +30,13 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
 /// Sample timeout. How long before a sampled address can be returned again.
 pub const SAMPLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(3);
 
+/// Time to keep collecting addresses from a peer that addresses were explicitly requested
+/// from, after the last `addr` message it sent.
+pub const COLLECT_TIMEOUT: LocalDuration = LocalDuration::from_secs(3);
+
+/// Period over which the `getaddr` budget applies.
+pub const GETADDR_BUDGET_PERIOD: LocalDuration = LocalDuration::from_hours(1);
+
 /// Maximum number of addresses expected in a `addr` message.
 const MAX_ADDR_ADDRESSES: usize = 1000;
 /// Maximum number of addresses we store for a given address range.
@@ -44,6 +54,20 @@ pub enum Event {
     },
     /// Address book exhausted.
     AddressBookExhausted,
+    /// A DNS seed was resolved, and its addresses added to the address book.
+    DnsSeedResolved {
+        /// The seed host name.
+        seed: String,
+        /// Number of addresses returned.
+        addresses: usize,
+    },
+    /// A DNS seed failed to resolve or timed out.
+    DnsSeedFailed {
+        /// The seed host name.
+        seed: String,
+        /// The resolution error.
+        error: String,
+    },
     /// An error was encountered.
     Error(String),
 }
@@ -64,6 +88,12 @@ impl std::fmt::Display for Event {
                     "Address book exhausted.. fetching new addresses from peers"
                 )
             }
+            Event::DnsSeedResolved { seed, addresses } => {
+                write!(fmt, "DNS seed {} returned {} address(es)", seed, addresses)
+            }
+            Event::DnsSeedFailed { seed, error } => {
+                write!(fmt, "DNS seed {} failed: {}", seed, error)
+            }
             Event::Error(msg) => {
                 write!(fmt, "error: {}", msg)
             }
@@ -108,6 +138,35 @@ impl<P: Store, U, C> AddressManager<P, U, C> {
     }
 }
 
+/// Thresholds for asking peers for addresses with `getaddr`.
+///
+/// Peers only answer one `getaddr` per connection, so outbound peers are asked once, soon
+/// after the handshake, as long as the address book has fewer than `target` addresses. An
+/// exhausted address book triggers requests regardless of the target. All requests count
+/// towards the hourly `budget`.
+///
+/// Once all outbound peers were asked, and the address book still has fewer than `target`
+/// addresses, an outbound peer is rotated every `interval`, so that a new peer can be asked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GetAddrLimits {
+    /// Maximum number of `getaddr` messages sent per hour, across all peers.
+    pub budget: usize,
+    /// Time between peer rotations used to refill the address book.
+    pub interval: LocalDuration,
+    /// Number of known addresses above which peers are no longer asked, and rotated.
+    pub target: usize,
+}
+
+impl Default for GetAddrLimits {
+    fn default() -> Self {
+        Self {
+            budget: 32,
+            interval: LocalDuration::from_hours(24),
+            target: 4096,
+        }
+    }
+}
+
 /// Address manager configuration.
 #[derive(Debug)]
 pub struct Config {
@@ -115,6 +174,11 @@ pub struct Config {
     pub required_services: ServiceFlags,
     /// Communication domains we're interested in.
     pub domains: Vec<Domain>,
+    /// Thresholds for asking peers for addresses.
+    pub getaddr: GetAddrLimits,
+    /// Look for new addresses, by asking peers for them. Disabled when no outbound
+    /// connections are made, since there would be no use for them.
+    pub discovery: bool,
 }
 
 impl Default for Config {
@@ -122,10 +186,58 @@ impl Default for Config {
         Self {
             required_services: ServiceFlags::NONE,
             domains: Domain::all(),
+            getaddr: GetAddrLimits::default(),
+            discovery: true,
         }
     }
 }
 
+/// Address book statistics.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AddrStats {
+    /// Total number of known addresses.
+    pub total: usize,
+    /// Addresses that came from DNS seeds.
+    pub dns: usize,
+    /// Addresses that were gossiped by peers.
+    pub gossip: usize,
+    /// Addresses that were imported, eg. from the configuration.
+    pub imported: usize,
+    /// IPv4 addresses.
+    pub ipv4: usize,
+    /// IPv6 addresses.
+    pub ipv6: usize,
+    /// Addresses we have successfully connected to at least once.
+    pub tried: usize,
+    /// Addresses we have never successfully connected to.
+    pub new: usize,
+    /// Addresses that were attempted, but never successfully connected to.
+    /// A subset of `new`.
+    pub failed: usize,
+    /// Number of banned IP addresses.
+    pub banned: usize,
+}
+
+/// Addresses explicitly requested from a peer, pending a reply.
+#[derive(Debug)]
+struct Request {
+    /// When the `getaddr` message was sent.
+    sent: LocalTime,
+    /// When the last `addr` message was received.
+    received: Option<LocalTime>,
+    /// Addresses received so far.
+    addrs: Vec<(BlockTime, Address)>,
+    /// Reply channel.
+    reply: chan::Sender<Result<Vec<(BlockTime, Address)>, CommandError>>,
+}
+
+impl Request {
+    /// Send the addresses received so far.
+    fn reply(self) {
+        self.reply.send(Ok(self.addrs)).ok();
+    }
+}
+
 /// Manages peer network addresses.
 #[derive(Debug)]
 pub struct AddressManager<P, U, C> {
@@ -138,6 +250,14 @@ pub struct AddressManager<P, U, C> {
     local_addrs: HashSet<net::SocketAddr>,
     /// The last time we asked our peers for new addresses.
     last_request: Option<LocalTime>,
+    /// The time we asked each source for addresses, on this connection.
+    last_getaddr: HashMap<net::SocketAddr, LocalTime>,
+    /// The last time a peer rotation was requested to refill the address book.
+    last_refill: Option<LocalTime>,
+    /// Times at which `getaddr` messages were sent, within the budget period.
+    getaddr_sent: VecDeque<LocalTime>,
+    /// Explicit address requests, by peer.
+    requests: HashMap<net::SocketAddr, Request>,
     /// The last time we idled.
     last_idle: Option<LocalTime>,
     cfg: Config,
@@ -157,13 +277,76 @@ impl<P: Store, U: Wire<Event> + Wakeup, C: Clock> AddressManager<P, U, C> {
         Iter(move || self.sample(services))
     }
 
-    /// Get addresses from peers.
+    /// Enable or disable address discovery. See [`Config::discovery`].
+    pub fn set_discovery(&mut self, enabled: bool) {
+        self.cfg.discovery = enabled;
+    }
+
+    /// Get addresses from peers that weren't asked yet, within the `getaddr` budget.
     pub fn get_addresses(&mut self) {
-        for peer in &self.sources {
-            self.upstream.get_addr(*peer);
+        let sources = self
+            .sources
+            .iter()
+            .filter(|addr| !self.last_getaddr.contains_key(addr))
+            .copied()
+            .collect::<Vec<_>>();
+
+        for peer in sources {
+            if !self.request_addresses(peer) {
+                break;
+            }
         }
     }
 
+    /// Ask the given peer for addresses, regardless of the `getaddr` budget. The addresses
+    /// it sends are replied with once it hasn't sent any for [`COLLECT_TIMEOUT`]. If it
+    /// doesn't respond within [`REQUEST_TIMEOUT`], or disconnects, whatever was received
+    /// is replied with.
+    ///
+    /// Nb. Peers usually only respond to one `getaddr` message per connection.
+    pub fn request_addresses_from(
+        &mut self,
+        addr: net::SocketAddr,
+        reply: chan::Sender<Result<Vec<(BlockTime, Address)>, CommandError>>,
+    ) {
+        let time = self.clock.local_time();
+        let request = Request {
+            sent: time,
+            received: None,
+            addrs: Vec::new(),
+            reply,
+        };
+
+        if let Some(previous) = self.requests.insert(addr, request) {
+            previous.reply();
+        }
+        self.last_getaddr.insert(addr, time);
+        self.upstream.get_addr(addr);
+        self.upstream.wakeup(REQUEST_TIMEOUT);
+    }
+
+    /// Called when we received an `addr` message from a peer.
+    pub fn received_addr(&mut self, peer: net::SocketAddr, addrs: Vec<(BlockTime, Address)>) {
+        if addrs.is_empty() || addrs.len() > MAX_ADDR_ADDRESSES {
+            // Peer misbehaving, got empty message or too many addresses.
+            return;
+        }
+        let source = Source::Peer(peer);
+
+        self.upstream.event(Event::AddressesReceived {
+            count: addrs.len(),
+            source,
+        });
+
+        if let Some(req) = self.requests.get_mut(&peer) {
+            req.addrs.extend(addrs.iter().cloned());
+            req.received = Some(self.clock.local_time());
+
+            self.upstream.wakeup(COLLECT_TIMEOUT);
+        }
+        self.insert(addrs.into_iter(), source);
+    }
+
     /// Called when we receive a `getaddr` message.
     pub fn received_getaddr(&mut self, from: &net::SocketAddr) {
         // TODO: We should only respond with peers who were last active within
@@ -189,7 +372,8 @@ impl<P: Store, U: Wire<Event> + Wakeup, C: Clock> AddressManager<P, U, C> {
         let local_time = self.clock.local_time();
 
         // If we're already using all the addresses we have available, we should fetch more.
-        if local_time - self.last_request.unwrap_or_default() >= REQUEST_TIMEOUT
+        if self.cfg.discovery
+            && local_time - self.last_request.unwrap_or_default() >= REQUEST_TIMEOUT
             && self.is_exhausted()
         {
             self.upstream.event(Event::AddressBookExhausted);
@@ -199,11 +383,57 @@ impl<P: Store, U: Wire<Event> + Wakeup, C: Clock> AddressManager<P, U, C> {
             self.upstream.wakeup(REQUEST_TIMEOUT);
         }
 
+        // Ask the peers we couldn't ask within the budget, until we know enough addresses.
+        if self.cfg.discovery && self.peers.len() < self.cfg.getaddr.target {
+            self.get_addresses();
+        }
+
+        // Reply to explicit address requests that are complete or timed out.
+        let done = self
+            .requests
+            .iter()
+            .filter(|(_, req)| {
+                req.received
+                    .map_or(local_time - req.sent >= REQUEST_TIMEOUT, |t| {
+                        local_time - t >= COLLECT_TIMEOUT
+                    })
+            })
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+
+        for addr in done {
+            if let Some(req) = self.requests.remove(&addr) {
+                req.reply();
+            }
+        }
+
         if local_time - self.last_idle.unwrap_or_default() >= IDLE_TIMEOUT {
             self.idle();
         }
     }
 
+    /// Check whether an outbound peer should be rotated to refill the address book, and if
+    /// so, record that it is. This is the case when we know fewer addresses than the target,
+    /// and all our outbound peers were already asked for addresses. See [`GetAddrLimits`].
+    pub fn refill(&mut self) -> bool {
+        let time = self.clock.local_time();
+
+        if !self.cfg.discovery
+            || self.peers.len() >= self.cfg.getaddr.target
+            || self.sources.is_empty()
+            || self
+                .sources
+                .iter()
+                .any(|addr| !self.last_getaddr.contains_key(addr))
+            || time - self.last_refill.unwrap_or_default() < self.cfg.getaddr.interval
+        {
+            return false;
+        }
+        self.last_refill = Some(time);
+
+        true
+    }
+
     /// Called when a peer signaled activity.
     pub fn peer_active(&mut self, addr: net::SocketAddr) {
         let time = self.clock.local_time();
@@ -243,16 +473,17 @@ impl<P: Store, U: Wire<Event> + Wakeup, C: Clock> AddressManager<P, U, C> {
         }
         if link.is_outbound() {
             self.sources.insert(*addr);
+
+            // Ask new outbound peers for addresses early on, unless we know enough of them.
+            if self.cfg.discovery && self.peers.len() < self.cfg.getaddr.target {
+                self.request_addresses(*addr);
+            }
         }
 
         // We're only interested in peers we already know, eg. from DNS or peer
         // exchange. Peers should only be added to our address book if they are DNS seeds
         // or are discovered via a DNS seed.
         if let Some(ka) = self.peers.get_mut(&addr.ip()) {
-            // Only ask for addresses when connecting for the first time.
-            if ka.last_success.is_none() {
-                self.upstream.get_addr(*addr);
-            }
             // Keep track of when the last successful handshake was.
             ka.last_success = Some(time);
             ka.last_active = Some(time);
@@ -266,9 +497,13 @@ impl<P: Store, U: Wire<Event> + Wakeup, C: Clock> AddressManager<P, U, C> {
         addr: &net::SocketAddr,
         reason: DisconnectReason<super::DisconnectReason>,
     ) {
+        if let Some(req) = self.requests.remove(addr) {
+            req.reply();
+        }
         if self.connected.remove(&addr.ip()) {
             // Disconnected peers cannot be used as a source for new addresses.
             self.sources.remove(addr);
+            self.last_getaddr.remove(addr);
 
             // If the reason for disconnecting the peer suggests that we shouldn't try to
             // connect to this peer again, then remove the peer from the address book.
@@ -286,6 +521,27 @@ impl<P: Store, U: Wire<Event> + Wakeup, C: Clock> AddressManager<P, U, C> {
 
     ////////////////////////////////////////////////////////////////////////////
 
+    /// Send a `getaddr` to the given peer, if the budget allows it.
+    /// Returns `false` if the budget is exhausted.
+    fn request_addresses(&mut self, addr: net::SocketAddr) -> bool {
+        let time = self.clock.local_time();
+
+        while let Some(sent) = self.getaddr_sent.front() {
+            if time - *sent < GETADDR_BUDGET_PERIOD {
+                break;
+            }
+            self.getaddr_sent.pop_front();
+        }
+        if self.getaddr_sent.len() >= self.cfg.getaddr.budget {
+            return false;
+        }
+        self.getaddr_sent.push_back(time);
+        self.last_getaddr.insert(addr, time);
+        self.upstream.get_addr(addr);
+
+        true
+    }
+
     fn idle(&mut self) {
         // If it's been a while, save addresses to store.
         if let Err(err) = self.peers.flush() {
@@ -310,6 +566,10 @@ impl<P: Store, U: Wire<Event>, C: Clock> AddressManager<P, U, C> {
             sources: HashSet::with_hasher(rng.clone().into()),
             local_addrs: HashSet::with_hasher(rng.clone().into()),
             last_request: None,
+            last_refill: None,
+            last_getaddr: HashMap::with_hasher(rng.clone().into()),
+            getaddr_sent: VecDeque::new(),
+            requests: HashMap::with_hasher(rng.clone().into()),
             last_idle: None,
             upstream,
             rng,
@@ -332,6 +592,37 @@ impl<P: Store, U: Wire<Event>, C: Clock> AddressManager<P, U, C> {
         self.peers.is_empty() || self.address_ranges.is_empty()
     }
 
+    /// Get address book statistics.
+    pub fn stats(&self) -> AddrStats {
+        let mut stats = AddrStats {
+            total: self.peers.len(),
+            banned: self.bans.len(),
+            ..AddrStats::default()
+        };
+
+        for (ip, ka) in self.peers.iter() {
+            match ka.source {
+                Source::Dns => stats.dns += 1,
+                Source::Peer(_) => stats.gossip += 1,
+                Source::Imported => stats.imported += 1,
+            }
+            match ip {
+                net::IpAddr::V4(_) => stats.ipv4 += 1,
+                net::IpAddr::V6(_) => stats.ipv6 += 1,
+            }
+            if ka.last_success.is_some() {
+                stats.tried += 1;
+            } else {
+                stats.new += 1;
+
+                if ka.last_attempt.is_some() {
+                    stats.failed += 1;
+                }
+            }
+        }
+        stats
+    }
+
     #[cfg(test)]
     /// Clear the address manager of all peers.
     pub fn clear(&mut self) {
@@ -339,21 +630,6 @@ impl<P: Store, U: Wire<Event>, C: Clock> AddressManager<P, U, C> {
         self.address_ranges.clear();
     }
 
-    /// Called when we received an `addr` message from a peer.
-    pub fn received_addr(&mut self, peer: net::SocketAddr, addrs: Vec<(BlockTime, Address)>) {
-        if addrs.is_empty() || addrs.len() > MAX_ADDR_ADDRESSES {
-            // Peer misbehaving, got empty message or too many addresses.
-            return;
-        }
-        let source = Source::Peer(peer);
-
-        self.upstream.event(Event::AddressesReceived {
-            count: addrs.len(),
-            source,
-        });
-        self.insert(addrs.into_iter(), source);
-    }
-
     /// Add addresses to the address manager. The input matches that of the `addr` message
     /// sent by peers on the network.
     pub fn insert(
@@ -429,6 +705,72 @@ impl<P: Store, U: Wire<Event>, C: Clock> AddressManager<P, U, C> {
         }
     }
 
+    /// Add addresses supplied by the user, eg. to bootstrap a private deployment.
+    ///
+    /// Unlike [`AddressManager::insert`], addresses are added regardless of their services
+    /// or last activity, and any ban on them is lifted. Known addresses keep their state, and
+    /// have the given services merged into theirs.
+    pub fn add(&mut self, addrs: impl IntoIterator<Item = (net::SocketAddr, ServiceFlags)>) {
+        for (addr, services) in addrs {
+            let ip = addr.ip();
+
+            if !self.cfg.domains.contains(&Domain::for_address(&addr)) {
+                continue;
+            }
+            // Ensure no self-connections.
+            if self.local_addrs.contains(&addr) {
+                continue;
+            }
+            if let Some(ka) = self.peers.get_mut(&ip) {
+                ka.addr.services |= services;
+                continue;
+            }
+            self.bans.remove(&ip);
+            self.peers.insert(
+                ip,
+                KnownAddress::new(Address::new(&addr, services), Source::Imported, None),
+            );
+            self.populate_address_ranges(&ip);
+        }
+    }
+
+    /// Record the outcome of a DNS seed lookup. Resolved addresses that aren't already
+    /// known are added to the address book. Since seeds don't tell us what services their
+    /// peers offer, these are only learned on connection.
+    pub fn dns_seed_resolved(
+        &mut self,
+        seed: String,
+        result: Result<Vec<net::SocketAddr>, String>,
+    ) {
+        let addrs = match result {
+            Ok(addrs) => addrs,
+            Err(error) => {
+                self.upstream.event(Event::DnsSeedFailed { seed, error });
+                return;
+            }
+        };
+        let addresses = addrs.len();
+
+        for addr in addrs {
+            let ip = addr.ip();
+
+            if !self.cfg.domains.contains(&Domain::for_address(&addr)) {
+                continue;
+            }
+            if self.local_addrs.contains(&addr) || self.bans.contains(&ip) {
+                continue;
+            }
+            if self.peers.insert(
+                ip,
+                KnownAddress::new(Address::new(&addr, ServiceFlags::NONE), Source::Dns, None),
+            ) {
+                self.populate_address_ranges(&ip);
+            }
+        }
+        self.upstream
+            .event(Event::DnsSeedResolved { seed, addresses });
+    }
+
     /// Pick an address at random from the set of known addresses.
     ///
     /// This function tries to ensure a good geo-diversity of addresses, such that an adversary
@@ -688,6 +1030,58 @@ mod tests {
         assert!(addrmgr.sample(ServiceFlags::NONE).is_none());
     }
 
+    #[test]
+    fn test_stats() {
+        let time = LocalTime::now();
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            HashMap::new(),
+            (),
+            time,
+        );
+        let services = ServiceFlags::NETWORK;
+        let tried: net::SocketAddr = ([33, 33, 33, 33], 8333).into();
+        let failed: net::SocketAddr = ([44, 44, 44, 44], 8333).into();
+        let gossiped: net::SocketAddr =
+            (net::Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 8333).into();
+
+        assert_eq!(addrmgr.stats(), AddrStats::default());
+
+        addrmgr.initialize();
+        addrmgr.insert(
+            [
+                (time.block_time(), Address::new(&tried, services)),
+                (time.block_time(), Address::new(&failed, services)),
+            ],
+            Source::Dns,
+        );
+        addrmgr.insert(
+            [(time.block_time(), Address::new(&gossiped, services))],
+            Source::Peer(tried),
+        );
+        addrmgr.peer_attempted(&tried);
+        addrmgr.peer_connected(&tried);
+        addrmgr.peer_negotiated(&tried, services, ConnDirection::Outbound);
+        addrmgr.peer_attempted(&failed);
+
+        assert_eq!(
+            addrmgr.stats(),
+            AddrStats {
+                total: 3,
+                dns: 2,
+                gossip: 1,
+                imported: 0,
+                ipv4: 2,
+                ipv6: 1,
+                tried: 1,
+                new: 2,
+                failed: 1,
+                banned: 0,
+            }
+        );
+    }
+
     #[test]
     fn test_known_addresses() {
         let time = LocalTime::now();
@@ -1000,6 +1394,276 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_getaddr_budget() {
+        use crate::fsm::output::{self, Outbox};
+        use nakamoto_common::bitcoin::network::message::NetworkMessage;
+        use std::collections::BTreeSet;
+
+        let clock = RefClock::from(LocalTime::now());
+        let mut upstream = Outbox::new(Network::Mainnet, 0);
+        let cfg = Config {
+            getaddr: GetAddrLimits {
+                budget: 2,
+                interval: LocalDuration::from_hours(2),
+                target: 8,
+            },
+            ..Config::default()
+        };
+        let mut addrmgr = AddressManager::new(
+            cfg,
+            fastrand::Rng::new(),
+            HashMap::new(),
+            upstream.clone(),
+            clock.clone(),
+        );
+        let services = ServiceFlags::NETWORK;
+        let peers: [net::SocketAddr; 3] = [
+            ([88, 88, 88, 1], 8333).into(),
+            ([88, 88, 88, 2], 8333).into(),
+            ([88, 88, 88, 3], 8333).into(),
+        ];
+        let getaddrs = |upstream: &mut Outbox| {
+            output::test::messages(upstream)
+                .filter(|(_, msg)| matches!(msg, NetworkMessage::GetAddr))
+                .map(|(addr, _)| addr)
+                .collect::<BTreeSet<_>>()
+        };
+
+        addrmgr.initialize();
+        // Make sure the address book isn't exhausted, so that we only test scheduled requests.
+        addrmgr.insert(
+            [(
+                clock.block_time(),
+                Address::new(&([99, 99, 99, 99], 8333).into(), services),
+            )],
+            Source::Dns,
+        );
+
+        // New outbound peers are asked for addresses, within the budget.
+        for peer in &peers {
+            addrmgr.peer_connected(peer);
+            addrmgr.peer_negotiated(peer, services, ConnDirection::Outbound);
+        }
+        assert_eq!(
+            getaddrs(&mut upstream),
+            peers[..2].iter().copied().collect()
+        );
+
+        // Once the budget period is over, the remaining peer is asked.
+        clock.elapse(GETADDR_BUDGET_PERIOD);
+        addrmgr.received_wake();
+        assert_eq!(
+            getaddrs(&mut upstream),
+            peers[2..].iter().copied().collect()
+        );
+
+        // Peers are only asked once per connection. Instead, a peer is rotated every
+        // interval, until we know enough addresses.
+        clock.elapse(GETADDR_BUDGET_PERIOD);
+        addrmgr.received_wake();
+        assert!(getaddrs(&mut upstream).is_empty());
+        assert!(addrmgr.refill());
+        assert!(!addrmgr.refill());
+
+        clock.elapse(LocalDuration::from_hours(2));
+        assert!(addrmgr.refill());
+
+        // A new peer is asked once connected.
+        let peer = ([88, 88, 88, 4], 8333).into();
+        addrmgr.peer_disconnected(&peers[0], fsm::DisconnectReason::PeerRotated.into());
+        addrmgr.peer_connected(&peer);
+        addrmgr.peer_negotiated(&peer, services, ConnDirection::Outbound);
+        assert_eq!(getaddrs(&mut upstream), [peer].into_iter().collect());
+
+        // Once we know enough addresses, peers are no longer asked, or rotated.
+        addrmgr.insert(
+            (0..8).map(|i| {
+                (
+                    clock.block_time(),
+                    Address::new(&([77, 77, 77, i], 8333).into(), services),
+                )
+            }),
+            Source::Dns,
+        );
+        clock.elapse(LocalDuration::from_hours(4));
+        addrmgr.received_wake();
+        assert!(getaddrs(&mut upstream).is_empty());
+        assert!(!addrmgr.refill());
+    }
+
+    #[test]
+    fn test_no_discovery() {
+        use crate::fsm::output::{self, Outbox};
+        use nakamoto_common::bitcoin::network::message::NetworkMessage;
+
+        let clock = RefClock::from(LocalTime::now());
+        let mut upstream = Outbox::new(Network::Mainnet, 0);
+        let cfg = Config {
+            discovery: false,
+            ..Config::default()
+        };
+        let mut addrmgr = AddressManager::new(
+            cfg,
+            fastrand::Rng::new(),
+            HashMap::new(),
+            upstream.clone(),
+            clock.clone(),
+        );
+        let peer = ([88, 88, 88, 1], 8333).into();
+
+        addrmgr.initialize();
+        addrmgr.peer_connected(&peer);
+        addrmgr.peer_negotiated(&peer, ServiceFlags::NETWORK, ConnDirection::Outbound);
+
+        // The address book is empty, but we aren't looking for addresses.
+        clock.elapse(REQUEST_TIMEOUT);
+        addrmgr.received_wake();
+
+        assert!(!output::test::messages(&mut upstream)
+            .any(|(_, msg)| matches!(msg, NetworkMessage::GetAddr)));
+        assert!(!output::test::events(&mut upstream)
+            .any(|e| matches!(e, crate::fsm::Event::Address(Event::AddressBookExhausted))));
+
+        // Once enabled, the exhausted address book is refilled.
+        addrmgr.set_discovery(true);
+        addrmgr.received_wake();
+
+        assert!(output::test::messages(&mut upstream)
+            .any(|(addr, msg)| addr == peer && matches!(msg, NetworkMessage::GetAddr)));
+    }
+
+    #[test]
+    fn test_request_addresses_from() {
+        use crate::fsm::output::{self, Outbox};
+        use nakamoto_common::bitcoin::network::message::NetworkMessage;
+
+        let clock = RefClock::from(LocalTime::now());
+        let mut upstream = Outbox::new(Network::Mainnet, 0);
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            HashMap::new(),
+            upstream.clone(),
+            clock.clone(),
+        );
+        let services = ServiceFlags::NETWORK;
+        let [peer, other]: [net::SocketAddr; 2] = [1, 2].map(|i| ([88, 88, 88, i], 8333).into());
+        let addrs = (0..4)
+            .map(|i| {
+                (
+                    clock.block_time(),
+                    Address::new(&([77, 77, 77, i], 8333).into(), services),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        addrmgr.initialize();
+        for addr in [peer, other] {
+            addrmgr.peer_connected(&addr);
+            addrmgr.peer_negotiated(&addr, services, ConnDirection::Inbound);
+        }
+        output::test::messages(&mut upstream).for_each(drop);
+
+        // Addresses are collected until the peer stops sending them.
+        let (transmit, receive) = chan::bounded(1);
+        addrmgr.request_addresses_from(peer, transmit);
+        assert!(output::test::messages(&mut upstream)
+            .any(|(a, m)| a == peer && matches!(m, NetworkMessage::GetAddr)));
+
+        addrmgr.received_addr(peer, addrs[..2].to_vec());
+        addrmgr.received_addr(other, addrs[2..3].to_vec());
+        clock.elapse(COLLECT_TIMEOUT - LocalDuration::from_secs(1));
+        addrmgr.received_wake();
+        assert!(receive.try_recv().is_err());
+
+        addrmgr.received_addr(peer, addrs[3..].to_vec());
+        clock.elapse(COLLECT_TIMEOUT);
+        addrmgr.received_wake();
+        assert_eq!(
+            receive.try_recv().unwrap().unwrap(),
+            [&addrs[..2], &addrs[3..]].concat()
+        );
+
+        // If the peer never responds, nothing is returned once the request times out.
+        let (transmit, receive) = chan::bounded(1);
+        addrmgr.request_addresses_from(other, transmit);
+        clock.elapse(REQUEST_TIMEOUT);
+        addrmgr.received_wake();
+        assert_eq!(receive.try_recv().unwrap().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_add() {
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            HashMap::new(),
+            (),
+            LocalTime::now(),
+        );
+        let alice: net::SocketAddr = ([33, 33, 33, 33], 8333).into();
+        let bob: net::SocketAddr = ([44, 44, 44, 44], 8333).into();
+
+        addrmgr.initialize();
+        addrmgr.add([
+            (alice, ServiceFlags::NETWORK),
+            (bob, ServiceFlags::NONE),
+            (alice, ServiceFlags::COMPACT_FILTERS),
+        ]);
+        assert_eq!(addrmgr.len(), 2);
+
+        // Services of duplicate addresses are merged.
+        let ka = addrmgr.peers.get(&alice.ip()).unwrap();
+        assert_eq!(ka.source, Source::Imported);
+        assert_eq!(
+            ka.addr.services,
+            ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS
+        );
+
+        // Added addresses can be sampled, even without a last active time.
+        let (addr, source) = addrmgr.sample(ServiceFlags::COMPACT_FILTERS).unwrap();
+        assert_eq!(addr.socket_addr().unwrap(), alice);
+        assert_eq!(source, Source::Imported);
+    }
+
+    #[test]
+    fn test_dns_seed_resolved() {
+        use crate::fsm::output::{self, Outbox};
+
+        let mut upstream = Outbox::new(Network::Mainnet, 0);
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            HashMap::new(),
+            upstream.clone(),
+            LocalTime::now(),
+        );
+        let alice: net::SocketAddr = ([33, 33, 33, 33], 8333).into();
+        let bob: net::SocketAddr = ([44, 44, 44, 44], 8333).into();
+
+        addrmgr.initialize();
+        addrmgr.dns_seed_resolved("seed.a".to_owned(), Ok(vec![alice, bob, alice]));
+        addrmgr.dns_seed_resolved("seed.b".to_owned(), Err("timed out".to_owned()));
+        assert_eq!(addrmgr.len(), 2);
+        assert_eq!(addrmgr.peers.get(&bob.ip()).unwrap().source, Source::Dns);
+
+        let events = output::test::events(&mut upstream)
+            .filter_map(|e| match e {
+                fsm::Event::Address(e) => Some(e),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert!(matches!(
+            &events[..],
+            [
+                Event::DnsSeedResolved { seed: a, addresses: 3 },
+                Event::DnsSeedFailed { seed: b, .. },
+            ] if a == "seed.a" && b == "seed.b"
+        ));
+    }
+
     #[test]
     fn test_insert() {
         use std::collections::HashMap;
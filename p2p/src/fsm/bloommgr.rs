@@ -0,0 +1,986 @@
+//! Bloom Filter Manager.
+//!
+//! Manages BIP 37 bloom filters and merkle blocks. Used as a fallback with peers that don't
+//! serve compact block filters. Since a bloom filter loaded on a peer reveals a lot about
+//! the scripts being watched, this is disabled by default.
+//!
+use std::ops::{Bound, RangeInclusive};
+
+use thiserror::Error;
+
+use nakamoto_common::bitcoin::blockdata::script::Instruction;
+use nakamoto_common::bitcoin::network::constants::ServiceFlags;
+use nakamoto_common::bitcoin::network::message_blockdata::Inventory;
+use nakamoto_common::bitcoin::network::message_bloom::{BloomFlags, FilterLoad};
+use nakamoto_common::bitcoin::util::merkleblock::MerkleBlock;
+use nakamoto_common::bitcoin::{Script, Transaction, Txid};
+use nakamoto_common::bitcoin_hashes::Hash;
+use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
+use nakamoto_common::block::tree::BlockReader;
+use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::collections::{AddressBook, HashMap, HashSet};
+
+use super::output::{Wakeup, Wire};
+use super::{cbfmgr, ConnDirection, PeerId, Socket};
+
+/// Services required from peers for BIP 37 functionality.
+pub const REQUIRED_SERVICES: ServiceFlags = ServiceFlags::BLOOM;
+
+/// Default false-positive rate of the bloom filters we load on peers.
+pub const DEFAULT_FALSE_POSITIVE_RATE: f64 = 0.0001;
+
+/// Maximum size of a bloom filter in bytes, as per BIP 37.
+const MAX_FILTER_SIZE: usize = 36_000;
+
+/// Maximum number of hash functions of a bloom filter, as per BIP 37.
+const MAX_HASH_FUNCS: u32 = 50;
+
+/// Multiplier used to derive the seed of each hash function, as per BIP 37.
+const HASH_SEED_MULTIPLIER: u32 = 0xfba4c795;
+
+/// Inventory type used to request merkle blocks, ie. `MSG_FILTERED_BLOCK`.
+const MSG_FILTERED_BLOCK: u32 = 3;
+
+/// Maximum number of merkle blocks requested from a peer in a single message.
+const MAX_MESSAGE_MERKLE_BLOCKS: usize = 500;
+
+/// Time to wait for a requested merkle block, before requesting it from another peer.
+pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
+
+/// An error originating in the bloom filter manager.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The message was ignored.
+    #[error("ignoring message from {from}: {msg}")]
+    Ignored {
+        /// Message that was ignored.
+        msg: &'static str,
+        /// Message sender.
+        from: PeerId,
+    },
+    /// Error due to an invalid peer message.
+    #[error("invalid message received from {from}: {reason}")]
+    InvalidMessage {
+        /// Message sender.
+        from: PeerId,
+        /// Reason why the message is invalid.
+        reason: &'static str,
+    },
+}
+
+/// An event originating in the bloom filter manager.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A bloom filter was loaded on a peer.
+    FilterLoaded {
+        /// Peer the filter was loaded on.
+        peer: PeerId,
+        /// Number of elements in the filter.
+        elements: usize,
+    },
+    /// A merkle block was received and validated.
+    MerkleBlockProcessed {
+        /// Peer we received from.
+        peer: PeerId,
+        /// Block height.
+        height: Height,
+        /// Block hash.
+        block: BlockHash,
+        /// Transactions of the block that matched the filter.
+        matched: Vec<Txid>,
+    },
+    /// A transaction matched by a merkle block was received.
+    TransactionMatched {
+        /// Peer we received from.
+        peer: PeerId,
+        /// Height of the block the transaction was included in.
+        height: Height,
+        /// Hash of the block the transaction was included in.
+        block: BlockHash,
+        /// The matched transaction.
+        transaction: Transaction,
+    },
+    /// A peer misbehaved, eg. by sending us merkle blocks we didn't ask for.
+    PeerMisbehaved {
+        /// The misbehaving peer.
+        peer: PeerId,
+        /// How the peer misbehaved.
+        reason: &'static str,
+    },
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::FilterLoaded { peer, elements } => {
+                write!(
+                    fmt,
+                    "Bloom filter with {} element(s) loaded on {}",
+                    elements, peer
+                )
+            }
+            Event::MerkleBlockProcessed {
+                peer,
+                height,
+                matched,
+                ..
+            } => {
+                write!(
+                    fmt,
+                    "Merkle block #{} from {} matched {} transaction(s)",
+                    height,
+                    peer,
+                    matched.len()
+                )
+            }
+            Event::TransactionMatched {
+                transaction,
+                height,
+                ..
+            } => {
+                write!(
+                    fmt,
+                    "Transaction {} matched in block #{}",
+                    transaction.txid(),
+                    height
+                )
+            }
+            Event::PeerMisbehaved { peer, reason } => {
+                write!(fmt, "{}: Peer misbehaved: {}", peer, reason)
+            }
+        }
+    }
+}
+
+/// Bloom filter manager configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Whether to fall back to bloom filters with peers that don't serve compact filters.
+    pub enabled: bool,
+    /// False-positive rate of the bloom filters loaded on peers.
+    pub false_positive_rate: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            false_positive_rate: DEFAULT_FALSE_POSITIVE_RATE,
+        }
+    }
+}
+
+/// A BIP 37 bloom filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    data: Vec<u8>,
+    hash_funcs: u32,
+    tweak: u32,
+}
+
+impl BloomFilter {
+    /// Create an empty filter sized for the given number of elements and false-positive rate.
+    pub fn new(elements: usize, false_positive_rate: f64, tweak: u32) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let elements = elements.max(1) as f64;
+        let bits = (-1. / (ln2 * ln2) * elements * false_positive_rate.ln()) as usize;
+        let size = (bits.min(MAX_FILTER_SIZE * 8) / 8).max(1);
+        let hash_funcs = ((size * 8) as f64 / elements * ln2) as u32;
+
+        Self {
+            data: vec![0; size],
+            hash_funcs: hash_funcs.clamp(1, MAX_HASH_FUNCS),
+            tweak,
+        }
+    }
+
+    /// Insert an element in the filter.
+    pub fn insert(&mut self, element: &[u8]) {
+        for n in 0..self.hash_funcs {
+            let ix = self.hash(n, element);
+            self.data[ix >> 3] |= 1 << (7 & ix);
+        }
+    }
+
+    /// Check whether the filter contains the given element. May return false positives.
+    pub fn contains(&self, element: &[u8]) -> bool {
+        (0..self.hash_funcs).all(|n| {
+            let ix = self.hash(n, element);
+            self.data[ix >> 3] & (1 << (7 & ix)) != 0
+        })
+    }
+
+    fn hash(&self, n: u32, element: &[u8]) -> usize {
+        let seed = n
+            .wrapping_mul(HASH_SEED_MULTIPLIER)
+            .wrapping_add(self.tweak);
+
+        murmur3(seed, element) as usize % (self.data.len() * 8)
+    }
+}
+
+impl From<BloomFilter> for FilterLoad {
+    fn from(filter: BloomFilter) -> Self {
+        FilterLoad {
+            filter: filter.data,
+            hash_funcs: filter.hash_funcs,
+            tweak: filter.tweak,
+            // Have the peer add the outpoints of matched outputs to the filter, so that
+            // transactions spending them are matched as well.
+            flags: BloomFlags::All,
+        }
+    }
+}
+
+/// The 32-bit variant of MurmurHash3, as used by BIP 37.
+fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+
+    let mut h1 = seed;
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+
+    for chunk in chunks {
+        let k1 = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+
+        h1 ^= k1.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h1 = h1.rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+    if !tail.is_empty() {
+        let k1 = tail
+            .iter()
+            .enumerate()
+            .fold(0u32, |k, (i, b)| k ^ ((*b as u32) << (8 * i)));
+
+        h1 ^= k1.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+    h1 ^= data.len() as u32;
+    h1 ^= h1 >> 16;
+    h1 = h1.wrapping_mul(0x85ebca6b);
+    h1 ^= h1 >> 13;
+    h1 = h1.wrapping_mul(0xc2b2ae35);
+    h1 ^= h1 >> 16;
+
+    h1
+}
+
+/// A bloom filter peer.
+#[derive(Debug)]
+struct Peer {
+    #[allow(dead_code)]
+    socket: Socket,
+}
+
+/// A merkle block request awaiting a response.
+#[derive(Debug)]
+struct Request {
+    /// Height of the requested block.
+    height: Height,
+    /// Peer the merkle block was requested from.
+    peer: PeerId,
+    /// Time at which the merkle block was requested.
+    time: LocalTime,
+    /// Peers that don't have the block, or failed to send it.
+    missing: Vec<PeerId>,
+}
+
+/// Manages bloom filters loaded on peers, and the merkle blocks they send us.
+#[derive(Debug)]
+pub struct BloomManager<U, C> {
+    /// Manager configuration.
+    pub config: Config,
+    /// Peers with a bloom filter loaded.
+    peers: AddressBook<PeerId, Peer>,
+    /// Scripts being watched.
+    watch: HashSet<Script>,
+    /// Tweak of the bloom filters we load.
+    tweak: u32,
+    /// Height up to which merkle blocks were requested.
+    requested: Height,
+    /// Transactions matched by merkle blocks that we're expecting to receive.
+    pending: HashMap<Txid, (Height, BlockHash)>,
+    /// Merkle blocks requested and not yet received.
+    inflight: HashMap<BlockHash, Request>,
+    upstream: U,
+    clock: C,
+}
+
+impl<U: Wire<Event> + Wakeup, C: Clock> BloomManager<U, C> {
+    /// Create a new bloom filter manager.
+    pub fn new(config: Config, rng: fastrand::Rng, upstream: U, clock: C) -> Self {
+        let peers = AddressBook::new(rng.clone());
+        let watch = HashSet::with_hasher(rng.clone().into());
+        let pending = HashMap::with_hasher(rng.clone().into());
+        let inflight = HashMap::with_hasher(rng.clone().into());
+
+        Self {
+            config,
+            peers,
+            watch,
+            tweak: rng.u32(..),
+            requested: 0,
+            pending,
+            inflight,
+            upstream,
+            clock,
+        }
+    }
+
+    /// Initialize the manager. Only blocks after the current tip are scanned, unless
+    /// a rescan is requested.
+    pub fn initialize<T: BlockReader>(&mut self, tree: &T) {
+        self.requested = tree.height();
+    }
+
+    /// Called when a peer is negotiated. Loads our bloom filter on the peer if it supports
+    /// bloom filters, but not compact block filters.
+    pub fn peer_negotiated(&mut self, socket: Socket, services: ServiceFlags, link: ConnDirection) {
+        if !self.config.enabled
+            || !link.is_outbound()
+            || !services.has(REQUIRED_SERVICES)
+            || services.has(cbfmgr::REQUIRED_SERVICES)
+        {
+            return;
+        }
+        let addr = socket.addr;
+
+        self.peers.insert(addr, Peer { socket });
+
+        if !self.watch.is_empty() {
+            self.load(addr);
+        }
+    }
+
+    /// Called when a peer disconnected. Merkle blocks requested from the peer are requested
+    /// from another peer.
+    pub fn peer_disconnected(&mut self, addr: &PeerId) {
+        self.peers.remove(addr);
+
+        let blocks = self
+            .inflight
+            .iter()
+            .filter(|(_, req)| req.peer == *addr)
+            .map(|(hash, _)| *hash)
+            .collect::<Vec<_>>();
+        self.retry(addr, blocks);
+    }
+
+    /// Called when a timer expires. Merkle blocks that weren't received in time are
+    /// requested from another peer.
+    pub fn received_wake(&mut self) {
+        let now = self.clock.local_time();
+        let mut timed_out: Vec<(PeerId, Vec<BlockHash>)> = Vec::new();
+
+        for (hash, req) in &self.inflight {
+            if now - req.time < REQUEST_TIMEOUT {
+                continue;
+            }
+            match timed_out.iter_mut().find(|(peer, _)| *peer == req.peer) {
+                Some((_, blocks)) => blocks.push(*hash),
+                None => timed_out.push((req.peer, vec![*hash])),
+            }
+        }
+        for (peer, blocks) in timed_out {
+            log::debug!(
+                target: "p2p",
+                "{}: Request for {} merkle block(s) timed out",
+                peer,
+                blocks.len()
+            );
+            self.retry(&peer, blocks);
+        }
+    }
+
+    /// Add scripts to the watchlist, and reload the bloom filter on peers if it changed.
+    pub fn watch(&mut self, scripts: Vec<Script>) -> bool {
+        let mut changed = false;
+
+        for script in scripts {
+            changed |= self.watch.insert(script);
+        }
+        if changed {
+            let peers = self.peers.keys().copied().collect::<Vec<_>>();
+
+            for addr in peers {
+                self.load(addr);
+            }
+        }
+        changed
+    }
+
+    /// Replace the watchlist, and reload the bloom filter on peers if it changed.
+    pub fn set_watchlist(&mut self, scripts: Vec<Script>) -> bool {
+        let previous = self.watch.clone();
+
+        self.watch.clear();
+        self.watch.extend(scripts);
+
+        if self.watch == previous {
+            return false;
+        }
+        let peers = self.peers.keys().copied().collect::<Vec<_>>();
+        for addr in peers {
+            self.load(addr);
+        }
+        true
+    }
+
+    /// Rescan the given range of blocks using merkle blocks.
+    pub fn rescan<T: BlockReader>(
+        &mut self,
+        start: Bound<Height>,
+        end: Bound<Height>,
+        watch: Vec<Script>,
+        tree: &T,
+    ) {
+        self.watch(watch);
+
+        let start = match start {
+            Bound::Unbounded => tree.height() + 1,
+            Bound::Included(h) => h,
+            Bound::Excluded(h) => h + 1,
+        };
+        let end = match end {
+            Bound::Unbounded => tree.height(),
+            Bound::Included(h) => h.min(tree.height()),
+            Bound::Excluded(h) => h.saturating_sub(1).min(tree.height()),
+        };
+        self.get_merkle_blocks(start..=end, tree);
+    }
+
+    /// Request merkle blocks for new blocks added to the active chain.
+    pub fn sync<T: BlockReader>(&mut self, tree: &T) {
+        let height = tree.height();
+
+        if self.watch.is_empty() {
+            self.requested = height;
+            return;
+        }
+        if self.peers.is_empty() {
+            // Leave the blocks to be requested once we are connected to a peer.
+            return;
+        }
+        self.get_merkle_blocks(self.requested + 1..=height, tree);
+        self.requested = self.requested.max(height);
+    }
+
+    /// Rollback to the given height, eg. after a chain re-org.
+    pub fn rollback(&mut self, height: Height) {
+        self.requested = self.requested.min(height);
+        self.pending.retain(|_, (h, _)| *h <= height);
+        self.inflight.retain(|_, req| req.height <= height);
+    }
+
+    /// Called when a `merkleblock` message was received. Returns the matched transactions.
+    pub fn received_merkleblock<T: BlockReader>(
+        &mut self,
+        from: &PeerId,
+        merkle_block: MerkleBlock,
+        tree: &T,
+    ) -> Result<Vec<Txid>, Error> {
+        if !self.peers.contains_key(from) {
+            return Err(Error::Ignored {
+                msg: "merkleblock: unknown peer",
+                from: *from,
+            });
+        }
+        let block = merkle_block.header.block_hash();
+
+        if !matches!(self.inflight.get(&block), Some(req) if req.peer == *from) {
+            self.upstream.event(Event::PeerMisbehaved {
+                peer: *from,
+                reason: "merkleblock: unsolicited merkle block",
+            });
+            return Err(Error::Ignored {
+                msg: "merkleblock: unsolicited merkle block",
+                from: *from,
+            });
+        }
+        let height = if let Some((height, _)) = tree.get_block(&block) {
+            height
+        } else {
+            return Err(Error::Ignored {
+                msg: "merkleblock: block not in active chain",
+                from: *from,
+            });
+        };
+
+        let mut matched = Vec::new();
+        let mut indexes = Vec::new();
+
+        // Nb. This also checks the merkle root of the partial tree against the header.
+        if merkle_block
+            .extract_matches(&mut matched, &mut indexes)
+            .is_err()
+        {
+            return Err(Error::InvalidMessage {
+                from: *from,
+                reason: "merkleblock: invalid partial merkle tree",
+            });
+        }
+        self.inflight.remove(&block);
+
+        for txid in &matched {
+            self.pending.insert(*txid, (height, block));
+        }
+        self.upstream.event(Event::MerkleBlockProcessed {
+            peer: *from,
+            height,
+            block,
+            matched: matched.clone(),
+        });
+
+        Ok(matched)
+    }
+
+    /// Called when a `tx` message was received. Returns `true` if the transaction was
+    /// matched by a merkle block.
+    pub fn received_tx(&mut self, from: &PeerId, transaction: Transaction) -> bool {
+        if let Some((height, block)) = self.pending.remove(&transaction.txid()) {
+            self.upstream.event(Event::TransactionMatched {
+                peer: *from,
+                height,
+                block,
+                transaction,
+            });
+            return true;
+        }
+        false
+    }
+
+    /// Called when a `notfound` message is received from a peer. Merkle blocks the peer
+    /// doesn't have are requested from another peer right away.
+    pub fn received_notfound(&mut self, from: &PeerId, invs: &[Inventory]) {
+        let blocks = invs
+            .iter()
+            .filter_map(|inv| match inv {
+                Inventory::Unknown {
+                    inv_type: MSG_FILTERED_BLOCK,
+                    hash,
+                } => Some(BlockHash::from_inner(*hash)),
+                Inventory::Block(hash) | Inventory::WitnessBlock(hash) => Some(*hash),
+                _ => None,
+            })
+            .filter(|hash| matches!(self.inflight.get(hash), Some(req) if req.peer == *from))
+            .collect();
+
+        self.retry(from, blocks);
+    }
+
+    /// Request merkle blocks that the given peer failed to send us from other peers.
+    /// Blocks that none of our peers can send us are dropped.
+    fn retry(&mut self, from: &PeerId, blocks: Vec<BlockHash>) {
+        let now = self.clock.local_time();
+        let mut retries: Vec<(PeerId, Vec<Inventory>)> = Vec::new();
+
+        for hash in blocks {
+            let req = match self.inflight.get_mut(&hash) {
+                Some(req) => req,
+                None => continue,
+            };
+            req.missing.push(*from);
+
+            let addr = if let Some((addr, _)) = self
+                .peers
+                .sample_with(|addr, _| !req.missing.contains(addr))
+            {
+                *addr
+            } else {
+                log::debug!(target: "p2p", "No peer has merkle block {}", hash);
+                self.inflight.remove(&hash);
+
+                continue;
+            };
+            req.peer = addr;
+            req.time = now;
+
+            let inv = Inventory::Unknown {
+                inv_type: MSG_FILTERED_BLOCK,
+                hash: hash.into_inner(),
+            };
+            match retries.iter_mut().find(|(peer, _)| *peer == addr) {
+                Some((_, invs)) => invs.push(inv),
+                None => retries.push((addr, vec![inv])),
+            }
+        }
+        if !retries.is_empty() {
+            self.upstream.wakeup(REQUEST_TIMEOUT);
+        }
+        for (addr, invs) in retries {
+            self.upstream.get_data(addr, invs);
+        }
+    }
+
+    /// Request merkle blocks for the given range from our peers.
+    fn get_merkle_blocks<T: BlockReader>(&mut self, range: RangeInclusive<Height>, tree: &T) {
+        if range.is_empty() || self.watch.is_empty() {
+            return;
+        }
+        let blocks = range
+            .filter_map(|h| {
+                tree.get_block_by_height(h)
+                    .map(|header| (h, header.block_hash()))
+            })
+            .collect::<Vec<_>>();
+        let now = self.clock.local_time();
+        let mut peers = self.peers.cycle();
+
+        for chunk in blocks.chunks(MAX_MESSAGE_MERKLE_BLOCKS) {
+            let addr = if let Some(addr) = peers.next() {
+                *addr
+            } else {
+                log::debug!(target: "p2p", "No peers to request merkle blocks from");
+                return;
+            };
+            let invs = chunk
+                .iter()
+                .map(|(height, hash)| {
+                    self.inflight.insert(
+                        *hash,
+                        Request {
+                            height: *height,
+                            peer: addr,
+                            time: now,
+                            missing: Vec::new(),
+                        },
+                    );
+                    Inventory::Unknown {
+                        inv_type: MSG_FILTERED_BLOCK,
+                        hash: hash.into_inner(),
+                    }
+                })
+                .collect();
+
+            self.upstream.get_data(addr, invs);
+            self.upstream.wakeup(REQUEST_TIMEOUT);
+        }
+    }
+
+    /// Build a bloom filter from our watchlist and load it on the given peer.
+    fn load(&mut self, addr: PeerId) {
+        let elements = self
+            .watch
+            .iter()
+            .flat_map(|script| script.instructions())
+            .filter_map(|i| match i {
+                Ok(Instruction::PushBytes(bytes)) if !bytes.is_empty() => Some(bytes),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let mut filter =
+            BloomFilter::new(elements.len(), self.config.false_positive_rate, self.tweak);
+
+        for element in &elements {
+            filter.insert(element);
+        }
+        self.upstream.filter_load(addr, filter.into());
+        self.upstream.event(Event::FilterLoaded {
+            peer: addr,
+            elements: elements.len(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fsm;
+    use crate::fsm::network::Network;
+    use crate::fsm::output::{self, Outbox};
+    use crate::fsm::PROTOCOL_VERSION;
+
+    use nakamoto_common::bitcoin::network::message::NetworkMessage;
+    use nakamoto_common::block::time::RefClock;
+    use nakamoto_common::block::tree::BlockReader as _;
+    use nakamoto_common::nonempty::NonEmpty;
+    use nakamoto_test::assert_matches;
+    use nakamoto_test::block::cache::model;
+    use nakamoto_test::block::gen;
+
+    #[test]
+    fn test_murmur3() {
+        // Test vectors from Bitcoin Core.
+        assert_eq!(murmur3(0x00000000, &[]), 0x00000000);
+        assert_eq!(murmur3(0xfba4c795, &[]), 0x6a396f08);
+        assert_eq!(murmur3(0x00000000, &[0x00]), 0x514e28b7);
+        assert_eq!(murmur3(0xfba4c795, &[0x00]), 0xea3f0b17);
+        assert_eq!(murmur3(0x00000000, &[0x00, 0x11]), 0x16c6b7ab);
+        assert_eq!(murmur3(0x00000000, &[0x00, 0x11, 0x22, 0x33]), 0xb4471bf8);
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut filter = BloomFilter::new(3, 0.01, 0);
+
+        filter.insert(b"alice");
+        filter.insert(b"bob");
+
+        assert!(filter.contains(b"alice"));
+        assert!(filter.contains(b"bob"));
+        assert!(filter.data.len() <= MAX_FILTER_SIZE);
+        assert!(filter.hash_funcs <= MAX_HASH_FUNCS);
+    }
+
+    #[test]
+    fn test_filter_load() {
+        let network = Network::Regtest;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let rng = fastrand::Rng::new();
+        let config = Config {
+            enabled: true,
+            ..Config::default()
+        };
+        let bloom = ([66, 66, 66, 66], 8333).into();
+        let cbf = ([77, 77, 77, 77], 8333).into();
+        let watch = vec![gen::script(&mut rng.clone())];
+
+        let mut bloommgr = BloomManager::new(config, rng, upstream.clone(), LocalTime::now());
+
+        bloommgr.watch(watch);
+        bloommgr.peer_negotiated(
+            Socket::new(bloom),
+            ServiceFlags::NETWORK | ServiceFlags::BLOOM,
+            ConnDirection::Outbound,
+        );
+        bloommgr.peer_negotiated(
+            Socket::new(cbf),
+            ServiceFlags::NETWORK | ServiceFlags::BLOOM | ServiceFlags::COMPACT_FILTERS,
+            ConnDirection::Outbound,
+        );
+
+        assert_matches!(
+            output::test::messages_from(&mut upstream, &bloom).next(),
+            Some(NetworkMessage::FilterLoad(FilterLoad {
+                flags: BloomFlags::All,
+                ..
+            }))
+        );
+        assert_eq!(output::test::messages_from(&mut upstream, &cbf).count(), 0);
+    }
+
+    #[test]
+    fn test_merkle_block() {
+        let network = Network::Regtest;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let mut rng = fastrand::Rng::new();
+        let config = Config {
+            enabled: true,
+            ..Config::default()
+        };
+        let remote = ([66, 66, 66, 66], 8333).into();
+
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis, 16, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let hash = tree.get_block_by_height(8).unwrap().block_hash();
+        let block = chain.iter().find(|b| b.block_hash() == hash).unwrap();
+        let tx = block.txdata[0].clone();
+        let txid = tx.txid();
+
+        let mut bloommgr =
+            BloomManager::new(config, rng.clone(), upstream.clone(), LocalTime::now());
+
+        bloommgr.watch(vec![gen::script(&mut rng)]);
+        bloommgr.peer_negotiated(
+            Socket::new(remote),
+            ServiceFlags::NETWORK | ServiceFlags::BLOOM,
+            ConnDirection::Outbound,
+        );
+
+        // A merkle block that wasn't requested is ignored.
+        let merkle_block = MerkleBlock::from_block_with_predicate(block, |t| *t == txid);
+        assert_matches!(
+            bloommgr.received_merkleblock(&remote, merkle_block.clone(), &tree),
+            Err(Error::Ignored { .. })
+        );
+        assert_matches!(
+            output::test::events(&mut upstream)
+                .filter_map(|e| match e {
+                    fsm::Event::Bloom(e @ Event::PeerMisbehaved { .. }) => Some(e),
+                    _ => None,
+                })
+                .next(),
+            Some(Event::PeerMisbehaved { peer, .. }) if peer == remote
+        );
+        bloommgr.rescan(Bound::Included(8), Bound::Included(9), vec![], &tree);
+
+        // A merkle block with a header that doesn't commit to the partial tree is rejected.
+        let mut invalid = MerkleBlock::from_block_with_predicate(block, |t| *t == txid);
+        invalid.header = *tree.get_block_by_height(9).unwrap();
+        assert_matches!(
+            bloommgr.received_merkleblock(&remote, invalid, &tree),
+            Err(Error::InvalidMessage { .. })
+        );
+
+        let matched = bloommgr
+            .received_merkleblock(&remote, merkle_block, &tree)
+            .unwrap();
+        assert_eq!(matched, vec![txid]);
+
+        assert!(bloommgr.received_tx(&remote, tx));
+        assert_matches!(
+            output::test::events(&mut upstream)
+                .filter_map(|e| match e {
+                    fsm::Event::Bloom(e @ Event::TransactionMatched { .. }) => Some(e),
+                    _ => None,
+                })
+                .next(),
+            Some(Event::TransactionMatched { height: 8, block: b, .. })
+            if b == hash
+        );
+    }
+
+    #[test]
+    fn test_merkle_block_notfound() {
+        let network = Network::Regtest;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let mut rng = fastrand::Rng::new();
+        let config = Config {
+            enabled: true,
+            ..Config::default()
+        };
+        let (alice, bob): (PeerId, PeerId) = (
+            ([66, 66, 66, 66], 8333).into(),
+            ([77, 77, 77, 77], 8333).into(),
+        );
+
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis, 16, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let hash = tree.get_block_by_height(8).unwrap().block_hash();
+        let inv = vec![Inventory::Unknown {
+            inv_type: MSG_FILTERED_BLOCK,
+            hash: hash.into_inner(),
+        }];
+
+        let mut bloommgr =
+            BloomManager::new(config, rng.clone(), upstream.clone(), LocalTime::now());
+
+        bloommgr.watch(vec![gen::script(&mut rng)]);
+        for addr in [alice, bob] {
+            bloommgr.peer_negotiated(
+                Socket::new(addr),
+                ServiceFlags::NETWORK | ServiceFlags::BLOOM,
+                ConnDirection::Outbound,
+            );
+        }
+        bloommgr.rescan(Bound::Included(8), Bound::Included(8), vec![], &tree);
+
+        let getdata = |upstream: &mut Outbox| {
+            output::test::messages(upstream)
+                .filter_map(|(addr, msg)| match msg {
+                    NetworkMessage::GetData(invs) => Some((addr, invs)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = match getdata(&mut upstream).as_slice() {
+            [(addr, invs)] if invs == &inv => *addr,
+            other => panic!("unexpected requests: {:?}", other),
+        };
+        let second = if first == alice { bob } else { alice };
+
+        // Only the peer the block was requested from is listened to.
+        bloommgr.received_notfound(&second, &inv);
+        assert_eq!(getdata(&mut upstream), vec![]);
+
+        // The block is requested from the other peer right away.
+        bloommgr.received_notfound(&first, &inv);
+        assert_eq!(getdata(&mut upstream), vec![(second, inv.clone())]);
+
+        // Neither peer has the block.
+        bloommgr.received_notfound(&second, &inv);
+        assert_eq!(getdata(&mut upstream), vec![]);
+        assert!(bloommgr.inflight.is_empty());
+    }
+
+    #[test]
+    fn test_merkle_block_retry() {
+        let network = Network::Regtest;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+        let config = Config {
+            enabled: true,
+            ..Config::default()
+        };
+        let peers: Vec<PeerId> = vec![
+            ([66, 66, 66, 66], 8333).into(),
+            ([77, 77, 77, 77], 8333).into(),
+            ([88, 88, 88, 88], 8333).into(),
+        ];
+
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis, 16, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let hash = tree.get_block_by_height(8).unwrap().block_hash();
+        let other = tree.get_block_by_height(9).unwrap().block_hash();
+        let other = chain.iter().find(|b| b.block_hash() == other).unwrap();
+        let inv = vec![Inventory::Unknown {
+            inv_type: MSG_FILTERED_BLOCK,
+            hash: hash.into_inner(),
+        }];
+
+        let mut bloommgr = BloomManager::new(config, rng.clone(), upstream.clone(), clock.clone());
+
+        bloommgr.watch(vec![gen::script(&mut rng)]);
+        for addr in &peers {
+            bloommgr.peer_negotiated(
+                Socket::new(*addr),
+                ServiceFlags::NETWORK | ServiceFlags::BLOOM,
+                ConnDirection::Outbound,
+            );
+        }
+        bloommgr.rescan(Bound::Included(8), Bound::Included(8), vec![], &tree);
+
+        let requested = |upstream: &mut Outbox| {
+            output::test::messages(upstream)
+                .filter_map(|(addr, msg)| match msg {
+                    NetworkMessage::GetData(invs) => Some((addr, invs)),
+                    _ => None,
+                })
+                .map(|(addr, invs)| {
+                    assert_eq!(invs, inv);
+                    addr
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = requested(&mut upstream);
+        assert_eq!(first.len(), 1);
+
+        // An invalid merkle block doesn't count as a response.
+        let mut invalid = MerkleBlock::from_block_with_predicate(other, |_| true);
+        invalid.header = *tree.get_block_by_height(8).unwrap();
+        assert!(bloommgr
+            .received_merkleblock(&first[0], invalid, &tree)
+            .is_err());
+        assert!(bloommgr.inflight.contains_key(&hash));
+
+        // The block is requested from another peer once the request times out.
+        clock.elapse(REQUEST_TIMEOUT - LocalDuration::from_secs(1));
+        bloommgr.received_wake();
+        assert_eq!(requested(&mut upstream), vec![]);
+
+        clock.elapse(LocalDuration::from_secs(1));
+        bloommgr.received_wake();
+        let second = requested(&mut upstream);
+        assert_eq!(second.len(), 1);
+        assert_ne!(second, first);
+
+        // The block is requested from the last peer when the peer disconnects.
+        bloommgr.peer_disconnected(&second[0]);
+        let third = requested(&mut upstream);
+        assert_eq!(third.len(), 1);
+        assert!(!first.contains(&third[0]) && !second.contains(&third[0]));
+
+        // No peer is left to request the block from.
+        bloommgr.peer_disconnected(&third[0]);
+        assert_eq!(requested(&mut upstream), vec![]);
+        assert!(bloommgr.inflight.is_empty());
+    }
+}
@@ -4,16 +4,19 @@
 //!
 mod rescan;
 
+use std::collections::BTreeMap;
 use std::ops::{Bound, RangeInclusive};
 
 use thiserror::Error;
 
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
-use nakamoto_common::bitcoin::network::message_filter::{CFHeaders, CFilter, GetCFHeaders};
+use nakamoto_common::bitcoin::network::message_filter::{
+    CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, GetCFHeaders, GetCFilters,
+};
 
 use nakamoto_common::bitcoin::{Script, Transaction, Txid};
 
-use nakamoto_common::block::filter::{self, BlockFilter, Filters};
+use nakamoto_common::block::filter::{self, BlockFilter, FilterHash, FilterHeader, Filters};
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
 use nakamoto_common::block::tree::BlockReader;
 use nakamoto_common::block::{BlockHash, Height};
@@ -22,7 +25,8 @@ use nakamoto_common::source;
 
 use super::filter_cache::FilterCache;
 use super::output::{Disconnect, Wakeup, Wire};
-use super::{ConnDirection, DisconnectReason, PeerId, Socket};
+use super::ratelimit::{RateLimiter, DEFAULT_SERVE_BUDGET};
+use super::{ConnDirection, DisconnectReason, InFlight, PeerId, Request, Socket};
 
 use rescan::Rescan;
 
@@ -35,6 +39,9 @@ pub const REQUIRED_SERVICES: ServiceFlags = ServiceFlags::COMPACT_FILTERS;
 /// Maximum filter headers to be expected in a message.
 pub const MAX_MESSAGE_CFHEADERS: usize = 2000;
 
+/// Number of blocks between filter header checkpoints, as per BIP 157.
+pub const CFCHECKPT_INTERVAL: Height = 1000;
+
 /// Maximum filters to be expected in a message.
 pub const MAX_MESSAGE_CFILTERS: usize = 1000;
 
@@ -44,6 +51,14 @@ pub const DEFAULT_FILTER_CACHE_SIZE: usize = 1024 * 1024; // 1 MB.
 /// How long to wait to receive a reply from a peer.
 pub const DEFAULT_REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(6);
 
+/// Maximum ratio of filters from a single peer that may match our watchlist, before the
+/// peer is considered suspicious. The BIP 158 false-positive rate is `1/784931` per
+/// watched item, so legitimate filters should match far less often than this.
+pub const DEFAULT_MAX_MATCH_RATE: f64 = 0.1;
+
+/// Default number of filters processed from a peer before its match rate is evaluated.
+pub const DEFAULT_MATCH_RATE_SAMPLE_SIZE: u64 = 100;
+
 /// An error originating in the CBF manager.
 #[derive(Error, Debug)]
 pub enum Error {
@@ -147,6 +162,24 @@ pub enum Event {
     /// Block header chain rollback detected.
     /// TODO: Use event or remove.
     RollbackDetected(Height),
+    /// Peers sent us conflicting filters for the same block.
+    FilterConflict {
+        /// Filter height.
+        height: Height,
+        /// Hash of corresponding block.
+        block_hash: BlockHash,
+        /// Peers that sent us a filter for this block.
+        peers: Vec<PeerId>,
+    },
+    /// Filters from a peer matched our watchlist implausibly often.
+    SuspiciousMatchRate {
+        /// The peer the filters were received from.
+        peer: PeerId,
+        /// Number of filters from this peer that were processed.
+        processed: u64,
+        /// Number of those filters that matched.
+        matched: u64,
+    },
 }
 
 impl std::fmt::Display for Event {
@@ -233,6 +266,28 @@ impl std::fmt::Display for Event {
                     height
                 )
             }
+            Event::FilterConflict {
+                height,
+                block_hash,
+                peers,
+            } => {
+                write!(
+                    fmt,
+                    "Conflicting filters received for block {} at height {} from {:?}",
+                    block_hash, height, peers
+                )
+            }
+            Event::SuspiciousMatchRate {
+                peer,
+                processed,
+                matched,
+            } => {
+                write!(
+                    fmt,
+                    "Peer {} sent suspicious filters: {} out of {} matched",
+                    peer, matched, processed
+                )
+            }
         }
     }
 }
@@ -252,6 +307,9 @@ pub enum GetFiltersError {
     /// Not connected to any compact filter peer.
     #[error("not connected to any peer with compact filters support")]
     NotConnected,
+    /// Compact filters are disabled, eg. in headers-only mode.
+    #[error("compact filters are disabled")]
+    Disabled,
 }
 
 /// CBF manager configuration.
@@ -261,6 +319,39 @@ pub struct Config {
     pub request_timeout: LocalDuration,
     /// Filter cache size, in bytes.
     pub filter_cache_size: usize,
+    /// Maximum number of filters in the cache. If not set, only the size in bytes is limited.
+    pub filter_cache_len: Option<usize>,
+    /// Filter header checkpoints. Received filter headers must match these.
+    pub checkpoints: Vec<(Height, filter::FilterHeader)>,
+    /// Maximum ratio of matching filters from a single peer, before the peer is
+    /// considered suspicious.
+    pub max_match_rate: f64,
+    /// Number of filters processed from a peer before its match rate is evaluated.
+    pub match_rate_sample_size: u64,
+    /// Whether to disconnect peers whose filters match suspiciously often.
+    pub disconnect_suspicious: bool,
+    /// Number of peers each filter is fetched from. Filters are only processed once
+    /// this many peers have sent them to us, and they all agree.
+    pub filter_redundancy: usize,
+    /// Probability that the block of a filter which doesn't match our watchlist is
+    /// downloaded anyway, as a decoy. This makes it harder for peers to tell which blocks
+    /// we are interested in, at the cost of bandwidth. Decoys are not reported as matches.
+    pub decoy_rate: f64,
+    /// Maximum number of filter headers requested in a `getcfheaders` message. Can't exceed
+    /// [`MAX_MESSAGE_CFHEADERS`].
+    pub max_message_cfheaders: usize,
+    /// Maximum number of `getcfheaders` requests in flight. Above one, filter header
+    /// checkpoints are first requested with `getcfcheckpt`. Batches of filter headers ending
+    /// at a checkpoint can then be verified as soon as they are received, and are requested
+    /// from several peers at once, instead of one after the other.
+    pub max_cfheaders_requests: usize,
+    /// Serve compact filters and filter header checkpoints to inbound peers. Only filters
+    /// in the filter cache can be served, so this is best-effort, and compact filters
+    /// support isn't advertised.
+    pub serve_filters: bool,
+    /// Maximum number of filter requests served to a single peer per
+    /// [`super::SERVE_BUDGET_PERIOD`]. Requests beyond this are ignored.
+    pub serve_budget: usize,
 }
 
 impl Default for Config {
@@ -268,6 +359,17 @@ impl Default for Config {
         Self {
             request_timeout: DEFAULT_REQUEST_TIMEOUT,
             filter_cache_size: DEFAULT_FILTER_CACHE_SIZE,
+            filter_cache_len: None,
+            checkpoints: Vec::new(),
+            max_match_rate: DEFAULT_MAX_MATCH_RATE,
+            match_rate_sample_size: DEFAULT_MATCH_RATE_SAMPLE_SIZE,
+            disconnect_suspicious: false,
+            filter_redundancy: 1,
+            decoy_rate: 0.,
+            max_message_cfheaders: MAX_MESSAGE_CFHEADERS,
+            max_cfheaders_requests: 1,
+            serve_filters: false,
+            serve_budget: DEFAULT_SERVE_BUDGET,
         }
     }
 }
@@ -282,6 +384,12 @@ struct Peer {
     #[allow(dead_code)]
     socket: Socket,
     persistent: bool,
+    /// Number of filters received from this peer that were processed.
+    processed: u64,
+    /// Number of filters received from this peer that matched our watchlist.
+    matched: u64,
+    /// Number of valid filters received from this peer.
+    filters: usize,
 }
 
 /// A compact block filter manager.
@@ -303,13 +411,39 @@ pub struct FilterManager<F, U, C> {
     last_processed: Option<LocalTime>,
     /// Inflight requests.
     inflight: HashMap<BlockHash, (Height, PeerId, LocalTime)>,
+    /// Inflight `getcfcheckpt` request, with the peer it was sent to and its expiry.
+    getcfcheckpt: Option<(PeerId, BlockHash, LocalTime)>,
+    /// Filter header checkpoints, one every [`CFCHECKPT_INTERVAL`] blocks, starting with the
+    /// first interval.
+    checkpoints: Vec<FilterHeader>,
+    /// Filter headers received ahead of our filter header tip, and verified against our
+    /// checkpoints, by start height and with their stop hash. They are imported once the
+    /// headers before them are.
+    ahead: BTreeMap<Height, (BlockHash, Vec<(FilterHash, FilterHeader)>)>,
+    /// Inflight `getcfilters` requests, by peer and stop hash, with the requested range
+    /// and the time they were sent.
+    getcfilters: HashMap<(PeerId, BlockHash), (RangeInclusive<Height>, LocalTime)>,
+    /// Filter requests served to each peer, within the budget period.
+    served: RateLimiter,
+    /// Peers we received filters from, that are pending processing.
+    sources: HashMap<Height, PeerId>,
+    /// Filters requested from more than one peer, by height, with the number of peers they
+    /// were requested from, and the responses received so far, waiting to be cross-checked.
+    responses: HashMap<Height, (usize, Vec<(PeerId, BlockFilter)>)>,
+    /// Used to pick decoy blocks.
+    rng: fastrand::Rng,
+    /// Number of blocks matched by filters, not including decoys.
+    matched: u64,
+    /// Whether filter syncing is paused. When paused, we don't initiate any requests,
+    /// but still process responses to inflight requests.
+    paused: bool,
 }
 
 impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F, U, C> {
     /// Create a new filter manager.
     pub fn new(config: Config, rng: fastrand::Rng, filters: F, upstream: U, clock: C) -> Self {
         let peers = AddressBook::new(rng.clone());
-        let rescan = Rescan::new(config.filter_cache_size);
+        let rescan = Rescan::new(config.filter_cache_size, config.filter_cache_len);
 
         Self {
             config,
@@ -318,9 +452,19 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
             upstream,
             clock,
             filters,
-            inflight: HashMap::with_hasher(rng.into()),
+            inflight: HashMap::with_hasher(rng.clone().into()),
+            getcfcheckpt: None,
+            checkpoints: Vec::new(),
+            ahead: BTreeMap::new(),
+            getcfilters: HashMap::with_hasher(rng.clone().into()),
+            served: RateLimiter::new(rng.clone()),
+            sources: HashMap::with_hasher(rng.clone().into()),
+            responses: HashMap::with_hasher(rng.clone().into()),
+            rng,
             last_idle: None,
             last_processed: None,
+            matched: 0,
+            paused: false,
         }
     }
 
@@ -333,6 +477,10 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
     pub fn received_wake<T: BlockReader>(&mut self, tree: &T) {
         self.idle(tree);
 
+        if self.paused {
+            return;
+        }
+
         let timeout = self.config.request_timeout;
         let now = self.clock.local_time();
 
@@ -362,11 +510,18 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
             }
         }
 
+        // If the checkpoint request expired, sync without checkpoints until it's retried.
+        if matches!(self.getcfcheckpt, Some((_, _, expiry)) if now >= expiry) {
+            self.getcfcheckpt = None;
+        }
+
         // If we've waited too long since the last processed filter, re-issue requests
         // for missing filters.
         if now - self.last_processed.unwrap_or_default() >= DEFAULT_REQUEST_TIMEOUT {
             if self.rescan.active {
                 self.rescan.reset(); // Clear pending request queue.
+                self.getcfilters.clear();
+                self.responses.clear();
                 self.get_cfilters(self.rescan.current..=self.filters.height(), tree)
                     .ok();
             }
@@ -375,6 +530,13 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
 
     /// Rollback filters to the given height.
     pub fn rollback(&mut self, height: Height) -> Result<(), filter::Error> {
+        // Checkpoints and filter headers ahead of our tip may be above the rollback height,
+        // even if our filter header chain isn't.
+        self.checkpoints
+            .truncate((height / CFCHECKPT_INTERVAL) as usize);
+        self.ahead
+            .retain(|start, (_, headers)| start + headers.len() as Height - 1 <= height);
+
         // It's possible that a rollback doesn't affect the filter chain, if the filter headers
         // haven't caught up to the block headers when the re-org happens.
         if height >= self.filters.height() {
@@ -431,6 +593,30 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         self.rescan.watch.extend(scripts);
     }
 
+    /// Replace the list of scripts to watch. Watched transactions are kept.
+    ///
+    /// If `rescan` is set, the range of the last rescan, whether active or completed, is
+    /// scanned again with the new watchlist, and the blocks matching cached filters are
+    /// returned, as with [`FilterManager::rescan`]. Otherwise, or if there was no rescan,
+    /// the new watchlist only applies to filters not yet processed.
+    pub fn set_watchlist<T: BlockReader>(
+        &mut self,
+        scripts: Vec<Script>,
+        rescan: bool,
+        tree: &T,
+    ) -> Vec<(Height, BlockHash)> {
+        // Nb. Unbounded rescans never complete, so a rescan without an end is still active.
+        if rescan && (self.rescan.active || self.rescan.end.is_some()) {
+            let start = Bound::Included(self.rescan.start);
+            let end = self.rescan.end.map_or(Bound::Unbounded, Bound::Included);
+
+            return self.rescan(start, end, scripts, tree);
+        }
+        self.rescan.watch = scripts.into_iter().collect();
+
+        vec![]
+    }
+
     /// Add transaction outputs to list of transactions to watch.
     pub fn watch_transaction(&mut self, tx: &Transaction) {
         self.rescan.transactions.insert(
@@ -452,12 +638,26 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         watch: Vec<Script>,
         tree: &T,
     ) -> Vec<(Height, BlockHash)> {
+        self.sources.clear();
+        self.responses.clear();
+        let mut start = match start {
+            Bound::Unbounded => tree.height() + 1,
+            Bound::Included(h) => h,
+            Bound::Excluded(h) => h + 1,
+        };
+        let pruned = tree.pruned();
+
+        // Blocks below the pruned height can't be scanned, since we don't have their hashes.
+        if pruned > 0 && start <= pruned {
+            log::warn!(
+                "[spv] Rescan start {} is below the pruned height, starting at {} instead",
+                start,
+                pruned + 1
+            );
+            start = pruned + 1;
+        }
         self.rescan.restart(
-            match start {
-                Bound::Unbounded => tree.height() + 1,
-                Bound::Included(h) => h,
-                Bound::Excluded(h) => h + 1,
-            },
+            start,
             match end {
                 Bound::Unbounded => None,
                 Bound::Included(h) => Some(h),
@@ -493,12 +693,15 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         match self.get_cfilters(range, tree) {
             Ok(()) => {}
             Err(GetFiltersError::NotConnected) => {}
-            Err(err) => panic!("{}: Error fetching filters: {}", source!(), err),
+            Err(err) => log::error!("[spv] Error fetching filters: {}", err),
         }
         // When we reset the rescan range, there is the possibility of getting immediate cache
         // hits from `get_cfilters`. Hence, process the filter queue.
-        let (matches, events, _) = self.rescan.process();
+        let (mut matches, events, _) = self.rescan.process();
+        self.matched += matches.len() as u64;
+
         for event in events {
+            matches.extend(self.decoy(&event));
             self.upstream.event(event);
         }
         matches
@@ -522,29 +725,44 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         assert!(*range.end() <= self.filters.height());
 
         // TODO: Only ask peers synced to a certain height.
-        // Choose a different peer for each requested range.
-        for (range, peer) in self
-            .rescan
-            .requests(range, tree)
-            .into_iter()
-            .zip(self.peers.cycle())
-        {
+        // Choose different peers for each requested range.
+        let requests = self.rescan.requests(range, tree);
+        let redundancy = self.config.filter_redundancy.clamp(1, self.peers.len());
+        let mut peers = self.peers.cycle();
+        let time = self.clock.local_time();
+
+        // Forget requests that were fulfilled by other peers.
+        self.getcfilters
+            .retain(|_, (range, _)| range.clone().any(|h| self.rescan.is_requested(h)));
+
+        for range in requests {
             let stop_hash = tree
                 .get_block_by_height(*range.end())
                 .ok_or(GetFiltersError::InvalidRange)?
                 .block_hash();
             let timeout = self.config.request_timeout;
 
-            log::debug!(
-                "Requested filter(s) in range {} to {} from {} (stop = {})",
-                range.start(),
-                range.end(),
-                peer,
-                stop_hash
-            );
+            for peer in peers.by_ref().take(redundancy) {
+                log::debug!(
+                    "Requested filter(s) in range {} to {} from {} (stop = {})",
+                    range.start(),
+                    range.end(),
+                    peer,
+                    stop_hash
+                );
 
-            self.upstream
-                .get_cfilters(*peer, *range.start(), stop_hash, timeout);
+                self.upstream
+                    .get_cfilters(*peer, *range.start(), stop_hash, timeout);
+                self.getcfilters
+                    .insert((*peer, stop_hash), (range.clone(), time));
+            }
+            // Filters are only processed once all the peers they were requested from
+            // have responded.
+            if redundancy > 1 {
+                for height in range {
+                    self.responses.insert(height, (redundancy, Vec::new()));
+                }
+            }
         }
 
         Ok(())
@@ -568,12 +786,14 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
             from
         );
 
-        if self.inflight.remove(&stop_hash).is_none() {
+        let requested_height = if let Some((height, _, _)) = self.inflight.remove(&stop_hash) {
+            height
+        } else {
             return Err(Error::Ignored {
                 from,
                 msg: "unsolicited `cfheaders` message",
             });
-        }
+        };
 
         if msg.filter_type != 0x0 {
             return Err(Error::InvalidMessage {
@@ -582,7 +802,6 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
             });
         }
 
-        let start_height = self.filters.height();
         let stop_height = if let Some((height, _)) = tree.get_block(&stop_hash) {
             height
         } else {
@@ -597,13 +816,32 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
             return Ok(self.filters.height());
         }
 
-        let (_, tip_header) = self.filters.tip();
-        let prev_header = msg.previous_filter_header;
+        // If these headers are ahead of our tip, they were requested based on our checkpoints,
+        // and must start at one.
+        let ahead = requested_height > self.filters.height() + 1;
+        let (start_height, prev_header) = if ahead {
+            let start_height = requested_height - 1;
+            let checkpoint = if let Some(checkpoint) = self.checkpoint(start_height) {
+                checkpoint
+            } else {
+                return Err(Error::Ignored {
+                    msg: "`cfheaders` start height is not at a checkpoint",
+                    from,
+                });
+            };
+            (start_height, checkpoint)
+        } else {
+            let (_, tip_header) = self.filters.tip();
+            (self.filters.height(), *tip_header)
+        };
 
         // If the previous header of the message does not match our tip, it could be
         // that our tip was updated while the message was inflight.
         // Schedule a wake to make sure we sync the correct range this time around.
-        if &prev_header != tip_header {
+        if msg.previous_filter_header != prev_header {
+            if ahead {
+                self.distrust_checkpoints();
+            }
             self.schedule_wake();
 
             return Err(Error::Ignored {
@@ -653,26 +891,123 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
             last_header = filter_hash.filter_header(&last_header);
             headers.push((filter_hash, last_header));
         }
-        self.filters
-            .import_headers(headers)
-            .map(|height| {
-                self.upstream.event(Event::FilterHeadersImported {
-                    count,
-                    height,
-                    block_hash: stop_hash,
-                });
-                self.headers_imported(start_height, height, tree).unwrap(); // TODO
 
-                assert!(height <= tree.height());
+        for (height, checkpoint) in &self.config.checkpoints {
+            if *height > start_height && *height <= stop_height {
+                let (_, header) = headers[(*height - start_height - 1) as usize];
 
-                if height == tree.height() {
-                    self.upstream.event(Event::Synced(height));
-                } else {
-                    self.sync(tree);
+                if header != *checkpoint {
+                    return Err(Error::InvalidMessage {
+                        from,
+                        reason: "`cfheaders` filter header does not match checkpoint",
+                    });
                 }
-                height
-            })
-            .map_err(Error::from)
+            }
+        }
+
+        // Headers ending at a checkpoint must match it. If they don't, either this peer or
+        // the one that sent us the checkpoints is lying, so we stop relying on checkpoints.
+        if let Some(checkpoint) = self.checkpoint(stop_height) {
+            if checkpoint != last_header {
+                self.distrust_checkpoints();
+
+                if ahead {
+                    self.schedule_wake();
+
+                    return Err(Error::Ignored {
+                        msg: "`cfheaders` do not match checkpoint",
+                        from,
+                    });
+                }
+            }
+        }
+        if ahead {
+            self.ahead.insert(requested_height, (stop_hash, headers));
+
+            return Ok(self.filters.height());
+        }
+
+        let mut height = self.import_cfheaders(headers, stop_hash, tree)?;
+        // Import the headers we received ahead of our tip, that now follow it.
+        while let Some((stop_hash, headers)) = self.ahead.remove(&(height + 1)) {
+            height = self.import_cfheaders(headers, stop_hash, tree)?;
+        }
+        assert!(height <= tree.height());
+
+        if height == tree.height() {
+            self.upstream.event(Event::Synced(height));
+        } else {
+            self.sync(tree);
+        }
+        Ok(height)
+    }
+
+    /// Handle a `cfcheckpt` message from a peer.
+    pub fn received_cfcheckpt<T: BlockReader>(
+        &mut self,
+        from: &PeerId,
+        msg: CFCheckpt,
+        tree: &T,
+    ) -> Result<(), Error> {
+        let from = *from;
+
+        match self.getcfcheckpt {
+            Some((peer, stop_hash, _)) if peer == from && stop_hash == msg.stop_hash => {
+                self.getcfcheckpt = None;
+            }
+            _ => {
+                return Err(Error::Ignored {
+                    from,
+                    msg: "unsolicited `cfcheckpt` message",
+                });
+            }
+        }
+        if msg.filter_type != 0x0 {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "invalid `cfcheckpt` filter type",
+            });
+        }
+        let stop_height = if let Some((height, _)) = tree.get_block(&msg.stop_hash) {
+            height
+        } else {
+            // The stop block may have been reverted while the request was inflight.
+            return Err(Error::Ignored {
+                from,
+                msg: "unknown `cfcheckpt` stop hash",
+            });
+        };
+        if msg.filter_headers.len() as Height != stop_height / CFCHECKPT_INTERVAL {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "`cfcheckpt` header count does not match stop height",
+            });
+        }
+        // Checkpoints we already have filter headers for must match them.
+        let synced = self.filters.height() / CFCHECKPT_INTERVAL;
+        for (i, checkpoint) in msg.filter_headers.iter().take(synced as usize).enumerate() {
+            let height = (i as Height + 1) * CFCHECKPT_INTERVAL;
+
+            if self
+                .filters
+                .get_header(height)
+                .map_or(false, |(_, header)| header != *checkpoint)
+            {
+                return Err(Error::Ignored {
+                    from,
+                    msg: "`cfcheckpt` does not match our filter headers",
+                });
+            }
+        }
+        log::debug!(
+            "[spv] Received {} filter header checkpoint(s) from {}",
+            msg.filter_headers.len(),
+            from
+        );
+        self.checkpoints = msg.filter_headers;
+        self.sync(tree);
+
+        Ok(())
     }
 
     /// Handle a `getcfheaders` message from a peer.
@@ -701,6 +1036,19 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
                 from,
             });
         };
+        if start_height > stop_height
+            || stop_height - start_height >= MAX_MESSAGE_CFHEADERS as Height
+        {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfheaders: invalid height range",
+            });
+        }
+        // Filter headers are always served, but only count against the budget of peers
+        // when we serve filters.
+        if self.config.serve_filters {
+            self.charge(from)?;
+        }
 
         let headers = self.filters.get_headers(start_height..=stop_height);
         if !headers.is_empty() {
@@ -728,9 +1076,184 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         })
     }
 
+    /// Handle a `getcfilters` message from a peer. Only filters in our cache are served, and
+    /// only if all of the requested filters are.
+    pub fn received_getcfilters<T: BlockReader>(
+        &mut self,
+        from: &PeerId,
+        msg: GetCFilters,
+        tree: &T,
+    ) -> Result<(), Error> {
+        let from = *from;
+
+        if !self.config.serve_filters {
+            return Err(Error::Ignored {
+                msg: "getcfilters: not serving filters",
+                from,
+            });
+        }
+        if msg.filter_type != 0x0 {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfilters: invalid filter type",
+            });
+        }
+        let start_height = msg.start_height as Height;
+        let stop_height = if let Some((height, _)) = tree.get_block(&msg.stop_hash) {
+            height
+        } else {
+            return Err(Error::Ignored {
+                msg: "getcfilters: unknown stop hash",
+                from,
+            });
+        };
+        if start_height > stop_height
+            || stop_height - start_height >= MAX_MESSAGE_CFILTERS as Height
+        {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfilters: invalid height range",
+            });
+        }
+        self.charge(from)?;
+
+        let mut filters = Vec::with_capacity((stop_height - start_height + 1) as usize);
+        for height in start_height..=stop_height {
+            match (
+                tree.get_block_by_height(height),
+                self.rescan.cache.get(&height),
+            ) {
+                (Some(header), Some(filter)) => filters.push(CFilter {
+                    filter_type: msg.filter_type,
+                    block_hash: header.block_hash(),
+                    filter: filter.content.clone(),
+                }),
+                _ => {
+                    return Err(Error::Ignored {
+                        msg: "getcfilters: filters not available",
+                        from,
+                    })
+                }
+            }
+        }
+        for filter in filters {
+            self.upstream.cfilter(from, filter);
+        }
+        Ok(())
+    }
+
+    /// Handle a `getcfcheckpt` message from a peer.
+    pub fn received_getcfcheckpt<T: BlockReader>(
+        &mut self,
+        from: &PeerId,
+        msg: GetCFCheckpt,
+        tree: &T,
+    ) -> Result<(), Error> {
+        let from = *from;
+
+        if !self.config.serve_filters {
+            return Err(Error::Ignored {
+                msg: "getcfcheckpt: not serving filters",
+                from,
+            });
+        }
+        if msg.filter_type != 0x0 {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfcheckpt: invalid filter type",
+            });
+        }
+        let stop_height = if let Some((height, _)) = tree.get_block(&msg.stop_hash) {
+            height
+        } else {
+            return Err(Error::Ignored {
+                msg: "getcfcheckpt: unknown stop hash",
+                from,
+            });
+        };
+        let count = stop_height / CFCHECKPT_INTERVAL;
+
+        if count * CFCHECKPT_INTERVAL > self.filters.height() {
+            // We're still syncing filter headers.
+            return Err(Error::Ignored {
+                msg: "getcfcheckpt: filter headers not available",
+                from,
+            });
+        }
+        self.charge(from)?;
+
+        let filter_headers = (1..=count)
+            .filter_map(|i| self.filters.get_header(i * CFCHECKPT_INTERVAL))
+            .map(|(_, header)| header)
+            .collect();
+
+        self.upstream.cfcheckpt(
+            from,
+            CFCheckpt {
+                filter_type: msg.filter_type,
+                stop_hash: msg.stop_hash,
+                filter_headers,
+            },
+        );
+        Ok(())
+    }
+
+    /// Whether we serve compact filters to peers.
+    pub fn is_serving(&self) -> bool {
+        self.config.serve_filters
+    }
+
+    /// Pause filter syncing. No new filter header or filter requests are made until
+    /// [`FilterManager::resume`] is called. Responses to inflight requests are still processed.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Resume filter syncing, continuing from the current filter header height and
+    /// rescan position. Filters of headers imported while paused are requested.
+    pub fn resume<T: BlockReader>(&mut self, tree: &T) {
+        if !self.paused {
+            return;
+        }
+        self.paused = false;
+        self.last_processed = Some(self.clock.local_time());
+        self.sync(tree);
+
+        // Heights that were already requested or received are skipped.
+        if let Err(err) = self.headers_imported(self.rescan.current, self.filters.height(), tree) {
+            log::debug!("[spv] Couldn't request filters on resume: {}", err);
+        }
+    }
+
+    /// Whether filter syncing is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Set the filter cache size, in bytes. Cached filters are evicted if the cache
+    /// is over the new size.
+    pub fn set_cache_size(&mut self, size: usize) {
+        self.config.filter_cache_size = size;
+        self.rescan.cache.set_capacity(size);
+    }
+
+    /// Set the maximum number of cached filters. Least recently used filters are evicted
+    /// if the cache holds more.
+    pub fn set_cache_len(&mut self, len: Option<usize>) {
+        self.config.filter_cache_len = len;
+        self.rescan.cache.set_max_len(len);
+    }
+
+    /// Set the probability of downloading a non-matching block as a decoy.
+    /// See [`Config::decoy_rate`].
+    pub fn set_decoy_rate(&mut self, rate: f64) {
+        self.config.decoy_rate = rate.clamp(0., 1.);
+    }
+
     /// Handle a `cfilter` message.
     ///
-    /// Returns a list of blocks that need to be fetched from the network.
+    /// Returns a list of blocks that need to be fetched from the network. This includes
+    /// decoy blocks, if enabled.
     pub fn received_cfilter<T: BlockReader>(
         &mut self,
         from: &PeerId,
@@ -739,6 +1262,9 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
     ) -> Result<Vec<(Height, BlockHash)>, Error> {
         let from = *from;
 
+        // Filters are sent in order, so the last one requested completes the request.
+        self.getcfilters.remove(&(from, msg.block_hash));
+
         if msg.filter_type != 0x0 {
             return Err(Error::Ignored {
                 msg: "cfilter",
@@ -776,12 +1302,25 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         let filter = BlockFilter::new(&msg.filter);
         let block_hash = msg.block_hash;
 
+        // When filters are fetched from multiple peers, wait until they have all responded.
+        let filter = if self.responses.contains_key(&height) {
+            match self.cross_check(from, height, block_hash, filter, &prev_header, &header) {
+                Some(filter) => filter,
+                None => return Ok(Vec::default()),
+            }
+        } else {
+            filter
+        };
+
         if filter.filter_header(&prev_header) != header {
             return Err(Error::InvalidMessage {
                 from,
                 reason: "cfilter: filter hash doesn't match header",
             });
         }
+        if let Some(peer) = self.peers.get_mut(&from) {
+            peer.filters += 1;
+        }
 
         self.upstream.event(Event::FilterReceived {
             from,
@@ -791,8 +1330,21 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         });
 
         if self.rescan.received(height, filter, block_hash) {
-            let (matches, events, processed) = self.rescan.process();
+            self.sources.insert(height, from);
+
+            let (mut matches, events, processed) = self.rescan.process();
+            self.matched += matches.len() as u64;
+
             for event in events {
+                if let Event::FilterProcessed {
+                    height, matched, ..
+                } = event
+                {
+                    if let Some(source) = self.sources.remove(&height) {
+                        self.record_match(source, matched);
+                    }
+                }
+                matches.extend(self.decoy(&event));
                 self.upstream.event(event);
             }
             // If we processed some filters, update the time to further delay requesting new
@@ -807,20 +1359,178 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         Ok(Vec::default())
     }
 
-    /// Called when a peer disconnected.
-    pub fn peer_disconnected(&mut self, id: &PeerId) {
-        self.peers.remove(id);
+    /// Randomly pick the block of a processed filter that didn't match, to be downloaded
+    /// as a decoy.
+    fn decoy(&mut self, event: &Event) -> Option<(Height, BlockHash)> {
+        match event {
+            Event::FilterProcessed {
+                block,
+                height,
+                matched: false,
+                valid: true,
+                ..
+            } if self.config.decoy_rate > 0. && self.rng.f64() < self.config.decoy_rate => {
+                log::debug!(target: "p2p", "Requesting block {} as a decoy", block);
+
+                Some((*height, *block))
+            }
+            _ => None,
+        }
     }
 
-    /// Called when a new peer was negotiated.
-    pub fn peer_negotiated<T: BlockReader>(
+    /// Collect a filter received from a peer, until all the peers it was requested from have
+    /// sent it to us. Returns the filter to process once all responses are in.
+    ///
+    /// If the peers disagree, the peers whose filter doesn't match our filter header chain
+    /// are disconnected.
+    fn cross_check(
         &mut self,
-        socket: Socket,
+        from: PeerId,
         height: Height,
-        services: ServiceFlags,
-        link: ConnDirection,
-        persistent: bool,
-        tree: &T,
+        block_hash: BlockHash,
+        filter: BlockFilter,
+        prev_header: &FilterHeader,
+        header: &FilterHeader,
+    ) -> Option<BlockFilter> {
+        if !self.rescan.is_requested(height) {
+            return None;
+        }
+        let (redundancy, responses) = self.responses.get_mut(&height)?;
+
+        if responses.iter().any(|(peer, _)| *peer == from) {
+            return None;
+        }
+        responses.push((from, filter));
+
+        if responses.len() < *redundancy {
+            return None;
+        }
+        let (_, responses) = self.responses.remove(&height)?;
+        let (_, first) = responses.first()?;
+
+        if responses.iter().all(|(_, f)| f.content == first.content) {
+            return Some(first.clone());
+        }
+        self.upstream.event(Event::FilterConflict {
+            height,
+            block_hash,
+            peers: responses.iter().map(|(peer, _)| *peer).collect(),
+        });
+
+        let mut valid = None;
+        for (peer, filter) in responses {
+            if filter.filter_header(prev_header) == *header {
+                valid = Some(filter);
+            } else {
+                self.peers.remove(&peer);
+                self.upstream.disconnect(
+                    peer,
+                    DisconnectReason::PeerMisbehaving("cfilter: conflicting filter"),
+                );
+            }
+        }
+        valid
+    }
+
+    /// Record whether a filter received from the given peer matched our watchlist, and check
+    /// the peer's match rate for plausibility.
+    fn record_match(&mut self, addr: PeerId, matched: bool) {
+        let peer = if let Some(peer) = self.peers.get_mut(&addr) {
+            peer
+        } else {
+            return;
+        };
+        peer.processed += 1;
+
+        if matched {
+            peer.matched += 1;
+        }
+        if peer.processed < self.config.match_rate_sample_size {
+            return;
+        }
+        let (processed, matched) = (peer.processed, peer.matched);
+
+        // Start a new sample.
+        peer.processed = 0;
+        peer.matched = 0;
+
+        if matched as f64 / processed as f64 > self.config.max_match_rate {
+            self.upstream.event(Event::SuspiciousMatchRate {
+                peer: addr,
+                processed,
+                matched,
+            });
+            if self.config.disconnect_suspicious {
+                self.upstream.disconnect(
+                    addr,
+                    DisconnectReason::PeerMisbehaving("filters match suspiciously often"),
+                );
+            }
+        }
+    }
+
+    /// Called when a peer disconnected.
+    pub fn peer_disconnected(&mut self, id: &PeerId) {
+        self.peers.remove(id);
+        self.served.remove(id);
+        self.getcfilters.retain(|(peer, _), _| peer != id);
+
+        if matches!(self.getcfcheckpt, Some((peer, _, _)) if peer == *id) {
+            self.getcfcheckpt = None;
+        }
+    }
+
+    /// Get the `getcfheaders` and `getcfilters` requests awaiting a response.
+    pub fn inflight(&self) -> impl Iterator<Item = InFlight> + '_ {
+        let now = self.clock.local_time();
+        let timeout = self.config.request_timeout;
+
+        let cfheaders =
+            self.inflight
+                .iter()
+                .map(move |(stop_hash, (start_height, peer, expiry))| InFlight {
+                    peer: *peer,
+                    request: Request::GetCFHeaders {
+                        start_height: *start_height,
+                        stop_hash: *stop_hash,
+                    },
+                    age: now - (*expiry - timeout),
+                });
+        let cfilters = self
+            .getcfilters
+            .iter()
+            .filter(|(_, (range, _))| range.clone().any(|h| self.rescan.is_requested(h)))
+            .map(move |((peer, stop_hash), (range, sent_at))| InFlight {
+                peer: *peer,
+                request: Request::GetCFilters {
+                    start_height: *range.start(),
+                    stop_hash: *stop_hash,
+                },
+                age: now - *sent_at,
+            });
+
+        cfheaders.chain(cfilters)
+    }
+
+    /// Get the number of valid filters received from a peer.
+    pub fn filters_received(&self, id: &PeerId) -> usize {
+        self.peers.get(id).map_or(0, |p| p.filters)
+    }
+
+    /// Get the number of blocks matched by filters so far, not including decoys.
+    pub fn blocks_matched(&self) -> u64 {
+        self.matched
+    }
+
+    /// Called when a new peer was negotiated.
+    pub fn peer_negotiated<T: BlockReader>(
+        &mut self,
+        socket: Socket,
+        height: Height,
+        services: ServiceFlags,
+        link: ConnDirection,
+        persistent: bool,
+        tree: &T,
     ) {
         if !link.is_outbound() {
             return;
@@ -837,6 +1547,9 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
                 height,
                 socket,
                 persistent,
+                processed: 0,
+                matched: 0,
+                filters: 0,
             },
         );
         self.sync(tree);
@@ -844,6 +1557,9 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
 
     /// Attempt to sync the filter header chain.
     pub fn sync<T: BlockReader>(&mut self, tree: &T) {
+        if self.paused {
+            return;
+        }
         let filter_height = self.filters.height();
         let block_height = tree.height();
 
@@ -864,13 +1580,20 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
                 block_height,
             });
 
-            // We need to sync the filter header chain.
-            let start_height = self.filters.height() + 1;
+            // We need to sync the filter header chain. If we're far behind, checkpoints
+            // allow us to sync faster.
             let stop_height = tree.height();
+            let checkpoints = stop_height / CFCHECKPT_INTERVAL;
 
-            if let Some((peer, start_height, stop_hash)) =
-                self.send_getcfheaders(start_height..=stop_height, tree)
+            if self.config.max_cfheaders_requests > 1
+                && self.getcfcheckpt.is_none()
+                && self.checkpoints.len() < checkpoints as usize
+                && filter_height + CFCHECKPT_INTERVAL < checkpoints * CFCHECKPT_INTERVAL
             {
+                self.get_cfcheckpt(tree);
+            }
+
+            if let Some((peer, start_height, stop_hash)) = self.request_cfheaders(tree) {
                 self.upstream.event(Event::Syncing {
                     peer,
                     start_height,
@@ -900,38 +1623,122 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         }
     }
 
-    /// Send a `getcfheaders` message to a random peer.
-    ///
-    /// # Panics
+    /// Send `getcfheaders` requests for the filter headers we're missing, starting at our
+    /// filter header tip. Past the first batch, only batches that follow a checkpoint are
+    /// requested, since they can be verified on their own, and no more than
+    /// [`Config::max_cfheaders_requests`] are inflight.
     ///
-    /// Panics if the range is not within the bounds of the active chain.
-    ///
-    fn send_getcfheaders<T: BlockReader>(
+    /// Returns the peer, start height and stop hash of the first batch, if it was requested.
+    fn request_cfheaders<T: BlockReader>(
         &mut self,
-        range: RangeInclusive<Height>,
         tree: &T,
     ) -> Option<(PeerId, Height, BlockHash)> {
-        let (start, end) = (*range.start(), *range.end());
+        let batch = self
+            .config
+            .max_message_cfheaders
+            .clamp(1, MAX_MESSAGE_CFHEADERS) as Height;
+        let last_checkpoint = self.checkpoints.len() as Height * CFCHECKPT_INTERVAL;
+        let first_height = self.filters.height() + 1;
+        let stop_height = tree.height();
+        let mut start_height = first_height;
+        let mut first = None;
+
+        while start_height <= stop_height {
+            let mut end_height = Height::min(start_height + batch - 1, stop_height);
+            let checkpoint =
+                Height::min(end_height, last_checkpoint) / CFCHECKPT_INTERVAL * CFCHECKPT_INTERVAL;
+            let verifiable = checkpoint >= start_height;
+
+            if verifiable {
+                end_height = checkpoint;
+            }
+            let stop_hash = tree
+                .get_block_by_height(end_height)
+                .unwrap_or_else(|| panic!("{}: Stop height is out of bounds", source!()))
+                .block_hash();
+
+            if start_height == first_height {
+                first = self.get_cfheaders(start_height, stop_hash);
+            } else if self.peers.is_empty()
+                || self.inflight.len() >= self.config.max_cfheaders_requests
+            {
+                break;
+            } else if !self.ahead.contains_key(&start_height) {
+                self.get_cfheaders(start_height, stop_hash);
+            }
+            if !verifiable {
+                break;
+            }
+            start_height = end_height + 1;
+        }
+        first
+    }
+
+    /// Request filter header checkpoints up to our block header tip, from a random peer.
+    fn get_cfcheckpt<T: BlockReader>(&mut self, tree: &T) {
+        if let Some((peer, _)) = self.peers.sample() {
+            let (_, tip) = tree.tip();
+            let timeout = self.config.request_timeout;
+            let expiry = self.clock.local_time() + timeout;
 
-        debug_assert!(start <= end);
-        debug_assert!(!range.is_empty());
+            self.upstream.get_cfcheckpt(*peer, tip, timeout);
+            self.getcfcheckpt = Some((*peer, tip, expiry));
+        }
+    }
 
-        if range.is_empty() {
+    /// Count a filter request from a peer against its budget. Fails if the peer has
+    /// exhausted its budget for the current period.
+    fn charge(&mut self, from: PeerId) -> Result<(), Error> {
+        if !self
+            .served
+            .charge(from, self.config.serve_budget, self.clock.local_time())
+        {
+            return Err(Error::Ignored {
+                msg: "filter request budget exceeded",
+                from,
+            });
+        }
+        Ok(())
+    }
+
+    /// Get the filter header checkpoint at the given height, if any.
+    fn checkpoint(&self, height: Height) -> Option<FilterHeader> {
+        if height == 0 || height % CFCHECKPT_INTERVAL != 0 {
             return None;
         }
-        // Cap requested header count.
-        let count = usize::min(MAX_MESSAGE_CFHEADERS, (end - start + 1) as usize);
-        let start_height = start;
-        let stop_hash = {
-            let stop_height = start + count as Height - 1;
-            let stop_block = tree
-                .get_block_by_height(stop_height)
-                .unwrap_or_else(|| panic!("{}: Stop height is out of bounds", source!()));
+        self.checkpoints
+            .get((height / CFCHECKPT_INTERVAL - 1) as usize)
+            .copied()
+    }
 
-            stop_block.block_hash()
-        };
+    /// Stop relying on filter header checkpoints, and drop the headers they verified.
+    /// Filter headers are then synced one batch after the other.
+    fn distrust_checkpoints(&mut self) {
+        log::debug!("[spv] Filter headers don't match checkpoints, dropping checkpoints");
+
+        self.checkpoints.clear();
+        self.ahead.clear();
+    }
+
+    /// Import filter headers following our filter header tip. Returns the new height.
+    fn import_cfheaders<T: BlockReader>(
+        &mut self,
+        headers: Vec<(FilterHash, FilterHeader)>,
+        stop_hash: BlockHash,
+        tree: &T,
+    ) -> Result<Height, Error> {
+        let start_height = self.filters.height();
+        let count = headers.len();
+        let height = self.filters.import_headers(headers)?;
+
+        self.upstream.event(Event::FilterHeadersImported {
+            count,
+            height,
+            block_hash: stop_hash,
+        });
+        self.headers_imported(start_height, height, tree).unwrap(); // TODO
 
-        self.get_cfheaders(start_height, stop_hash)
+        Ok(height)
     }
 
     /// Lower level function that takes a start height and stop hash.
@@ -945,10 +1752,24 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
             return None;
         }
         // TODO: We should select peers that are caught up to the requested height.
-        if let Some((peer, _)) = self.peers.sample() {
+        // Prefer peers we're not already waiting on.
+        if let Some((peer, _)) = self
+            .peers
+            .sample_with(|addr, _| !self.inflight.values().any(|(_, peer, _)| peer == addr))
+            .or_else(|| self.peers.sample())
+        {
             let time = self.clock.local_time();
             let timeout = self.config.request_timeout;
 
+            #[cfg(feature = "tracing")]
+            let _span = tracing::debug_span!(
+                "getcfheaders",
+                peer = %peer,
+                start_height,
+                %stop_hash,
+            )
+            .entered();
+
             self.upstream
                 .get_cfheaders(*peer, start_height, stop_hash, timeout);
             self.inflight
@@ -978,7 +1799,7 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         stop: Height,
         tree: &T,
     ) -> Result<(), GetFiltersError> {
-        if !self.rescan.active {
+        if !self.rescan.active || self.paused {
             return Ok(());
         }
 
@@ -1026,6 +1847,7 @@ impl Iterator for HeightIterator {
 mod tests {
     use std::iter;
     use std::ops::RangeBounds;
+    use std::rc::Rc;
 
     use nakamoto_common::bitcoin;
     use nakamoto_common::bitcoin_hashes;
@@ -1102,6 +1924,59 @@ mod tests {
             )
         }
 
+        /// Setup a filter manager without filter headers, that syncs them from checkpoints,
+        /// with a peer at the tip of a chain of the given height. Returns the filter manager,
+        /// the block tree, the chain and its filter headers.
+        pub fn setup_checkpoints(
+            network: Network,
+            height: Height,
+            remote: PeerId,
+        ) -> (
+            FilterManager<FilterCache<store::Memory<StoredHeader>>, Outbox, LocalTime>,
+            BlockCache<store::Memory<BlockHeader>>,
+            NonEmpty<bitcoin::Block>,
+            Vec<(FilterHash, FilterHeader)>,
+        ) {
+            let mut rng = fastrand::Rng::with_seed(772092983);
+            let mut cbfmgr = {
+                let cache = FilterCache::load(store::memory::Memory::genesis(network)).unwrap();
+                let upstream = Outbox::new(network, PROTOCOL_VERSION);
+                let config = Config {
+                    max_message_cfheaders: 1000,
+                    max_cfheaders_requests: 3,
+                    ..Config::default()
+                };
+                FilterManager::new(
+                    config,
+                    fastrand::Rng::new(),
+                    cache,
+                    upstream,
+                    LocalTime::now(),
+                )
+            };
+
+            let chain = gen::blockchain(network.genesis_block(), height, &mut rng);
+            let cfheaders =
+                gen::cfheaders_from_blocks(FilterHeader::genesis(network), chain.tail.iter());
+            let tree = {
+                let params = network.params();
+                let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+                BlockCache::from(store::Memory::new(headers), params, &[]).unwrap()
+            };
+
+            cbfmgr.initialize(&tree);
+            cbfmgr.peer_negotiated(
+                Socket::new(remote),
+                height,
+                REQUIRED_SERVICES,
+                ConnDirection::Outbound,
+                false,
+                &tree,
+            );
+
+            (cbfmgr, tree, chain, cfheaders)
+        }
+
         pub fn cfilters<'a>(
             blocks: impl IntoIterator<Item = &'a bitcoin::Block> + 'a,
         ) -> impl Iterator<Item = CFilter> + 'a {
@@ -1319,6 +2194,168 @@ mod tests {
         todo!()
     }
 
+    /// Test that peers whose filters match too often are reported, and disconnected.
+    #[test]
+    fn test_suspicious_match_rate() {
+        let network = Network::Regtest;
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, _) = util::setup(network, 16, 0, RefClock::from(time));
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+
+        cbfmgr.config.disconnect_suspicious = true;
+        cbfmgr.peer_negotiated(
+            Socket::new(remote),
+            16,
+            REQUIRED_SERVICES,
+            ConnDirection::Outbound,
+            false,
+            &tree,
+        );
+        cbfmgr.upstream.drain().for_each(drop);
+
+        // A plausible number of matches.
+        for i in 0..DEFAULT_MATCH_RATE_SAMPLE_SIZE {
+            cbfmgr.record_match(remote, i == 0);
+        }
+        assert!(util::events(cbfmgr.upstream.drain())
+            .all(|e| !matches!(e, Event::SuspiciousMatchRate { .. })));
+
+        // Every filter matches.
+        for _ in 0..DEFAULT_MATCH_RATE_SAMPLE_SIZE {
+            cbfmgr.record_match(remote, true);
+        }
+        let outputs = cbfmgr.upstream.drain().collect::<Vec<_>>();
+        let event = outputs.iter().find_map(|o| match o {
+            fsm::Io::NotifySubscribers(fsm::Event::Filter(e)) => Some(e.clone()),
+            _ => None,
+        });
+
+        assert_matches!(
+            event,
+            Some(Event::SuspiciousMatchRate { peer, matched, .. })
+            if peer == remote && matched == DEFAULT_MATCH_RATE_SAMPLE_SIZE
+        );
+        assert!(outputs
+            .iter()
+            .any(|o| matches!(o, fsm::Io::DisconnectPeer(addr, _) if *addr == remote)));
+    }
+
+    /// Test that filters fetched from several peers are cross-checked, and that peers sending
+    /// conflicting filters are disconnected.
+    #[test]
+    fn test_filter_redundancy() {
+        let birth = 11;
+        let best = 42;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let network = Network::Regtest;
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, 0, RefClock::from(time));
+        let (alice, bob): (PeerId, PeerId) = (
+            ([88, 88, 88, 88], 8333).into(),
+            ([99, 99, 99, 99], 8333).into(),
+        );
+        let cfilters = util::cfilters(chain.iter()).collect::<Vec<_>>();
+
+        cbfmgr.config.filter_redundancy = 2;
+        for peer in [alice, bob] {
+            cbfmgr.peer_negotiated(
+                Socket::new(peer),
+                best,
+                REQUIRED_SERVICES,
+                ConnDirection::Outbound,
+                false,
+                &tree,
+            );
+        }
+        cbfmgr.rescan(
+            Bound::Included(birth),
+            Bound::Included(birth + 1),
+            vec![gen::script(&mut rng)],
+            &tree,
+        );
+        for peer in [alice, bob] {
+            output::test::messages_from(&mut cbfmgr.upstream, &peer)
+                .find(|m| matches!(m, NetworkMessage::GetCFilters(_)))
+                .expect("`getcfilters` is sent to all peers");
+        }
+
+        // The filter isn't processed until both peers have sent it.
+        cbfmgr
+            .received_cfilter(&alice, cfilters[birth as usize].clone(), &tree)
+            .unwrap();
+        assert_eq!(cbfmgr.rescan.current, birth);
+
+        cbfmgr
+            .received_cfilter(&bob, cfilters[birth as usize].clone(), &tree)
+            .unwrap();
+        assert_eq!(cbfmgr.rescan.current, birth + 1);
+
+        // Bob lies about the next filter.
+        let bogus = CFilter {
+            filter: vec![0x1, 0x2, 0x3],
+            ..cfilters[birth as usize + 1].clone()
+        };
+        cbfmgr
+            .received_cfilter(&alice, cfilters[birth as usize + 1].clone(), &tree)
+            .unwrap();
+        cbfmgr.received_cfilter(&bob, bogus, &tree).unwrap();
+        assert_eq!(cbfmgr.rescan.current, birth + 2);
+
+        let outputs = cbfmgr.upstream.drain().collect::<Vec<_>>();
+        assert!(outputs.iter().any(|o| matches!(
+            o,
+            fsm::Io::NotifySubscribers(fsm::Event::Filter(Event::FilterConflict { height, .. }))
+            if *height == birth + 1
+        )));
+        assert!(outputs
+            .iter()
+            .any(|o| matches!(o, fsm::Io::DisconnectPeer(addr, _) if *addr == bob)));
+        assert!(!outputs
+            .iter()
+            .any(|o| matches!(o, fsm::Io::DisconnectPeer(addr, _) if *addr == alice)));
+    }
+
+    /// Test that with fewer peers than the configured redundancy, filters are processed once
+    /// all the peers they were requested from have sent them.
+    #[test]
+    fn test_filter_redundancy_fewer_peers() {
+        let birth = 11;
+        let best = 42;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let network = Network::Regtest;
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, 0, RefClock::from(time));
+        let (alice, bob): (PeerId, PeerId) = (
+            ([88, 88, 88, 88], 8333).into(),
+            ([99, 99, 99, 99], 8333).into(),
+        );
+        let cfilters = util::cfilters(chain.iter()).collect::<Vec<_>>();
+
+        cbfmgr.config.filter_redundancy = 3;
+        for peer in [alice, bob] {
+            cbfmgr.peer_negotiated(
+                Socket::new(peer),
+                best,
+                REQUIRED_SERVICES,
+                ConnDirection::Outbound,
+                false,
+                &tree,
+            );
+        }
+        cbfmgr.rescan(
+            Bound::Included(birth),
+            Bound::Included(birth),
+            vec![gen::script(&mut rng)],
+            &tree,
+        );
+        for peer in [alice, bob] {
+            cbfmgr
+                .received_cfilter(&peer, cfilters[birth as usize].clone(), &tree)
+                .unwrap();
+        }
+        assert_eq!(cbfmgr.rescan.current, birth + 1);
+    }
+
     /// Test that we can specify a birth date in the future.
     #[test]
     #[ignore]
@@ -1404,22 +2441,21 @@ mod tests {
             .expect("Rescanning should trigger filters to be fetched");
     }
 
-    /// Test that `getcfilters` request is retried.
+    /// Test that rescanning from below the pruned height starts at the first block kept.
     #[test]
-    fn test_rescan_getcfilters_retry() {
-        let birth = 11;
-        let best = 42;
+    fn test_rescan_below_pruned_height() {
+        let network = Network::Regtest;
+        let interval = Params::new(network.into()).difficulty_adjustment_interval();
+        let best = interval + 100;
         let mut rng = fastrand::Rng::new();
         let time = LocalTime::now();
-        let network = Network::Regtest;
-        let filter_type = 0x0;
-        let (mut cbfmgr, tree, chain) = util::setup(network, best, 0, RefClock::from(time));
+        let (mut cbfmgr, mut tree, _) = util::setup(network, best, 0, RefClock::from(time));
         let remote: PeerId = ([88, 88, 88, 88], 8333).into();
-        let previous_filter_header = FilterHeader::genesis(network);
-        let cfheaders = util::cfheaders(previous_filter_header, &chain.tail);
-        let cfilters = util::cfilters(chain.iter()).collect::<Vec<_>>();
+        let tip = tree.get_block_by_height(best).unwrap().block_hash();
+
+        assert_eq!(tree.prune(50).unwrap(), 50);
+        assert_eq!(tree.pruned(), 49);
 
-        cbfmgr.filters.clear().unwrap();
         cbfmgr.initialize(&tree);
         cbfmgr.peer_negotiated(
             Socket::new(remote),
@@ -1429,10 +2465,53 @@ mod tests {
             false,
             &tree,
         );
-        cbfmgr
-            .received_cfheaders(&remote, cfheaders, &tree)
-            .unwrap();
-        assert_eq!(cbfmgr.filters.height(), best);
+        cbfmgr.rescan(
+            Bound::Included(11),
+            Bound::Unbounded,
+            vec![gen::script(&mut rng)],
+            &tree,
+        );
+        assert_eq!(cbfmgr.rescan.start, 50);
+
+        let expected = GetCFilters {
+            filter_type: 0x0,
+            start_height: 50,
+            stop_hash: tip,
+        };
+        output::test::messages_from(&mut cbfmgr.upstream, &remote)
+            .find(|m| matches!(m, NetworkMessage::GetCFilters(msg) if msg == &expected))
+            .expect("Filters should be fetched from the first block kept");
+    }
+
+    /// Test that `getcfilters` request is retried.
+    #[test]
+    fn test_rescan_getcfilters_retry() {
+        let birth = 11;
+        let best = 42;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let network = Network::Regtest;
+        let filter_type = 0x0;
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, 0, RefClock::from(time));
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let previous_filter_header = FilterHeader::genesis(network);
+        let cfheaders = util::cfheaders(previous_filter_header, &chain.tail);
+        let cfilters = util::cfilters(chain.iter()).collect::<Vec<_>>();
+
+        cbfmgr.filters.clear().unwrap();
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            Socket::new(remote),
+            best,
+            REQUIRED_SERVICES,
+            ConnDirection::Outbound,
+            false,
+            &tree,
+        );
+        cbfmgr
+            .received_cfheaders(&remote, cfheaders, &tree)
+            .unwrap();
+        assert_eq!(cbfmgr.filters.height(), best);
 
         // Start rescan.
         cbfmgr.rescan(
@@ -1488,6 +2567,51 @@ mod tests {
         assert_eq!(cbfmgr.rescan.current, current + 1);
     }
 
+    /// Test that filter headers that don't match a checkpoint are rejected.
+    #[test]
+    fn test_cfheaders_checkpoint_mismatch() {
+        let best = 16;
+        let checkpoint = 8;
+        let mut rng = fastrand::Rng::new();
+        let network = Network::Regtest;
+        let (mut cbfmgr, tree, chain) =
+            util::setup(network, best, 0, RefClock::from(LocalTime::now()));
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let previous_filter_header = FilterHeader::genesis(network);
+        let (_, expected) = cbfmgr.filters.get_header(checkpoint).unwrap();
+        let cfheaders = util::cfheaders(previous_filter_header, &chain.tail);
+
+        // Filter headers of a different chain, for the same range of blocks.
+        let fork = gen::blockchain(network.genesis_block(), best, &mut rng);
+        let mut forged = util::cfheaders(previous_filter_header, &fork.tail);
+        forged.stop_hash = cfheaders.stop_hash;
+
+        cbfmgr.config.checkpoints = vec![(checkpoint, expected)];
+        cbfmgr.filters.clear().unwrap();
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            Socket::new(remote),
+            best,
+            REQUIRED_SERVICES,
+            ConnDirection::Outbound,
+            false,
+            &tree,
+        );
+
+        assert_matches!(
+            cbfmgr.received_cfheaders(&remote, forged, &tree),
+            Err(Error::InvalidMessage { from, .. }) if from == remote
+        );
+        assert_eq!(cbfmgr.filters.height(), 0);
+
+        // The filter headers are requested again, and the valid ones are imported.
+        cbfmgr.sync(&tree);
+        cbfmgr
+            .received_cfheaders(&remote, cfheaders, &tree)
+            .unwrap();
+        assert_eq!(cbfmgr.filters.height(), best);
+    }
+
     /// Test that if we start with our cfheader chain behind our header
     /// chain, we immediately try to catch up.
     #[test]
@@ -1552,6 +2676,345 @@ mod tests {
         }).expect("GetCFHeaders request");
     }
 
+    #[test]
+    fn test_cfheaders_checkpoints() {
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let height = 2500;
+        let (mut cbfmgr, tree, chain, cfheaders) = util::setup_checkpoints(network, height, remote);
+        let blocks = &chain.tail;
+        let tip = chain.last().block_hash();
+
+        let requests = |msgs: Vec<NetworkMessage>| {
+            msgs.into_iter()
+                .filter_map(|m| match m {
+                    NetworkMessage::GetCFHeaders(GetCFHeaders {
+                        start_height,
+                        stop_hash,
+                        ..
+                    }) => Some((start_height as Height, stop_hash)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let stop_hash = |height: Height| blocks[height as usize - 1].block_hash();
+
+        // Without checkpoints, only the first batch can be requested.
+        let msgs = output::test::messages_from(&mut cbfmgr.upstream, &remote).collect::<Vec<_>>();
+        assert!(msgs
+            .iter()
+            .any(|m| matches!(m, NetworkMessage::GetCFCheckpt(msg) if msg.stop_hash == tip)));
+        assert_eq!(requests(msgs), vec![(1, stop_hash(1000))]);
+
+        cbfmgr
+            .received_cfcheckpt(
+                &remote,
+                CFCheckpt {
+                    filter_type: 0x0,
+                    stop_hash: tip,
+                    filter_headers: vec![cfheaders[999].1, cfheaders[1999].1],
+                },
+                &tree,
+            )
+            .unwrap();
+
+        // With checkpoints, the following batches are requested in parallel.
+        assert_eq!(
+            requests(output::test::messages_from(&mut cbfmgr.upstream, &remote).collect()),
+            vec![(1001, stop_hash(2000)), (2001, tip)]
+        );
+
+        // Headers received ahead of our tip are kept until the gap is filled.
+        cbfmgr
+            .received_cfheaders(
+                &remote,
+                util::cfheaders(cfheaders[999].1, &blocks[1000..2000]),
+                &tree,
+            )
+            .unwrap();
+        assert_eq!(cbfmgr.filters.height(), 0);
+
+        cbfmgr
+            .received_cfheaders(
+                &remote,
+                util::cfheaders(FilterHeader::genesis(network), &blocks[..1000]),
+                &tree,
+            )
+            .unwrap();
+        assert_eq!(cbfmgr.filters.height(), 2000);
+
+        cbfmgr
+            .received_cfheaders(
+                &remote,
+                util::cfheaders(cfheaders[1999].1, &blocks[2000..]),
+                &tree,
+            )
+            .unwrap();
+        assert_eq!(cbfmgr.filters.height(), height);
+        assert_eq!(cbfmgr.filters.tip().1, &cfheaders.last().unwrap().1);
+    }
+
+    #[test]
+    fn test_cfheaders_checkpoint_mismatch() {
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let height = 2500;
+        let (mut cbfmgr, tree, chain, cfheaders) = util::setup_checkpoints(network, height, remote);
+        let blocks = &chain.tail;
+        let tip = chain.last().block_hash();
+        // The second checkpoint is bogus.
+        cbfmgr
+            .received_cfcheckpt(
+                &remote,
+                CFCheckpt {
+                    filter_type: 0x0,
+                    stop_hash: tip,
+                    filter_headers: vec![cfheaders[999].1, cfheaders[0].1],
+                },
+                &tree,
+            )
+            .unwrap();
+
+        // Headers ending at the bogus checkpoint are rejected, and checkpoints are dropped.
+        assert_matches!(
+            cbfmgr.received_cfheaders(
+                &remote,
+                util::cfheaders(cfheaders[999].1, &blocks[1000..2000]),
+                &tree,
+            ),
+            Err(Error::Ignored { .. })
+        );
+        assert!(cbfmgr.checkpoints.is_empty());
+
+        // Syncing carries on without them.
+        cbfmgr
+            .received_cfheaders(
+                &remote,
+                util::cfheaders(FilterHeader::genesis(network), &blocks[..1000]),
+                &tree,
+            )
+            .unwrap();
+        assert_eq!(cbfmgr.filters.height(), 1000);
+    }
+
+    #[test]
+    fn test_serve_filters() {
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let time = LocalTime::now();
+        let clock = RefClock::from(time);
+        let best = 1000;
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, 0, clock.clone());
+
+        cbfmgr.config.serve_filters = true;
+        cbfmgr.config.serve_budget = 3;
+
+        for (height, block) in chain.iter().enumerate().skip(1).take(10) {
+            cbfmgr
+                .rescan
+                .cache
+                .push(height as Height, Rc::new(gen::cfilter(block)));
+        }
+        let getcfilters = |start_height: u32, stop_height: usize| GetCFilters {
+            filter_type: 0x0,
+            start_height,
+            stop_hash: chain[stop_height].block_hash(),
+        };
+
+        // Cached filters are served.
+        cbfmgr
+            .received_getcfilters(&remote, getcfilters(1, 10), &tree)
+            .unwrap();
+        let served = output::test::messages_from(&mut cbfmgr.upstream, &remote)
+            .filter_map(|m| match m {
+                NetworkMessage::CFilter(msg) => Some(msg),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            served,
+            util::cfilters(chain.iter().skip(1).take(10)).collect::<Vec<_>>()
+        );
+
+        // Filters that aren't all cached are not served.
+        assert_matches!(
+            cbfmgr.received_getcfilters(&remote, getcfilters(5, 15), &tree),
+            Err(Error::Ignored { .. })
+        );
+        assert_matches!(
+            cbfmgr.received_getcfilters(&remote, getcfilters(10, 5), &tree),
+            Err(Error::InvalidMessage { .. })
+        );
+
+        // Filter header checkpoints are served.
+        cbfmgr
+            .received_getcfcheckpt(
+                &remote,
+                GetCFCheckpt {
+                    filter_type: 0x0,
+                    stop_hash: chain.last().block_hash(),
+                },
+                &tree,
+            )
+            .unwrap();
+        let (_, checkpoint) = cbfmgr.filters.get_header(CFCHECKPT_INTERVAL).unwrap();
+        assert!(output::test::messages_from(&mut cbfmgr.upstream, &remote)
+            .any(|m| matches!(m, NetworkMessage::CFCheckpt(msg) if msg.filter_headers == vec![checkpoint])));
+
+        // Once the budget is exhausted, requests are ignored until the next period.
+        assert_matches!(
+            cbfmgr.received_getcfilters(&remote, getcfilters(1, 10), &tree),
+            Err(Error::Ignored { .. })
+        );
+        clock.elapse(fsm::SERVE_BUDGET_PERIOD);
+        cbfmgr
+            .received_getcfilters(&remote, getcfilters(1, 10), &tree)
+            .unwrap();
+
+        // Nothing is served when disabled.
+        cbfmgr.config.serve_filters = false;
+        clock.elapse(fsm::SERVE_BUDGET_PERIOD);
+        assert_matches!(
+            cbfmgr.received_getcfilters(&remote, getcfilters(1, 10), &tree),
+            Err(Error::Ignored { .. })
+        );
+    }
+
+    #[test]
+    fn test_pause_resume() {
+        let cfheader_height = 10;
+        let header_height = 15;
+
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::with_seed(772092983);
+        let time = LocalTime::now();
+
+        let mut cbfmgr = {
+            let cache = FilterCache::load(store::memory::Memory::genesis(network)).unwrap();
+            let rng = fastrand::Rng::new();
+            let upstream = Outbox::new(network, PROTOCOL_VERSION);
+            FilterManager::new(Config::default(), rng, cache, upstream, time)
+        };
+
+        let chain = gen::blockchain(network.genesis_block(), header_height, &mut rng);
+        let cfheaders = gen::cfheaders_from_blocks(
+            FilterHeader::genesis(network),
+            chain.tail.iter().take(cfheader_height),
+        );
+        cbfmgr.filters.import_headers(cfheaders).unwrap();
+
+        let tree = {
+            let params = network.params();
+            let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+            BlockCache::from(store::Memory::new(headers), params, &[]).unwrap()
+        };
+        cbfmgr.pause();
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            Socket::new(remote),
+            header_height,
+            REQUIRED_SERVICES,
+            ConnDirection::Outbound,
+            false,
+            &tree,
+        );
+        assert!(cbfmgr.is_paused());
+        assert!(
+            !output::test::messages_from(&mut cbfmgr.upstream, &remote)
+                .any(|m| matches!(m, NetworkMessage::GetCFHeaders(_))),
+            "no requests are made while paused"
+        );
+
+        cbfmgr.resume(&tree);
+        assert!(!cbfmgr.is_paused());
+        output::test::messages_from(&mut cbfmgr.upstream, &remote)
+            .find(|m| {
+                matches!(
+                    m,
+                    NetworkMessage::GetCFHeaders(GetCFHeaders { start_height, .. })
+                    if (*start_height as usize) == (cfheader_height + 1)
+                )
+            })
+            .expect("syncing resumes from the current height");
+    }
+
+    #[test]
+    fn test_resume_requests_skipped_filters() {
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let header_height: Height = 16;
+        let cfheader_height: usize = 10;
+
+        let mut cbfmgr = {
+            let cache = FilterCache::load(store::memory::Memory::genesis(network)).unwrap();
+            let rng = fastrand::Rng::new();
+            let upstream = Outbox::new(network, PROTOCOL_VERSION);
+            FilterManager::new(Config::default(), rng, cache, upstream, time)
+        };
+        let chain = gen::blockchain(network.genesis_block(), header_height, &mut rng);
+        let cfheaders =
+            gen::cfheaders_from_blocks(FilterHeader::genesis(network), chain.tail.iter());
+        cbfmgr
+            .filters
+            .import_headers(cfheaders[..cfheader_height].to_vec())
+            .unwrap();
+
+        let tree = {
+            let params = network.params();
+            let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+            BlockCache::from(store::Memory::new(headers), params, &[]).unwrap()
+        };
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            Socket::new(remote),
+            header_height,
+            REQUIRED_SERVICES,
+            ConnDirection::Outbound,
+            false,
+            &tree,
+        );
+        cbfmgr.rescan(
+            Bound::Included(1),
+            Bound::Unbounded,
+            vec![gen::script(&mut rng)],
+            &tree,
+        );
+        cbfmgr.upstream.drain().for_each(drop);
+
+        // The response to the inflight filter header request is processed while paused,
+        // but the corresponding filters aren't requested.
+        cbfmgr.pause();
+        cbfmgr
+            .received_cfheaders(
+                &remote,
+                util::cfheaders(
+                    cfheaders[cfheader_height - 1].1,
+                    &chain.tail[cfheader_height..],
+                ),
+                &tree,
+            )
+            .unwrap();
+        assert_eq!(cbfmgr.filters.height(), header_height);
+        assert!(!output::test::messages_from(&mut cbfmgr.upstream, &remote)
+            .any(|m| matches!(m, NetworkMessage::GetCFilters(_))));
+
+        // They are requested on resume.
+        cbfmgr.resume(&tree);
+        output::test::messages_from(&mut cbfmgr.upstream, &remote)
+            .find(|m| {
+                matches!(
+                    m,
+                    NetworkMessage::GetCFilters(GetCFilters { start_height, stop_hash, .. })
+                    if *start_height as usize == cfheader_height + 1
+                        && *stop_hash == chain.last().block_hash()
+                )
+            })
+            .expect("skipped filters are requested");
+    }
+
     #[test]
     fn test_partial_cache_hit_overlap_max() {
         // Head              8
@@ -1935,6 +3398,56 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_set_watchlist() {
+        let mut rng = fastrand::Rng::new();
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let birth = 11;
+        let best = 17;
+
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, DEFAULT_FILTER_CACHE_SIZE, time);
+        let (watch, matches, _) = gen::watchlist_rng(birth, chain.iter(), &mut rng);
+        let other = vec![Script::new()];
+
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            Socket::new(remote),
+            best,
+            REQUIRED_SERVICES,
+            ConnDirection::Outbound,
+            false,
+            &tree,
+        );
+        cbfmgr.rescan(
+            Bound::Included(birth),
+            Bound::Included(best),
+            other.clone(),
+            &tree,
+        );
+        for msg in util::cfilters(chain.iter().take(best as usize + 1)) {
+            cbfmgr.received_cfilter(&remote, msg, &tree).unwrap();
+        }
+        assert!(!cbfmgr.rescan.active, "The rescan is complete");
+
+        // Without a rescan, the watchlist is replaced and nothing is matched.
+        let matched = cbfmgr.set_watchlist(watch.clone(), false, &tree);
+        assert!(matched.is_empty());
+        assert_eq!(cbfmgr.rescan.watch, watch.iter().cloned().collect());
+        assert!(!cbfmgr.rescan.active);
+
+        // With a rescan, the range of the last rescan is scanned again.
+        let matched = cbfmgr.set_watchlist(watch.clone(), true, &tree);
+        assert_eq!(matched.iter().map(|(h, _)| *h).collect::<Vec<_>>(), matches);
+        assert_eq!(cbfmgr.rescan.start, birth);
+        assert_eq!(cbfmgr.rescan.end, Some(best));
+
+        let matched = cbfmgr.set_watchlist(other.clone(), true, &tree);
+        assert!(matched.is_empty());
+        assert_eq!(cbfmgr.rescan.watch, other.into_iter().collect());
+    }
+
     // TODO: Test that we panic if we get filters beyond the allowed range
     // TODO: Test rescan when the filter header chain is not caught up to the start of the range.
 
@@ -1992,6 +3505,59 @@ mod tests {
         assert_eq!(cbfmgr.rescan.watch, watch.into_iter().collect());
     }
 
+    #[test]
+    fn test_rescan_decoys() {
+        let mut rng = fastrand::Rng::new();
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let birth = 11;
+        let best = 17;
+
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, DEFAULT_FILTER_CACHE_SIZE, time);
+        let (watch, matches, _) = gen::watchlist_rng(birth, chain.iter(), &mut rng);
+
+        cbfmgr.set_decoy_rate(1.);
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            Socket::new(remote),
+            best,
+            REQUIRED_SERVICES,
+            ConnDirection::Outbound,
+            false,
+            &tree,
+        );
+        cbfmgr.rescan(Bound::Included(birth), Bound::Unbounded, watch, &tree);
+
+        let mut fetched = Vec::new();
+        for msg in util::cfilters(chain.iter().take(best as usize + 1)) {
+            fetched.extend(
+                cbfmgr
+                    .received_cfilter(&remote, msg, &tree)
+                    .unwrap()
+                    .into_iter()
+                    .map(|(h, _)| h),
+            );
+        }
+        fetched.sort_unstable();
+
+        // Every block in the rescan range is fetched, whether it matched or not.
+        assert_eq!(fetched, (birth..=best).collect::<Vec<_>>());
+
+        // Only actual matches are reported as such.
+        let matched = util::events(cbfmgr.upstream.drain())
+            .filter_map(|e| match e {
+                Event::FilterProcessed {
+                    height,
+                    matched: true,
+                    ..
+                } => Some(height),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(matched, matches);
+    }
+
     /// Test that we re-request all filters after blocks are reverted and eventually
     /// get back in sync.
     #[test]
@@ -38,9 +38,11 @@ pub struct Rescan {
 }
 
 impl Rescan {
-    /// Create a new rescan state.
-    pub fn new(cache: usize) -> Self {
-        let cache = FilterCache::new(cache);
+    /// Create a new rescan state, with the given filter cache size in bytes, and
+    /// maximum number of cached filters.
+    pub fn new(cache: usize, cache_len: Option<usize>) -> Self {
+        let mut cache = FilterCache::new(cache);
+        cache.set_max_len(cache_len);
 
         Self {
             cache,
@@ -81,6 +83,11 @@ impl Rescan {
         self.requested.clear();
     }
 
+    /// Check whether the filter at the given height was requested and not yet received.
+    pub fn is_requested(&self, height: Height) -> bool {
+        self.requested.contains(&height)
+    }
+
     /// Rollback state to height.
     pub fn rollback(&mut self, to: Height) {
         self.cache.rollback(to)
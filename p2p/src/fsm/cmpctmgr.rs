@@ -0,0 +1,529 @@
+//! Compact Block Manager.
+//!
+//! Implements BIP 152 compact block relay. Peers that support it are asked to announce new
+//! blocks with `cmpctblock` messages, which carry the block header and a short identifier
+//! for each transaction in the block. This lets new tips propagate to us in a single
+//! message.
+//!
+//! Blocks we are waiting for are reconstructed from the transactions we already know of,
+//! and any missing transactions are requested with `getblocktxn`. Since we don't keep a
+//! full mempool, most transactions will usually have to be requested.
+//!
+use thiserror::Error;
+
+use nakamoto_common::bitcoin::network::constants::ServiceFlags;
+use nakamoto_common::bitcoin::util::bip152::{
+    BlockTransactions, BlockTransactionsRequest, HeaderAndShortIds, ShortId,
+};
+use nakamoto_common::bitcoin::{Block, BlockHeader, Transaction};
+use nakamoto_common::block::BlockHash;
+use nakamoto_common::collections::{AddressBook, HashMap};
+
+use super::output::Wire;
+use super::{ConnDirection, PeerId, Socket};
+
+/// Services required from peers for compact block relay. We only support version 2 of the
+/// protocol, which uses witness transaction ids.
+pub const REQUIRED_SERVICES: ServiceFlags = ServiceFlags::WITNESS;
+
+/// Minimum protocol version required for compact block relay.
+pub const MIN_PROTOCOL_VERSION: u32 = 70014;
+
+/// Compact block protocol version we support.
+pub const COMPACT_BLOCK_VERSION: u64 = 2;
+
+/// Maximum number of peers we ask to announce new blocks with compact blocks, ie. in
+/// "high-bandwidth" mode. Other peers announce new blocks with headers.
+pub const MAX_HIGH_BANDWIDTH_PEERS: usize = 3;
+
+/// Maximum number of partially reconstructed blocks we keep around.
+const MAX_PENDING_BLOCKS: usize = 16;
+
+/// An error originating in the compact block manager.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The message was ignored.
+    #[error("ignoring message from {from}: {msg}")]
+    Ignored {
+        /// Message that was ignored.
+        msg: &'static str,
+        /// Message sender.
+        from: PeerId,
+    },
+    /// Error due to an invalid peer message.
+    #[error("invalid message received from {from}: {reason}")]
+    InvalidMessage {
+        /// Message sender.
+        from: PeerId,
+        /// Reason why the message is invalid.
+        reason: &'static str,
+    },
+}
+
+/// An event originating in the compact block manager.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// Compact block relay was negotiated with a peer.
+    Negotiated {
+        /// The peer.
+        peer: PeerId,
+        /// Whether the peer announces new blocks with compact blocks.
+        high_bandwidth: bool,
+    },
+    /// A block was reconstructed from a compact block.
+    BlockReconstructed {
+        /// Peer we received the compact block from.
+        peer: PeerId,
+        /// Block hash.
+        block: BlockHash,
+        /// Number of transactions that had to be requested from the peer.
+        requested: usize,
+    },
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::Negotiated {
+                peer,
+                high_bandwidth,
+            } => {
+                write!(
+                    fmt,
+                    "Compact block relay negotiated with {} (high-bandwidth = {})",
+                    peer, high_bandwidth
+                )
+            }
+            Event::BlockReconstructed {
+                peer,
+                block,
+                requested,
+            } => {
+                write!(
+                    fmt,
+                    "Block {} reconstructed from {} with {} requested transaction(s)",
+                    block, peer, requested
+                )
+            }
+        }
+    }
+}
+
+/// Compact block manager configuration.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Whether to use compact block relay with peers that support it.
+    pub enabled: bool,
+}
+
+/// A compact block peer.
+#[derive(Debug)]
+struct Peer {
+    #[allow(dead_code)]
+    socket: Socket,
+    /// Whether this peer announces new blocks with compact blocks.
+    high_bandwidth: bool,
+}
+
+/// A block waiting for missing transactions.
+#[derive(Debug)]
+struct Pending {
+    /// Peer the missing transactions were requested from.
+    from: PeerId,
+    /// Block header.
+    header: BlockHeader,
+    /// Block transactions, in order. Missing transactions are `None`.
+    transactions: Vec<Option<Transaction>>,
+}
+
+/// Manages compact block relay with peers.
+#[derive(Debug)]
+pub struct CompactBlockManager<U> {
+    /// Manager configuration.
+    pub config: Config,
+    /// Peers with compact block relay negotiated.
+    peers: AddressBook<PeerId, Peer>,
+    /// Blocks waiting for missing transactions.
+    pending: HashMap<BlockHash, Pending>,
+    upstream: U,
+}
+
+impl<U: Wire<Event>> CompactBlockManager<U> {
+    /// Create a new compact block manager.
+    pub fn new(config: Config, rng: fastrand::Rng, upstream: U) -> Self {
+        let peers = AddressBook::new(rng.clone());
+        let pending = HashMap::with_hasher(rng.into());
+
+        Self {
+            config,
+            peers,
+            pending,
+            upstream,
+        }
+    }
+
+    /// Called when a peer is negotiated. Asks the peer to use compact block relay, if it
+    /// supports it.
+    pub fn peer_negotiated(
+        &mut self,
+        socket: Socket,
+        services: ServiceFlags,
+        version: u32,
+        link: ConnDirection,
+    ) {
+        if !self.config.enabled
+            || !link.is_outbound()
+            || !services.has(REQUIRED_SERVICES)
+            || version < MIN_PROTOCOL_VERSION
+        {
+            return;
+        }
+        let addr = socket.addr;
+        let high_bandwidth =
+            self.peers.values().filter(|p| p.high_bandwidth).count() < MAX_HIGH_BANDWIDTH_PEERS;
+
+        self.upstream
+            .send_cmpct(addr, high_bandwidth, COMPACT_BLOCK_VERSION);
+        self.peers.insert(
+            addr,
+            Peer {
+                socket,
+                high_bandwidth,
+            },
+        );
+        self.upstream.event(Event::Negotiated {
+            peer: addr,
+            high_bandwidth,
+        });
+    }
+
+    /// Called when a peer disconnected.
+    pub fn peer_disconnected(&mut self, addr: &PeerId) {
+        self.peers.remove(addr);
+        self.pending.retain(|_, p| p.from != *addr);
+    }
+
+    /// Called when a `cmpctblock` message was received. The block header should be imported
+    /// separately. If the block is `wanted`, it is reconstructed from the given known
+    /// transactions, and returned if complete. Otherwise, the missing transactions are
+    /// requested from the peer.
+    pub fn received_cmpctblock<'a>(
+        &mut self,
+        from: &PeerId,
+        compact: HeaderAndShortIds,
+        known: impl Iterator<Item = &'a Transaction>,
+        wanted: bool,
+    ) -> Result<Option<Block>, Error> {
+        if !self.peers.contains_key(from) {
+            return Err(Error::Ignored {
+                msg: "cmpctblock: unknown peer",
+                from: *from,
+            });
+        }
+        let HeaderAndShortIds {
+            header,
+            nonce,
+            short_ids,
+            prefilled_txs,
+        } = compact;
+
+        let count = short_ids.len() + prefilled_txs.len();
+        if count == 0 || count > u16::MAX as usize {
+            return Err(Error::InvalidMessage {
+                from: *from,
+                reason: "cmpctblock: invalid transaction count",
+            });
+        }
+        let mut transactions: Vec<Option<Transaction>> = vec![None; count];
+        let mut last: Option<usize> = None;
+
+        for prefilled in prefilled_txs {
+            // Nb. Prefilled transaction indexes are differentially encoded.
+            let ix = last.map_or(0, |l| l + 1) + prefilled.idx as usize;
+
+            if ix >= count {
+                return Err(Error::InvalidMessage {
+                    from: *from,
+                    reason: "cmpctblock: invalid prefilled transaction index",
+                });
+            }
+            transactions[ix] = Some(prefilled.tx);
+            last = Some(ix);
+        }
+        if !wanted {
+            return Ok(None);
+        }
+        let hash = header.block_hash();
+        let keys = ShortId::calculate_siphash_keys(&header, nonce);
+        let known = known
+            .map(|tx| (ShortId::with_siphash_keys(&tx.wtxid(), keys), tx))
+            .collect::<HashMap<_, _>>();
+        let mut short_ids = short_ids.into_iter();
+
+        for slot in transactions.iter_mut().filter(|t| t.is_none()) {
+            if let Some(tx) = short_ids.next().and_then(|id| known.get(&id)) {
+                *slot = Some((*tx).clone());
+            }
+        }
+        let missing = transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.is_none())
+            .map(|(i, _)| i as u64)
+            .collect::<Vec<_>>();
+
+        if missing.is_empty() {
+            return Ok(self.reconstructed(*from, header, transactions, 0));
+        }
+        if self.pending.len() >= MAX_PENDING_BLOCKS {
+            return Err(Error::Ignored {
+                msg: "cmpctblock: too many pending blocks",
+                from: *from,
+            });
+        }
+        self.pending.insert(
+            hash,
+            Pending {
+                from: *from,
+                header,
+                transactions,
+            },
+        );
+        self.upstream.get_block_txn(
+            *from,
+            BlockTransactionsRequest {
+                block_hash: hash,
+                indexes: missing,
+            },
+        );
+
+        Ok(None)
+    }
+
+    /// Called when a `blocktxn` message was received. Returns the reconstructed block, if
+    /// the transactions completed a pending block.
+    pub fn received_blocktxn(
+        &mut self,
+        from: &PeerId,
+        txs: BlockTransactions,
+    ) -> Result<Option<Block>, Error> {
+        let pending = match self.pending.remove(&txs.block_hash) {
+            Some(pending) if pending.from == *from => pending,
+            Some(pending) => {
+                self.pending.insert(txs.block_hash, pending);
+
+                return Err(Error::Ignored {
+                    msg: "blocktxn: unexpected peer",
+                    from: *from,
+                });
+            }
+            None => {
+                return Err(Error::Ignored {
+                    msg: "blocktxn: unknown block",
+                    from: *from,
+                });
+            }
+        };
+        let Pending {
+            header,
+            mut transactions,
+            ..
+        } = pending;
+
+        let missing = transactions.iter().filter(|t| t.is_none()).count();
+        if txs.transactions.len() != missing {
+            return Err(Error::InvalidMessage {
+                from: *from,
+                reason: "blocktxn: unexpected transaction count",
+            });
+        }
+        let mut received = txs.transactions.into_iter();
+
+        for slot in transactions.iter_mut().filter(|t| t.is_none()) {
+            *slot = received.next();
+        }
+        Ok(self.reconstructed(*from, header, transactions, missing))
+    }
+
+    /// Assemble a block from its complete list of transactions. Returns `None` if the
+    /// transactions don't match the header, eg. due to a short id collision, in which case
+    /// the block should be downloaded in full.
+    fn reconstructed(
+        &mut self,
+        from: PeerId,
+        header: BlockHeader,
+        transactions: Vec<Option<Transaction>>,
+        requested: usize,
+    ) -> Option<Block> {
+        let block = Block {
+            header,
+            txdata: transactions.into_iter().collect::<Option<Vec<_>>>()?,
+        };
+        if !block.check_merkle_root() {
+            log::debug!(
+                target: "p2p",
+                "Failed to reconstruct block {} from {}: merkle root mismatch",
+                block.block_hash(),
+                from
+            );
+            return None;
+        }
+        self.upstream.event(Event::BlockReconstructed {
+            peer: from,
+            block: block.block_hash(),
+            requested,
+        });
+
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fsm;
+    use crate::fsm::network::Network;
+    use crate::fsm::output::{self, Outbox};
+    use crate::fsm::PROTOCOL_VERSION;
+
+    use nakamoto_common::bitcoin::network::message::NetworkMessage;
+    use nakamoto_common::bitcoin::network::message_compact_blocks::{GetBlockTxn, SendCmpct};
+    use nakamoto_test::assert_matches;
+    use nakamoto_test::block::gen;
+
+    fn setup(rng: &mut fastrand::Rng) -> (CompactBlockManager<Outbox>, Outbox, PeerId, Block) {
+        let network = Network::Regtest;
+        let upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let remote = ([66, 66, 66, 66], 8333).into();
+        let config = Config { enabled: true };
+
+        let mut txdata = vec![gen::coinbase(rng)];
+        txdata.extend((0..4).map(|_| gen::transaction(rng)));
+
+        let block = gen::block_with(&network.genesis(), txdata, rng);
+        let mut cmpctmgr = CompactBlockManager::new(config, rng.clone(), upstream.clone());
+
+        cmpctmgr.peer_negotiated(
+            Socket::new(remote),
+            ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+            PROTOCOL_VERSION,
+            ConnDirection::Outbound,
+        );
+
+        (cmpctmgr, upstream, remote, block)
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let mut rng = fastrand::Rng::new();
+        let (mut cmpctmgr, mut upstream, remote, _) = setup(&mut rng);
+        let legacy = ([77, 77, 77, 77], 8333).into();
+
+        cmpctmgr.peer_negotiated(
+            Socket::new(legacy),
+            ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
+            ConnDirection::Outbound,
+        );
+
+        assert_matches!(
+            output::test::messages_from(&mut upstream, &remote).next(),
+            Some(NetworkMessage::SendCmpct(SendCmpct {
+                send_compact: true,
+                version: COMPACT_BLOCK_VERSION,
+            }))
+        );
+        assert_eq!(
+            output::test::messages_from(&mut upstream, &legacy).count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_reconstruct_from_known() {
+        let mut rng = fastrand::Rng::new();
+        let (mut cmpctmgr, mut upstream, remote, block) = setup(&mut rng);
+        let compact = HeaderAndShortIds::from_block(&block, rng.u64(..), 2, &[]).unwrap();
+
+        // Not wanted, so nothing to reconstruct.
+        assert_matches!(
+            cmpctmgr.received_cmpctblock(&remote, compact.clone(), block.txdata.iter(), false),
+            Ok(None)
+        );
+
+        let reconstructed = cmpctmgr
+            .received_cmpctblock(&remote, compact, block.txdata.iter().skip(1), true)
+            .unwrap();
+        assert_eq!(reconstructed, Some(block));
+        assert!(output::test::events(&mut upstream).any(|e| matches!(
+            e,
+            fsm::Event::CompactBlock(Event::BlockReconstructed { requested: 0, .. })
+        )));
+    }
+
+    #[test]
+    fn test_reconstruct_with_missing() {
+        let mut rng = fastrand::Rng::new();
+        let (mut cmpctmgr, mut upstream, remote, block) = setup(&mut rng);
+        let compact = HeaderAndShortIds::from_block(&block, rng.u64(..), 2, &[]).unwrap();
+        let hash = block.block_hash();
+
+        // We only know of the last two transactions.
+        let result = cmpctmgr
+            .received_cmpctblock(&remote, compact, block.txdata.iter().skip(3), true)
+            .unwrap();
+        assert_eq!(result, None);
+
+        assert_matches!(
+            output::test::messages_from(&mut upstream, &remote)
+                .find(|m| matches!(m, NetworkMessage::GetBlockTxn(_))),
+            Some(NetworkMessage::GetBlockTxn(GetBlockTxn { txs_request }))
+            if txs_request.block_hash == hash && txs_request.indexes == vec![1, 2]
+        );
+
+        // An incomplete response is invalid.
+        let invalid = BlockTransactions {
+            block_hash: hash,
+            transactions: block.txdata[1..2].to_vec(),
+        };
+        assert_matches!(
+            cmpctmgr.received_blocktxn(&remote, invalid),
+            Err(Error::InvalidMessage { .. })
+        );
+        assert_matches!(
+            cmpctmgr.received_blocktxn(
+                &remote,
+                BlockTransactions {
+                    block_hash: hash,
+                    transactions: block.txdata[1..3].to_vec(),
+                }
+            ),
+            Err(Error::Ignored { .. }),
+            "the pending block is dropped after an invalid response"
+        );
+    }
+
+    #[test]
+    fn test_reconstruct_with_blocktxn() {
+        let mut rng = fastrand::Rng::new();
+        let (mut cmpctmgr, _upstream, remote, block) = setup(&mut rng);
+        let compact = HeaderAndShortIds::from_block(&block, rng.u64(..), 2, &[]).unwrap();
+        let hash = block.block_hash();
+
+        cmpctmgr
+            .received_cmpctblock(&remote, compact, block.txdata.iter().skip(3), true)
+            .unwrap();
+
+        let reconstructed = cmpctmgr
+            .received_blocktxn(
+                &remote,
+                BlockTransactions {
+                    block_hash: hash,
+                    transactions: block.txdata[1..3].to_vec(),
+                },
+            )
+            .unwrap();
+        assert_eq!(reconstructed, Some(block));
+    }
+}
@@ -1,5 +1,6 @@
 //! State machine events.
 use nakamoto_common::bitcoin::network::message::NetworkMessage;
+use nakamoto_common::block::time::TimeOffset;
 
 use crate::fsm::{self, Height, LocalTime, PeerId};
 
@@ -14,9 +15,19 @@ pub enum Event {
         height: Height,
         /// Filter header height.
         filter_height: Height,
+        /// Whether compact block filters are disabled.
+        headers_only: bool,
         /// Local time.
         time: LocalTime,
     },
+    /// Our local clock is too far off from the network-adjusted time. This is determined
+    /// from the median time offset of our peers, and usually means the system clock is
+    /// wrong.
+    ClockSkewDetected {
+        /// Median offset of peer clocks relative to ours, in seconds. A positive offset
+        /// means our clock is behind.
+        offset: TimeOffset,
+    },
     /// Received a message from a peer.
     Received(PeerId, NetworkMessage),
     /// An address manager event.
@@ -27,6 +38,10 @@ pub enum Event {
     Peer(fsm::PeerEvent),
     /// A CBF manager event.
     Filter(fsm::FilterEvent),
+    /// A bloom filter manager event.
+    Bloom(fsm::BloomEvent),
+    /// A compact block manager event.
+    CompactBlock(fsm::CompactBlockEvent),
     /// An inventory manager event.
     Inventory(fsm::InventoryEvent),
     /// A ping manager event.
@@ -51,6 +66,18 @@ impl From<fsm::FilterEvent> for Event {
     }
 }
 
+impl From<fsm::BloomEvent> for Event {
+    fn from(e: fsm::BloomEvent) -> Self {
+        Self::Bloom(e)
+    }
+}
+
+impl From<fsm::CompactBlockEvent> for Event {
+    fn from(e: fsm::CompactBlockEvent) -> Self {
+        Self::CompactBlock(e)
+    }
+}
+
 impl From<fsm::AddressEvent> for Event {
     fn from(e: fsm::AddressEvent) -> Self {
         Self::Address(e)
@@ -4,6 +4,7 @@ use std::collections::VecDeque;
 use nakamoto_common::bitcoin::blockdata::constants::WITNESS_SCALE_FACTOR;
 use nakamoto_common::bitcoin::{Block, OutPoint, Transaction, TxOut};
 
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
 use nakamoto_common::collections::HashMap;
 use nakamoto_common::nonempty::NonEmpty;
 
@@ -13,6 +14,20 @@ use super::Height;
 
 /// Maximum depth of a re-org that we are able to handle.
 pub const MAX_UTXO_SNAPSHOTS: usize = 12;
+/// Number of recent block fee estimates kept, to answer estimate queries.
+pub const MAX_RECENT_ESTIMATES: usize = 144;
+/// Maximum age of a saved fee history for it to be restored. Fee rates move with demand
+/// for block space, so older estimates are of little use.
+pub const MAX_HISTORY_AGE: LocalDuration = LocalDuration::from_hours(6);
+
+/// Maximum number of unconfirmed transaction fee rates sampled per [`MEMPOOL_SAMPLE_WINDOW`].
+pub const MAX_MEMPOOL_SAMPLES: usize = 1000;
+/// Time window over which unconfirmed transaction fee rates are sampled.
+pub const MEMPOOL_SAMPLE_WINDOW: LocalDuration = LocalDuration::from_mins(30);
+/// Fee rate buckets used to sample unconfirmed transactions. Each sample is counted in
+/// the highest bucket that doesn't exceed its rate.
+pub const FEE_RATE_BUCKETS: [FeeRate; 16] =
+    [1, 2, 3, 4, 5, 7, 10, 15, 20, 30, 50, 75, 100, 150, 250, 500];
 
 /// Transaction fee rate in satoshis/vByte.
 pub type FeeRate = u64;
@@ -79,6 +94,212 @@ impl FeeEstimate {
             }
         })
     }
+
+    /// Convert to a JSON value.
+    pub fn to_json(&self) -> microserde::json::Value {
+        use microserde::json::{Number, Object, Value};
+
+        let mut obj = Object::new();
+
+        obj.insert("low".to_owned(), Value::Number(Number::U64(self.low)));
+        obj.insert("median".to_owned(), Value::Number(Number::U64(self.median)));
+        obj.insert("high".to_owned(), Value::Number(Number::U64(self.high)));
+
+        Value::Object(obj)
+    }
+
+    /// Convert from a JSON value.
+    pub fn from_json(value: microserde::json::Value) -> Result<Self, microserde::Error> {
+        use microserde::json::{Number, Value};
+
+        let obj = match value {
+            Value::Object(obj) => obj,
+            _ => return Err(microserde::Error),
+        };
+        let rate = |key: &str| match obj.get(key) {
+            Some(Value::Number(Number::U64(n))) => Ok(*n),
+            _ => Err(microserde::Error),
+        };
+
+        Ok(Self {
+            low: rate("low")?,
+            median: rate("median")?,
+            high: rate("high")?,
+        })
+    }
+
+    /// Blend two estimates by averaging their rates. If only one estimate is available,
+    /// it is returned as is.
+    ///
+    /// ```
+    /// use nakamoto_p2p::fsm::fees::FeeEstimate;
+    ///
+    /// let block = FeeEstimate { low: 2, median: 10, high: 40 };
+    /// let mempool = FeeEstimate { low: 4, median: 15, high: 50 };
+    ///
+    /// assert_eq!(
+    ///     FeeEstimate::blend(Some(block.clone()), Some(mempool)),
+    ///     Some(FeeEstimate { low: 3, median: 13, high: 45 }),
+    /// );
+    /// assert_eq!(FeeEstimate::blend(Some(block.clone()), None), Some(block));
+    /// assert_eq!(FeeEstimate::blend(None, None), None);
+    /// ```
+    pub fn blend(a: Option<Self>, b: Option<Self>) -> Option<Self> {
+        match (a, b) {
+            (Some(a), Some(b)) => {
+                let avg = |x: FeeRate, y: FeeRate| ((x + y) as f64 / 2.).round() as FeeRate;
+
+                Some(Self {
+                    low: avg(a.low, b.low),
+                    median: avg(a.median, b.median),
+                    high: avg(a.high, b.high),
+                })
+            }
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// Fee rates of unconfirmed transactions announced by peers, used to estimate the current
+/// demand for block space. Rates are bucketed with [`FEE_RATE_BUCKETS`].
+#[derive(Debug, Default)]
+pub struct MempoolFees {
+    /// Sample times and bucket indexes, oldest first.
+    samples: VecDeque<(LocalTime, usize)>,
+}
+
+impl MempoolFees {
+    /// Record the fee rate of an unconfirmed transaction, seen at the given time.
+    pub fn record(&mut self, rate: FeeRate, time: LocalTime) {
+        let bucket = FEE_RATE_BUCKETS
+            .iter()
+            .rposition(|b| *b <= rate)
+            .unwrap_or_default();
+
+        self.prune(time);
+        self.samples.push_back((time, bucket));
+
+        if self.samples.len() > MAX_MEMPOOL_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Get an estimate from the samples within [`MEMPOOL_SAMPLE_WINDOW`] of the given time,
+    /// with rates rounded down to their bucket. Returns [`None`] if there are no samples.
+    pub fn estimate(&self, now: LocalTime) -> Option<FeeEstimate> {
+        let mut buckets = self
+            .samples
+            .iter()
+            .filter(|(t, _)| now - *t <= MEMPOOL_SAMPLE_WINDOW)
+            .map(|(_, b)| *b)
+            .collect::<Vec<_>>();
+        buckets.sort_unstable();
+
+        NonEmpty::from_vec(buckets).map(|buckets| FeeEstimate {
+            low: FEE_RATE_BUCKETS[*buckets.first()],
+            median: FEE_RATE_BUCKETS[buckets[buckets.len() / 2]],
+            high: FEE_RATE_BUCKETS[*buckets.last()],
+        })
+    }
+
+    /// Get the number of samples.
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Check whether there are no samples.
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Remove samples that are outside the window.
+    fn prune(&mut self, now: LocalTime) {
+        while let Some((t, _)) = self.samples.front() {
+            if now - *t <= MEMPOOL_SAMPLE_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+}
+
+/// Fee estimates of recently processed blocks, saved so that they can be restored across
+/// restarts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeeHistory {
+    /// Time at which the history was saved.
+    pub time: LocalTime,
+    /// Block heights and their fee estimates, oldest first.
+    pub estimates: Vec<(Height, FeeEstimate)>,
+}
+
+impl FeeHistory {
+    /// Check whether the history is recent enough to be restored at the given time,
+    /// ie. it is at most [`MAX_HISTORY_AGE`] old.
+    pub fn is_fresh(&self, now: LocalTime) -> bool {
+        self.time <= now && now - self.time <= MAX_HISTORY_AGE
+    }
+
+    /// Convert to a JSON value.
+    pub fn to_json(&self) -> microserde::json::Value {
+        use microserde::json::{Number, Object, Value};
+
+        let mut obj = Object::new();
+
+        obj.insert(
+            "time".to_owned(),
+            Value::Number(Number::U64(self.time.as_secs())),
+        );
+        obj.insert(
+            "estimates".to_owned(),
+            Value::Array(
+                self.estimates
+                    .iter()
+                    .map(|(height, estimate)| {
+                        let mut value = estimate.to_json();
+                        if let Value::Object(obj) = &mut value {
+                            obj.insert("height".to_owned(), Value::Number(Number::U64(*height)));
+                        }
+                        value
+                    })
+                    .collect(),
+            ),
+        );
+
+        Value::Object(obj)
+    }
+
+    /// Convert from a JSON value.
+    pub fn from_json(value: microserde::json::Value) -> Result<Self, microserde::Error> {
+        use microserde::json::{Number, Value};
+
+        let mut obj = match value {
+            Value::Object(obj) => obj,
+            _ => return Err(microserde::Error),
+        };
+        let time = match obj.get("time") {
+            Some(Value::Number(Number::U64(secs))) => LocalTime::from_secs(*secs),
+            _ => return Err(microserde::Error),
+        };
+        let estimates = match obj.remove("estimates") {
+            Some(Value::Array(ary)) => ary
+                .into_iter()
+                .map(|value| {
+                    let height = match &value {
+                        Value::Object(obj) => match obj.get("height") {
+                            Some(Value::Number(Number::U64(h))) => *h,
+                            _ => return Err(microserde::Error),
+                        },
+                        _ => return Err(microserde::Error),
+                    };
+                    Ok((height, FeeEstimate::from_json(value)?))
+                })
+                .collect::<Result<_, _>>()?,
+            _ => return Err(microserde::Error),
+        };
+
+        Ok(Self { time, estimates })
+    }
 }
 
 /// Set of unspent transaction outputs (UTXO).
@@ -94,6 +315,8 @@ pub struct FeeEstimator {
     /// UTXO set snapshots.
     /// These are used to return to a previous state in the case of a re-org.
     snapshots: VecDeque<(Height, UtxoSet)>,
+    /// Fee estimates of recently processed blocks, oldest first.
+    recent: VecDeque<(Height, FeeEstimate)>,
 }
 
 impl FeeEstimator {
@@ -120,11 +343,80 @@ impl FeeEstimator {
         }
         self.height = height;
 
-        FeeEstimate::from(fees)
+        let estimate = FeeEstimate::from(fees);
+        if let Some(estimate) = &estimate {
+            self.recent.push_back((height, estimate.clone()));
+            if self.recent.len() > MAX_RECENT_ESTIMATES {
+                self.recent.pop_front();
+            }
+        }
+        estimate
+    }
+
+    /// Get a fee estimate for confirmation within the given number of blocks, based on the
+    /// estimates of the most recent `target_blocks` blocks processed: the lowest and highest
+    /// rates included in any of them, and the median of their median rates. Returns [`None`]
+    /// if no block could be estimated yet.
+    pub fn estimate(&self, target_blocks: usize) -> Option<FeeEstimate> {
+        let recent = self
+            .recent
+            .iter()
+            .rev()
+            .take(target_blocks.max(1))
+            .map(|(_, e)| e);
+        let medians = recent.clone().map(|e| e.median).collect();
+
+        FeeEstimate::from(medians).map(|median| FeeEstimate {
+            low: recent.clone().map(|e| e.low).min().unwrap_or(median.low),
+            median: median.median,
+            high: recent.map(|e| e.high).max().unwrap_or(median.high),
+        })
+    }
+
+    /// Calculate the fee rate of an unconfirmed transaction, without applying it. Returns
+    /// [`None`] if any of its inputs isn't in the UTXO set, ie. doesn't spend the output of
+    /// a processed block.
+    pub fn fee_rate(&self, tx: &Transaction) -> Option<FeeRate> {
+        if tx.is_coin_base() {
+            return None;
+        }
+        let mut received = 0;
+        for input in tx.input.iter() {
+            received += self.utxos.get(&input.previous_output)?.value;
+        }
+        let sent = tx.output.iter().map(|o| o.value).sum::<u64>();
+        let fee = received.checked_sub(sent)?;
+
+        Some(Self::rate(fee, tx))
+    }
+
+    /// Get the fee estimates of recently processed blocks, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &(Height, FeeEstimate)> + '_ {
+        self.recent.iter()
+    }
+
+    /// Restore the fee estimates of recently processed blocks, eg. from a previous run.
+    /// Estimates of blocks above the given tip height are ignored, since they may have
+    /// been reverted.
+    pub fn restore(
+        &mut self,
+        estimates: impl IntoIterator<Item = (Height, FeeEstimate)>,
+        tip: Height,
+    ) {
+        let mut estimates = estimates
+            .into_iter()
+            .filter(|(h, _)| *h <= tip)
+            .collect::<Vec<_>>();
+        estimates.sort_by_key(|(h, _)| *h);
+        estimates.dedup_by_key(|(h, _)| *h);
+
+        let skip = estimates.len().saturating_sub(MAX_RECENT_ESTIMATES);
+        self.recent = estimates.into_iter().skip(skip).collect();
     }
 
     /// Rollback to a certain height.
     pub fn rollback(&mut self, height: Height) {
+        self.recent.retain(|(h, _)| h <= &height);
         self.snapshots.retain(|(h, _)| h <= &height);
 
         if let Some((h, snapshot)) = self.snapshots.pop_back() {
@@ -168,11 +460,15 @@ impl FeeEstimator {
         }
         assert!(received >= sent, "you can't spend what you don't have",);
 
-        let fee = received - sent;
+        Some(Self::rate(received - sent, tx))
+    }
+
+    /// Calculate the fee rate of a transaction paying the given fee.
+    fn rate(fee: u64, tx: &Transaction) -> FeeRate {
         let weight = tx.weight();
         let rate = fee as f64 / (weight as f64 / WITNESS_SCALE_FACTOR as f64);
 
-        Some(rate.round() as FeeRate)
+        rate.round() as FeeRate
     }
 }
 
@@ -213,6 +509,148 @@ mod tests {
         assert_matches!(fe.snapshots.back(), Some((18, _)));
     }
 
+    #[test]
+    fn test_estimate() {
+        let mut fe = FeeEstimator::default();
+        assert_eq!(fe.estimate(1), None);
+
+        for (height, fees) in [vec![2, 4, 6], vec![8, 10, 30], vec![1, 3, 5]]
+            .into_iter()
+            .enumerate()
+        {
+            let estimate = FeeEstimate::from(fees).unwrap();
+            fe.recent.push_back((height as Height + 1, estimate));
+        }
+        assert_eq!(
+            fe.estimate(1),
+            Some(FeeEstimate {
+                low: 1,
+                median: 3,
+                high: 5
+            })
+        );
+        assert_eq!(
+            fe.estimate(3),
+            Some(FeeEstimate {
+                low: 1,
+                median: 4,
+                high: 30
+            })
+        );
+        // Targets beyond the number of known blocks use all of them.
+        assert_eq!(fe.estimate(6), fe.estimate(3));
+
+        fe.rollback(2);
+        assert_eq!(
+            fe.estimate(1),
+            Some(FeeEstimate {
+                low: 8,
+                median: 10,
+                high: 30
+            })
+        );
+    }
+
+    #[test]
+    fn test_history() {
+        let estimate = |median| FeeEstimate {
+            low: 1,
+            median,
+            high: 99,
+        };
+        let history = FeeHistory {
+            time: LocalTime::from_secs(1680000000),
+            estimates: vec![(7, estimate(4)), (9, estimate(6)), (8, estimate(5))],
+        };
+        let value =
+            microserde::json::from_str(&microserde::json::to_string(&history.to_json())).unwrap();
+        assert_eq!(FeeHistory::from_json(value).unwrap(), history);
+
+        assert!(history.is_fresh(history.time));
+        assert!(history.is_fresh(history.time + MAX_HISTORY_AGE));
+        assert!(!history.is_fresh(history.time + MAX_HISTORY_AGE + LocalDuration::from_secs(1)));
+        assert!(!history.is_fresh(history.time - LocalDuration::from_secs(1)));
+
+        let mut fe = FeeEstimator::default();
+        fe.restore(history.estimates, 8);
+        assert_eq!(
+            fe.history().cloned().collect::<Vec<_>>(),
+            vec![(7, estimate(4)), (8, estimate(5))]
+        );
+        assert_eq!(fe.estimate(1), Some(estimate(5)));
+    }
+
+    #[test]
+    fn test_mempool_fees() {
+        let mut mempool = MempoolFees::default();
+        let time = LocalTime::from_secs(1680000000);
+
+        assert_eq!(mempool.estimate(time), None);
+
+        for rate in [0, 6, 12, 12, 1000] {
+            mempool.record(rate, time);
+        }
+        assert_eq!(
+            mempool.estimate(time),
+            Some(FeeEstimate {
+                low: 1,
+                median: 10,
+                high: 500
+            })
+        );
+
+        // Samples outside the window are ignored, and pruned on the next record.
+        let later = time + MEMPOOL_SAMPLE_WINDOW + LocalDuration::from_secs(1);
+        assert_eq!(mempool.estimate(later), None);
+
+        mempool.record(20, later);
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(
+            mempool.estimate(later),
+            Some(FeeEstimate {
+                low: 20,
+                median: 20,
+                high: 20
+            })
+        );
+
+        for _ in 0..MAX_MEMPOOL_SAMPLES * 2 {
+            mempool.record(1, later);
+        }
+        assert_eq!(mempool.len(), MAX_MEMPOOL_SAMPLES);
+    }
+
+    #[test]
+    fn test_fee_rate() {
+        let mut fe = FeeEstimator::default();
+        let mut rng = fastrand::Rng::new();
+        let genesis = gen::genesis(&mut rng);
+        let blocks = gen::blockchain(genesis, 2, &mut rng);
+
+        for (height, block) in blocks.iter().cloned().enumerate().skip(1) {
+            fe.process(block, height as Height);
+        }
+        let tx = &blocks.last().txdata[0];
+
+        // The outputs of the last block are known, so a transaction spending them can be
+        // measured. Measuring it doesn't apply it to the UTXO set.
+        let spend = gen::transaction_with(
+            OutPoint {
+                txid: tx.txid(),
+                vout: 0,
+            },
+            tx.output[0].value,
+            &mut rng,
+        );
+        let rate = fe.fee_rate(&spend);
+        assert!(rate.is_some());
+        assert_eq!(fe.fee_rate(&spend), rate);
+
+        // Outputs that were already spent are unknown.
+        let spent = gen::transaction_with(tx.input[0].previous_output, 1, &mut rng);
+        assert_eq!(fe.fee_rate(&spent), None);
+    }
+
     #[test]
     fn test_rollback_missing_height() {
         let mut fe = FeeEstimator::default();
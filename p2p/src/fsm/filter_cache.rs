@@ -1,4 +1,7 @@
 //! Compact filter cache.
+//!
+//! Filters are evicted in least-recently-used order, where a filter is used when it is
+//! pushed into the cache or retrieved from it.
 use std::collections::BTreeMap;
 use std::rc::Rc;
 
@@ -27,21 +30,23 @@ impl Filter for BlockFilter {
 /// An in-memory compact filter cache with a fixed capacity.
 #[derive(Debug)]
 pub struct FilterCache<T: Filter> {
-    /// Cache.
-    cache: BTreeMap<Height, T>,
+    /// Cache. Filters are stored with the tick at which they were last used.
+    cache: BTreeMap<Height, (T, u64)>,
+    /// Cached heights, by the tick at which they were last used.
+    used: BTreeMap<u64, Height>,
+    /// Incremented every time a filter is used.
+    tick: u64,
     /// Cache size in bytes.
     size: usize,
     /// Cache capacity in bytes.
     capacity: usize,
+    /// Maximum number of filters in the cache, if any.
+    max_len: Option<usize>,
 }
 
 impl<T: Filter> Default for FilterCache<T> {
     fn default() -> Self {
-        Self {
-            cache: BTreeMap::new(),
-            size: 0,
-            capacity: 0,
-        }
+        Self::new(0)
     }
 }
 
@@ -50,11 +55,37 @@ impl<T: Filter> FilterCache<T> {
     pub fn new(capacity: usize) -> Self {
         Self {
             cache: BTreeMap::new(),
+            used: BTreeMap::new(),
+            tick: 0,
             size: 0,
             capacity,
+            max_len: None,
         }
     }
 
+    /// Set the maximum number of filters in the cache. If the cache holds more filters,
+    /// the least recently used are evicted. If `None`, only the size in bytes is limited.
+    ///
+    /// ```
+    /// use nakamoto_p2p::fsm::filter_cache::FilterCache;
+    /// use nakamoto_common::block::filter::BlockFilter;
+    ///
+    /// let mut cache = FilterCache::new(32);
+    ///
+    /// cache.push(3, BlockFilter::new(&[1]));
+    /// cache.push(4, BlockFilter::new(&[2]));
+    /// cache.push(5, BlockFilter::new(&[3]));
+    /// cache.get(&3);
+    ///
+    /// cache.set_max_len(Some(2));
+    /// assert_eq!(cache.len(), 2);
+    /// assert_eq!(cache.heights().collect::<Vec<_>>(), vec![3, 5]);
+    /// ```
+    pub fn set_max_len(&mut self, max_len: Option<usize>) {
+        self.max_len = max_len;
+        self.evict();
+    }
+
     /// Return the size of the cache filters in bytes.
     pub fn size(&self) -> usize {
         self.size
@@ -73,6 +104,29 @@ impl<T: Filter> FilterCache<T> {
         self.capacity
     }
 
+    /// Set the cache capacity in bytes. If the cache is over the new capacity, filters are
+    /// evicted, starting with the least recently used.
+    ///
+    /// ```
+    /// use nakamoto_p2p::fsm::filter_cache::FilterCache;
+    /// use nakamoto_common::block::filter::BlockFilter;
+    ///
+    /// let mut cache = FilterCache::new(8);
+    ///
+    /// cache.push(3, BlockFilter::new(&[1, 2, 3]));
+    /// cache.push(4, BlockFilter::new(&[4, 5]));
+    /// cache.push(5, BlockFilter::new(&[6]));
+    ///
+    /// cache.set_capacity(4);
+    /// assert_eq!(cache.capacity(), 4);
+    /// assert_eq!(cache.size(), 3);
+    /// assert_eq!(cache.start(), Some(4));
+    /// ```
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
     /// Return the number of filters in the cache.
     pub fn len(&self) -> usize {
         self.cache.len()
@@ -123,17 +177,13 @@ impl<T: Filter> FilterCache<T> {
         if size > self.capacity {
             return false;
         }
-
-        self.cache.insert(height, filter);
+        self.remove(&height);
+        self.tick += 1;
+        self.cache.insert(height, (filter, self.tick));
+        self.used.insert(self.tick, height);
         self.size += size;
+        self.evict();
 
-        while self.size > self.capacity {
-            if let Some(height) = self.cache.keys().cloned().next() {
-                if let Some(filter) = self.cache.remove(&height) {
-                    self.size -= filter.len();
-                }
-            }
-        }
         true
     }
 
@@ -163,7 +213,7 @@ impl<T: Filter> FilterCache<T> {
 
     /// Iterate over cached filters.
     pub fn iter(&self) -> impl Iterator<Item = (&Height, &T)> {
-        self.cache.iter().map(|(h, b)| (h, b))
+        self.cache.iter().map(|(h, (b, _))| (h, b))
     }
 
     /// Iterate over cached heights.
@@ -171,7 +221,7 @@ impl<T: Filter> FilterCache<T> {
         self.cache.keys().copied()
     }
 
-    /// Get a filter in the cache by height.
+    /// Get a filter in the cache by height, marking it as recently used.
     ///
     /// ```
     /// use nakamoto_p2p::fsm::filter_cache::FilterCache;
@@ -188,8 +238,15 @@ impl<T: Filter> FilterCache<T> {
     /// assert_eq!(cache.get(&1), None);
     ///
     /// ```
-    pub fn get(&self, height: &Height) -> Option<&T> {
-        self.cache.get(height)
+    pub fn get(&mut self, height: &Height) -> Option<&T> {
+        let (filter, tick) = self.cache.get_mut(height)?;
+
+        self.used.remove(tick);
+        self.tick += 1;
+        self.used.insert(self.tick, *height);
+        *tick = self.tick;
+
+        Some(filter)
     }
 
     /// Rollback the cache to a certain height. Drops all filters with a height greater
@@ -217,16 +274,29 @@ impl<T: Filter> FilterCache<T> {
     pub fn rollback(&mut self, height: Height) {
         while let Some(h) = self.end() {
             if h > height {
-                if let Some(k) = self.cache.keys().cloned().next_back() {
-                    if let Some(filter) = self.cache.remove(&k) {
-                        self.size -= filter.len();
-                    }
-                }
+                self.remove(&h);
             } else {
                 break;
             }
         }
     }
+
+    /// Remove a filter from the cache.
+    fn remove(&mut self, height: &Height) {
+        if let Some((filter, tick)) = self.cache.remove(height) {
+            self.used.remove(&tick);
+            self.size -= filter.len();
+        }
+    }
+
+    /// Evict the least recently used filters until the cache is within its limits.
+    fn evict(&mut self) {
+        while self.size > self.capacity || self.max_len.map_or(false, |n| self.len() > n) {
+            if let Some(height) = self.used.values().cloned().next() {
+                self.remove(&height);
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -238,6 +308,7 @@ mod tests {
     #[derive(Clone, Debug)]
     enum Op {
         Push(BlockFilter),
+        Get,
         Rollback,
     }
 
@@ -251,6 +322,11 @@ mod tests {
                         cache.push(rng.u64(..), filter);
                     }
                 }
+                Self::Get => {
+                    if let (Some(start), Some(end)) = (cache.start(), cache.end()) {
+                        cache.get(&rng.u64(start..=end));
+                    }
+                }
                 Self::Rollback => {
                     if let (Some(start), Some(end)) = (cache.start(), cache.end()) {
                         cache.rollback(rng.u64(start - 1..=end + 1));
@@ -264,14 +340,15 @@ mod tests {
         fn arbitrary(g: &mut Gen) -> Self {
             let n = u8::arbitrary(g);
 
-            match n % 4 {
+            match n % 5 {
                 0..=2 => {
                     let content: Vec<_> = Arbitrary::arbitrary(g);
                     let filter = BlockFilter::new(&content);
 
                     Op::Push(filter)
                 }
-                3 => Op::Rollback,
+                3 => Op::Get,
+                4 => Op::Rollback,
 
                 _ => unreachable! {},
             }
@@ -279,8 +356,9 @@ mod tests {
     }
 
     #[quickcheck]
-    fn prop_capacity(capacity: usize, operations: Vec<Op>, seed: u64) {
+    fn prop_capacity(capacity: usize, max_len: Option<u8>, operations: Vec<Op>, seed: u64) {
         let mut cache = FilterCache::new(capacity);
+        cache.set_max_len(max_len.map(usize::from));
         let mut rng = fastrand::Rng::with_seed(seed);
 
         for op in operations.into_iter() {
@@ -289,11 +367,13 @@ mod tests {
             let size = cache
                 .cache
                 .iter()
-                .map(|(_, f)| f.content.len())
+                .map(|(_, (f, _))| f.content.len())
                 .sum::<usize>();
 
             assert!(cache.size <= cache.capacity);
             assert!(size == cache.size);
+            assert_eq!(cache.used.len(), cache.cache.len());
+            assert!(cache.len() <= cache.max_len.unwrap_or(usize::MAX));
         }
     }
 }
@@ -19,27 +19,35 @@
 //! the [`InventoryManager::received_wake`] function is called. Confirmed transactions are removed
 //! after they are burried at a certain depth.
 //!
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
+use nakamoto_common::bitcoin::hashes::sha256d;
+use nakamoto_common::bitcoin::network::message_network::{Reject, RejectReason};
 use nakamoto_common::bitcoin::network::{constants::ServiceFlags, message_blockdata::Inventory};
-use nakamoto_common::bitcoin::{Block, BlockHash, Transaction, Txid, Wtxid};
+use nakamoto_common::bitcoin::{Block, BlockHash, OutPoint, Transaction, Txid, Wtxid};
 
 // TODO: Timeout should be configurable
 // TODO: Add exponential back-off
 
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
 use nakamoto_common::block::tree::BlockReader;
-use nakamoto_common::collections::{AddressBook, HashMap};
+use nakamoto_common::collections::{AddressBook, HashMap, HashSet};
 
-use super::fees::{FeeEstimate, FeeEstimator};
-use super::output::{Wakeup, Wire};
-use super::{Height, PeerId, Socket};
+use super::fees::{FeeEstimate, FeeEstimator, FeeRate, MempoolFees};
+use super::fees::{MAX_MEMPOOL_SAMPLES, MEMPOOL_SAMPLE_WINDOW};
+use super::output::{Disconnect, Wakeup, Wire};
+use super::{DisconnectReason, Height, InFlight, PeerId, Request, Socket};
 
 /// Time between re-broadcasts of inventories.
 pub const REBROADCAST_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
 
-/// Time between request retries.
-pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(15);
+/// Default time to wait for a requested block, before requesting it from another peer.
+/// The timeout grows with each attempt, since the block size isn't known in advance, and
+/// large blocks can take a while to download over slow links.
+pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
+
+/// Maximum time to wait for a requested block.
+pub const MAX_REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_mins(10);
 
 /// Maximum number of attempts to send inventories to a peer.
 pub const MAX_ATTEMPTS: usize = 3;
@@ -50,6 +58,17 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
 /// Block depth at which confirmed transactions are pruned and no longer reverted after a re-org.
 pub const TRANSACTION_PRUNE_DEPTH: Height = 12;
 
+/// Minimum protocol version for BIP 133 `feefilter` messages.
+pub const FEEFILTER_VERSION: u32 = 70013;
+
+/// Number of recent blocks whose lowest included fee rate is used as our fee filter, if
+/// none is configured.
+pub const FEEFILTER_TARGET_BLOCKS: usize = 6;
+
+/// Time given to a peer to apply our fee filter, during which transactions below it
+/// aren't held against the peer.
+pub const FEEFILTER_GRACE_PERIOD: LocalDuration = LocalDuration::from_secs(30);
+
 /// An event emitted by the inventory manager.
 #[derive(Debug, Clone)]
 pub enum Event {
@@ -95,6 +114,69 @@ pub enum Event {
         /// Peer who timed out.
         peer: PeerId,
     },
+    /// A block was requested for the first time, eg. after a filter match.
+    BlockDownloadStarted {
+        /// The requested block.
+        block: BlockHash,
+        /// Height of the block.
+        height: Height,
+        /// Peer the block was requested from.
+        peer: PeerId,
+    },
+    /// A block request timed out. The block is requested from another peer, if possible.
+    BlockRequestTimedOut {
+        /// The requested block.
+        block: BlockHash,
+        /// Peer who timed out.
+        peer: PeerId,
+    },
+    /// A downloaded block didn't match its header and was discarded. The sending peer
+    /// is disconnected and the block is requested from another peer.
+    BlockRejected {
+        /// The rejected block.
+        block: BlockHash,
+        /// Peer who sent the block.
+        peer: PeerId,
+        /// Reason for rejecting the block.
+        reason: &'static str,
+    },
+    /// A watched outpoint was spent.
+    OutpointSpent {
+        /// The spent outpoint.
+        outpoint: OutPoint,
+        /// The spending transaction.
+        txid: Txid,
+        /// The block in which the outpoint was spent.
+        block: BlockHash,
+        /// Height of the block.
+        height: Height,
+    },
+    /// None of our peers have the requested block, eg. because they are pruned.
+    /// The request is retried when new peers connect.
+    BlockNotFound {
+        /// The requested block.
+        block: BlockHash,
+    },
+    /// A peer rejected one of our messages, using the legacy `reject` message.
+    MessageRejected {
+        /// The rejecting peer.
+        peer: PeerId,
+        /// Command of the rejected message, eg. `tx`.
+        message: String,
+        /// The rejected transaction, if it's one we submitted.
+        txid: Option<Txid>,
+        /// Reject code.
+        code: RejectReason,
+        /// Reason given by the peer.
+        reason: String,
+    },
+    /// A peer misbehaved, eg. by sending us transactions below our fee filter.
+    PeerMisbehaved {
+        /// The misbehaving peer.
+        peer: PeerId,
+        /// How the peer misbehaved.
+        reason: &'static str,
+    },
 }
 
 impl std::fmt::Display for Event {
@@ -128,6 +210,69 @@ impl std::fmt::Display for Event {
                 write!(fmt, "Transaction {} was reverted", transaction.txid(),)
             }
             Event::TimedOut { peer } => write!(fmt, "Peer {} timed out", peer),
+            Event::BlockDownloadStarted {
+                block,
+                height,
+                peer,
+            } => {
+                write!(
+                    fmt,
+                    "Downloading block {} at height {} from {}",
+                    block, height, peer
+                )
+            }
+            Event::BlockRequestTimedOut { block, peer } => {
+                write!(
+                    fmt,
+                    "Request for block {} to peer {} timed out",
+                    block, peer
+                )
+            }
+            Event::BlockRejected {
+                block,
+                peer,
+                reason,
+            } => {
+                write!(fmt, "{}: Rejected block {}: {}", peer, block, reason)
+            }
+            Event::OutpointSpent {
+                outpoint,
+                txid,
+                height,
+                ..
+            } => write!(
+                fmt,
+                "Outpoint {} was spent by transaction {} in block #{}",
+                outpoint, txid, height
+            ),
+            Event::BlockNotFound { block } => {
+                write!(fmt, "Block {} not found on any peer", block)
+            }
+            Event::MessageRejected {
+                peer,
+                message,
+                txid: Some(txid),
+                code,
+                reason,
+            } => write!(
+                fmt,
+                "{}: Transaction {} was rejected in `{}` message ({:?}): {}",
+                peer, txid, message, code, reason
+            ),
+            Event::MessageRejected {
+                peer,
+                message,
+                code,
+                reason,
+                ..
+            } => write!(
+                fmt,
+                "{}: Message `{}` was rejected ({:?}): {}",
+                peer, message, code, reason
+            ),
+            Event::PeerMisbehaved { peer, reason } => {
+                write!(fmt, "{}: Peer misbehaved: {}", peer, reason)
+            }
         }
     }
 }
@@ -141,6 +286,14 @@ pub struct Peer {
     pub services: ServiceFlags,
     /// Does this peer use BIP-339?
     pub wtxidrelay: bool,
+    /// Does this peer support BIP-133 `feefilter` messages?
+    pub feefilter: bool,
+
+    /// Minimum fee rate of transactions announced to this peer, in satoshis/kvB,
+    /// as requested with a `feefilter` message.
+    fee_filter: u64,
+    /// Fee rate we last asked this peer to filter transactions with, and when.
+    fee_filter_sent: Option<(FeeRate, LocalTime)>,
 
     /// Inventories we are attempting to send to this peer.
     outbox: HashMap<Wtxid, Txid>,
@@ -152,6 +305,13 @@ pub struct Peer {
     /// Number of times a certain block was requested.
     #[allow(dead_code)]
     requests: HashMap<BlockHash, usize>,
+    /// Blocks this peer told us it doesn't have.
+    missing: HashSet<BlockHash>,
+    /// Number of block requests this peer didn't fulfill in time. Peers with fewer
+    /// timeouts are preferred for block requests.
+    timeouts: usize,
+    /// Number of requested blocks received from this peer.
+    blocks: usize,
 
     /// Peer socket.
     _socket: Socket,
@@ -184,16 +344,32 @@ pub struct InventoryManager<U, C> {
     /// Confirmed transactions by block height.
     /// Pruned after a certain depth.
     confirmed: HashMap<Height, Vec<Transaction>>,
+    /// Outpoints watched for spends.
+    outpoints: HashSet<OutPoint>,
+    /// Watched outpoints spent, by block height.
+    /// Pruned after a certain depth.
+    spent: HashMap<Height, Vec<OutPoint>>,
 
     /// Transaction fee estimator.
     estimator: FeeEstimator,
+    /// Fee rates of unconfirmed transactions. Only tracked if enabled.
+    mempool_fees: Option<MempoolFees>,
+    /// Unconfirmed transactions requested to sample their fee rates, keyed by txid or
+    /// wtxid, with the time they were requested and whether they were received.
+    sampled: HashMap<sha256d::Hash, (LocalTime, bool)>,
 
     /// Transaction mempool. Stores unconfirmed transactions sent to the network.
     pub mempool: BTreeMap<Wtxid, Transaction>,
-    /// Blocks requested and the time at which they were last requested.
-    pub remaining: HashMap<BlockHash, Option<LocalTime>>,
+    /// Blocks requested, and the time at which and peer from which they were last requested.
+    pub remaining: HashMap<BlockHash, Option<(LocalTime, PeerId)>>,
     /// Blocks received, waiting to be processed.
     pub received: HashMap<Height, Block>,
+    /// Number of times each remaining block was requested.
+    attempts: HashMap<BlockHash, u32>,
+    /// Time to wait for a block on the first request.
+    block_timeout: LocalDuration,
+    /// Configured fee filter sent to peers. If not set, it's derived from fee estimates.
+    fee_filter: Option<FeeRate>,
 
     last_tick: Option<LocalTime>,
     rng: fastrand::Rng,
@@ -201,16 +377,23 @@ pub struct InventoryManager<U, C> {
     clock: C,
 }
 
-impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
+impl<U: Wire<Event> + Wakeup + Disconnect, C: Clock> InventoryManager<U, C> {
     /// Create a new inventory manager.
     pub fn new(rng: fastrand::Rng, upstream: U, clock: C) -> Self {
         Self {
             peers: AddressBook::new(rng.clone()),
             mempool: BTreeMap::new(),
             estimator: FeeEstimator::default(),
+            mempool_fees: None,
+            sampled: HashMap::with_hasher(rng.clone().into()),
             confirmed: HashMap::with_hasher(rng.clone().into()),
+            outpoints: HashSet::with_hasher(rng.clone().into()),
+            spent: HashMap::with_hasher(rng.clone().into()),
             remaining: HashMap::with_hasher(rng.clone().into()),
             received: HashMap::with_hasher(rng.clone().into()),
+            attempts: HashMap::with_hasher(rng.clone().into()),
+            block_timeout: REQUEST_TIMEOUT,
+            fee_filter: None,
             timeout: REBROADCAST_TIMEOUT,
             last_tick: None,
             rng,
@@ -236,9 +419,12 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
         &mut self,
         socket: Socket,
         services: ServiceFlags,
+        version: u32,
         relay: bool,
         wtxidrelay: bool,
     ) {
+        let addr = socket.addr;
+
         // Add existing inventories to this peer's outbox so that they are announced.
         let mut outbox = HashMap::with_hasher(self.rng.clone().into());
         for (wtxid, tx) in self.mempool.iter() {
@@ -252,12 +438,19 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
                 attempts: 0,
                 relay,
                 wtxidrelay,
+                feefilter: version >= FEEFILTER_VERSION,
+                fee_filter: 0,
+                fee_filter_sent: None,
                 outbox,
                 last_attempt: None,
                 requests: HashMap::with_hasher(self.rng.clone().into()),
+                missing: HashSet::with_hasher(self.rng.clone().into()),
+                timeouts: 0,
+                blocks: 0,
                 _socket: socket,
             },
         );
+        self.send_fee_filter(&addr);
     }
 
     /// Called when a peer disconnected.
@@ -265,10 +458,90 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
         self.peers.remove(id);
     }
 
+    /// Get the number of requested blocks received from a peer.
+    pub fn blocks_received(&self, id: &PeerId) -> usize {
+        self.peers.get(id).map_or(0, |p| p.blocks)
+    }
+
+    /// Set the time to wait for a requested block on the first attempt, before requesting
+    /// it from another peer. Subsequent attempts wait longer, up to [`MAX_REQUEST_TIMEOUT`].
+    pub fn set_block_timeout(&mut self, timeout: LocalDuration) {
+        self.block_timeout = timeout;
+    }
+
+    /// Set the minimum fee rate of transactions we want announced to us, sent to peers in
+    /// `feefilter` messages. If not set, the lowest fee rate included in the last
+    /// [`FEEFILTER_TARGET_BLOCKS`] processed blocks is used, once known.
+    pub fn set_fee_filter(&mut self, rate: FeeRate) {
+        self.fee_filter = Some(rate);
+    }
+
+    /// Get the fee rate we ask peers to filter transaction announcements with, if any.
+    pub fn fee_filter(&self) -> Option<FeeRate> {
+        self.fee_filter.or_else(|| {
+            self.estimator
+                .estimate(FEEFILTER_TARGET_BLOCKS)
+                .map(|e| e.low)
+        })
+    }
+
+    /// Track the fee rates of unconfirmed transactions announced by peers, and blend them
+    /// with block fee estimates. Announced transactions are downloaded to measure their fee
+    /// rate, which is only possible if they spend outputs of processed blocks.
+    pub fn track_mempool_fees(&mut self) {
+        self.mempool_fees.get_or_insert_with(MempoolFees::default);
+    }
+
+    /// Watch an outpoint, and emit an event when a processed block spends it.
+    ///
+    /// Note that blocks are only processed if they are downloaded, so the outpoint's
+    /// script should also be added to the filter watchlist.
+    pub fn watch_outpoint(&mut self, outpoint: OutPoint) {
+        self.outpoints.insert(outpoint);
+    }
+
+    /// Get a fee estimate for confirmation within the given number of blocks.
+    pub fn fee_estimate(&self, target_blocks: usize) -> Option<FeeEstimate> {
+        let mempool = self
+            .mempool_fees
+            .as_ref()
+            .and_then(|m| m.estimate(self.clock.local_time()));
+
+        FeeEstimate::blend(self.estimator.estimate(target_blocks), mempool)
+    }
+
+    /// Get the fee estimates of recently processed blocks, oldest first.
+    pub fn fee_history(&self) -> Vec<(Height, FeeEstimate)> {
+        self.estimator.history().cloned().collect()
+    }
+
+    /// Restore the fee estimates of recently processed blocks, up to the given tip height.
+    pub fn restore_fee_history(&mut self, estimates: Vec<(Height, FeeEstimate)>, tip: Height) {
+        self.estimator.restore(estimates, tip);
+    }
+
+    /// Get the block requests awaiting a response.
+    pub fn inflight(&self) -> impl Iterator<Item = InFlight> + '_ {
+        let now = self.clock.local_time();
+
+        self.remaining.iter().filter_map(move |(hash, request)| {
+            request.map(|(sent_at, peer)| InFlight {
+                peer,
+                request: Request::GetBlock { hash: *hash },
+                age: now - sent_at,
+            })
+        })
+    }
+
     /// Called when a block is reverted.
     pub fn block_reverted(&mut self, height: Height) -> Vec<Transaction> {
         self.estimator.rollback(height - 1);
 
+        // Outpoints spent in a reverted block may be spent again elsewhere.
+        if let Some(outpoints) = self.spent.remove(&height) {
+            self.outpoints.extend(outpoints);
+        }
+
         if let Some(transactions) = self.confirmed.remove(&height) {
             for tx in transactions.iter().cloned() {
                 self.announce(tx);
@@ -295,6 +568,8 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
             let height = tree.height();
             self.confirmed
                 .retain(|h, _| height - h <= TRANSACTION_PRUNE_DEPTH);
+            self.spent
+                .retain(|h, _| height - h <= TRANSACTION_PRUNE_DEPTH);
         }
 
         // Handle retries annd disconnects.
@@ -344,23 +619,61 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
         }
 
         // Handle block request queue.
-        let queue = self
-            .remaining
-            .iter_mut()
-            .filter(|(_, t)| now - t.unwrap_or_default() >= REQUEST_TIMEOUT);
+        for (block_hash, last_request) in self.remaining.iter_mut() {
+            let attempts = self.attempts.get(block_hash).copied().unwrap_or_default();
+            let mut slow = None;
 
-        for (block_hash, last_request) in queue {
-            if let Some((addr, _)) = self
+            if let Some((time, peer)) = last_request {
+                if now - *time < block_timeout(self.block_timeout, attempts) {
+                    continue;
+                }
+                log::debug!("Request for block {} to {} timed out", block_hash, peer);
+
+                if let Some(p) = self.peers.get_mut(peer) {
+                    p.timeouts += 1;
+                }
+                self.upstream.event(Event::BlockRequestTimedOut {
+                    block: *block_hash,
+                    peer: *peer,
+                });
+                slow = Some(*peer);
+            }
+            let eligible =
+                |p: &Peer| p.services.has(ServiceFlags::NETWORK) && !p.missing.contains(block_hash);
+
+            // Prefer peers that timed out the least, other than the one that just timed out.
+            let timeouts = self
                 .peers
-                .sample_with(|_, p| p.services.has(ServiceFlags::NETWORK))
-            {
+                .iter()
+                .filter(|(a, p)| Some(**a) != slow && eligible(p))
+                .map(|(_, p)| p.timeouts)
+                .min();
+            let peer = self
+                .peers
+                .sample_with(|a, p| Some(*a) != slow && eligible(p) && Some(p.timeouts) == timeouts)
+                .or_else(|| self.peers.sample_with(|_, p| eligible(p)));
+
+            if let Some((addr, _)) = peer {
+                let timeout = block_timeout(self.block_timeout, attempts + 1);
+
                 log::debug!("Requesting block {} from {}", block_hash, addr);
 
                 self.upstream
                     .get_data(*addr, vec![Inventory::Block(*block_hash)]);
-                self.upstream.wakeup(REQUEST_TIMEOUT);
+                self.upstream.wakeup(timeout);
+                self.attempts.insert(*block_hash, attempts + 1);
+
+                if attempts == 0 {
+                    if let Some((height, _)) = tree.get_block(block_hash) {
+                        self.upstream.event(Event::BlockDownloadStarted {
+                            block: *block_hash,
+                            height,
+                            peer: *addr,
+                        });
+                    }
+                }
 
-                *last_request = Some(now);
+                *last_request = Some((now, *addr));
             } else {
                 log::debug!(
                     "No peers with required services to request block {} from",
@@ -439,13 +752,36 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
         let hash = block.block_hash();
         let from = *from;
 
-        if self.remaining.remove(&hash).is_none() {
+        if !self.remaining.contains_key(&hash) {
             // Nb. The remote isn't necessarily sending an unsolicited block here.
             // We often have to ask multiple peers to get a response, so we may
             // have already received this block once.
             return vec![];
         }
+        // Make sure the transactions actually belong to the block. If not, we'll request
+        // the block again from a different peer.
+        if let Err(reason) = validate(&block) {
+            self.peers.remove(&from);
+            self.upstream
+                .disconnect(from, DisconnectReason::PeerMisbehaving(reason));
+            self.upstream.event(Event::BlockRejected {
+                block: hash,
+                peer: from,
+                reason,
+            });
+            if let Some(last_request) = self.remaining.get_mut(&hash) {
+                *last_request = None;
+            }
+            self.schedule_tick();
 
+            return vec![];
+        }
+        self.remaining.remove(&hash);
+        self.attempts.remove(&hash);
+
+        if let Some(peer) = self.peers.get_mut(&from) {
+            peer.blocks += 1;
+        }
         // We're done requesting this block.
         for peer in self.peers.values_mut() {
             peer.requests.remove(&hash);
@@ -506,6 +842,23 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
                         height,
                     });
                 }
+
+                if self.outpoints.is_empty() || tx.is_coin_base() {
+                    continue;
+                }
+                for input in &tx.input {
+                    let outpoint = input.previous_output;
+
+                    if self.outpoints.remove(&outpoint) {
+                        self.spent.entry(height).or_default().push(outpoint);
+                        self.upstream.event(Event::OutpointSpent {
+                            outpoint,
+                            txid: tx.txid(),
+                            block: hash,
+                            height,
+                        });
+                    }
+                }
             }
             // Process block through fee estimator.
             let fees = self.estimator.process(block.clone(), height);
@@ -516,9 +869,158 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
                 fees,
             });
         }
+        // Our fee filter may have changed with the new fee estimates.
+        let addrs = self.peers.keys().copied().collect::<Vec<_>>();
+        for addr in addrs {
+            self.send_fee_filter(&addr);
+        }
         confirmed
     }
 
+    /// Called when an `inv` message is received from a peer. If mempool fees are tracked,
+    /// announced transactions are requested, to sample their fee rates.
+    pub fn received_inv(&mut self, addr: PeerId, invs: &[Inventory]) {
+        if self.mempool_fees.is_none() || !self.peers.contains_key(&addr) {
+            return;
+        }
+        let now = self.clock.local_time();
+        let mut getdata = Vec::new();
+
+        self.sampled
+            .retain(|_, (time, _)| now - *time <= MEMPOOL_SAMPLE_WINDOW);
+
+        for inv in invs {
+            if self.sampled.len() >= MAX_MEMPOOL_SAMPLES {
+                break;
+            }
+            let (hash, inv) = match inv {
+                Inventory::Transaction(txid) => {
+                    (txid.as_hash(), Inventory::WitnessTransaction(*txid))
+                }
+                Inventory::WTx(wtxid) => (wtxid.as_hash(), Inventory::WTx(*wtxid)),
+                _ => continue,
+            };
+            if self.sampled.contains_key(&hash) {
+                continue;
+            }
+            self.sampled.insert(hash, (now, false));
+            getdata.push(inv);
+        }
+        if !getdata.is_empty() {
+            self.upstream.get_data(addr, getdata);
+        }
+    }
+
+    /// Called when a `tx` message is received from a peer. If we requested it to sample
+    /// its fee rate, the rate is recorded.
+    ///
+    /// Peers that send us transactions below the fee filter we sent them are recorded as
+    /// misbehaving. This can only be checked for transactions whose fee rate is known, ie.
+    /// that spend outputs of processed blocks.
+    pub fn received_tx(&mut self, from: &PeerId, tx: &Transaction) {
+        let now = self.clock.local_time();
+        let rate = self.estimator.fee_rate(tx);
+
+        if let Some(mempool) = &mut self.mempool_fees {
+            let hashes = [tx.txid().as_hash(), tx.wtxid().as_hash()];
+
+            // Only sample transactions we asked for, and didn't sample yet.
+            if hashes
+                .iter()
+                .any(|h| matches!(self.sampled.get(h), Some((_, false))))
+            {
+                for hash in hashes {
+                    self.sampled.insert(hash, (now, true));
+                }
+                if let Some(rate) = rate {
+                    mempool.record(rate, now);
+                }
+            }
+        }
+
+        let (filter, since) = match self.peers.get(from).and_then(|p| p.fee_filter_sent) {
+            Some(sent) => sent,
+            None => return,
+        };
+        if now - since < FEEFILTER_GRACE_PERIOD {
+            return;
+        }
+        if matches!(rate, Some(rate) if rate < filter) {
+            self.record_misbehavior(from, "transaction below `feefilter` sent");
+        }
+    }
+
+    /// Called when a `feefilter` message is received from a peer, with a fee rate in
+    /// satoshis/kvB. Transactions below this rate are no longer announced to the peer.
+    pub fn received_feefilter(&mut self, from: &PeerId, rate: i64) {
+        let estimator = &self.estimator;
+        let mempool = &self.mempool;
+
+        if let Some(peer) = self.peers.get_mut(from) {
+            // Negative rates are invalid, and treated as no filter.
+            let filter = u64::try_from(rate).unwrap_or(0);
+
+            peer.fee_filter = filter;
+            peer.outbox
+                .retain(|wtxid, _| !below_filter(estimator, &mempool[wtxid], filter));
+        }
+    }
+
+    /// Called when a `reject` message is received from a peer.
+    /// If it concerns one of our transactions, the transaction is included in the event.
+    pub fn received_reject(&mut self, from: &PeerId, msg: Reject) {
+        let message = msg.message.to_string();
+        let txid = Some(Txid::from_hash(msg.hash))
+            .filter(|_| message == "tx")
+            .filter(|txid| self.mempool.values().any(|tx| tx.txid() == *txid));
+
+        log::debug!(
+            target: "p2p",
+            "{}: Received `reject` for `{}` message ({:?}): {}",
+            from,
+            message,
+            msg.ccode,
+            msg.reason
+        );
+
+        self.upstream.event(Event::MessageRejected {
+            peer: *from,
+            message,
+            txid,
+            code: msg.ccode,
+            reason: msg.reason.into_owned(),
+        });
+    }
+
+    /// Called when a `notfound` message is received from a peer.
+    pub fn received_notfound(&mut self, from: &PeerId, invs: Vec<Inventory>) {
+        let peer = if let Some(peer) = self.peers.get_mut(from) {
+            peer
+        } else {
+            return;
+        };
+
+        let mut missing = Vec::new();
+        for inv in invs {
+            if let Inventory::Block(hash) | Inventory::WitnessBlock(hash) = inv {
+                if let Some(last_request) = self.remaining.get_mut(&hash) {
+                    peer.missing.insert(hash);
+                    missing.push(hash);
+                    // Retry right away with another peer.
+                    *last_request = None;
+                }
+            }
+        }
+
+        // Let the user know about blocks that none of our peers have.
+        for hash in missing {
+            if self.peers.values().all(|p| p.missing.contains(&hash)) {
+                self.upstream.event(Event::BlockNotFound { block: hash });
+            }
+        }
+        self.schedule_tick();
+    }
+
     /// Announce inventories to all matching peers. Retries if necessary.
     pub fn announce(&mut self, tx: Transaction) -> Vec<PeerId> {
         // All peers we are sending inventories to.
@@ -531,6 +1033,9 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
         self.mempool.insert(wtxid, tx);
 
         for (addr, peer) in self.peers.iter_mut().filter(|(_, p)| p.relay) {
+            if below_filter(&self.estimator, &self.mempool[&wtxid], peer.fee_filter) {
+                continue;
+            }
             peer.outbox.insert(wtxid, txid);
             addrs.push(*addr);
         }
@@ -549,12 +1054,74 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
 
     ////////////////////////////////////////////////////////////////////////////
 
+    /// Send our fee filter to a peer, if it supports it, and it changed since it was last sent.
+    fn send_fee_filter(&mut self, addr: &PeerId) {
+        // Transactions are only relayed to us if we're tracking mempool fees.
+        if self.mempool_fees.is_none() {
+            return;
+        }
+        let rate = if let Some(rate) = self.fee_filter() {
+            rate
+        } else {
+            return;
+        };
+        let now = self.clock.local_time();
+
+        if let Some(peer) = self.peers.get_mut(addr) {
+            if !peer.feefilter || matches!(peer.fee_filter_sent, Some((r, _)) if r == rate) {
+                return;
+            }
+            peer.fee_filter_sent = Some((rate, now));
+            self.upstream
+                .fee_filter(*addr, rate.saturating_mul(1000) as i64);
+        }
+    }
+
+    fn record_misbehavior(&mut self, peer: &PeerId, reason: &'static str) {
+        self.upstream.event(Event::PeerMisbehaved {
+            peer: *peer,
+            reason,
+        });
+    }
+
     fn schedule_tick(&mut self) {
         self.last_tick = None; // Disable rate-limiting for the next tick.
         self.upstream.wakeup(LocalDuration::from_secs(1));
     }
 }
 
+/// Check whether a transaction's fee rate is known to be below a fee filter, in satoshis/kvB.
+fn below_filter(estimator: &FeeEstimator, tx: &Transaction, filter: u64) -> bool {
+    estimator
+        .fee_rate(tx)
+        .map_or(false, |rate| rate.saturating_mul(1000) < filter)
+}
+
+/// Get the time to wait for a block on the given request attempt.
+fn block_timeout(base: LocalDuration, attempt: u32) -> LocalDuration {
+    base.saturating_mul(attempt.max(1) as u64)
+        .min(MAX_REQUEST_TIMEOUT)
+}
+
+/// Check that a block's transactions match its header.
+///
+/// Besides the merkle root, we check for duplicate transactions, since a block with its
+/// last transactions repeated has the same merkle root as the original (CVE-2012-2459),
+/// and for a valid witness commitment, since witness data isn't covered by the merkle root.
+fn validate(block: &Block) -> Result<(), &'static str> {
+    if !block.check_merkle_root() {
+        return Err("block: invalid merkle root");
+    }
+    let mut txids = BTreeSet::new();
+    if !block.txdata.iter().all(|tx| txids.insert(tx.txid())) {
+        return Err("block: duplicate transaction");
+    }
+    if !block.check_witness_commitment() {
+        return Err("block: invalid witness commitment");
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -567,6 +1134,7 @@ mod tests {
     use crate::fsm::{Io, PROTOCOL_VERSION};
 
     use nakamoto_common::bitcoin::network::message::NetworkMessage;
+    use nakamoto_common::bitcoin::OutPoint;
     use nakamoto_common::block::time::RefClock;
     use nakamoto_common::block::tree::BlockTree as _;
     use nakamoto_common::collections::HashSet;
@@ -606,24 +1174,28 @@ mod tests {
         invmgr.peer_negotiated(
             Socket::new(([66, 66, 66, 66], 8333)),
             ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
             true,
             true,
         );
         invmgr.peer_negotiated(
             Socket::new(([77, 77, 77, 77], 8333)),
             ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
             true,
             true,
         );
         invmgr.peer_negotiated(
             Socket::new(([88, 88, 88, 88], 8333)),
             ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
             true,
             true,
         );
         invmgr.peer_negotiated(
             Socket::new(([99, 99, 99, 99], 8333)),
             ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
             true,
             true,
         );
@@ -674,6 +1246,292 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_get_block_notfound() {
+        let network = Network::Regtest;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis, 16, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let hash = tree.get_block_by_height(6).unwrap().block_hash();
+        let inv = vec![Inventory::Block(hash)];
+        let (alice, bob): (net::SocketAddr, net::SocketAddr) = (
+            ([66, 66, 66, 66], 8333).into(),
+            ([77, 77, 77, 77], 8333).into(),
+        );
+
+        let mut invmgr = InventoryManager::new(rng, upstream.clone(), clock.clone());
+
+        invmgr.peer_negotiated(
+            Socket::new(alice),
+            ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
+            true,
+            true,
+        );
+        invmgr.peer_negotiated(
+            Socket::new(bob),
+            ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
+            true,
+            true,
+        );
+        invmgr.get_block(hash);
+        invmgr.received_wake(&tree);
+
+        let (first, _) = output::test::messages(&mut upstream)
+            .find(|(_, m)| matches!(m, NetworkMessage::GetData(i) if i == &inv))
+            .unwrap();
+        let second = if first == alice { bob } else { alice };
+
+        // The first peer doesn't have the block, so we ask the other one right away.
+        invmgr.received_notfound(&first, inv.clone());
+        invmgr.received_wake(&tree);
+
+        assert_eq!(
+            output::test::messages(&mut upstream)
+                .find(|(_, m)| matches!(m, NetworkMessage::GetData(i) if i == &inv))
+                .map(|(addr, _)| addr),
+            Some(second)
+        );
+        assert!(events(upstream.drain()).all(|e| !matches!(e, Event::BlockNotFound { .. })));
+
+        // Neither peer has the block.
+        invmgr.received_notfound(&second, inv);
+        assert_matches!(
+            events(upstream.drain()).find(|e| matches!(e, Event::BlockNotFound { .. })),
+            Some(Event::BlockNotFound { block }) if block == hash
+        );
+        assert!(!invmgr.remaining.is_empty(), "The block is still requested");
+    }
+
+    #[test]
+    fn test_get_block_timeout() {
+        let network = Network::Regtest;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis, 16, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let hash = tree.get_block_by_height(6).unwrap().block_hash();
+        let inv = vec![Inventory::Block(hash)];
+        let (alice, bob): (net::SocketAddr, net::SocketAddr) = (
+            ([66, 66, 66, 66], 8333).into(),
+            ([77, 77, 77, 77], 8333).into(),
+        );
+        let timeout = LocalDuration::from_secs(60);
+
+        let mut invmgr = InventoryManager::new(rng, upstream.clone(), clock.clone());
+        let requested = |upstream: &mut Outbox| {
+            output::test::messages(upstream)
+                .find(|(_, m)| matches!(m, NetworkMessage::GetData(i) if i == &inv))
+                .map(|(addr, _)| addr)
+        };
+
+        invmgr.set_block_timeout(timeout);
+        invmgr.peer_negotiated(
+            Socket::new(alice),
+            ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
+            true,
+            true,
+        );
+        invmgr.peer_negotiated(
+            Socket::new(bob),
+            ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
+            true,
+            true,
+        );
+        invmgr.get_block(hash);
+        invmgr.received_wake(&tree);
+
+        let first = requested(&mut upstream).unwrap();
+        let second = if first == alice { bob } else { alice };
+        assert_matches!(
+            events(upstream.drain()).find(|e| matches!(e, Event::BlockDownloadStarted { .. })),
+            Some(Event::BlockDownloadStarted { block, height: 6, peer })
+            if block == hash && peer == first
+        );
+
+        clock.elapse(timeout - LocalDuration::from_secs(1));
+        invmgr.received_wake(&tree);
+        assert_eq!(requested(&mut upstream), None);
+
+        // The first peer is too slow, so we ask the other one.
+        clock.elapse(LocalDuration::from_secs(1));
+        invmgr.received_wake(&tree);
+        assert_eq!(requested(&mut upstream), Some(second));
+        assert_matches!(
+            events(upstream.drain()).find(|e| matches!(e, Event::BlockRequestTimedOut { .. })),
+            Some(Event::BlockRequestTimedOut { block, peer }) if block == hash && peer == first
+        );
+        assert!(
+            events(upstream.drain()).all(|e| !matches!(e, Event::BlockDownloadStarted { .. })),
+            "Retries aren't reported as new downloads"
+        );
+
+        // The second attempt is given more time.
+        clock.elapse(timeout);
+        invmgr.received_wake(&tree);
+        assert_eq!(requested(&mut upstream), None);
+
+        clock.elapse(timeout);
+        invmgr.received_wake(&tree);
+        assert_eq!(requested(&mut upstream), Some(first));
+    }
+
+    #[test]
+    fn test_get_block_invalid_merkle_root() {
+        let network = Network::Regtest;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis, 16, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let hash = tree.get_block_by_height(6).unwrap().block_hash();
+        let block = chain.iter().find(|b| b.block_hash() == hash).unwrap();
+        let inv = vec![Inventory::Block(hash)];
+        let (alice, bob): (net::SocketAddr, net::SocketAddr) = (
+            ([66, 66, 66, 66], 8333).into(),
+            ([77, 77, 77, 77], 8333).into(),
+        );
+
+        let mut invmgr = InventoryManager::new(rng.clone(), upstream.clone(), clock);
+        let requested = |upstream: &mut Outbox| {
+            output::test::messages(upstream)
+                .find(|(_, m)| matches!(m, NetworkMessage::GetData(i) if i == &inv))
+                .map(|(addr, _)| addr)
+        };
+
+        invmgr.peer_negotiated(
+            Socket::new(alice),
+            ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
+            true,
+            true,
+        );
+        invmgr.peer_negotiated(
+            Socket::new(bob),
+            ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
+            true,
+            true,
+        );
+        invmgr.get_block(hash);
+        invmgr.received_wake(&tree);
+
+        let first = requested(&mut upstream).unwrap();
+        let second = if first == alice { bob } else { alice };
+        upstream.drain().for_each(drop);
+
+        let mut forged = block.clone();
+        forged.txdata.push(gen::transaction(&mut rng));
+        invmgr.received_block(&first, forged, &tree);
+
+        assert!(
+            invmgr.remaining.contains_key(&hash),
+            "The block is still requested"
+        );
+        let outputs = upstream.drain().collect::<Vec<_>>();
+        assert!(outputs
+            .iter()
+            .any(|o| matches!(o, Io::DisconnectPeer(addr, _) if *addr == first)));
+        assert_matches!(
+            events(outputs.into_iter()).find(|e| matches!(e, Event::BlockRejected { .. })),
+            Some(Event::BlockRejected { block, peer, .. }) if block == hash && peer == first
+        );
+
+        // The block is requested again, from the other peer.
+        invmgr.received_wake(&tree);
+        assert_eq!(requested(&mut upstream), Some(second));
+        upstream.drain().for_each(drop);
+
+        invmgr.received_block(&second, block.clone(), &tree);
+        assert!(invmgr.remaining.is_empty(), "The block was accepted");
+        assert_matches!(
+            events(upstream.drain()).find(|e| matches!(e, Event::BlockProcessed { .. })),
+            Some(Event::BlockProcessed { block: b, .. }) if b.block_hash() == hash
+        );
+    }
+
+    #[test]
+    fn test_watch_outpoint() {
+        let network = Network::Regtest;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis, 16, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let block = chain.iter().skip(1).find(|b| b.txdata.len() > 1).unwrap();
+        let hash = block.block_hash();
+        let (height, _) = tree.get_block(&hash).unwrap();
+        let spender = &block.txdata[1];
+        let outpoint = spender.input[0].previous_output;
+        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+
+        let mut invmgr = InventoryManager::new(rng, upstream.clone(), clock);
+
+        invmgr.peer_negotiated(
+            Socket::new(remote),
+            ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
+            true,
+            true,
+        );
+        invmgr.watch_outpoint(outpoint);
+        invmgr.get_block(hash);
+        invmgr.received_wake(&tree);
+        upstream.drain().for_each(drop);
+
+        invmgr.received_block(&remote, block.clone(), &tree);
+
+        assert_matches!(
+            events(upstream.drain()).find(|e| matches!(e, Event::OutpointSpent { .. })),
+            Some(Event::OutpointSpent { outpoint: o, txid, block: b, height: h })
+            if o == outpoint && txid == spender.txid() && b == hash && h == height
+        );
+        assert!(!invmgr.outpoints.contains(&outpoint));
+
+        // If the block is reverted, the outpoint is watched again.
+        invmgr.block_reverted(height);
+        assert!(invmgr.outpoints.contains(&outpoint));
+    }
+
+    #[test]
+    fn test_validate_duplicate_transactions() {
+        let mut rng = fastrand::Rng::new();
+        let genesis = Network::Regtest.genesis_block();
+        let txdata = vec![
+            gen::coinbase(&mut rng),
+            gen::transaction(&mut rng),
+            gen::transaction(&mut rng),
+        ];
+        let mut block = gen::block_with(&genesis.header, txdata, &mut rng);
+
+        assert_eq!(validate(&block), Ok(()));
+
+        // With an odd number of transactions, duplicating the last one doesn't change
+        // the merkle root.
+        block.txdata.push(block.txdata[2].clone());
+
+        assert!(block.check_merkle_root());
+        assert_eq!(validate(&block), Err("block: duplicate transaction"));
+    }
+
     #[test]
     fn test_rebroadcast_timeout() {
         let network = Network::Mainnet;
@@ -687,7 +1545,13 @@ mod tests {
 
         let mut invmgr = InventoryManager::new(rng, upstream.clone(), clock.clone());
 
-        invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, false);
+        invmgr.peer_negotiated(
+            remote.into(),
+            ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
+            true,
+            false,
+        );
         invmgr.announce(tx);
         invmgr.received_wake(&tree);
 
@@ -728,7 +1592,13 @@ mod tests {
 
         let mut invmgr = InventoryManager::new(rng, upstream.clone(), clock.clone());
 
-        invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, false);
+        invmgr.peer_negotiated(
+            remote.into(),
+            ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
+            true,
+            false,
+        );
         invmgr.announce(tx.clone());
 
         // We attempt to broadcast up to `MAX_ATTEMPTS` times.
@@ -776,7 +1646,13 @@ mod tests {
         let mut tree = model::Cache::from(headers);
         let mut invmgr = InventoryManager::new(rng, upstream.clone(), time);
 
-        invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, false);
+        invmgr.peer_negotiated(
+            remote.into(),
+            ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
+            true,
+            false,
+        );
         invmgr.announce(tx.clone());
         invmgr.get_block(main_block1.block_hash());
         invmgr.received_block(&remote, main_block1, &tree);
@@ -840,7 +1716,13 @@ mod tests {
 
         let mut invmgr = InventoryManager::new(rng, upstream.clone(), time);
 
-        invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, true);
+        invmgr.peer_negotiated(
+            remote.into(),
+            ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
+            true,
+            true,
+        );
         invmgr.announce(tx);
 
         invmgr.received_wake(&tree);
@@ -856,7 +1738,13 @@ mod tests {
             .unwrap();
         assert_matches!(invs.first(), Some(Inventory::WTx(_)));
 
-        invmgr.peer_negotiated(remote2.into(), ServiceFlags::NETWORK, true, false);
+        invmgr.peer_negotiated(
+            remote2.into(),
+            ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
+            true,
+            false,
+        );
         invmgr.received_wake(&tree);
         let invs = output::test::messages_from(&mut upstream, &remote2)
             .filter_map(|m| match m {
@@ -880,7 +1768,13 @@ mod tests {
 
         let mut invmgr = InventoryManager::new(rng, upstream.clone(), LocalTime::now());
 
-        invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, true);
+        invmgr.peer_negotiated(
+            remote.into(),
+            ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
+            true,
+            true,
+        );
         invmgr.announce(tx.clone());
 
         invmgr.received_getdata(remote, &[Inventory::Transaction(tx.txid())]);
@@ -909,4 +1803,156 @@ mod tests {
             .unwrap();
         assert_eq!(tr.wtxid(), tx.wtxid());
     }
+
+    #[test]
+    fn test_mempool_fees() {
+        let network = Network::Regtest;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let mut rng = fastrand::Rng::with_seed(1);
+        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+
+        let chain = gen::blockchain(network.genesis_block(), 2, &mut rng);
+        let block = chain.last().clone();
+        let tree = model::Cache::from(
+            NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap(),
+        );
+        let tx = &block.txdata[0];
+        let spend = gen::transaction_with(
+            OutPoint {
+                txid: tx.txid(),
+                vout: 0,
+            },
+            tx.output[0].value,
+            &mut rng,
+        );
+        let inv = Inventory::Transaction(spend.txid());
+
+        let mut invmgr = InventoryManager::new(rng, upstream.clone(), LocalTime::now());
+        invmgr.peer_negotiated(
+            remote.into(),
+            ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
+            true,
+            false,
+        );
+        invmgr.get_block(block.block_hash());
+        invmgr.received_block(&remote, block, &tree);
+
+        // Mempool fees are not tracked by default.
+        invmgr.received_inv(remote, &[inv]);
+        assert!(invmgr.sampled.is_empty());
+        upstream.drain().for_each(drop);
+
+        invmgr.track_mempool_fees();
+        assert_eq!(invmgr.fee_estimate(1), None);
+
+        invmgr.received_inv(remote, &[inv, inv]);
+        let getdata = output::test::messages_from(&mut upstream, &remote)
+            .filter_map(|m| match m {
+                NetworkMessage::GetData(invs) => Some(invs),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            getdata,
+            vec![vec![Inventory::WitnessTransaction(spend.txid())]],
+            "Announced transactions are requested once"
+        );
+
+        // Unsolicited transactions are ignored.
+        invmgr.received_tx(&remote, &gen::transaction(&mut fastrand::Rng::new()));
+        assert_eq!(invmgr.fee_estimate(1), None);
+
+        invmgr.received_tx(&remote, &spend);
+        invmgr.received_tx(&remote, &spend);
+        assert_eq!(invmgr.mempool_fees.as_ref().unwrap().len(), 1);
+        assert!(invmgr.fee_estimate(1).is_some());
+    }
+
+    #[test]
+    fn test_fee_filter() {
+        let network = Network::Regtest;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let mut rng = fastrand::Rng::with_seed(1);
+        let clock = RefClock::from(LocalTime::now());
+        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let legacy: net::SocketAddr = ([99, 99, 99, 99], 8333).into();
+
+        let chain = gen::blockchain(network.genesis_block(), 2, &mut rng);
+        let block = chain.last().clone();
+        let tree = model::Cache::from(
+            NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap(),
+        );
+        let tx = &block.txdata[0];
+        let spend = gen::transaction_with(
+            OutPoint {
+                txid: tx.txid(),
+                vout: 0,
+            },
+            tx.output[0].value,
+            &mut rng,
+        );
+
+        let mut invmgr = InventoryManager::new(rng, upstream.clone(), clock.clone());
+        invmgr.track_mempool_fees();
+        invmgr.get_block(block.block_hash());
+        invmgr.received_block(&remote, block, &tree);
+
+        let rate = invmgr.estimator.fee_rate(&spend).unwrap();
+        let filter = (rate as i64 + 1) * 1000;
+
+        invmgr.set_fee_filter(rate + 1);
+        invmgr.peer_negotiated(
+            remote.into(),
+            ServiceFlags::NETWORK,
+            PROTOCOL_VERSION,
+            true,
+            false,
+        );
+        invmgr.peer_negotiated(
+            legacy.into(),
+            ServiceFlags::NETWORK,
+            FEEFILTER_VERSION - 1,
+            true,
+            false,
+        );
+
+        // Our fee filter is sent to peers that support it.
+        assert!(output::test::messages_from(&mut upstream, &remote)
+            .any(|m| m == NetworkMessage::FeeFilter(filter)));
+        assert!(!output::test::messages_from(&mut upstream, &legacy)
+            .any(|m| matches!(m, NetworkMessage::FeeFilter(_))));
+
+        // Transactions below a peer's fee filter aren't announced to it.
+        invmgr.received_feefilter(&remote, filter);
+        assert_eq!(invmgr.announce(spend.clone()), vec![legacy]);
+
+        invmgr.received_feefilter(&remote, filter - 1000);
+        assert!(invmgr.announce(spend.clone()).contains(&remote));
+
+        // Raising the filter drops pending announcements below it.
+        invmgr.received_feefilter(&remote, filter);
+        assert!(invmgr.peers.get(&remote).unwrap().outbox.is_empty());
+
+        // Peers that send transactions below our fee filter are recorded as misbehaving,
+        // once they had time to apply it.
+        upstream.drain().for_each(drop);
+        invmgr.received_tx(&remote, &spend);
+        assert!(events(upstream.drain()).all(|e| !matches!(e, Event::PeerMisbehaved { .. })));
+
+        clock.elapse(FEEFILTER_GRACE_PERIOD);
+        invmgr.received_tx(&remote, &spend);
+        assert_matches!(
+            events(upstream.drain()).find(|e| matches!(e, Event::PeerMisbehaved { .. })),
+            Some(Event::PeerMisbehaved { peer, .. }) if peer == remote
+        );
+        assert!(
+            invmgr.peers.contains_key(&remote),
+            "The peer isn't disconnected"
+        );
+
+        // Peers we didn't send a fee filter to aren't held to one.
+        invmgr.received_tx(&legacy, &spend);
+        assert!(events(upstream.drain()).all(|e| !matches!(e, Event::PeerMisbehaved { .. })));
+    }
 }
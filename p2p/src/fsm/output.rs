@@ -6,7 +6,7 @@
 //! with specific capabilities, eg. peer disconnection, message sending etc. to
 //! communicate with the network.
 use log::*;
-use std::cell::{Ref, RefCell};
+use std::cell::{Cell, Ref, RefCell};
 use std::collections::VecDeque;
 use std::net;
 use std::rc::Rc;
@@ -16,10 +16,13 @@ pub use crossbeam_channel as chan;
 use nakamoto_common::bitcoin::network::address::Address;
 use nakamoto_common::bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
 use nakamoto_common::bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
+use nakamoto_common::bitcoin::network::message_bloom::FilterLoad;
+use nakamoto_common::bitcoin::network::message_compact_blocks::{GetBlockTxn, SendCmpct};
 use nakamoto_common::bitcoin::network::message_filter::{
-    CFHeaders, CFilter, GetCFHeaders, GetCFilters,
+    CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, GetCFHeaders, GetCFilters,
 };
 use nakamoto_common::bitcoin::network::message_network::VersionMessage;
+use nakamoto_common::bitcoin::util::bip152::BlockTransactionsRequest;
 use nakamoto_common::bitcoin::Transaction;
 use nakamoto_common::block::time::LocalDuration;
 use nakamoto_common::block::{BlockHash, BlockHeader, BlockTime, Height};
@@ -103,6 +106,9 @@ pub trait Wire<E> {
         timeout: LocalDuration,
     );
 
+    /// Get compact filter header checkpoints from a peer, up to the stop hash.
+    fn get_cfcheckpt(&mut self, addr: PeerId, stop_hash: BlockHash, timeout: LocalDuration);
+
     /// Get compact filters from a peer.
     fn get_cfilters(
         &mut self,
@@ -118,6 +124,9 @@ pub trait Wire<E> {
     /// Send a compact filter to a peer.
     fn cfilter(&mut self, addr: PeerId, filter: CFilter);
 
+    /// Send compact filter header checkpoints to a peer.
+    fn cfcheckpt(&mut self, addr: PeerId, checkpoint: CFCheckpt);
+
     // Header sync /////////////////////////////////////////////////////////////
 
     /// Get headers from a peer.
@@ -136,6 +145,22 @@ pub trait Wire<E> {
 
     /// Sends a `tx` message to a peer.
     fn tx(&mut self, addr: PeerId, tx: Transaction);
+
+    /// Sends a BIP 133 `feefilter` message to a peer, with a fee rate in satoshis/kvB.
+    fn fee_filter(&mut self, addr: PeerId, rate: i64);
+
+    // Bloom filters ///////////////////////////////////////////////////////////
+
+    /// Sends a `filterload` message to a peer.
+    fn filter_load(&mut self, addr: PeerId, filter: FilterLoad);
+
+    // Compact blocks //////////////////////////////////////////////////////////
+
+    /// Sends a `sendcmpct` message to a peer.
+    fn send_cmpct(&mut self, addr: PeerId, high_bandwidth: bool, version: u64);
+
+    /// Sends a `getblocktxn` message to a peer.
+    fn get_block_txn(&mut self, addr: PeerId, request: BlockTransactionsRequest);
 }
 
 /// Holds protocol outputs and pending I/O.
@@ -147,6 +172,8 @@ pub struct Outbox {
     network: Network,
     /// Output queue.
     outbound: Rc<RefCell<VecDeque<Io>>>,
+    /// Number of events pushed so far.
+    events: Rc<Cell<u64>>,
 }
 
 impl Iterator for Outbox {
@@ -165,14 +192,23 @@ impl Outbox {
             version,
             network,
             outbound: Rc::new(RefCell::new(VecDeque::new())),
+            events: Rc::new(Cell::new(0)),
         }
     }
 
     /// Push an output to the channel.
     pub fn push(&self, output: Io) {
+        if let Io::NotifySubscribers(_) = output {
+            self.events.set(self.events.get() + 1);
+        }
         self.outbound.borrow_mut().push_back(output);
     }
 
+    /// Number of events pushed to the channel so far.
+    pub fn events(&self) -> u64 {
+        self.events.get()
+    }
+
     /// Drain the outbound queue.
     pub fn drain(&mut self) -> Drain {
         Drain {
@@ -235,7 +271,7 @@ impl Wakeup for Outbox {
 
 impl Connect for Outbox {
     fn connect(&self, addr: net::SocketAddr, timeout: LocalDuration) {
-        self.push(Io::ConnectPeer(addr));
+        self.push(Io::ConnectPeer(addr, timeout));
         self.push(Io::SetTimer(timeout));
     }
 }
@@ -319,6 +355,17 @@ impl<E: Into<Event> + std::fmt::Display> Wire<E> for Outbox {
         self.wakeup(timeout);
     }
 
+    fn get_cfcheckpt(&mut self, addr: PeerId, stop_hash: BlockHash, timeout: LocalDuration) {
+        self.message(
+            addr,
+            NetworkMessage::GetCFCheckpt(GetCFCheckpt {
+                filter_type: 0x0,
+                stop_hash,
+            }),
+        );
+        self.wakeup(timeout);
+    }
+
     fn cfheaders(&mut self, addr: PeerId, headers: CFHeaders) {
         self.message(addr, NetworkMessage::CFHeaders(headers));
     }
@@ -345,6 +392,10 @@ impl<E: Into<Event> + std::fmt::Display> Wire<E> for Outbox {
         self.message(addr, NetworkMessage::CFilter(cfilter));
     }
 
+    fn cfcheckpt(&mut self, addr: PeerId, checkpoint: CFCheckpt) {
+        self.message(addr, NetworkMessage::CFCheckpt(checkpoint));
+    }
+
     fn inv(&mut self, addr: PeerId, inventories: Vec<Inventory>) {
         self.message(addr, NetworkMessage::Inv(inventories));
     }
@@ -356,6 +407,33 @@ impl<E: Into<Event> + std::fmt::Display> Wire<E> for Outbox {
     fn tx(&mut self, addr: PeerId, tx: Transaction) {
         self.message(addr, NetworkMessage::Tx(tx));
     }
+
+    fn fee_filter(&mut self, addr: PeerId, rate: i64) {
+        self.message(addr, NetworkMessage::FeeFilter(rate));
+    }
+
+    fn filter_load(&mut self, addr: PeerId, filter: FilterLoad) {
+        self.message(addr, NetworkMessage::FilterLoad(filter));
+    }
+
+    fn send_cmpct(&mut self, addr: PeerId, high_bandwidth: bool, version: u64) {
+        self.message(
+            addr,
+            NetworkMessage::SendCmpct(SendCmpct {
+                send_compact: high_bandwidth,
+                version,
+            }),
+        );
+    }
+
+    fn get_block_txn(&mut self, addr: PeerId, request: BlockTransactionsRequest) {
+        self.message(
+            addr,
+            NetworkMessage::GetBlockTxn(GetBlockTxn {
+                txs_request: request,
+            }),
+        );
+    }
 }
 
 #[cfg(test)]
@@ -363,6 +441,7 @@ impl<E: Into<Event> + std::fmt::Display> Wire<E> for Outbox {
 impl<E> Wire<E> for () {
     fn event(&self, event: E) {}
     fn tx(&mut self, addr: PeerId, tx: Transaction) {}
+    fn fee_filter(&mut self, addr: PeerId, rate: i64) {}
     fn inv(&mut self, addr: PeerId, inventories: Vec<Inventory>) {}
     fn get_data(&mut self, addr: PeerId, inventories: Vec<Inventory>) {}
     fn get_headers(&mut self, addr: PeerId, locators: Locators) {}
@@ -371,6 +450,10 @@ impl<E> Wire<E> for () {
     fn headers(&mut self, addr: PeerId, headers: Vec<BlockHeader>) {}
     fn addr(&mut self, addr: PeerId, addrs: Vec<(BlockTime, Address)>) {}
     fn cfheaders(&mut self, addr: PeerId, headers: CFHeaders) {}
+    fn cfcheckpt(&mut self, addr: PeerId, checkpoint: CFCheckpt) {}
+    fn filter_load(&mut self, addr: PeerId, filter: FilterLoad) {}
+    fn send_cmpct(&mut self, addr: PeerId, high_bandwidth: bool, version: u64) {}
+    fn get_block_txn(&mut self, addr: PeerId, request: BlockTransactionsRequest) {}
     fn ping(&mut self, addr: net::SocketAddr, nonce: u64) -> &Self {
         self
     }
@@ -405,6 +488,7 @@ impl<E> Wire<E> for () {
         timeout: LocalDuration,
     ) {
     }
+    fn get_cfcheckpt(&mut self, addr: PeerId, stop_hash: BlockHash, timeout: LocalDuration) {}
 }
 
 #[cfg(test)]
@@ -432,6 +516,271 @@ pub mod test {
     use super::*;
     use nakamoto_common::bitcoin::network::message::NetworkMessage;
 
+    use crate::fsm::{DisconnectReason, PROTOCOL_VERSION};
+
+    /// A call made to a [`Mock`] upstream.
+    #[derive(Debug, Clone)]
+    pub enum Call {
+        /// A message was sent to a peer.
+        Message(PeerId, NetworkMessage),
+        /// An event was emitted.
+        Event(Event),
+        /// A wakeup was scheduled.
+        Wakeup(LocalDuration),
+        /// A connection to a peer was attempted, with the given timeout.
+        Connect(PeerId, LocalDuration),
+        /// A peer was disconnected.
+        Disconnect(PeerId, DisconnectReason),
+    }
+
+    /// Lightweight upstream for testing a manager in isolation. Records the calls made
+    /// to it, without any network framing. Clones share the same record.
+    #[derive(Debug, Default, Clone)]
+    pub struct Mock {
+        calls: Rc<RefCell<Vec<Call>>>,
+    }
+
+    impl Mock {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Take the calls recorded so far, oldest first.
+        pub fn take(&self) -> Vec<Call> {
+            self.calls.borrow_mut().drain(..).collect()
+        }
+
+        /// Get the messages sent so far.
+        pub fn messages(&self) -> Vec<(PeerId, NetworkMessage)> {
+            self.calls
+                .borrow()
+                .iter()
+                .filter_map(|c| match c {
+                    Call::Message(addr, msg) => Some((*addr, msg.clone())),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        /// Get the nonces of the pings sent to a peer so far.
+        pub fn pings(&self, addr: &PeerId) -> Vec<u64> {
+            self.messages()
+                .into_iter()
+                .filter_map(|(a, msg)| match msg {
+                    NetworkMessage::Ping(nonce) if a == *addr => Some(nonce),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        /// Get the wakeups scheduled so far.
+        pub fn wakeups(&self) -> Vec<LocalDuration> {
+            self.calls
+                .borrow()
+                .iter()
+                .filter_map(|c| match c {
+                    Call::Wakeup(d) => Some(*d),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        /// Get the peers disconnected so far.
+        pub fn disconnects(&self) -> Vec<(PeerId, DisconnectReason)> {
+            self.calls
+                .borrow()
+                .iter()
+                .filter_map(|c| match c {
+                    Call::Disconnect(addr, reason) => Some((*addr, reason.clone())),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        fn record(&self, call: Call) {
+            self.calls.borrow_mut().push(call);
+        }
+
+        fn message(&self, addr: PeerId, msg: NetworkMessage) -> &Self {
+            self.record(Call::Message(addr, msg));
+            self
+        }
+    }
+
+    impl Connect for Mock {
+        fn connect(&self, addr: net::SocketAddr, timeout: LocalDuration) {
+            self.record(Call::Connect(addr, timeout));
+        }
+    }
+
+    impl Disconnect for Mock {
+        fn disconnect(&self, addr: net::SocketAddr, reason: DisconnectReason) {
+            self.record(Call::Disconnect(addr, reason));
+        }
+    }
+
+    impl Wakeup for Mock {
+        fn wakeup(&self, duration: LocalDuration) -> &Self {
+            self.record(Call::Wakeup(duration));
+            self
+        }
+    }
+
+    impl<E: Into<Event>> Wire<E> for Mock {
+        fn event(&self, event: E) {
+            self.record(Call::Event(event.into()));
+        }
+
+        fn version(&mut self, addr: PeerId, msg: VersionMessage) -> &mut Self {
+            self.message(addr, NetworkMessage::Version(msg));
+            self
+        }
+
+        fn verack(&mut self, addr: PeerId) -> &mut Self {
+            self.message(addr, NetworkMessage::Verack);
+            self
+        }
+
+        fn wtxid_relay(&mut self, addr: PeerId) -> &mut Self {
+            self.message(addr, NetworkMessage::WtxidRelay);
+            self
+        }
+
+        fn send_headers(&mut self, addr: PeerId) -> &mut Self {
+            self.message(addr, NetworkMessage::SendHeaders);
+            self
+        }
+
+        fn ping(&mut self, addr: net::SocketAddr, nonce: u64) -> &Self {
+            self.message(addr, NetworkMessage::Ping(nonce))
+        }
+
+        fn pong(&mut self, addr: net::SocketAddr, nonce: u64) -> &Self {
+            self.message(addr, NetworkMessage::Pong(nonce))
+        }
+
+        fn get_addr(&mut self, addr: PeerId) {
+            self.message(addr, NetworkMessage::GetAddr);
+        }
+
+        fn addr(&mut self, addr: PeerId, addrs: Vec<(BlockTime, Address)>) {
+            self.message(addr, NetworkMessage::Addr(addrs));
+        }
+
+        fn get_cfheaders(
+            &mut self,
+            addr: PeerId,
+            start_height: Height,
+            stop_hash: BlockHash,
+            timeout: LocalDuration,
+        ) {
+            self.message(
+                addr,
+                NetworkMessage::GetCFHeaders(GetCFHeaders {
+                    filter_type: 0x0,
+                    start_height: start_height as u32,
+                    stop_hash,
+                }),
+            )
+            .wakeup(timeout);
+        }
+
+        fn get_cfcheckpt(&mut self, addr: PeerId, stop_hash: BlockHash, timeout: LocalDuration) {
+            self.message(
+                addr,
+                NetworkMessage::GetCFCheckpt(GetCFCheckpt {
+                    filter_type: 0x0,
+                    stop_hash,
+                }),
+            )
+            .wakeup(timeout);
+        }
+
+        fn get_cfilters(
+            &mut self,
+            addr: PeerId,
+            start_height: Height,
+            stop_hash: BlockHash,
+            timeout: LocalDuration,
+        ) {
+            self.message(
+                addr,
+                NetworkMessage::GetCFilters(GetCFilters {
+                    filter_type: 0x0,
+                    start_height: start_height as u32,
+                    stop_hash,
+                }),
+            )
+            .wakeup(timeout);
+        }
+
+        fn cfheaders(&mut self, addr: PeerId, headers: CFHeaders) {
+            self.message(addr, NetworkMessage::CFHeaders(headers));
+        }
+
+        fn cfilter(&mut self, addr: PeerId, filter: CFilter) {
+            self.message(addr, NetworkMessage::CFilter(filter));
+        }
+
+        fn cfcheckpt(&mut self, addr: PeerId, checkpoint: CFCheckpt) {
+            self.message(addr, NetworkMessage::CFCheckpt(checkpoint));
+        }
+
+        fn get_headers(&mut self, addr: PeerId, (locator_hashes, stop_hash): Locators) {
+            self.message(
+                addr,
+                NetworkMessage::GetHeaders(GetHeadersMessage {
+                    version: PROTOCOL_VERSION,
+                    locator_hashes,
+                    stop_hash,
+                }),
+            );
+        }
+
+        fn headers(&mut self, addr: PeerId, headers: Vec<BlockHeader>) {
+            self.message(addr, NetworkMessage::Headers(headers));
+        }
+
+        fn inv(&mut self, addr: PeerId, inventories: Vec<Inventory>) {
+            self.message(addr, NetworkMessage::Inv(inventories));
+        }
+
+        fn get_data(&mut self, addr: PeerId, inventories: Vec<Inventory>) {
+            self.message(addr, NetworkMessage::GetData(inventories));
+        }
+
+        fn tx(&mut self, addr: PeerId, tx: Transaction) {
+            self.message(addr, NetworkMessage::Tx(tx));
+        }
+
+        fn fee_filter(&mut self, addr: PeerId, rate: i64) {
+            self.message(addr, NetworkMessage::FeeFilter(rate));
+        }
+
+        fn filter_load(&mut self, addr: PeerId, filter: FilterLoad) {
+            self.message(addr, NetworkMessage::FilterLoad(filter));
+        }
+
+        fn send_cmpct(&mut self, addr: PeerId, high_bandwidth: bool, version: u64) {
+            self.message(
+                addr,
+                NetworkMessage::SendCmpct(SendCmpct {
+                    send_compact: high_bandwidth,
+                    version,
+                }),
+            );
+        }
+
+        fn get_block_txn(&mut self, addr: PeerId, request: BlockTransactionsRequest) {
+            self.message(
+                addr,
+                NetworkMessage::GetBlockTxn(GetBlockTxn {
+                    txs_request: request,
+                }),
+            );
+        }
+    }
+
     pub fn messages_from(
         outbox: &mut Outbox,
         addr: &net::SocketAddr,
@@ -14,6 +14,7 @@
 //!   3. Send `verack` message.
 //!   4. Expect `verack` message from remote.
 //!
+use std::io;
 use std::net;
 use std::sync::Arc;
 
@@ -22,7 +23,7 @@ use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::message_network::VersionMessage;
 
 use nakamoto_common::p2p::peer::{AddressSource, Source};
-use nakamoto_common::p2p::Domain;
+use nakamoto_common::p2p::{Cidr, Domain};
 
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
 use nakamoto_common::block::Height;
@@ -30,16 +31,22 @@ use nakamoto_common::collections::{HashMap, HashSet};
 use nakamoto_common::source;
 use nakamoto_net as network;
 
-use crate::fsm::addrmgr;
 use crate::fsm::DisconnectReason;
+use crate::fsm::{addrmgr, bloommgr, cbfmgr, cmpctmgr, syncmgr};
 
 use super::output::{Connect, Disconnect, Wakeup, Wire};
 use super::{ConnDirection, Hooks, PeerId, Socket, Whitelist};
 
-/// Time to wait for response during peer handshake before disconnecting the peer.
-pub const HANDSHAKE_TIMEOUT: LocalDuration = LocalDuration::from_secs(12);
-/// Time to wait for a new connection.
-/// TODO: Should be in config.
+/// Disconnect reason given to peers that send handshake messages out of order, or more
+/// than once.
+pub const HANDSHAKE_VIOLATION: &str = "handshake protocol violation";
+
+/// Default time to wait for a peer to complete the handshake, from the moment it's connected,
+/// before disconnecting it.
+pub const HANDSHAKE_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
+/// Minimum protocol version for BIP 339 wtxid-based transaction relay.
+pub const WTXID_RELAY_VERSION: u32 = 70016;
+/// Default time to wait for a new connection.
 pub const CONNECTION_TIMEOUT: LocalDuration = LocalDuration::from_secs(6);
 /// Time to wait until idle.
 pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
@@ -47,6 +54,21 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
 pub const TARGET_OUTBOUND_PEERS: usize = 8;
 /// Maximum number of inbound peer connections.
 pub const MAX_INBOUND_PEERS: usize = 16;
+/// Base time to wait before reconnecting to a persistent peer.
+pub const RETRY_MIN_WAIT: LocalDuration = LocalDuration::from_secs(1);
+/// Maximum time to wait before reconnecting to a persistent peer, not including jitter.
+pub const RETRY_MAX_WAIT: LocalDuration = LocalDuration::from_mins(60);
+/// Inbound peers that were useful to us within this period aren't evicted to make room
+/// for new inbound peers.
+pub const EVICTION_PROTECTION_PERIOD: LocalDuration = LocalDuration::from_mins(30);
+/// Number of inbound peers from distinct, under-represented network groups that are
+/// protected from eviction.
+pub const EVICTION_PROTECT_NETGROUPS: usize = 4;
+/// Number of inbound peers with the lowest latency that are protected from eviction.
+pub const EVICTION_PROTECT_LATENCY: usize = 8;
+/// Minimum number of distinct network groups among outbound peers. With less, we may be
+/// eclipsed, ie. an attacker may control all our connections to the network.
+pub const MIN_NETGROUPS: usize = 2;
 
 /// Maximum height difference for a stale peer, to maintain the connection (2 weeks).
 const MAX_STALE_HEIGHT_DIFFERENCE: Height = 2016;
@@ -78,6 +100,11 @@ pub enum Event {
         height: Height,
         /// Protocol version.
         version: u32,
+        /// Whether the peer relays transactions.
+        relay: bool,
+        /// Offset in seconds between the timestamp reported by the peer and our
+        /// local time. A positive offset means the peer's clock is ahead of ours.
+        time_offset: TimeOffset,
     },
     /// Connecting to a peer found from the specified source.
     Connecting(PeerId, Source, ServiceFlags),
@@ -89,6 +116,25 @@ pub enum Event {
     Connected(PeerId, ConnDirection),
     /// A peer has been disconnected.
     Disconnected(PeerId, network::DisconnectReason<DisconnectReason>),
+    /// The current failover peer disconnected or couldn't be reached, and the next
+    /// one in the list is tried.
+    FailedOver {
+        /// The peer that was given up on.
+        from: PeerId,
+        /// The peer tried next.
+        to: PeerId,
+    },
+    /// Our outbound peers are spread over fewer than [`MIN_NETGROUPS`] network groups, or
+    /// their addresses were all learned from the same peer. This may mean that we are being
+    /// eclipsed. Emitted once, until diversity is restored.
+    PossibleEclipse {
+        /// Number of outbound peers.
+        peers: usize,
+        /// Number of distinct network groups among outbound peers.
+        groups: usize,
+        /// The peer all outbound peer addresses were learned from, if any.
+        source: Option<Source>,
+    },
 }
 
 impl std::fmt::Display for Event {
@@ -123,6 +169,23 @@ impl std::fmt::Display for Event {
             Self::Disconnected(addr, reason) => {
                 write!(fmt, "Disconnected from {} ({})", &addr, reason)
             }
+            Self::FailedOver { from, to } => {
+                write!(fmt, "Failing over from {} to {}", from, to)
+            }
+            Self::PossibleEclipse {
+                peers,
+                groups,
+                source: Some(source),
+            } => write!(
+                fmt,
+                "Possible eclipse: {} outbound peer(s) in {} network group(s), all from source `{}`",
+                peers, groups, source
+            ),
+            Self::PossibleEclipse { peers, groups, .. } => write!(
+                fmt,
+                "Possible eclipse: {} outbound peer(s) in {} network group(s)",
+                peers, groups
+            ),
         }
     }
 }
@@ -132,12 +195,23 @@ impl std::fmt::Display for Event {
 pub struct Config {
     /// Protocol version.
     pub protocol_version: u32,
+    /// Minimum protocol version of peers. Peers with an older version are disconnected
+    /// during the handshake.
+    pub min_protocol_version: u32,
     /// Peer whitelist.
     pub whitelist: Whitelist,
     /// Services offered by this implementation.
     pub services: ServiceFlags,
     /// Peer addresses to persist connections with.
     pub persistent: Vec<net::SocketAddr>,
+    /// Only ever connect to persistent peers. Addresses from the address book are
+    /// never dialed.
+    pub exclusive: bool,
+    /// Ordered list of peers to fail over between. Only one of them is connected to at a
+    /// time, starting with the first. When it disconnects, the next one is tried, and once
+    /// the whole list has failed, it is retried from the start with backoff. Addresses from
+    /// the address book are never dialed if this is non-empty.
+    pub failover: Vec<net::SocketAddr>,
     /// Services required by peers.
     pub required_services: ServiceFlags,
     /// Peer services preferred. We try to maintain as many
@@ -147,21 +221,36 @@ pub struct Config {
     pub target_outbound_peers: usize,
     /// Maximum number of inbound peer connections.
     pub max_inbound_peers: usize,
+    /// Address ranges allowed to connect to us. If empty, all ranges are allowed.
+    pub inbound_allow: Vec<Cidr>,
+    /// Address ranges denied from connecting to us. Takes precedence over
+    /// [`Config::inbound_allow`].
+    pub inbound_deny: Vec<Cidr>,
     /// Maximum time to wait between reconnection attempts.
     pub retry_max_wait: LocalDuration,
     /// Minimum time to wait between reconnection attempts.
     pub retry_min_wait: LocalDuration,
+    /// Time to wait for an outbound connection to be established.
+    pub connect_timeout: LocalDuration,
+    /// Time to wait for a connected peer to complete the handshake, ie. to send both its
+    /// `version` and `verack`, before disconnecting it.
+    pub handshake_timeout: LocalDuration,
     /// Our user agent.
-    pub user_agent: &'static str,
+    pub user_agent: String,
     /// Supported communication domains.
     pub domains: Vec<Domain>,
+    /// Ask peers to announce transactions to us, in the `version` message.
+    pub relay: bool,
+    /// Rotate an outbound peer at this interval, for a peer in another network group.
+    /// See [`PeerManager::rotate`]. If not set, peers are only rotated on request.
+    pub rotate_interval: Option<LocalDuration>,
 }
 
 /// Peer negotiation (handshake) state.
 #[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Ord, Eq)]
 enum HandshakeState {
     /// Received "version" and waiting for "verack" message from remote.
-    ReceivedVersion { since: LocalTime },
+    ReceivedVersion,
     /// Received "verack". Handshake is complete.
     ReceivedVerack { since: LocalTime },
 }
@@ -178,6 +267,10 @@ pub struct Connection {
     pub link: ConnDirection,
     /// Connected since this time.
     pub since: LocalTime,
+    /// Last time this peer sent us something useful, eg. new headers or blocks.
+    pub last_useful: Option<LocalTime>,
+    /// Average ping latency of this peer, if known.
+    pub latency: Option<LocalDuration>,
 }
 
 /// Peer state.
@@ -215,6 +308,8 @@ pub struct PeerInfo {
     pub relay: bool,
     /// Whether this peer supports BIP-339.
     pub wtxidrelay: bool,
+    /// Whether this peer supports BIP-155, ie. sent `sendaddrv2`.
+    pub sendaddrv2: bool,
     /// The max protocol version supported by both the peer and nakamoto.
     pub version: u32,
     /// Whether this is a persistent peer.
@@ -231,6 +326,67 @@ impl PeerInfo {
     pub fn is_negotiated(&self) -> bool {
         matches!(self.state, HandshakeState::ReceivedVerack { .. })
     }
+
+    /// Get the protocol features supported by this peer. Only complete once the peer is
+    /// negotiated, since some features are signaled during the handshake.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            headers: self.services.has(syncmgr::REQUIRED_SERVICES),
+            compact_filters: self.services.has(cbfmgr::REQUIRED_SERVICES),
+            bloom_filters: self.services.has(bloommgr::REQUIRED_SERVICES),
+            compact_blocks: self.services.has(cmpctmgr::REQUIRED_SERVICES)
+                && self.version >= cmpctmgr::MIN_PROTOCOL_VERSION,
+            wtxid_relay: self.wtxidrelay,
+            addrv2: self.sendaddrv2,
+        }
+    }
+}
+
+/// Protocol features supported by a peer, derived from its negotiated protocol version,
+/// its services, and the feature negotiation messages it sent during the handshake.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Serves the full block chain, and can be used for header sync.
+    pub headers: bool,
+    /// Serves compact block filters (BIP 157).
+    pub compact_filters: bool,
+    /// Serves bloom filters and merkle blocks (BIP 37).
+    pub bloom_filters: bool,
+    /// Supports compact block relay with witnesses (BIP 152).
+    pub compact_blocks: bool,
+    /// Relays transactions by witness transaction id (BIP 339).
+    pub wtxid_relay: bool,
+    /// Supports `addrv2` messages (BIP 155).
+    pub addrv2: bool,
+}
+
+/// Chooses the addresses of outbound peers to connect to. Set with [`Hooks::select_peer`]
+/// to customize peer selection, eg. to prefer low latency or certain address ranges.
+pub trait Selector: Send + Sync {
+    /// Choose an address to connect to, out of the given candidates, or `None` to connect
+    /// to none of them. Candidates are sampled from the address book as they are drawn,
+    /// and have the services needed. Candidates drawn but not chosen aren't offered again
+    /// for a few minutes. `peers` are our negotiated outbound peers.
+    fn select(
+        &self,
+        candidates: &mut dyn Iterator<Item = (Address, Source)>,
+        peers: &[(&PeerInfo, &Connection)],
+    ) -> Option<(Address, Source)>;
+}
+
+/// The default [`Selector`]. Chooses the first candidate: the address book already
+/// samples addresses at random, across address ranges.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RandomSelector;
+
+impl Selector for RandomSelector {
+    fn select(
+        &self,
+        candidates: &mut dyn Iterator<Item = (Address, Source)>,
+        _peers: &[(&PeerInfo, &Connection)],
+    ) -> Option<(Address, Source)> {
+        candidates.next()
+    }
 }
 
 /// Manages peer connections and handshake.
@@ -241,11 +397,21 @@ pub struct PeerManager<U, C> {
 
     retry_at: HashMap<net::SocketAddr, LocalTime>,
     retry_attempts: HashMap<net::SocketAddr, u32>,
+    /// Index of the current peer in the failover list.
+    failover: usize,
+    /// Failover peers that failed in a row, since a handshake last succeeded.
+    failover_failures: usize,
 
     /// Last time we were idle.
     last_idle: Option<LocalTime>,
     /// Connection states.
     peers: HashMap<net::SocketAddr, Peer>,
+    /// Where the addresses of the peers we connected to were found.
+    sources: HashMap<net::SocketAddr, Source>,
+    /// Whether a possible eclipse was reported, and diversity wasn't restored since.
+    eclipsed: bool,
+    /// Last time an outbound peer was rotated.
+    last_rotated: LocalTime,
     upstream: U,
     rng: fastrand::Rng,
     hooks: Hooks,
@@ -256,13 +422,19 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
     /// Create a new peer manager.
     pub fn new(config: Config, rng: fastrand::Rng, hooks: Hooks, upstream: U, clock: C) -> Self {
         let peers = HashMap::with_hasher(rng.clone().into());
+        let last_rotated = clock.local_time();
 
         Self {
             config,
             retry_at: HashMap::with_hasher(rng.clone().into()),
             retry_attempts: HashMap::with_hasher(rng.clone().into()),
+            failover: 0,
+            failover_failures: 0,
             last_idle: None,
             peers,
+            sources: HashMap::with_hasher(rng.clone().into()),
+            eclipsed: false,
+            last_rotated,
             upstream,
             rng,
             hooks,
@@ -272,11 +444,23 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
 
     /// Initialize the peer manager. Must be called once.
     pub fn initialize<A: AddressSource>(&mut self, addrs: &mut A) {
+        let limit = if self.config.exclusive {
+            self.config.persistent.len()
+        } else {
+            self.config.target_outbound_peers
+        };
         let peers = self
             .config
             .persistent
             .iter()
-            .take(self.config.target_outbound_peers)
+            .take(limit)
+            // In inbound-only mode, failover peers aren't connected to either.
+            .chain(
+                self.config
+                    .failover
+                    .first()
+                    .filter(|_| self.config.target_outbound_peers > 0),
+            )
             .cloned()
             .collect::<Vec<_>>();
 
@@ -290,13 +474,24 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
             }
         }
         self.upstream.wakeup(IDLE_TIMEOUT);
+        if let Some(interval) = self.config.rotate_interval {
+            self.upstream.wakeup(interval);
+        }
         self.maintain_connections(addrs);
     }
 
     fn retrier_add_peer(&mut self, addr: &net::SocketAddr, local_time: LocalTime) {
         let attempts = self.retry_attempts.entry(*addr).or_default();
-        let delay = LocalDuration::from_secs(2_u64.saturating_pow(*attempts))
-            .clamp(self.config.retry_min_wait, self.config.retry_max_wait);
+        let backoff = self
+            .config
+            .retry_min_wait
+            .saturating_mul(2_u64.saturating_pow(*attempts))
+            .min(self.config.retry_max_wait);
+        // Add up to 50% of random jitter, so that retries to the same peer don't happen
+        // in lockstep.
+        let jitter = LocalDuration::from_millis(self.rng.u128(..=backoff.as_millis() / 2));
+        let delay = backoff + jitter;
+
         self.retry_at.insert(*addr, local_time + delay);
         self.upstream.wakeup(delay);
         *attempts += 1;
@@ -308,6 +503,29 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
         self.retry_at.remove(addr);
     }
 
+    /// Whether the given peer is the current failover peer.
+    fn is_failover(&self, addr: &net::SocketAddr) -> bool {
+        self.config.failover.get(self.failover) == Some(addr)
+    }
+
+    /// Move on to the next peer in the failover list. If every peer in the list failed,
+    /// the next attempt is delayed.
+    fn failover_next(&mut self, from: &net::SocketAddr, local_time: LocalTime) {
+        let len = self.config.failover.len();
+
+        self.failover = (self.failover + 1) % len;
+        self.failover_failures += 1;
+
+        let to = self.config.failover[self.failover];
+        self.upstream.event(Event::FailedOver { from: *from, to });
+
+        if self.failover_failures % len == 0 {
+            self.retrier_add_peer(&to, local_time);
+        } else if !self.connect(&to) {
+            log::debug!(target: "p2p", "Unable to connect to failover peer {}", to);
+        }
+    }
+
     fn retrier_reconnect(&mut self) {
         let local_time = self.clock.local_time();
         let peers: Vec<_> = self
@@ -353,20 +571,30 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
                     local_addr,
                     link,
                     since: local_time,
+                    last_useful: None,
+                    latency: None,
                 },
                 peer: None,
             },
         );
-        self.retrier_remove_peer(&addr);
+        // Retry attempts are only reset once the handshake succeeds.
+        self.retry_at.remove(&addr);
 
         match link {
             ConnDirection::Inbound => {
-                if self.connected().filter(|c| c.link.is_inbound()).count()
-                    >= self.config.max_inbound_peers
+                if !self.is_inbound_allowed(&addr.ip()) {
+                    // Don't allow inbound connections from denied address ranges.
+                    self._disconnect(addr, DisconnectReason::ConnectionDenied);
+                } else if self.connected().filter(|c| c.link.is_inbound()).count()
+                    > self.config.max_inbound_peers
                 {
-                    // TODO: Test this branch.
-                    // Don't allow inbound connections beyond the configured limit.
-                    self._disconnect(addr, DisconnectReason::ConnectionLimit);
+                    // Don't allow inbound connections beyond the configured limit, unless
+                    // we can make room by evicting a less useful inbound peer.
+                    if let Some(evicted) = self.eviction_candidate(&addr) {
+                        self._disconnect(evicted, DisconnectReason::PeerEvicted);
+                    } else {
+                        self._disconnect(addr, DisconnectReason::ConnectionLimit);
+                    }
                 } else {
                     // Wait for their version message..
                 }
@@ -380,7 +608,7 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
             }
         }
         // Set a timeout for receiving the `version` message.
-        self.upstream.wakeup(HANDSHAKE_TIMEOUT);
+        self.upstream.wakeup(self.config.handshake_timeout);
         self.upstream.event(Event::Connected(addr, link));
     }
 
@@ -396,19 +624,32 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
         debug_assert!(self.peers.contains_key(addr));
         debug_assert!(!self.is_disconnected(addr));
 
-        if self.is_disconnecting(addr) || self.is_connected(addr) {
+        if let network::DisconnectReason::OnDemand(DisconnectReason::ConnectionTimeout) = reason {
+            // The connection was never established, so this is reported as a failed
+            // connection attempt rather than a disconnection.
+            self.upstream.event(Event::ConnectionFailed(
+                *addr,
+                Arc::new(io::Error::from(io::ErrorKind::TimedOut)),
+            ));
+        } else if self.is_disconnecting(addr) || self.is_connected(addr) {
             self.upstream.event(Event::Disconnected(*addr, reason));
         } else if self.is_connecting(addr) {
             // If we haven't yet established a connection, the disconnect reason
-            // should always be a `ConnectionError`.
-            if let network::DisconnectReason::ConnectionError(err) = reason {
+            // should always be a `ConnectionError` or `DialError`.
+            if let network::DisconnectReason::ConnectionError(err)
+            | network::DisconnectReason::DialError(err) = reason
+            {
                 self.upstream.event(Event::ConnectionFailed(*addr, err));
             }
         }
 
         self.peers.remove(addr);
+        self.sources.remove(addr);
+        self.check_diversity();
 
-        if self.config.persistent.contains(addr) {
+        if self.is_failover(addr) {
+            self.failover_next(addr, local_time);
+        } else if self.config.persistent.contains(addr) {
             self.retrier_add_peer(addr, local_time);
         } else {
             // If an outbound peer disconnected, we should make sure to maintain
@@ -419,12 +660,16 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
 
     /// Called when a `wtxidrelay` message was received.
     pub fn received_wtxidrelay(&mut self, addr: &PeerId) {
-        if let Some(Peer::Connected {
-            peer: Some(peer),
-            conn: _,
-        }) = self.peers.get_mut(addr)
-        {
-            match peer.state {
+        match self.peers.get_mut(addr) {
+            Some(Peer::Connected {
+                peer: Some(peer),
+                conn: _,
+            }) => match peer.state {
+                // As per BIP 339, the message is ignored from peers with an older version,
+                // since we didn't signal wtxid relay to them.
+                HandshakeState::ReceivedVersion { .. } if peer.version < WTXID_RELAY_VERSION => {
+                    log::debug!(target: "p2p", "Ignoring `wtxidrelay` from {}: version {} is too old", addr, peer.version);
+                }
                 HandshakeState::ReceivedVersion { .. } => peer.wtxidrelay = true,
                 _ => self.disconnect(
                     *addr,
@@ -432,7 +677,37 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
                         "`wtxidrelay` must be received before `verack`",
                     ),
                 ),
-            }
+            },
+            // The message is only valid after `version`.
+            Some(Peer::Connected { peer: None, .. }) => self.disconnect(
+                *addr,
+                DisconnectReason::PeerMisbehaving(HANDSHAKE_VIOLATION),
+            ),
+            _ => {}
+        }
+    }
+
+    /// Called when a `sendaddrv2` message was received.
+    pub fn received_sendaddrv2(&mut self, addr: &PeerId) {
+        match self.peers.get_mut(addr) {
+            Some(Peer::Connected {
+                peer: Some(peer),
+                conn: _,
+            }) => match peer.state {
+                HandshakeState::ReceivedVersion { .. } => peer.sendaddrv2 = true,
+                _ => self.disconnect(
+                    *addr,
+                    DisconnectReason::PeerMisbehaving(
+                        "`sendaddrv2` must be received before `verack`",
+                    ),
+                ),
+            },
+            // The message is only valid after `version`.
+            Some(Peer::Connected { peer: None, .. }) => self.disconnect(
+                *addr,
+                DisconnectReason::PeerMisbehaving(HANDSHAKE_VIOLATION),
+            ),
+            _ => {}
         }
     }
 
@@ -458,7 +733,11 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
     ) -> Result<(), DisconnectReason> {
         let now = self.clock.local_time();
 
-        if let Some(Peer::Connected { conn, .. }) = self.peers.get(addr) {
+        if let Some(Peer::Connected { conn, peer }) = self.peers.get(addr) {
+            // A peer may only send one `version` message.
+            if peer.is_some() {
+                return Err(DisconnectReason::PeerMisbehaving(HANDSHAKE_VIOLATION));
+            }
             self.upstream.event(Event::VersionReceived {
                 addr: *addr,
                 msg: msg.clone(),
@@ -490,13 +769,14 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
                 || addrmgr::is_local(&addr.ip());
 
             // Don't support peers with too old of a protocol version.
-            if version < super::MIN_PROTOCOL_VERSION {
+            if version < self.config.min_protocol_version {
                 return Err(DisconnectReason::PeerProtocolVersion(version));
             }
 
-            // Peers that don't advertise the `NETWORK` service are not full nodes.
-            // It's not so useful for us to connect to them, because they're likely
-            // to be less secure.
+            // Peers that don't advertise the required services are of no use to us,
+            // eg. peers without `NETWORK` are not full nodes, and peers without
+            // `COMPACT_FILTERS` can't serve us filters. They would only waste an
+            // outbound slot.
             if conn.link.is_outbound() && !services.has(self.config.required_services) && !trusted {
                 return Err(DisconnectReason::PeerServices(services));
             }
@@ -535,26 +815,22 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
                 addrs.record_local_address(addr);
             }
 
-            match conn.link {
-                ConnDirection::Inbound => {
-                    self.upstream
-                        .version(
-                            conn.socket.addr,
-                            self.version(conn.socket.addr, conn.local_addr, nonce, height, now),
-                        )
-                        .wtxid_relay(conn.socket.addr)
-                        .verack(conn.socket.addr)
-                        .send_headers(conn.socket.addr)
-                        .wakeup(HANDSHAKE_TIMEOUT);
-                }
-                ConnDirection::Outbound => {
-                    self.upstream
-                        .wtxid_relay(conn.socket.addr)
-                        .verack(conn.socket.addr)
-                        .send_headers(conn.socket.addr)
-                        .wakeup(HANDSHAKE_TIMEOUT);
-                }
+            let version = u32::min(self.config.protocol_version, version);
+
+            if conn.link.is_inbound() {
+                self.upstream.version(
+                    conn.socket.addr,
+                    self.version(conn.socket.addr, conn.local_addr, nonce, height, now),
+                );
+            }
+            // Only signal wtxid relay to peers that understand it, as per BIP 339.
+            if version >= WTXID_RELAY_VERSION {
+                self.upstream.wtxid_relay(conn.socket.addr);
             }
+            self.upstream
+                .verack(conn.socket.addr)
+                .send_headers(conn.socket.addr)
+                .wakeup(self.config.handshake_timeout);
             let conn = conn.clone();
             let persistent = self.config.persistent.contains(&conn.socket.addr);
 
@@ -569,10 +845,11 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
                         services,
                         persistent,
                         user_agent,
-                        state: HandshakeState::ReceivedVersion { since: now },
+                        state: HandshakeState::ReceivedVersion,
                         relay,
                         wtxidrelay: false,
-                        version: u32::min(self.config.protocol_version, version),
+                        sendaddrv2: false,
+                        version,
                     }),
                 },
             );
@@ -587,12 +864,11 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
         addr: &PeerId,
         local_time: LocalTime,
     ) -> Option<(PeerInfo, Connection)> {
-        if let Some(Peer::Connected {
-            peer: Some(peer),
-            conn,
-        }) = self.peers.get_mut(addr)
-        {
-            if let HandshakeState::ReceivedVersion { .. } = peer.state {
+        match self.peers.get_mut(addr) {
+            Some(Peer::Connected {
+                peer: Some(peer),
+                conn,
+            }) if peer.state == HandshakeState::ReceivedVersion => {
                 self.upstream.event(Event::Negotiated {
                     addr: *addr,
                     link: conn.link,
@@ -600,17 +876,34 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
                     user_agent: peer.user_agent.clone(),
                     height: peer.height,
                     version: peer.version,
+                    relay: peer.relay,
+                    time_offset: peer.time_offset,
                 });
 
                 peer.state = HandshakeState::ReceivedVerack { since: local_time };
 
-                return Some((peer.clone(), conn.clone()));
-            } else {
+                let result = (peer.clone(), conn.clone());
+                self.retrier_remove_peer(addr);
+
+                if self.is_failover(addr) {
+                    // Backoff starts over once a failover peer is reached.
+                    self.failover_failures = 0;
+                    for addr in &self.config.failover {
+                        self.retry_attempts.remove(addr);
+                    }
+                }
+                self.check_diversity();
+
+                return Some(result);
+            }
+            // The `verack` must follow the peer's `version`, and may only be sent once.
+            Some(Peer::Connected { .. }) => {
                 self._disconnect(
                     *addr,
-                    DisconnectReason::PeerMisbehaving("unexpected `verack` message received"),
+                    DisconnectReason::PeerMisbehaving(HANDSHAKE_VIOLATION),
                 );
             }
+            _ => {}
         }
         None
     }
@@ -621,27 +914,21 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
         let local_time = self.clock.local_time();
 
         // Time out all peers that have been idle in a "connecting" state for too long.
+        // Nb. The reactor usually aborts these attempts first, with a dial error.
         for addr in self.idle_peers(local_time).collect::<Vec<_>>() {
-            timed_out.push((addr, "connection"));
-        }
-        // Time out peers that haven't sent a `verack` quickly enough.
-        for (peer, conn) in self.peers() {
-            match peer.state {
-                HandshakeState::ReceivedVersion { since } => {
-                    if local_time - since >= HANDSHAKE_TIMEOUT {
-                        timed_out.push((conn.socket.addr, "handshake"));
-                    }
-                }
-                HandshakeState::ReceivedVerack { .. } => {}
-            }
+            self._disconnect(addr, DisconnectReason::ConnectionTimeout);
         }
-        // Time out peers that haven't sent a `version` quickly enough.
-        for connected in self.peers.values().filter_map(|c| match c {
-            Peer::Connected { conn, peer: None } => Some(conn),
+        // Time out peers that haven't sent their `version` and `verack` quickly enough.
+        // The time is counted from the connection, so that peers can't hold on to a
+        // half-negotiated connection by delaying each message.
+        for (conn, peer) in self.peers.values().filter_map(|c| match c {
+            Peer::Connected { conn, peer } => Some((conn, peer)),
             _ => None,
         }) {
-            if local_time - connected.since >= HANDSHAKE_TIMEOUT {
-                timed_out.push((connected.socket.addr, "handshake"));
+            if !peer.as_ref().map_or(false, |p| p.is_negotiated())
+                && local_time - conn.since >= self.config.handshake_timeout
+            {
+                timed_out.push((conn.socket.addr, "handshake"));
             }
         }
         // Disconnect all timed out peers.
@@ -702,11 +989,11 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
             // A nonce to detect connections to self.
             nonce,
             // Our user agent string.
-            user_agent: self.config.user_agent.to_owned(),
+            user_agent: self.config.user_agent.clone(),
             // Our best height.
             start_height,
             // Whether we want to receive transaction `inv` messages.
-            relay: false,
+            relay: self.config.relay,
         }
     }
 }
@@ -783,6 +1070,178 @@ impl<U: Connect + Disconnect + Wakeup + Wire<Event>, C: Clock> PeerManager<U, C>
         })
     }
 
+    /// Record that a peer sent us something useful, eg. headers that extend our chain.
+    /// Recently useful inbound peers are protected from eviction.
+    pub fn record_useful(&mut self, addr: &PeerId) {
+        let time = self.clock.local_time();
+
+        if let Some(Peer::Connected { conn, .. }) = self.peers.get_mut(addr) {
+            conn.last_useful = Some(time);
+        }
+    }
+
+    /// Record the average ping latency of a peer. Low latency inbound peers are protected
+    /// from eviction.
+    pub fn record_latency(&mut self, addr: &PeerId, latency: LocalDuration) {
+        if let Some(Peer::Connected { conn, .. }) = self.peers.get_mut(addr) {
+            conn.latency = Some(latency);
+        }
+    }
+
+    /// Change the outbound peer target and inbound peer limit. Excess peers are disconnected,
+    /// and new outbound connections are made if the target was raised.
+    ///
+    /// Persistent and whitelisted peers are never disconnected. Otherwise, outbound peers
+    /// without our preferred services go first, and inbound peers are picked as with
+    /// eviction, though recently useful peers aren't spared.
+    pub fn set_limits<A: AddressSource>(
+        &mut self,
+        max_outbound_peers: usize,
+        max_inbound_peers: usize,
+        addrs: &mut A,
+    ) {
+        self.config.target_outbound_peers = max_outbound_peers;
+        self.config.max_inbound_peers = max_inbound_peers;
+
+        let outbound = self
+            .connected()
+            .filter(|c| c.link.is_outbound())
+            .count()
+            .saturating_sub(max_outbound_peers);
+        let inbound = self
+            .connected()
+            .filter(|c| c.link.is_inbound())
+            .count()
+            .saturating_sub(max_inbound_peers);
+
+        let mut candidates = self
+            .peers
+            .iter()
+            .filter_map(|(addr, peer)| match peer {
+                Peer::Connected { conn, peer } => Some((addr, conn, peer.as_ref())),
+                _ => None,
+            })
+            .filter(|(addr, _, peer)| {
+                !self.config.persistent.contains(addr)
+                    && !self.config.failover.contains(addr)
+                    && !peer.map_or(false, |p| {
+                        self.config.whitelist.contains(&addr.ip(), &p.user_agent)
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        // Most recently connected peers go first.
+        candidates.sort_by_key(|(_, conn, _)| std::cmp::Reverse(conn.since));
+
+        let mut outbound_candidates = candidates
+            .iter()
+            .filter(|(_, conn, _)| conn.link.is_outbound())
+            .collect::<Vec<_>>();
+        outbound_candidates.sort_by_key(|(_, _, peer)| {
+            peer.map_or(false, |p| p.services.has(self.config.preferred_services))
+        });
+
+        let mut inbound_candidates = candidates
+            .iter()
+            .filter(|(_, conn, _)| conn.link.is_inbound())
+            .collect::<Vec<_>>();
+        inbound_candidates.sort_by_key(|(_, conn, _)| conn.last_useful);
+
+        let excess = outbound_candidates
+            .into_iter()
+            .take(outbound)
+            .chain(inbound_candidates.into_iter().take(inbound))
+            .map(|(addr, _, _)| **addr)
+            .collect::<Vec<_>>();
+
+        for addr in excess {
+            self._disconnect(addr, DisconnectReason::ConnectionLimitLowered);
+        }
+        self.maintain_connections(addrs);
+    }
+
+    /// Select an inbound peer to evict in favor of the given new peer.
+    ///
+    /// As in Bitcoin Core, peers are protected from eviction by a blend of criteria that
+    /// are hard for an attacker to game all at once:
+    ///
+    /// 1. Whitelisted peers, and peers that were useful within the last
+    ///    [`EVICTION_PROTECTION_PERIOD`].
+    /// 2. One peer from each of the [`EVICTION_PROTECT_NETGROUPS`] least represented
+    ///    network groups.
+    /// 3. The [`EVICTION_PROTECT_LATENCY`] peers with the lowest ping latency.
+    /// 4. The half of the remaining peers that were connected the longest.
+    ///
+    /// If any peers are left, one from the most represented network group is evicted: the
+    /// least recently useful, and amongst equals, the most recently connected.
+    fn eviction_candidate(&self, new: &PeerId) -> Option<PeerId> {
+        let now = self.clock.local_time();
+        let mut candidates = self
+            .peers
+            .iter()
+            .filter_map(|(addr, peer)| match peer {
+                Peer::Connected { conn, peer } if conn.link.is_inbound() && addr != new => {
+                    Some((addr, conn, peer))
+                }
+                _ => None,
+            })
+            .filter(|(addr, _, peer)| {
+                !peer.as_ref().map_or(false, |p| {
+                    self.config.whitelist.contains(&addr.ip(), &p.user_agent)
+                })
+            })
+            .filter(|(_, conn, _)| {
+                conn.last_useful
+                    .map_or(true, |t| now - t > EVICTION_PROTECTION_PERIOD)
+            })
+            .map(|(addr, conn, _)| (netgroup(&addr.ip()), conn))
+            .collect::<Vec<_>>();
+
+        let mut groups = HashMap::with_hasher(self.rng.clone().into());
+        for (group, _) in &candidates {
+            *groups.entry(*group).or_insert(0) += 1;
+        }
+
+        // Protect peers from the least represented network groups, one per group.
+        candidates.sort_by_key(|(group, conn)| (groups[group], conn.since));
+        let mut protected = HashSet::with_hasher(self.rng.clone().into());
+        candidates.retain(|(group, _)| {
+            !(protected.len() < EVICTION_PROTECT_NETGROUPS && protected.insert(*group))
+        });
+
+        // Protect the peers with the lowest latency.
+        candidates.sort_by_key(|(_, conn)| (conn.latency.is_none(), conn.latency));
+        let fastest = candidates
+            .iter()
+            .take(EVICTION_PROTECT_LATENCY)
+            .filter(|(_, conn)| conn.latency.is_some())
+            .count();
+        candidates.drain(..fastest);
+
+        // Protect the longest connected half of the remaining peers.
+        candidates.sort_by_key(|(_, conn)| conn.since);
+        candidates.drain(..candidates.len() / 2);
+
+        let (group, _) = candidates
+            .iter()
+            .max_by_key(|(group, conn)| (groups[group], conn.since))?;
+
+        candidates
+            .iter()
+            .filter(|(g, _)| g == group)
+            .min_by_key(|(_, conn)| (conn.last_useful, std::cmp::Reverse(conn.since)))
+            .map(|(_, conn)| conn.socket.addr)
+    }
+
+    /// Check whether inbound connections from the given address are allowed.
+    pub fn is_inbound_allowed(&self, ip: &net::IpAddr) -> bool {
+        if self.config.inbound_deny.iter().any(|r| r.contains(ip)) {
+            return false;
+        }
+        self.config.inbound_allow.is_empty()
+            || self.config.inbound_allow.iter().any(|r| r.contains(ip))
+    }
+
     /// Iterator over fully negotiated peers.
     pub fn negotiated(
         &self,
@@ -804,11 +1263,81 @@ impl<U: Connect + Disconnect + Wakeup + Wire<Event>, C: Clock> PeerManager<U, C>
             return false;
         }
         self.peers.insert(*addr, Peer::Connecting { time });
-        self.upstream.connect(*addr, CONNECTION_TIMEOUT);
+        self.upstream.connect(*addr, self.config.connect_timeout);
 
         true
     }
 
+    /// Rotate a random outbound peer: connect to an address in a network group none of our
+    /// outbound peers are in, and disconnect the peer. Peers in `keep` and peers chosen by
+    /// the user are never rotated.
+    ///
+    /// Returns the disconnected peer and the new one. Returns `None` if there was no peer
+    /// to rotate, or no address to replace it with.
+    pub fn rotate<A: AddressSource>(
+        &mut self,
+        keep: &[PeerId],
+        addrs: &mut A,
+    ) -> Option<(PeerId, PeerId)> {
+        self.last_rotated = self.clock.local_time();
+        if let Some(interval) = self.config.rotate_interval {
+            self.upstream.wakeup(interval);
+        }
+
+        if self.config.exclusive || !self.config.failover.is_empty() {
+            return None;
+        }
+        let outbound = self
+            .negotiated(ConnDirection::Outbound)
+            .map(|(_, conn)| conn.socket.addr)
+            .collect::<Vec<_>>();
+        let candidates = outbound
+            .iter()
+            .filter(|addr| !keep.contains(addr) && !self.config.persistent.contains(addr))
+            .copied()
+            .collect::<Vec<_>>();
+
+        if candidates.is_empty() {
+            return None;
+        }
+        let groups = outbound
+            .iter()
+            .map(|addr| netgroup(&addr.ip()))
+            .collect::<Vec<_>>();
+        let (address, source) = {
+            let peers = self.negotiated(ConnDirection::Outbound).collect::<Vec<_>>();
+            let mut addresses = addrs
+                .iter(self.config.preferred_services)
+                .filter(|(addr, _)| {
+                    addr.socket_addr().map_or(false, |addr| {
+                        !groups.contains(&netgroup(&addr.ip())) && self.is_disconnected(&addr)
+                    })
+                });
+            self.hooks.select_peer.select(&mut addresses, &peers)?
+        };
+        let addr = address.socket_addr().ok()?;
+
+        if !self.connect(&addr) {
+            return None;
+        }
+        self.sources.insert(addr, source);
+        self.upstream
+            .event(Event::Connecting(addr, source, address.services));
+
+        let peer = candidates[self.rng.usize(..candidates.len())];
+        self._disconnect(peer, DisconnectReason::PeerRotated);
+
+        Some((peer, addr))
+    }
+
+    /// Whether it's time to rotate an outbound peer, according to
+    /// [`Config::rotate_interval`].
+    pub fn is_rotation_due(&self) -> bool {
+        self.config.rotate_interval.map_or(false, |interval| {
+            self.clock.local_time() - self.last_rotated >= interval
+        })
+    }
+
     /// Disconnect from a peer.
     pub fn disconnect(&mut self, addr: PeerId, reason: DisconnectReason) {
         if self.is_connected(&addr) {
@@ -864,6 +1393,11 @@ impl<U: Connect + Disconnect + Wakeup + Wire<Event>, C: Clock> PeerManager<U, C>
 
     /// Attempt to maintain a certain number of outbound peers.
     fn maintain_connections<A: AddressSource>(&mut self, addrs: &mut A) {
+        if self.config.exclusive || !self.config.failover.is_empty() {
+            // Persistent peers are reconnected to by the retrier, and failover peers
+            // are cycled through on disconnect.
+            return;
+        }
         let delta = self.delta();
         let negotiated = self.negotiated(ConnDirection::Outbound).count();
         let target = self.config.target_outbound_peers;
@@ -873,18 +1407,18 @@ impl<U: Connect + Disconnect + Wakeup + Wire<Event>, C: Clock> PeerManager<U, C>
         let mut connecting = HashSet::with_hasher(self.rng.clone().into());
 
         while connecting.len() < delta {
-            if let Some((addr, source)) =
-                addrs.sample(self.config.preferred_services).or_else(|| {
+            if let Some((addr, source)) = self
+                .select(addrs, self.config.preferred_services)
+                .or_else(|| {
                     // Only try to connect to non-preferred peers if we are below our target.
                     if negotiated < target {
-                        addrs
-                            .sample(self.config.required_services)
+                        self.select(addrs, self.config.required_services)
                             // If we can't find peers with any kind of useful services, then
                             // perhaps we should connect to peers that may know of such peers. This
                             // is especially important when doing an initial DNS sync, since DNS
                             // addresses don't come with service information. This will draw from
                             // that pool.
-                            .or_else(|| addrs.sample(ServiceFlags::NONE))
+                            .or_else(|| self.select(addrs, ServiceFlags::NONE))
                     } else {
                         None
                     }
@@ -897,6 +1431,7 @@ impl<U: Connect + Disconnect + Wakeup + Wire<Event>, C: Clock> PeerManager<U, C>
 
                     if self.connect(&sockaddr) {
                         connecting.insert(sockaddr);
+                        self.sources.insert(sockaddr, source);
                         self.upstream
                             .event(Event::Connecting(sockaddr, source, addr.services));
                     }
@@ -911,11 +1446,69 @@ impl<U: Connect + Disconnect + Wakeup + Wire<Event>, C: Clock> PeerManager<U, C>
         }
     }
 
-    /// Peers that have been idle longer than [`CONNECTION_TIMEOUT`].
+    /// Choose the next outbound peer to connect to, out of addresses with the given services.
+    /// See [`Hooks::select_peer`].
+    fn select<A: AddressSource>(
+        &self,
+        addrs: &mut A,
+        services: ServiceFlags,
+    ) -> Option<(Address, Source)> {
+        let peers = self.negotiated(ConnDirection::Outbound).collect::<Vec<_>>();
+
+        self.hooks
+            .select_peer
+            .select(&mut addrs.iter(services), &peers)
+    }
+
+    /// Check the diversity of our outbound peers, and report a possible eclipse if it's
+    /// too low. Peers chosen by the user aren't checked.
+    fn check_diversity(&mut self) {
+        if self.config.exclusive || !self.config.failover.is_empty() {
+            return;
+        }
+        let peers = self
+            .negotiated(ConnDirection::Outbound)
+            .map(|(_, conn)| conn.socket.addr)
+            .filter(|addr| !self.config.persistent.contains(addr))
+            .collect::<Vec<_>>();
+
+        if peers.len() < MIN_NETGROUPS {
+            // Too few peers to tell. Report the next eclipse again, once there are enough.
+            self.eclipsed = false;
+            return;
+        }
+        let mut groups = peers
+            .iter()
+            .map(|addr| netgroup(&addr.ip()))
+            .collect::<Vec<_>>();
+        groups.sort();
+        groups.dedup();
+
+        let groups = groups.len();
+        // If a single peer told us about all our outbound peers, it chose them for us.
+        let source = peers
+            .iter()
+            .map(|addr| self.sources.get(addr).copied())
+            .reduce(|a, b| if a == b { a } else { None })
+            .flatten()
+            .filter(|source| matches!(source, Source::Peer(_)));
+        let eclipsed = groups < MIN_NETGROUPS || source.is_some();
+
+        if eclipsed && !self.eclipsed {
+            self.upstream.event(Event::PossibleEclipse {
+                peers: peers.len(),
+                groups,
+                source,
+            });
+        }
+        self.eclipsed = eclipsed;
+    }
+
+    /// Peers that have been idle longer than the configured connection timeout.
     fn idle_peers(&self, now: LocalTime) -> impl Iterator<Item = PeerId> + '_ {
         self.peers.iter().filter_map(move |(addr, c)| {
             if let Peer::Connecting { time } = c {
-                if now - *time >= CONNECTION_TIMEOUT {
+                if now - *time >= self.config.connect_timeout {
                     return Some(*addr);
                 }
             }
@@ -924,6 +1517,21 @@ impl<U: Connect + Disconnect + Wakeup + Wire<Event>, C: Clock> PeerManager<U, C>
     }
 }
 
+/// Get the network group of an IP address, ie. its /16 range for IPv4 and its /32 range
+/// for IPv6. Addresses in the same group are likely to be operated by the same entity.
+fn netgroup(ip: &net::IpAddr) -> net::IpAddr {
+    match ip {
+        net::IpAddr::V4(ip) => {
+            let [a, b, _, _] = ip.octets();
+            net::Ipv4Addr::new(a, b, 0, 0).into()
+        }
+        net::IpAddr::V6(ip) => {
+            let [a, b, ..] = ip.segments();
+            net::Ipv6Addr::new(a, b, 0, 0, 0, 0, 0, 0).into()
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -939,17 +1547,26 @@ mod tests {
         pub fn config() -> Config {
             Config {
                 protocol_version: crate::fsm::PROTOCOL_VERSION,
+                min_protocol_version: crate::fsm::MIN_PROTOCOL_VERSION,
                 target_outbound_peers: TARGET_OUTBOUND_PEERS,
                 max_inbound_peers: MAX_INBOUND_PEERS,
+                inbound_allow: vec![],
+                inbound_deny: vec![],
                 domains: Domain::all(),
-                user_agent: crate::fsm::USER_AGENT,
+                user_agent: crate::fsm::USER_AGENT.to_owned(),
                 persistent: vec![],
-                retry_max_wait: LocalDuration::from_mins(60),
-                retry_min_wait: LocalDuration::from_secs(1),
+                exclusive: false,
+                failover: vec![],
+                retry_max_wait: RETRY_MAX_WAIT,
+                retry_min_wait: RETRY_MIN_WAIT,
+                connect_timeout: CONNECTION_TIMEOUT,
+                handshake_timeout: HANDSHAKE_TIMEOUT,
                 services: ServiceFlags::NONE,
                 preferred_services: ServiceFlags::COMPACT_FILTERS | ServiceFlags::NETWORK,
                 required_services: ServiceFlags::NETWORK,
                 whitelist: Whitelist::default(),
+                relay: false,
+                rotate_interval: None,
             }
         }
     }
@@ -988,7 +1605,8 @@ mod tests {
         assert!(peermgr.is_disconnected(&remote));
         assert_eq!(peermgr.connected().next(), None);
 
-        time.elapse(LocalDuration::from_secs(1));
+        // Wait for the base delay, plus maximum jitter.
+        time.elapse(LocalDuration::from_millis(1500));
         peermgr.received_wake(&mut addrs);
         assert_eq!(peermgr.connecting().next(), Some(&remote));
 
@@ -1005,74 +1623,765 @@ mod tests {
         peermgr.received_wake(&mut addrs);
         assert_eq!(peermgr.connecting().next(), None);
 
-        time.elapse(LocalDuration::from_secs(1));
+        time.elapse(LocalDuration::from_secs(2));
         peermgr.received_wake(&mut addrs);
         assert_eq!(peermgr.connecting().next(), Some(&remote));
     }
 
     #[test]
-    fn test_wtxidrelay_outbound() {
+    fn test_inbound_allow_deny() {
         let rng = fastrand::Rng::with_seed(1);
-        let time = LocalTime::now();
+        let time = RefClock::from(LocalTime::now());
+        let local = ([99, 99, 99, 99], 9999).into();
+        let cfg = Config {
+            inbound_allow: vec!["10.0.0.0/8".parse().unwrap()],
+            inbound_deny: vec!["10.1.0.0/16".parse().unwrap()],
+            ..util::config()
+        };
+        let mut peermgr = PeerManager::new(cfg, rng, Hooks::default(), (), time);
 
-        let mut addrs = VecDeque::new();
-        let mut peermgr = PeerManager::new(util::config(), rng.clone(), Hooks::default(), (), time);
+        let allowed: net::SocketAddr = ([10, 2, 0, 1], 8333).into();
+        let denied: net::SocketAddr = ([10, 1, 0, 1], 8333).into();
+        let other: net::SocketAddr = ([124, 43, 110, 1], 8333).into();
 
-        let height = 144;
+        assert!(peermgr.is_inbound_allowed(&allowed.ip()));
+        assert!(!peermgr.is_inbound_allowed(&denied.ip()));
+        assert!(!peermgr.is_inbound_allowed(&other.ip()));
+
+        for addr in [allowed, denied, other] {
+            peermgr.peer_connected(addr, local, ConnDirection::Inbound, 0);
+        }
+        assert!(!peermgr.is_disconnecting(&allowed));
+        assert!(peermgr.is_disconnecting(&denied));
+        assert!(peermgr.is_disconnecting(&other));
+    }
+
+    #[test]
+    fn test_inbound_eviction() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
         let local = ([99, 99, 99, 99], 9999).into();
-        let remote = ([124, 43, 110, 1], 8333).into();
-        let version = VersionMessage {
-            services: ServiceFlags::NETWORK,
-            ..peermgr.version(local, remote, rng.u64(..), height, time)
+        let cfg = Config {
+            max_inbound_peers: 16,
+            ..util::config()
         };
+        let mut peermgr = PeerManager::new(cfg, rng, Hooks::default(), (), time.clone());
+        // Peers from distinct network groups.
+        let diverse = (1..=4)
+            .map(|i| ([10, i, 0, 1], 8333).into())
+            .collect::<Vec<net::SocketAddr>>();
+        // Peers from the same network group.
+        let crowd = (1..=12)
+            .map(|i| ([124, 43, 110, i], 8333).into())
+            .collect::<Vec<net::SocketAddr>>();
+        let new = ([124, 43, 110, 100], 8333).into();
+
+        for addr in diverse.iter().chain(&crowd) {
+            peermgr.peer_connected(*addr, local, ConnDirection::Inbound, 0);
+            time.elapse(LocalDuration::from_secs(1));
+        }
+        for (i, addr) in crowd[..8].iter().enumerate() {
+            peermgr.record_latency(addr, LocalDuration::from_millis(10 * (i as u128 + 1)));
+        }
+        peermgr.record_useful(&crowd[8]);
 
-        peermgr.initialize(&mut addrs);
-        peermgr.connect(&remote);
-        peermgr.peer_connected(remote, local, ConnDirection::Outbound, height);
-        peermgr.received_version(&remote, version, height, &mut addrs);
-
-        assert_matches!(
-            peermgr.peers.get(&remote),
-            Some(Peer::Connected{peer: Some(p), ..}) if !p.wtxidrelay
+        // The diverse peers, the fastest peers and the useful peer are protected, as well as
+        // the longest connected of the rest. The youngest remaining peer is evicted.
+        peermgr.peer_connected(new, local, ConnDirection::Inbound, 0);
+        assert!(peermgr.is_disconnecting(&crowd[11]));
+        assert!(peermgr.is_connected(&new));
+        assert_eq!(
+            peermgr
+                .peers
+                .values()
+                .filter(|p| matches!(p, Peer::Connected { .. }))
+                .count(),
+            16
         );
 
-        peermgr.received_wtxidrelay(&remote);
-        peermgr.received_verack(&remote, time);
+        // If all peers are protected, the new peer is refused.
+        let rng = fastrand::Rng::with_seed(1);
+        let cfg = Config {
+            max_inbound_peers: 1,
+            ..util::config()
+        };
+        let mut peermgr = PeerManager::new(cfg, rng, Hooks::default(), (), time);
 
-        assert_matches!(
-            peermgr.peers.get(&remote),
-            Some(Peer::Connected{peer: Some(p), ..}) if p.wtxidrelay
-        );
+        peermgr.peer_connected(crowd[0], local, ConnDirection::Inbound, 0);
+        peermgr.peer_connected(crowd[1], local, ConnDirection::Inbound, 0);
+        assert!(peermgr.is_connected(&crowd[0]));
+        assert!(peermgr.is_disconnecting(&crowd[1]));
     }
 
     #[test]
-    fn test_wtxidrelay_misbehavior() {
-        let rng = fastrand::Rng::with_seed(1);
-        let time = LocalTime::now();
+    fn test_netgroup() {
+        let ip = |s: &str| s.parse::<net::IpAddr>().unwrap();
 
-        let mut addrs = VecDeque::new();
-        let mut peermgr = PeerManager::new(util::config(), rng.clone(), Hooks::default(), (), time);
+        assert_eq!(netgroup(&ip("124.43.110.1")), netgroup(&ip("124.43.7.9")));
+        assert_ne!(netgroup(&ip("124.43.110.1")), netgroup(&ip("124.44.110.1")));
+        assert_eq!(
+            netgroup(&ip("2001:db8::1")),
+            netgroup(&ip("2001:db8:ff::2"))
+        );
+        assert_ne!(netgroup(&ip("2001:db8::1")), netgroup(&ip("2001:db9::1")));
+    }
 
-        let height = 144;
+    #[test]
+    fn test_set_limits() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
         let local = ([99, 99, 99, 99], 9999).into();
-        let remote = ([124, 43, 110, 1], 8333).into();
-        let version = VersionMessage {
-            services: ServiceFlags::NETWORK,
-            ..peermgr.version(local, remote, rng.u64(..), height, time)
-        };
-
-        peermgr.initialize(&mut addrs);
-        peermgr.connect(&remote);
-        peermgr.peer_connected(remote, local, ConnDirection::Outbound, height);
-        peermgr.received_version(&remote, version, height, &mut addrs);
-        peermgr.received_verack(&remote, time);
-        peermgr.received_wtxidrelay(&remote);
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(util::config(), rng, Hooks::default(), (), time.clone());
+        let [a, b, c, d, e]: [net::SocketAddr; 5] =
+            [1, 2, 3, 4, 5].map(|i| ([124, 43, 110, i], 8333).into());
 
-        assert_matches!(peermgr.peers.get(&remote), Some(Peer::Disconnecting));
+        for addr in [a, b, c] {
+            peermgr.peer_connected(addr, local, ConnDirection::Outbound, 0);
+            time.elapse(LocalDuration::from_secs(1));
+        }
+        for addr in [d, e] {
+            peermgr.peer_connected(addr, local, ConnDirection::Inbound, 0);
+        }
+        peermgr.record_useful(&d);
+
+        // The most recently connected outbound peers and the least useful
+        // inbound peer are disconnected.
+        peermgr.set_limits(1, 1, &mut addrs);
+        assert!(peermgr.is_connected(&a));
+        assert!(peermgr.is_disconnecting(&b));
+        assert!(peermgr.is_disconnecting(&c));
+        assert!(peermgr.is_connected(&d));
+        assert!(peermgr.is_disconnecting(&e));
     }
 
     #[test]
-    fn test_connect_timeout() {
+    fn test_exclusive_connect() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
+        let height = 144;
+
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+        let other: net::SocketAddr = ([124, 43, 110, 2], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        addrs.push_back((Address::new(&other, ServiceFlags::NETWORK), Source::Dns));
+        let cfg = Config {
+            persistent: vec![remote],
+            exclusive: true,
+            ..util::config()
+        };
+        let mut peermgr = PeerManager::new(cfg, rng, Hooks::default(), (), time.clone());
+
+        peermgr.initialize(&mut addrs);
+        assert_eq!(peermgr.connecting().collect::<Vec<_>>(), vec![&remote]);
+
+        peermgr.peer_connected(remote, local, ConnDirection::Outbound, height);
+        peermgr.peer_disconnected(
+            &remote,
+            &mut addrs,
+            DisconnectReason::PeerTimeout("").into(),
+        );
+        assert_eq!(peermgr.connecting().next(), None);
+
+        // Only the persistent peer is reconnected to, after the base delay plus maximum jitter.
+        time.elapse(RETRY_MIN_WAIT * 3 / 2);
+        peermgr.received_wake(&mut addrs);
+        assert_eq!(peermgr.connecting().collect::<Vec<_>>(), vec![&remote]);
+        assert_eq!(addrs.len(), 1, "The address book is never sampled");
+    }
+
+    #[test]
+    fn test_failover() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
+        let height = 144;
+
+        let local = ([99, 99, 99, 99], 9999).into();
+        let [a, b, c, other]: [net::SocketAddr; 4] =
+            [1, 2, 3, 4].map(|i| ([124, 43, 110, i], 8333).into());
+
+        let mut addrs = VecDeque::new();
+        addrs.push_back((Address::new(&other, ServiceFlags::NETWORK), Source::Dns));
+        let cfg = Config {
+            failover: vec![a, b, c],
+            ..util::config()
+        };
+        let mut peermgr = PeerManager::new(cfg, rng, Hooks::default(), (), time.clone());
+
+        // Only the first peer in the list is connected to.
+        peermgr.initialize(&mut addrs);
+        assert_eq!(peermgr.connecting().collect::<Vec<_>>(), vec![&a]);
+
+        // When it drops, we fail over to the next one straight away.
+        peermgr.peer_connected(a, local, ConnDirection::Outbound, height);
+        peermgr.peer_disconnected(&a, &mut addrs, DisconnectReason::PeerTimeout("").into());
+        assert_eq!(peermgr.connecting().collect::<Vec<_>>(), vec![&b]);
+
+        peermgr.peer_disconnected(
+            &b,
+            &mut addrs,
+            network::DisconnectReason::DialError(Arc::new(io::ErrorKind::ConnectionRefused.into())),
+        );
+        assert_eq!(peermgr.connecting().collect::<Vec<_>>(), vec![&c]);
+
+        // Once the whole list has failed, we back off before starting over.
+        peermgr.peer_disconnected(
+            &c,
+            &mut addrs,
+            network::DisconnectReason::DialError(Arc::new(io::ErrorKind::ConnectionRefused.into())),
+        );
+        assert_eq!(peermgr.connecting().next(), None);
+
+        time.elapse(LocalDuration::from_millis(1500));
+        peermgr.received_wake(&mut addrs);
+        assert_eq!(peermgr.connecting().collect::<Vec<_>>(), vec![&a]);
+        assert_eq!(addrs.len(), 1, "The address book is never sampled");
+    }
+
+    #[test]
+    fn test_failover_inbound_only() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
+        let addr = ([124, 43, 110, 1], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        let cfg = Config {
+            failover: vec![addr],
+            target_outbound_peers: 0,
+            ..util::config()
+        };
+        let mut peermgr = PeerManager::new(cfg, rng, Hooks::default(), (), time);
+
+        peermgr.initialize(&mut addrs);
+        assert_eq!(peermgr.connecting().next(), None);
+    }
+
+    #[test]
+    fn test_wtxidrelay_outbound() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(util::config(), rng.clone(), Hooks::default(), (), time);
+
+        let height = 144;
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+        let version = VersionMessage {
+            services: ServiceFlags::NETWORK,
+            ..peermgr.version(local, remote, rng.u64(..), height, time)
+        };
+
+        peermgr.initialize(&mut addrs);
+        peermgr.connect(&remote);
+        peermgr.peer_connected(remote, local, ConnDirection::Outbound, height);
+        peermgr.received_version(&remote, version, height, &mut addrs);
+
+        assert_matches!(
+            peermgr.peers.get(&remote),
+            Some(Peer::Connected{peer: Some(p), ..}) if !p.wtxidrelay
+        );
+
+        peermgr.received_wtxidrelay(&remote);
+        peermgr.received_verack(&remote, time);
+
+        assert_matches!(
+            peermgr.peers.get(&remote),
+            Some(Peer::Connected{peer: Some(p), ..}) if p.wtxidrelay
+        );
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(util::config(), rng.clone(), Hooks::default(), (), time);
+
+        let height = 144;
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+        let version = VersionMessage {
+            services: ServiceFlags::NETWORK | ServiceFlags::WITNESS | ServiceFlags::COMPACT_FILTERS,
+            ..peermgr.version(local, remote, rng.u64(..), height, time)
+        };
+
+        peermgr.initialize(&mut addrs);
+        peermgr.connect(&remote);
+        peermgr.peer_connected(remote, local, ConnDirection::Outbound, height);
+        peermgr.received_version(&remote, version, height, &mut addrs);
+        peermgr.received_sendaddrv2(&remote);
+        peermgr.received_wtxidrelay(&remote);
+        peermgr.received_verack(&remote, time);
+
+        assert_matches!(
+            peermgr.peers.get(&remote),
+            Some(Peer::Connected{peer: Some(p), ..}) if p.capabilities() == Capabilities {
+                headers: true,
+                compact_filters: true,
+                bloom_filters: false,
+                compact_blocks: true,
+                wtxid_relay: true,
+                addrv2: true,
+            }
+        );
+
+        // `sendaddrv2` is only valid during the handshake.
+        peermgr.received_sendaddrv2(&remote);
+        assert_matches!(peermgr.peers.get(&remote), Some(Peer::Disconnecting));
+    }
+
+    #[test]
+    fn test_wtxidrelay_old_version() {
+        use crate::fsm::network::Network;
+        use crate::fsm::output::{self, Outbox};
+        use nakamoto_common::bitcoin::network::message::NetworkMessage;
+
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+
+        let mut upstream = Outbox::new(Network::Mainnet, WTXID_RELAY_VERSION);
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(
+            util::config(),
+            rng.clone(),
+            Hooks::default(),
+            upstream.clone(),
+            time,
+        );
+
+        let height = 144;
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+        let version = VersionMessage {
+            services: ServiceFlags::NETWORK,
+            version: WTXID_RELAY_VERSION - 1,
+            ..peermgr.version(local, remote, rng.u64(..), height, time)
+        };
+
+        peermgr.initialize(&mut addrs);
+        peermgr.connect(&remote);
+        peermgr.peer_connected(remote, local, ConnDirection::Outbound, height);
+        peermgr.received_version(&remote, version, height, &mut addrs);
+
+        let msgs = output::test::messages_from(&mut upstream, &remote).collect::<Vec<_>>();
+        assert!(msgs.contains(&NetworkMessage::Verack));
+        assert!(!msgs.contains(&NetworkMessage::WtxidRelay));
+
+        // The peer's `wtxidrelay` is ignored, since we didn't signal it.
+        peermgr.received_wtxidrelay(&remote);
+        peermgr.received_verack(&remote, time);
+
+        assert_matches!(
+            peermgr.peers.get(&remote),
+            Some(Peer::Connected{peer: Some(p), ..}) if !p.wtxidrelay && p.is_negotiated()
+        );
+    }
+
+    #[test]
+    fn test_wtxidrelay_misbehavior() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(util::config(), rng.clone(), Hooks::default(), (), time);
+
+        let height = 144;
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+        let version = VersionMessage {
+            services: ServiceFlags::NETWORK,
+            ..peermgr.version(local, remote, rng.u64(..), height, time)
+        };
+
+        peermgr.initialize(&mut addrs);
+        peermgr.connect(&remote);
+        peermgr.peer_connected(remote, local, ConnDirection::Outbound, height);
+        peermgr.received_version(&remote, version, height, &mut addrs);
+        peermgr.received_verack(&remote, time);
+        peermgr.received_wtxidrelay(&remote);
+
+        assert_matches!(peermgr.peers.get(&remote), Some(Peer::Disconnecting));
+    }
+
+    #[test]
+    fn test_min_protocol_version() {
+        use crate::fsm::output::test::Mock;
+
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+        let height = 144;
+        let local = ([99, 99, 99, 99], 9999).into();
+        let cfg = Config {
+            min_protocol_version: crate::fsm::PROTOCOL_VERSION,
+            ..util::config()
+        };
+        let upstream = Mock::new();
+        let mut peermgr =
+            PeerManager::new(cfg, rng.clone(), Hooks::default(), upstream.clone(), time);
+
+        // Peers are checked for their version, then for their services.
+        for (i, (version, services)) in [
+            (crate::fsm::PROTOCOL_VERSION - 1, ServiceFlags::NETWORK),
+            (crate::fsm::PROTOCOL_VERSION - 1, ServiceFlags::NONE),
+            (crate::fsm::PROTOCOL_VERSION, ServiceFlags::NONE),
+            (crate::fsm::PROTOCOL_VERSION, ServiceFlags::NETWORK),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let remote = ([124, 43, 110, i as u8], 8333).into();
+            let msg = VersionMessage {
+                version,
+                services,
+                ..peermgr.version(local, remote, rng.u64(..), height, time)
+            };
+            peermgr.connect(&remote);
+            peermgr.peer_connected(remote, local, ConnDirection::Outbound, height);
+            peermgr.received_version(&remote, msg, height, &mut VecDeque::new());
+        }
+
+        let disconnects = upstream.disconnects();
+        assert_eq!(disconnects.len(), 3);
+        assert_matches!(
+            disconnects[0].1,
+            DisconnectReason::PeerProtocolVersion(v) if v == crate::fsm::PROTOCOL_VERSION - 1
+        );
+        assert_matches!(disconnects[1].1, DisconnectReason::PeerProtocolVersion(_));
+        assert_matches!(disconnects[2].1, DisconnectReason::PeerServices(_));
+    }
+
+    #[test]
+    fn test_possible_eclipse() {
+        use crate::fsm::output::test::{Call, Mock};
+
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+        let local = ([99, 99, 99, 99], 9999).into();
+        let gossip: PeerId = ([77, 77, 77, 77], 8333).into();
+        let reason: network::DisconnectReason<DisconnectReason> = DisconnectReason::Command.into();
+
+        let negotiate = |peermgr: &mut PeerManager<Mock, LocalTime>, remote: PeerId| {
+            let version = VersionMessage {
+                services: ServiceFlags::NETWORK,
+                ..peermgr.version(remote, local, rng.u64(..), 144, time)
+            };
+            if !peermgr.is_connecting(&remote) {
+                peermgr.connect(&remote);
+            }
+            peermgr.peer_connected(remote, local, ConnDirection::Outbound, 144);
+            peermgr.received_version(&remote, version, 144, &mut VecDeque::new());
+            peermgr.received_verack(&remote, time);
+        };
+        let eclipses = |upstream: &Mock| {
+            upstream
+                .take()
+                .into_iter()
+                .filter_map(|call| match call {
+                    Call::Event(crate::fsm::Event::Peer(Event::PossibleEclipse {
+                        peers,
+                        groups,
+                        source,
+                    })) => Some((peers, groups, source)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // All outbound peers are in the same network group.
+        let upstream = Mock::new();
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(
+            util::config(),
+            rng.clone(),
+            Hooks::default(),
+            upstream.clone(),
+            time,
+        );
+        let (remote1, remote2, remote3) = (
+            ([88, 88, 1, 1], 8333).into(),
+            ([88, 88, 2, 2], 8333).into(),
+            ([124, 43, 110, 1], 8333).into(),
+        );
+        peermgr.initialize(&mut addrs);
+
+        negotiate(&mut peermgr, remote1);
+        assert_eq!(eclipses(&upstream), vec![], "a single peer isn't checked");
+
+        negotiate(&mut peermgr, remote2);
+        assert_eq!(eclipses(&upstream), vec![(2, 1, None)]);
+
+        // Diversity is restored by a peer from another group, and lost again when it leaves.
+        negotiate(&mut peermgr, remote3);
+        assert_eq!(eclipses(&upstream), vec![]);
+
+        peermgr.disconnect(remote3, DisconnectReason::Command);
+        peermgr.peer_disconnected(&remote3, &mut addrs, reason);
+        assert_eq!(eclipses(&upstream), vec![(2, 1, None)]);
+
+        // Once there are too few peers to tell, the next eclipse is reported again.
+        peermgr.disconnect(remote2, DisconnectReason::Command);
+        peermgr.peer_disconnected(&remote2, &mut addrs, DisconnectReason::Command.into());
+        assert_eq!(eclipses(&upstream), vec![]);
+
+        negotiate(&mut peermgr, remote2);
+        assert_eq!(eclipses(&upstream), vec![(2, 1, None)]);
+
+        // All outbound peer addresses were learned from the same peer.
+        let upstream = Mock::new();
+        let mut addrs = VecDeque::from([
+            (
+                Address::new(&remote1, ServiceFlags::NETWORK),
+                Source::Peer(gossip),
+            ),
+            (
+                Address::new(&remote3, ServiceFlags::NETWORK),
+                Source::Peer(gossip),
+            ),
+        ]);
+        let mut peermgr = PeerManager::new(
+            util::config(),
+            rng.clone(),
+            Hooks::default(),
+            upstream.clone(),
+            time,
+        );
+
+        peermgr.initialize(&mut addrs);
+        assert_eq!(peermgr.connecting().count(), 2);
+
+        for remote in [remote1, remote3] {
+            negotiate(&mut peermgr, remote);
+        }
+        assert_eq!(
+            eclipses(&upstream),
+            vec![(2, 2, Some(Source::Peer(gossip)))]
+        );
+    }
+
+    #[test]
+    fn test_select_peer() {
+        struct Port(u16);
+
+        impl Selector for Port {
+            fn select(
+                &self,
+                candidates: &mut dyn Iterator<Item = (Address, Source)>,
+                _peers: &[(&PeerInfo, &Connection)],
+            ) -> Option<(Address, Source)> {
+                candidates.find(|(addr, _)| addr.port == self.0)
+            }
+        }
+
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
+        let cfg = Config {
+            target_outbound_peers: 1,
+            ..util::config()
+        };
+        let hooks = Hooks {
+            select_peer: Arc::new(Port(18333)),
+            ..Hooks::default()
+        };
+        let mut peermgr = PeerManager::new(cfg, rng, hooks, (), time);
+
+        let selected: PeerId = ([124, 43, 110, 1], 18333).into();
+        let mut addrs = [
+            ([88, 88, 1, 1], 8333).into(),
+            ([51, 15, 1, 1], 8333).into(),
+            selected,
+        ]
+        .iter()
+        .map(|addr| (Address::new(addr, ServiceFlags::NETWORK), Source::Imported))
+        .collect::<VecDeque<_>>();
+
+        peermgr.initialize(&mut addrs);
+
+        assert_eq!(peermgr.connecting().collect::<Vec<_>>(), vec![&selected]);
+        assert!(addrs.is_empty(), "the other candidates were drawn");
+    }
+
+    #[test]
+    fn test_rotate() {
+        use crate::fsm::output::test::Mock;
+
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
+        let local = ([99, 99, 99, 99], 9999).into();
+        let gossip = ([77, 77, 77, 77], 8333).into();
+        let cfg = Config {
+            rotate_interval: Some(LocalDuration::from_mins(10)),
+            ..util::config()
+        };
+        let mut addrs = VecDeque::new();
+        let upstream = Mock::new();
+        let mut peermgr = PeerManager::new(
+            cfg,
+            rng.clone(),
+            Hooks::default(),
+            upstream.clone(),
+            time.clone(),
+        );
+
+        let syncing: PeerId = ([88, 88, 1, 1], 8333).into();
+        let other: PeerId = ([124, 43, 110, 1], 8333).into();
+
+        peermgr.initialize(&mut addrs);
+        assert!(upstream.wakeups().contains(&LocalDuration::from_mins(10)));
+
+        for remote in [syncing, other] {
+            let version = VersionMessage {
+                services: ServiceFlags::NETWORK,
+                ..peermgr.version(remote, local, rng.u64(..), 144, time.local_time())
+            };
+            peermgr.connect(&remote);
+            peermgr.peer_connected(remote, local, ConnDirection::Outbound, 144);
+            peermgr.received_version(&remote, version, 144, &mut addrs);
+            peermgr.received_verack(&remote, time.local_time());
+        }
+        assert!(!peermgr.is_rotation_due());
+
+        time.elapse(LocalDuration::from_mins(10));
+        assert!(peermgr.is_rotation_due());
+
+        // Addresses in the network groups of our outbound peers are skipped.
+        let same_group: PeerId = ([124, 43, 9, 9], 8333).into();
+        let new_group: PeerId = ([51, 15, 1, 1], 8333).into();
+        upstream.take();
+
+        let mut addrs = VecDeque::from([
+            (
+                Address::new(&same_group, ServiceFlags::NETWORK),
+                Source::Peer(gossip),
+            ),
+            (
+                Address::new(&new_group, ServiceFlags::NETWORK),
+                Source::Peer(gossip),
+            ),
+        ]);
+        assert_eq!(
+            peermgr.rotate(&[syncing], &mut addrs),
+            Some((other, new_group))
+        );
+        assert!(peermgr.is_disconnecting(&other));
+        assert!(peermgr.is_connecting(&new_group));
+        assert!(peermgr.is_connected(&syncing));
+        assert!(!peermgr.is_rotation_due());
+        // The next rotation is scheduled.
+        assert!(upstream.wakeups().contains(&LocalDuration::from_mins(10)));
+
+        // The peer we're syncing from is never rotated, and a peer isn't dropped without
+        // a replacement.
+        let mut addrs = VecDeque::from([(
+            Address::new(&([5, 9, 1, 1], 8333).into(), ServiceFlags::NETWORK),
+            Source::Peer(gossip),
+        )]);
+        assert_eq!(peermgr.rotate(&[syncing], &mut addrs), None);
+        assert_eq!(peermgr.rotate(&[], &mut VecDeque::new()), None);
+        assert!(peermgr.is_connected(&syncing));
+    }
+
+    #[test]
+    fn test_handshake_ordering_violations() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+        let height = 144;
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+
+        // Each sequence of handshake messages is invalid, and should get the peer disconnected.
+        let cases: &[(&str, &[&str])] = &[
+            ("verack before version", &["verack"]),
+            ("wtxidrelay before version", &["wtxidrelay"]),
+            ("sendaddrv2 before version", &["sendaddrv2"]),
+            ("duplicate version", &["version", "version"]),
+            ("duplicate verack", &["version", "verack", "verack"]),
+        ];
+
+        for (case, msgs) in cases {
+            let mut addrs = VecDeque::new();
+            let mut peermgr =
+                PeerManager::new(util::config(), rng.clone(), Hooks::default(), (), time);
+            let version = VersionMessage {
+                services: ServiceFlags::NETWORK,
+                ..peermgr.version(local, remote, rng.u64(..), height, time)
+            };
+
+            peermgr.initialize(&mut addrs);
+            peermgr.connect(&remote);
+            peermgr.peer_connected(remote, local, ConnDirection::Outbound, height);
+
+            for msg in msgs.iter() {
+                match *msg {
+                    "version" => {
+                        peermgr.received_version(&remote, version.clone(), height, &mut addrs)
+                    }
+                    "verack" => {
+                        peermgr.received_verack(&remote, time);
+                    }
+                    "wtxidrelay" => peermgr.received_wtxidrelay(&remote),
+                    "sendaddrv2" => peermgr.received_sendaddrv2(&remote),
+                    _ => unreachable!(),
+                }
+            }
+            assert!(
+                matches!(peermgr.peers.get(&remote), Some(Peer::Disconnecting)),
+                "{}",
+                case
+            );
+        }
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
+        let cfg = Config {
+            handshake_timeout: LocalDuration::from_secs(20),
+            ..util::config()
+        };
+
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(cfg, rng.clone(), Hooks::default(), (), time.clone());
+
+        let height = 144;
+        let local = ([99, 99, 99, 99], 9999).into();
+        let slow = ([124, 43, 110, 1], 8333).into();
+        let honest = ([124, 43, 110, 2], 8333).into();
+
+        peermgr.initialize(&mut addrs);
+        for remote in [slow, honest] {
+            peermgr.connect(&remote);
+            peermgr.peer_connected(remote, local, ConnDirection::Outbound, height);
+        }
+        time.elapse(LocalDuration::from_secs(15));
+
+        // Both peers send their `version` late, but only one of them sends a `verack`.
+        for remote in [slow, honest] {
+            let version = VersionMessage {
+                services: ServiceFlags::NETWORK,
+                ..peermgr.version(local, remote, rng.u64(..), height, time.local_time())
+            };
+            peermgr.received_version(&remote, version, height, &mut addrs);
+        }
+        peermgr.received_verack(&honest, time.local_time());
+
+        time.elapse(LocalDuration::from_secs(4));
+        peermgr.received_wake(&mut addrs);
+        assert!(peermgr.is_connected(&slow));
+
+        // The timeout is counted from the connection, not from the `version` message.
+        time.elapse(LocalDuration::from_secs(1));
+        peermgr.received_wake(&mut addrs);
+        assert!(peermgr.is_disconnecting(&slow));
+        assert!(peermgr.is_connected(&honest));
+    }
+
+    #[test]
+    fn test_connect_timeout() {
         let rng = fastrand::Rng::with_seed(1);
         let time = RefClock::from(LocalTime::now());
 
@@ -1103,6 +2412,63 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_connect_timeout_connection_failed() {
+        use crate::fsm::network::Network;
+        use crate::fsm::output::{self, Outbox};
+        use crate::fsm::{self, Io, PROTOCOL_VERSION};
+
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
+        let remote = ([124, 43, 110, 1], 8333).into();
+        let cfg = Config {
+            connect_timeout: LocalDuration::from_secs(3),
+            ..util::config()
+        };
+
+        let mut upstream = Outbox::new(Network::Mainnet, PROTOCOL_VERSION);
+        let mut addrs = VecDeque::new();
+        let mut peermgr =
+            PeerManager::new(cfg, rng, Hooks::default(), upstream.clone(), time.clone());
+
+        peermgr.initialize(&mut addrs);
+        peermgr.connect(&remote);
+
+        upstream
+            .drain()
+            .find(|o| matches!(o, Io::SetTimer(t) if *t == LocalDuration::from_secs(3)))
+            .expect("A timer is set for the configured connection timeout");
+
+        time.elapse(LocalDuration::from_secs(3));
+        peermgr.received_wake(&mut addrs);
+
+        upstream
+            .drain()
+            .find(|o| {
+                matches!(
+                    o,
+                    Io::DisconnectPeer(a, DisconnectReason::ConnectionTimeout) if a == &remote
+                )
+            })
+            .expect("The connection attempt is aborted");
+
+        peermgr.peer_disconnected(
+            &remote,
+            &mut addrs,
+            DisconnectReason::ConnectionTimeout.into(),
+        );
+
+        output::test::events(&mut upstream)
+            .find(|e| {
+                matches!(
+                    e,
+                    fsm::Event::Peer(Event::ConnectionFailed(a, err))
+                    if a == &remote && err.kind() == io::ErrorKind::TimedOut
+                )
+            })
+            .expect("The connection failure is reported");
+    }
+
     #[test]
     fn test_peer_dropped() {
         let rng = fastrand::Rng::with_seed(1);
@@ -22,8 +22,84 @@ pub const PING_INTERVAL: LocalDuration = LocalDuration::from_mins(2);
 /// Time to wait to receive a pong when sending a ping.
 pub const PING_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
 
-/// Maximum number of latencies recorded per peer.
-const MAX_RECORDED_LATENCIES: usize = 64;
+/// Default maximum number of latencies recorded per peer.
+pub const MAX_RECORDED_LATENCIES: usize = 64;
+
+/// Ping manager configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Time to wait for a `pong`, after which the peer is disconnected.
+    pub ping_timeout: LocalDuration,
+    /// Maximum number of round-trip latencies recorded per peer, from which its average
+    /// latency is computed. Each sample takes 16 bytes, ie. 1 KiB per peer with the default
+    /// of [`MAX_RECORDED_LATENCIES`]. If zero, latencies aren't recorded.
+    pub max_recorded_latencies: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ping_timeout: PING_TIMEOUT,
+            max_recorded_latencies: MAX_RECORDED_LATENCIES,
+        }
+    }
+}
+
+/// Percentiles of a set of round-trip latency samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Percentiles {
+    /// Median latency.
+    pub p50: LocalDuration,
+    /// 90th percentile latency.
+    pub p90: LocalDuration,
+    /// 99th percentile latency.
+    pub p99: LocalDuration,
+    /// Number of samples the percentiles were computed from.
+    pub samples: usize,
+}
+
+impl Percentiles {
+    /// Compute percentiles from latency samples, using the nearest-rank method: each
+    /// percentile is the smallest sample that is greater than or equal to that percentage
+    /// of samples. With few samples, the higher percentiles are therefore the maximum.
+    /// Returns [`None`] if there are no samples.
+    ///
+    /// ```
+    /// use nakamoto_common::block::time::LocalDuration;
+    /// use nakamoto_p2p::fsm::Percentiles;
+    ///
+    /// let ms = LocalDuration::from_millis;
+    /// let p = Percentiles::from([ms(40), ms(10), ms(30), ms(20)]).unwrap();
+    ///
+    /// assert_eq!((p.p50, p.p90, p.p99, p.samples), (ms(20), ms(40), ms(40), 4));
+    /// assert_eq!(Percentiles::from([]), None);
+    /// ```
+    pub fn from(samples: impl IntoIterator<Item = LocalDuration>) -> Option<Self> {
+        let mut samples = samples.into_iter().collect::<Vec<_>>();
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_unstable();
+
+        let rank = |p: usize| samples[(p * samples.len() + 99) / 100 - 1];
+
+        Some(Self {
+            p50: rank(50),
+            p90: rank(90),
+            p99: rank(99),
+            samples: samples.len(),
+        })
+    }
+}
+
+/// Latency percentiles of peers, and of all peers together.
+#[derive(Debug, Clone, Default)]
+pub struct Latencies {
+    /// Latency percentiles of each peer with at least one sample.
+    pub peers: Vec<(PeerId, Percentiles)>,
+    /// Latency percentiles of all samples of all peers. [`None`] if there are no samples.
+    pub network: Option<Percentiles>,
+}
 
 /// A ping-related event.
 #[derive(Clone, Debug)]
@@ -51,16 +127,15 @@ struct Peer {
 
 impl Peer {
     /// Calculate the average latency of this peer.
-    #[allow(dead_code)]
     fn latency(&self) -> LocalDuration {
         let sum: LocalDuration = self.latencies.iter().sum();
 
         sum / self.latencies.len() as u32
     }
 
-    fn record_latency(&mut self, sample: LocalDuration) {
+    fn record_latency(&mut self, sample: LocalDuration, max: usize) {
         self.latencies.push_front(sample);
-        self.latencies.truncate(MAX_RECORDED_LATENCIES);
+        self.latencies.truncate(max);
     }
 }
 
@@ -68,7 +143,7 @@ impl Peer {
 #[derive(Debug)]
 pub struct PingManager<U, C> {
     peers: HashMap<PeerId, Peer>,
-    ping_timeout: LocalDuration,
+    config: Config,
     /// Random number generator.
     rng: fastrand::Rng,
     upstream: U,
@@ -77,12 +152,12 @@ pub struct PingManager<U, C> {
 
 impl<U: Wire<Event> + Wakeup + Disconnect, C: Clock> PingManager<U, C> {
     /// Create a new ping manager.
-    pub fn new(ping_timeout: LocalDuration, rng: fastrand::Rng, upstream: U, clock: C) -> Self {
+    pub fn new(config: Config, rng: fastrand::Rng, upstream: U, clock: C) -> Self {
         let peers = HashMap::with_hasher(rng.clone().into());
 
         Self {
             peers,
-            ping_timeout,
+            config,
             rng,
             upstream,
             clock,
@@ -94,6 +169,9 @@ impl<U: Wire<Event> + Wakeup + Disconnect, C: Clock> PingManager<U, C> {
         let nonce = self.rng.u64(..);
         let now = self.clock.local_time();
 
+        #[cfg(feature = "tracing")]
+        tracing::debug!(peer = %address, nonce, "Sending ping");
+
         self.upstream.ping(address, nonce);
         self.peers.insert(
             address,
@@ -124,7 +202,7 @@ impl<U: Wire<Event> + Wakeup + Disconnect, C: Clock> PingManager<U, C> {
                     // A ping was sent and we're waiting for a `pong`. If too much
                     // time has passed, we consider this peer dead, and disconnect
                     // from them.
-                    if now - since >= self.ping_timeout {
+                    if now - since >= self.config.ping_timeout {
                         self.upstream
                             .disconnect(peer.address, DisconnectReason::PeerTimeout("ping"));
                     }
@@ -135,9 +213,12 @@ impl<U: Wire<Event> + Wakeup + Disconnect, C: Clock> PingManager<U, C> {
                     if now - since >= PING_INTERVAL {
                         let nonce = self.rng.u64(..);
 
+                        #[cfg(feature = "tracing")]
+                        tracing::debug!(peer = %peer.address, nonce, "Sending ping");
+
                         self.upstream
                             .ping(peer.address, nonce)
-                            .wakeup(self.ping_timeout)
+                            .wakeup(self.config.ping_timeout)
                             .wakeup(PING_INTERVAL);
 
                         peer.state = State::AwaitingPong { nonce, since: now };
@@ -147,6 +228,33 @@ impl<U: Wire<Event> + Wakeup + Disconnect, C: Clock> PingManager<U, C> {
         }
     }
 
+    /// Get the average round-trip latency of a peer, if any pings were answered.
+    pub fn latency(&self, addr: &PeerId) -> Option<LocalDuration> {
+        self.peers
+            .get(addr)
+            .filter(|p| !p.latencies.is_empty())
+            .map(|p| p.latency())
+    }
+
+    /// Get the latency percentiles of each peer, computed from its recorded samples, and of
+    /// all samples together. Peers without samples are left out.
+    pub fn latencies(&self) -> Latencies {
+        let peers = self
+            .peers
+            .iter()
+            .filter_map(|(addr, p)| {
+                Percentiles::from(p.latencies.iter().copied()).map(|l| (*addr, l))
+            })
+            .collect();
+        let network = Percentiles::from(
+            self.peers
+                .values()
+                .flat_map(|p| p.latencies.iter().copied()),
+        );
+
+        Latencies { peers, network }
+    }
+
     /// Called when a `ping` is received.
     pub fn received_ping(&mut self, addr: PeerId, nonce: u64) -> bool {
         if self.peers.contains_key(&addr) {
@@ -166,7 +274,7 @@ impl<U: Wire<Event> + Wakeup + Disconnect, C: Clock> PingManager<U, C> {
                     since,
                 } => {
                     if nonce == last_nonce {
-                        peer.record_latency(now - since);
+                        peer.record_latency(now - since, self.config.max_recorded_latencies);
                         peer.state = State::Idle { since: now };
 
                         return true;
@@ -179,3 +287,152 @@ impl<U: Wire<Event> + Wakeup + Disconnect, C: Clock> PingManager<U, C> {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nakamoto_common::block::time::RefClock;
+
+    use crate::fsm::output::test::Mock;
+
+    #[test]
+    fn test_ping_pong() {
+        let rng = fastrand::Rng::with_seed(1);
+        let clock = RefClock::from(LocalTime::now());
+        let upstream = Mock::new();
+        let remote = ([88, 88, 88, 88], 8333).into();
+        let mut pingmgr = PingManager::new(Config::default(), rng, upstream.clone(), clock.clone());
+
+        pingmgr.peer_negotiated(remote);
+
+        let nonce = match upstream.pings(&remote)[..] {
+            [nonce] => nonce,
+            ref pings => panic!("Expected exactly one ping, got {:?}", pings),
+        };
+        assert_eq!(pingmgr.latency(&remote), None);
+
+        clock.elapse(LocalDuration::from_millis(300));
+        assert!(!pingmgr.received_pong(remote, nonce.wrapping_add(1), clock.local_time()));
+        assert!(pingmgr.received_pong(remote, nonce, clock.local_time()));
+        assert_eq!(
+            pingmgr.latency(&remote),
+            Some(LocalDuration::from_millis(300))
+        );
+
+        // A new ping is sent after the ping interval, with a different nonce.
+        upstream.take();
+        clock.elapse(PING_INTERVAL);
+        pingmgr.received_wake();
+
+        let pings = upstream.pings(&remote);
+        assert_eq!(pings.len(), 1);
+        assert_ne!(pings[0], nonce);
+        assert!(upstream.wakeups().contains(&PING_TIMEOUT));
+        assert!(upstream.disconnects().is_empty());
+
+        // The peer is disconnected if it doesn't reply in time.
+        clock.elapse(PING_TIMEOUT);
+        pingmgr.received_wake();
+
+        assert!(matches!(
+            upstream.disconnects()[..],
+            [(addr, DisconnectReason::PeerTimeout("ping"))] if addr == remote
+        ));
+    }
+
+    #[test]
+    fn test_max_recorded_latencies() {
+        let rng = fastrand::Rng::with_seed(1);
+        let clock = RefClock::from(LocalTime::now());
+        let upstream = Mock::new();
+        let remote = ([88, 88, 88, 88], 8333).into();
+        let config = Config {
+            max_recorded_latencies: 2,
+            ..Config::default()
+        };
+        let mut pingmgr = PingManager::new(config, rng, upstream.clone(), clock.clone());
+
+        pingmgr.peer_negotiated(remote);
+
+        for millis in [900, 100, 300] {
+            let nonce = *upstream.pings(&remote).last().unwrap();
+
+            clock.elapse(LocalDuration::from_millis(millis));
+            assert!(pingmgr.received_pong(remote, nonce, clock.local_time()));
+
+            clock.elapse(PING_INTERVAL);
+            pingmgr.received_wake();
+        }
+        // Only the last two samples are kept.
+        assert_eq!(
+            pingmgr.latency(&remote),
+            Some(LocalDuration::from_millis(200))
+        );
+    }
+
+    #[test]
+    fn test_latencies() {
+        let rng = fastrand::Rng::with_seed(1);
+        let clock = RefClock::from(LocalTime::now());
+        let upstream = Mock::new();
+        let alice = ([88, 88, 88, 88], 8333).into();
+        let bob = ([99, 99, 99, 99], 8333).into();
+        let eve = ([77, 77, 77, 77], 8333).into();
+        let mut pingmgr = PingManager::new(Config::default(), rng, upstream.clone(), clock.clone());
+
+        pingmgr.peer_negotiated(alice);
+        pingmgr.peer_negotiated(bob);
+        pingmgr.peer_negotiated(eve);
+
+        // Alice replies in 10ms to 100ms, Bob in 500ms, and Eve never replies.
+        for i in 1..=10 {
+            let nonce = *upstream.pings(&alice).last().unwrap();
+            let time = clock.local_time() + LocalDuration::from_millis(i * 10);
+            assert!(pingmgr.received_pong(alice, nonce, time));
+
+            if i == 1 {
+                let nonce = *upstream.pings(&bob).last().unwrap();
+                let time = clock.local_time() + LocalDuration::from_millis(500);
+                assert!(pingmgr.received_pong(bob, nonce, time));
+            }
+            clock.elapse(PING_INTERVAL + LocalDuration::from_secs(1));
+            pingmgr.received_wake();
+        }
+        let latencies = pingmgr.latencies();
+        let ms = LocalDuration::from_millis;
+
+        assert_eq!(
+            latencies.peers.len(),
+            2,
+            "Peers without samples are left out"
+        );
+        assert!(latencies.peers.contains(&(
+            alice,
+            Percentiles {
+                p50: ms(50),
+                p90: ms(90),
+                p99: ms(100),
+                samples: 10
+            }
+        )));
+        assert!(latencies.peers.contains(&(
+            bob,
+            Percentiles {
+                p50: ms(500),
+                p90: ms(500),
+                p99: ms(500),
+                samples: 1
+            }
+        )));
+        assert_eq!(
+            latencies.network,
+            Some(Percentiles {
+                p50: ms(60),
+                p90: ms(100),
+                p99: ms(500),
+                samples: 11
+            })
+        );
+    }
+}
@@ -0,0 +1,81 @@
+//! Per-peer rate limiting of the requests we serve.
+use std::collections::VecDeque;
+
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+use nakamoto_common::collections::HashMap;
+
+use super::PeerId;
+
+/// Default maximum number of requests served to a single peer per [`SERVE_BUDGET_PERIOD`].
+pub const DEFAULT_SERVE_BUDGET: usize = 100;
+/// Period over which the serve budget of a peer applies.
+pub const SERVE_BUDGET_PERIOD: LocalDuration = LocalDuration::from_mins(1);
+
+/// Keeps track of the requests served to each peer, over a sliding [`SERVE_BUDGET_PERIOD`].
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Times at which we served requests from each peer, within the budget period.
+    served: HashMap<PeerId, VecDeque<LocalTime>>,
+}
+
+impl RateLimiter {
+    /// Create a new rate limiter.
+    pub fn new(rng: fastrand::Rng) -> Self {
+        Self {
+            served: HashMap::with_hasher(rng.into()),
+        }
+    }
+
+    /// Charge a request from a peer to its budget, at the given time. Returns `false`
+    /// if the peer has already been served `budget` requests in the current period, in
+    /// which case the request shouldn't be served.
+    pub fn charge(&mut self, peer: PeerId, budget: usize, now: LocalTime) -> bool {
+        let served = self.served.entry(peer).or_default();
+
+        while let Some(time) = served.front() {
+            if now - *time < SERVE_BUDGET_PERIOD {
+                break;
+            }
+            served.pop_front();
+        }
+        if served.len() >= budget {
+            return false;
+        }
+        served.push_back(now);
+
+        true
+    }
+
+    /// Forget about a peer, eg. when it disconnects.
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.served.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_charge() {
+        let mut limiter = RateLimiter::new(fastrand::Rng::new());
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let time = LocalTime::now();
+
+        assert!(limiter.charge(alice, 2, time));
+        assert!(limiter.charge(alice, 2, time));
+        assert!(!limiter.charge(alice, 2, time));
+        // Budgets are per peer.
+        assert!(limiter.charge(bob, 2, time));
+
+        // The budget is replenished as the period slides.
+        assert!(!limiter.charge(alice, 2, time + SERVE_BUDGET_PERIOD / 2));
+        assert!(limiter.charge(alice, 2, time + SERVE_BUDGET_PERIOD));
+
+        // Peers that are removed start over.
+        limiter.remove(&alice);
+        assert!(limiter.charge(alice, 2, time + SERVE_BUDGET_PERIOD));
+        assert!(limiter.charge(alice, 2, time + SERVE_BUDGET_PERIOD));
+    }
+}
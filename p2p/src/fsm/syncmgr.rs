@@ -1,6 +1,14 @@
 //!
 //! Manages header synchronization with peers.
 //!
+//! Headers received from a peer must form a chain, or the peer is disconnected. Headers
+//! that don't connect to our chain, eg. because a new block was announced while we're still
+//! syncing, are buffered and the missing headers are requested from the peer. If they don't
+//! arrive within [`ORPHAN_HEADERS_TIMEOUT`], the buffered headers are discarded.
+//!
+use std::collections::HashSet;
+use std::mem;
+
 use nakamoto_common::bitcoin::consensus::params::Params;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::message_blockdata::Inventory;
@@ -8,13 +16,14 @@ use nakamoto_common::bitcoin::network::message_blockdata::Inventory;
 use nakamoto_common::bitcoin_hashes::Hash;
 use nakamoto_common::block::store;
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
-use nakamoto_common::block::tree::{BlockReader, BlockTree, Error, ImportResult};
-use nakamoto_common::block::{BlockHash, BlockHeader, Height};
+use nakamoto_common::block::tree::{BlockReader, BlockTree, Branch, Error, ImportResult};
+use nakamoto_common::block::{BlockHash, BlockHeader, Height, Work};
 use nakamoto_common::collections::{AddressBook, HashMap};
 use nakamoto_common::nonempty::NonEmpty;
 
 use super::output::{Disconnect, Wakeup, Wire};
-use super::{ConnDirection, DisconnectReason, Locators, PeerId, Socket};
+use super::ratelimit::RateLimiter;
+use super::{ConnDirection, DisconnectReason, InFlight, Locators, PeerId, Request, Socket};
 
 /// How long to wait for a request, eg. `getheaders` to be fulfilled.
 pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
@@ -29,11 +38,21 @@ pub const MAX_MESSAGE_INVS: usize = 50000;
 pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::BLOCK_INTERVAL;
 /// Services required from peers for header sync.
 pub const REQUIRED_SERVICES: ServiceFlags = ServiceFlags::NETWORK;
+/// Maximum number of blocks of the active chain that can be reverted by a re-org.
+pub const MAX_REORG_DEPTH: Height = 100;
+/// How long to buffer headers that don't connect to our chain, waiting for their parent.
+pub const ORPHAN_HEADERS_TIMEOUT: LocalDuration = LocalDuration::from_secs(60);
 
 /// Maximum headers announced in a `headers` message, when unsolicited.
 const MAX_UNSOLICITED_HEADERS: usize = 24;
+/// Maximum number of consecutive header announcements from a peer that don't connect to
+/// our chain, before the peer is considered misbehaving.
+const MAX_UNCONNECTING_HEADERS: usize = 10;
 /// How long to wait between checks for longer chains from peers.
 const PEER_SAMPLE_INTERVAL: LocalDuration = LocalDuration::from_mins(60);
+/// Maximum number of full `headers` batches held back from a peer, while the chain they
+/// form doesn't have the minimum work.
+const MAX_HELD_BATCHES: usize = 8;
 
 /// What to do if a timeout for a peer is received.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -55,6 +74,16 @@ struct Peer {
     link: ConnDirection,
     last_active: Option<LocalTime>,
     last_asked: Option<Locators>,
+    /// Number of headers received from this peer that extended our chain.
+    headers: usize,
+    /// Number of consecutive header announcements that didn't connect to our chain.
+    unconnecting: usize,
+    /// Last headers received from this peer that didn't connect to our chain, buffered
+    /// until their parent arrives, and the time they were received.
+    orphans: Option<(NonEmpty<BlockHeader>, LocalTime)>,
+    /// Headers received from this peer that extend our chain, held back until the chain
+    /// they form has the minimum work.
+    held: Vec<BlockHeader>,
 
     _socket: Socket,
 }
@@ -62,12 +91,28 @@ struct Peer {
 /// Sync manager configuration.
 #[derive(Debug)]
 pub struct Config {
-    /// Maximum number of messages in a `headers` message.
+    /// Maximum number of headers in a `headers` message. This is the most we send to peers,
+    /// and the batch size we expect from them: a smaller batch means the peer has no more
+    /// headers, unless it announced a higher chain.
     pub max_message_headers: usize,
+    /// Number of consecutive block hashes at the start of our `getheaders` locators, before
+    /// the locator steps back exponentially.
+    pub locator_density: usize,
     /// How long to wait for a response from a peer.
     pub request_timeout: LocalDuration,
     /// Consensus parameters.
     pub params: Params,
+    /// Maximum number of blocks of the active chain that can be reverted by a re-org.
+    /// Headers forking off deeper than this are rejected, whatever their work.
+    pub max_reorg_depth: Height,
+    /// Minimum proof-of-work of the best chain. We don't consider ourselves synced to a
+    /// chain with less work, and peers whose chain ends with less work are disconnected.
+    pub minimum_chain_work: Work,
+    /// Answer `getheaders` requests from peers with headers from our active chain.
+    pub serve_headers: bool,
+    /// Maximum number of `getheaders` requests served to a single peer per
+    /// [`super::SERVE_BUDGET_PERIOD`]. Requests beyond this are ignored.
+    pub serve_budget: usize,
 }
 
 /// The sync manager state.
@@ -86,6 +131,11 @@ pub struct SyncManager<U, C> {
     last_idle: Option<LocalTime>,
     /// In-flight requests to peers.
     inflight: HashMap<PeerId, GetHeaders>,
+    /// `getheaders` requests served to each peer, within the budget period.
+    served: RateLimiter,
+    /// Whether our active chain was found to have the minimum chain work. Since the work of
+    /// the active chain never decreases, this only has to be checked until it's reached.
+    minimum_work_reached: bool,
     /// Upstream protocol channel.
     upstream: U,
     /// Clock.
@@ -129,6 +179,15 @@ pub enum Event {
         /// Best height known.
         height: Height,
     },
+    /// A peer proposed a chain that would re-org more than the maximum allowed depth.
+    DeepReorgDetected {
+        /// Peer that proposed the chain.
+        peer: PeerId,
+        /// Number of blocks of the active chain that would be reverted.
+        depth: Height,
+        /// Tip of the competing chain.
+        competing_tip: BlockHash,
+    },
 }
 
 impl std::fmt::Display for Event {
@@ -167,6 +226,17 @@ impl std::fmt::Display for Event {
             Event::BlockDiscovered(from, hash) => {
                 write!(fmt, "{}: Discovered new block: {}", from, &hash)
             }
+            Event::DeepReorgDetected {
+                peer,
+                depth,
+                competing_tip,
+            } => {
+                write!(
+                    fmt,
+                    "{}: Rejected re-org of depth {} to competing tip {}",
+                    peer, depth, competing_tip
+                )
+            }
             Event::StaleTip(last_update) => {
                 write!(
                     fmt,
@@ -196,7 +266,8 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
         let last_tip_update = None;
         let last_peer_sample = None;
         let last_idle = None;
-        let inflight = HashMap::with_hasher(rng.into());
+        let inflight = HashMap::with_hasher(rng.clone().into());
+        let served = RateLimiter::new(rng);
 
         Self {
             peers,
@@ -205,6 +276,8 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
             last_peer_sample,
             last_idle,
             inflight,
+            served,
+            minimum_work_reached: false,
             upstream,
             clock,
         }
@@ -261,7 +334,16 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
         self.unregister(id);
     }
 
+    /// Get the number of headers received from a peer that extended our chain.
+    pub fn headers_received(&self, id: &PeerId) -> usize {
+        self.peers.get(id).map_or(0, |p| p.headers)
+    }
+
     /// Called when we received a `getheaders` message from a peer.
+    ///
+    /// Nb. Requests aren't limited to inbound peers: our outbound peers see us as inbound,
+    /// and legitimately sync headers from us, eg. after we announce a block. Every peer is
+    /// instead limited to [`Config::serve_budget`] requests per [`super::SERVE_BUDGET_PERIOD`].
     pub fn received_getheaders<T: BlockReader>(
         &mut self,
         addr: &PeerId,
@@ -270,7 +352,17 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
     ) {
         let max = self.config.max_message_headers;
 
-        if self.is_syncing() || max == 0 {
+        if !self.config.serve_headers || self.is_syncing() || max == 0 {
+            return;
+        }
+        if !self
+            .served
+            .charge(*addr, self.config.serve_budget, self.clock.local_time())
+        {
+            log::debug!(
+                "[sync] Ignoring `getheaders` from {}: budget exceeded",
+                addr
+            );
             return;
         }
         let headers = tree.locate_headers(&locator_hashes, stop_hash, max);
@@ -316,6 +408,30 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
         }
     }
 
+    /// Roll our active chain back to the given height, and sync the missing headers again.
+    /// Returns the reverted blocks, from the tip down.
+    pub fn rollback<T: BlockTree>(
+        &mut self,
+        height: Height,
+        tree: &mut T,
+    ) -> Result<Vec<(Height, BlockHeader)>, Error> {
+        let reverted = tree.rollback(height)?;
+
+        if reverted.is_empty() {
+            return Ok(reverted);
+        }
+        for (height, header) in reverted.iter().cloned() {
+            self.upstream
+                .event(Event::BlockDisconnected { height, header });
+        }
+        let (tip, _) = tree.tip();
+
+        self.upstream.event(Event::Synced(tip, height));
+        self.sync(tree);
+
+        Ok(reverted)
+    }
+
     /// Called when we receive headers from a peer.
     pub fn received_headers<T: BlockTree>(
         &mut self,
@@ -324,7 +440,19 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
         clock: &impl Clock,
         tree: &mut T,
     ) -> Result<ImportResult, store::Error> {
-        let request = self.inflight.remove(from);
+        // Headers are a response to our request if they start from one of the locators we
+        // sent. Otherwise, they are an announcement, eg. of a new tip as per BIP 130, and
+        // the request stays in flight.
+        let solicited = self.inflight.get(from).map_or(false, |req| {
+            headers
+                .first()
+                .map_or(true, |h| req.locators.0.contains(&h.prev_blockhash))
+        });
+        let request = if solicited {
+            self.inflight.remove(from)
+        } else {
+            None
+        };
         let headers = if let Some(headers) = NonEmpty::from_vec(headers) {
             headers
         } else {
@@ -342,6 +470,23 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
 
             return Ok(ImportResult::TipUnchanged);
         }
+        // Headers must form a chain. Unlike headers that don't connect to our chain, this
+        // can't be resolved by waiting for more headers.
+        if headers
+            .iter()
+            .zip(headers.iter().skip(1))
+            .any(|(prev, h)| h.prev_blockhash != prev.block_hash())
+        {
+            log::debug!("Received non-continuous headers from {}", from);
+
+            self.record_misbehavior(from);
+            self.upstream.disconnect(
+                *from,
+                DisconnectReason::PeerMisbehaving("non-continuous headers"),
+            );
+
+            return Ok(ImportResult::TipUnchanged);
+        }
         // When unsolicited, we don't want to process too many headers in case of a DoS.
         if length > MAX_UNSOLICITED_HEADERS && request.is_none() {
             log::debug!("Received {} unsolicited headers from {}", length, from);
@@ -351,23 +496,88 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
 
         if let Some(peer) = self.peers.get_mut(from) {
             peer.last_active = Some(clock.local_time());
+
+            // Announcements that don't connect to our chain are answered with a `getheaders`,
+            // to find the missing headers. Too many of them in a row is a sign of misbehavior.
+            if request.is_none() && !tree.contains(&headers.first().prev_blockhash) {
+                peer.unconnecting += 1;
+            }
+            if peer.unconnecting > MAX_UNCONNECTING_HEADERS {
+                self.record_misbehavior(from);
+                self.upstream.disconnect(
+                    *from,
+                    DisconnectReason::PeerMisbehaving("too many unconnecting headers"),
+                );
+                return Ok(ImportResult::TipUnchanged);
+            }
         } else {
             return Ok(ImportResult::TipUnchanged);
         }
         log::debug!("[sync] Received {} block header(s) from {}", length, from);
 
-        let root = headers.first().block_hash();
         let best = headers.last().block_hash();
 
         if tree.contains(&best) {
             return Ok(ImportResult::TipUnchanged);
         }
 
-        match self.import_blocks(headers.into_iter(), tree) {
+        // Until our chain has the minimum work, headers aren't imported until the chain they
+        // form has it too, so that a low-work chain never becomes our best chain. Headers up
+        // to the last checkpoint are pinned by it, and don't need to be held back.
+        let checkpoint = tree.checkpoints().keys().next_back().copied().unwrap_or(0);
+        let headers = if self.has_minimum_work(tree) || tree.height() < checkpoint {
+            headers
+        } else {
+            match self.hold(from, headers, length, tree) {
+                Some(headers) => headers,
+                None => return Ok(ImportResult::TipUnchanged),
+            }
+        };
+        let root = headers.first().block_hash();
+
+        // Buffer headers that don't connect to our chain, and ask the peer for the missing
+        // headers. We only keep the latest such headers from each peer.
+        if !tree.contains(&headers.first().prev_blockhash) {
+            if let Some(peer) = self.peers.get_mut(from) {
+                peer.orphans = Some((headers, clock.local_time()));
+            }
+            let locators = (self.locators(tree), root);
+            let timeout = self.config.request_timeout;
+
+            self.request(*from, locators, timeout, OnTimeout::Ignore);
+            self.upstream.wakeup(ORPHAN_HEADERS_TIMEOUT);
+
+            return Ok(ImportResult::TipUnchanged);
+        }
+        // Import any buffered headers that connect to the ones we received.
+        let orphans = self.take_orphans(&headers);
+
+        if let Some(depth) = self.reorg_depth(&headers, tree) {
+            if depth > self.config.max_reorg_depth {
+                log::warn!(
+                    target: "p2p",
+                    "Rejecting headers from {} forking {} block(s) below our tip",
+                    from,
+                    depth
+                );
+                self.upstream.event(Event::DeepReorgDetected {
+                    peer: *from,
+                    depth,
+                    competing_tip: best,
+                });
+                self.record_misbehavior(from);
+                self.upstream
+                    .disconnect(*from, DisconnectReason::PeerMisbehaving("deep re-org"));
+
+                return Ok(ImportResult::TipUnchanged);
+            }
+        }
+
+        match self.import_blocks(headers.into_iter().chain(orphans), tree) {
             Ok(ImportResult::TipUnchanged) => {
                 // Try to find a common ancestor that leads up to the first header in
                 // the list we received.
-                let locators = (tree.locator_hashes(tree.height()), root);
+                let locators = (self.locators(tree), root);
                 let timeout = self.config.request_timeout;
 
                 self.request(*from, locators, timeout, OnTimeout::Ignore);
@@ -377,6 +587,9 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
             Ok(ImportResult::TipChanged(header, tip, height, reverted, connected)) => {
                 // Update peer height.
                 if let Some(peer) = self.peers.get_mut(from) {
+                    peer.headers += length;
+                    peer.unconnecting = 0;
+
                     if height > peer.height {
                         peer.tip = tip;
                         peer.height = height;
@@ -386,9 +599,27 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
                 // whether our tip is stale.
                 self.last_tip_update = Some(clock.local_time());
 
-                // If we received less than the maximum number of headers, we must be in sync.
-                // Otherwise, ask for the next batch of headers.
-                if length < MAX_MESSAGE_HEADERS {
+                // If we received less than the maximum number of headers, we must be in sync,
+                // unless the peer told us it has a longer chain, in which case it's capping
+                // its responses. Otherwise, ask for the next batch of headers.
+                let behind = self.peers.get(from).map_or(false, |p| p.height > height);
+
+                if length < self.config.max_message_headers && !behind {
+                    // The peer has no more headers to send us. If its chain doesn't have
+                    // enough work, it's either on a fake chain or not synced itself.
+                    if !self.has_minimum_work(tree) {
+                        log::warn!(
+                            target: "p2p",
+                            "Chain from {} ending at height {} doesn't have the minimum work",
+                            from,
+                            height
+                        );
+                        self.record_misbehavior(from);
+                        self.upstream.disconnect(
+                            *from,
+                            DisconnectReason::PeerMisbehaving("insufficient chain work"),
+                        );
+                    }
                     // If these headers were unsolicited, we may already be ready/synced.
                     // Otherwise, we're finally in sync.
                     self.broadcast_tip(&tip, tree);
@@ -410,6 +641,160 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
         }
     }
 
+    /// Hold back headers extending our chain, until the chain they form has the minimum work.
+    /// Returns the headers to import once it does, or `None` while they are held back.
+    /// Headers that don't extend our chain or the held headers are returned as they are.
+    fn hold<T: BlockReader>(
+        &mut self,
+        from: &PeerId,
+        headers: NonEmpty<BlockHeader>,
+        length: usize,
+        tree: &T,
+    ) -> Option<NonEmpty<BlockHeader>> {
+        let held = &self.peers.get(from)?.held;
+        let prev = headers.first().prev_blockhash;
+
+        // Find the parent of the headers, and its height.
+        let parent = if let Some((height, header)) = tree.get_block(&prev) {
+            (height, *header)
+        } else {
+            match (held.first(), held.last()) {
+                (Some(first), Some(last)) if last.block_hash() == prev => {
+                    let (height, _) = tree.get_block(&first.prev_blockhash)?;
+                    (height + held.len() as Height, *last)
+                }
+                _ => return Some(headers),
+            }
+        };
+
+        // Held headers are only validated by the block tree once they're imported, so we
+        // check their proof-of-work here, to make sure the work we count is real.
+        if !self.validate_pow(parent, &headers) {
+            log::debug!("Received headers with invalid proof-of-work from {}", from);
+
+            self.peers.get_mut(from)?.held.clear();
+            self.record_misbehavior(from);
+            self.upstream.disconnect(
+                *from,
+                DisconnectReason::PeerMisbehaving("invalid header proof-of-work"),
+            );
+            return None;
+        }
+        let max_held = self.config.max_message_headers * MAX_HELD_BATCHES;
+        let peer = self.peers.get_mut(from)?;
+
+        if tree.contains(&prev) {
+            peer.held.clear();
+        }
+        peer.held.extend(headers);
+
+        // If the held headers fork off below our tip, this overestimates the work of their
+        // chain. They are then imported sooner, and compete with our chain as usual.
+        let work = tree.chain_work() + Branch(peer.held.as_slice()).work();
+
+        if work >= self.config.minimum_chain_work {
+            return NonEmpty::from_vec(mem::take(&mut peer.held));
+        }
+        let height = parent.0 + peer.held.len() as Height;
+        let tip = peer.held.last().map(|h| h.block_hash())?;
+
+        if peer.held.len() > max_held {
+            // The peer is sending us a long chain that still doesn't have enough work.
+            log::warn!(
+                target: "p2p",
+                "Chain from {} doesn't have the minimum work after {} header(s)",
+                from,
+                peer.held.len()
+            );
+            peer.held.clear();
+
+            self.record_misbehavior(from);
+            self.upstream.disconnect(
+                *from,
+                DisconnectReason::PeerMisbehaving("too many low-work headers"),
+            );
+        } else if length < self.config.max_message_headers && peer.height <= height {
+            // The peer has no more headers to send us, and its chain doesn't have enough
+            // work. It's either on a fake chain or not synced itself.
+            log::warn!(
+                target: "p2p",
+                "Chain from {} ending at height {} doesn't have the minimum work",
+                from,
+                height
+            );
+            peer.held.clear();
+
+            self.record_misbehavior(from);
+            self.upstream.disconnect(
+                *from,
+                DisconnectReason::PeerMisbehaving("insufficient chain work"),
+            );
+        } else {
+            let locators = (vec![tip], BlockHash::all_zeros());
+            let timeout = self.config.request_timeout;
+
+            self.request(*from, locators, timeout, OnTimeout::Disconnect);
+        }
+        None
+    }
+
+    /// Take the buffered headers that connect to the given headers, ie. whose parent is
+    /// one of them, or one of the other buffered headers taken.
+    fn take_orphans(&mut self, headers: &NonEmpty<BlockHeader>) -> Vec<BlockHeader> {
+        let mut hashes = headers
+            .iter()
+            .map(|h| h.block_hash())
+            .collect::<HashSet<_>>();
+        let mut orphans = Vec::new();
+
+        while let Some((connecting, _)) = self
+            .peers
+            .values_mut()
+            .find(|p| {
+                p.orphans
+                    .as_ref()
+                    .map_or(false, |(o, _)| hashes.contains(&o.first().prev_blockhash))
+            })
+            .and_then(|p| p.orphans.take())
+        {
+            hashes.extend(connecting.iter().map(|h| h.block_hash()));
+            orphans.extend(connecting);
+        }
+        orphans
+    }
+
+    /// Check the proof-of-work of headers extending the given parent block. Each header must
+    /// meet its own target, which can't be above the network's limit. Unless the network
+    /// allows minimum difficulty blocks, the target may only change at difficulty adjustment
+    /// intervals, and by no more than a factor of four.
+    fn validate_pow(&self, parent: (Height, BlockHeader), headers: &NonEmpty<BlockHeader>) -> bool {
+        let params = &self.config.params;
+        let (mut height, mut prev) = parent;
+
+        for header in headers.iter() {
+            let target = header.target();
+
+            if target > params.pow_limit || header.validate_pow(&target).is_err() {
+                return false;
+            }
+            height += 1;
+
+            if !params.allow_min_difficulty_blocks {
+                let prev_target = prev.target();
+
+                if height % params.difficulty_adjustment_interval() != 0 {
+                    if header.bits != prev.bits {
+                        return false;
+                    }
+                } else if target > prev_target.mul_u32(4) || target < prev_target >> 2 {
+                    return false;
+                }
+            }
+            prev = *header;
+        }
+        true
+    }
+
     fn request(
         &mut self,
         addr: PeerId,
@@ -426,6 +811,15 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
 
             peer.last_asked = Some(locators.clone());
 
+            #[cfg(feature = "tracing")]
+            let _span = tracing::debug_span!(
+                "getheaders",
+                peer = %addr,
+                locator = ?locators.0.first(),
+                height = peer.height,
+            )
+            .entered();
+
             let sent_at = self.clock.local_time();
             let req = GetHeaders {
                 locators,
@@ -439,6 +833,19 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
         }
     }
 
+    /// Get the `getheaders` requests awaiting a response.
+    pub fn inflight(&self) -> impl Iterator<Item = InFlight> + '_ {
+        let now = self.clock.local_time();
+
+        self.inflight.iter().map(move |(peer, req)| InFlight {
+            peer: *peer,
+            request: Request::GetHeaders {
+                locators: req.locators.0.clone(),
+            },
+            age: now - req.sent_at,
+        })
+    }
+
     /// Called when we received an `inv` message. This will happen if we are out of sync with a
     /// peer, and blocks are being announced. Otherwise, we expect to receive a `headers` message.
     pub fn received_inv<T: BlockReader>(&mut self, addr: PeerId, inv: Vec<Inventory>, tree: &T) {
@@ -474,7 +881,7 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
         }
 
         if let Some(stop_hash) = best_block {
-            let locators = (tree.locator_hashes(tree.height()), *stop_hash);
+            let locators = (self.locators(tree), *stop_hash);
             let timeout = self.config.request_timeout;
 
             // Try to find headers leading up to the `inv` entry.
@@ -523,6 +930,27 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
             }
         }
 
+        // Discard buffered headers whose parent didn't arrive in time. Unless we're still
+        // fetching headers from the peer, it failed to send us the missing headers.
+        let mut expired = Vec::new();
+        for (addr, peer) in &mut *self.peers {
+            if peer
+                .orphans
+                .as_ref()
+                .map_or(false, |(_, t)| local_time - *t >= ORPHAN_HEADERS_TIMEOUT)
+            {
+                peer.orphans = None;
+                expired.push(*addr);
+            }
+        }
+        for addr in expired {
+            log::debug!("[sync] Discarding unconnecting headers from {}", addr);
+
+            if !self.inflight.contains_key(&addr) {
+                self.record_misbehavior(&addr);
+            }
+        }
+
         // If some of the requests timed out, force a sync, otherwise just idle.
         if sync {
             self.sync(tree);
@@ -576,6 +1004,23 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
         }
     }
 
+    /// Get the number of active chain blocks that would be reverted if we switched to the
+    /// chain ending with the given headers. Returns `None` if the headers don't connect to
+    /// a known block.
+    fn reorg_depth<T: BlockReader>(
+        &self,
+        headers: &NonEmpty<BlockHeader>,
+        tree: &T,
+    ) -> Option<Height> {
+        // Skip headers we already have on our active chain.
+        let first = headers
+            .iter()
+            .find(|h| tree.get_block(&h.block_hash()).is_none())?;
+        let (fork_height, _) = tree.find_branch(&first.prev_blockhash)?;
+
+        Some(tree.height().saturating_sub(fork_height))
+    }
+
     fn record_misbehavior(&mut self, peer: &PeerId) {
         self.upstream.event(Event::PeerMisbehaved(*peer));
     }
@@ -621,6 +1066,10 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
                 preferred,
                 last_active,
                 last_asked,
+                headers: 0,
+                unconnecting: 0,
+                orphans: None,
+                held: Vec::new(),
                 _socket: socket,
             },
         );
@@ -629,6 +1078,7 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
     /// Unregister a peer.
     fn unregister(&mut self, id: &PeerId) {
         self.inflight.remove(id);
+        self.served.remove(id);
         self.peers.remove(id);
     }
 
@@ -665,7 +1115,7 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
     }
 
     /// Check whether or not we are in sync with the network.
-    fn is_synced<T: BlockReader>(&self, tree: &T) -> bool {
+    fn is_synced<T: BlockReader>(&mut self, tree: &T) -> bool {
         if let Some(last_update) = self.stale_tip(tree) {
             self.upstream.event(Event::StaleTip(last_update));
 
@@ -675,13 +1125,27 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
 
         // Find the peer with the longest chain and compare our height to it.
         if let Some(peer_height) = self.best_height() {
-            return height >= peer_height;
+            return height >= peer_height && self.has_minimum_work(tree);
         }
 
         // Assume we're out of sync.
         false
     }
 
+    /// Check whether our active chain has at least the configured minimum work.
+    fn has_minimum_work<T: BlockReader>(&mut self, tree: &T) -> bool {
+        if self.minimum_work_reached {
+            return true;
+        }
+        self.minimum_work_reached = tree.chain_work() >= self.config.minimum_chain_work;
+        self.minimum_work_reached
+    }
+
+    /// Get the locator hashes of our active chain, starting at the tip.
+    fn locators<T: BlockReader>(&self, tree: &T) -> Vec<BlockHash> {
+        tree.locator_hashes_with(tree.height(), self.config.locator_density)
+    }
+
     /// Check if we're currently syncing with these locators.
     fn syncing(&self, locators: &Locators) -> bool {
         self.inflight.values().any(|r| &r.locators == locators)
@@ -707,7 +1171,7 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
 
         // ... It looks like we're out of sync ...
 
-        let locators = (tree.locator_hashes(tree.height()), BlockHash::all_zeros());
+        let locators = (self.locators(tree), BlockHash::all_zeros());
 
         // If we're already fetching these headers, just wait.
         if self.syncing(&locators) {
@@ -756,7 +1220,7 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
 
         // If we think we're in sync and we haven't asked other peers in a while, then
         // sample their headers just to make sure we're on the right chain.
-        let locators = tree.locator_hashes(tree.height());
+        let locators = self.locators(tree);
         let addrs = self
             .peers
             .iter()
@@ -15,8 +15,8 @@ use nakamoto_common::bitcoin::network::message_blockdata::GetHeadersMessage;
 use super::{addrmgr, cbfmgr, invmgr, peermgr, pingmgr, syncmgr};
 use super::{
     chan, network::Network, BlockHash, BlockHeader, Command, Config, DisconnectReason, Event,
-    HashSet, Height, Io, Limits, NetworkMessage, PeerId, RawNetworkMessage, ServiceFlags,
-    VersionMessage,
+    GetFiltersError, HashSet, Height, InFlight, Io, Limits, NetworkMessage, Params, PeerId,
+    RawNetworkMessage, ServiceFlags, SyncStatus, VersionMessage, Work,
 };
 use super::{PROTOCOL_VERSION, USER_AGENT};
 
@@ -130,11 +130,91 @@ fn test_initial_sync() {
     let mut simulation =
         Simulation::new(time, rng, Options::default()).initialize([&mut alice, &mut bob]);
 
-    while simulation.step([&mut alice, &mut bob]) {
-        if alice.protocol.tree.height() == height as Height {
-            break;
-        }
-    }
+    simulation
+        .run_until_converged([&mut alice, &mut bob], 10_000, |p| p.tree.tip().0)
+        .unwrap();
+
+    assert_eq!(alice.protocol.tree.height(), height as Height);
+}
+
+/// Test that the headers supplied by a peer are reported in its peer info.
+#[test]
+fn test_peer_contribution() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let height = 32;
+    let headers = BITCOIN_HEADERS.tail[0..height].to_vec();
+    let time = LocalTime::from_block_time(headers.last().unwrap().time);
+
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng.clone());
+    let mut bob = Peer::new(
+        "bob",
+        [97, 97, 97, 97],
+        network,
+        headers,
+        vec![],
+        vec![],
+        rng.clone(),
+    );
+    alice.command(Command::Connect(bob.addr));
+
+    let mut simulation =
+        Simulation::new(time, rng, Options::default()).initialize([&mut alice, &mut bob]);
+
+    simulation
+        .run_until_converged([&mut alice, &mut bob], 10_000, |p| p.tree.tip().0)
+        .unwrap();
+
+    let (reply, peers) = chan::bounded(1);
+    alice.command(Command::GetPeers(ServiceFlags::NONE, reply));
+
+    let peers = peers.try_recv().unwrap();
+    let peer = peers.iter().find(|p| p.addr == bob.addr).unwrap();
+
+    assert_eq!(peer.headers, height);
+    assert_eq!(peer.filters, 0);
+    assert_eq!(peer.blocks, 0);
+}
+
+/// Test that we sync from a peer sending fewer headers per message than we expect, without
+/// mistaking a short batch for the end of its chain.
+#[test]
+fn test_initial_sync_capped_responses() {
+    let rng = fastrand::Rng::new();
+    let height = 144;
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail[0..height].to_vec();
+    let time = LocalTime::from_block_time(headers.last().unwrap().time);
+    let work = headers
+        .iter()
+        .fold(Work::default(), |acc, h| acc + h.work());
+
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng.clone());
+    let mut bob = Peer::new(
+        "bob",
+        [97, 97, 97, 97],
+        network,
+        headers,
+        vec![],
+        vec![],
+        rng.clone(),
+    );
+    bob.protocol.syncmgr.config.max_message_headers = 10;
+
+    // Alice would disconnect bob if she thought his chain ended after the first batch.
+    alice.protocol.syncmgr.config.minimum_chain_work = work;
+    alice.protocol.syncmgr.config.locator_density = 1;
+    alice.command(Command::Connect(bob.addr));
+
+    let mut simulation =
+        Simulation::new(time, rng, Options::default()).initialize([&mut alice, &mut bob]);
+
+    simulation
+        .run_until_converged([&mut alice, &mut bob], 10_000, |p| p.tree.tip().0)
+        .unwrap();
+
+    assert_eq!(alice.protocol.tree.height(), height as Height);
+    assert!(alice.protocol.peermgr.is_connected(&bob.addr));
 }
 
 /// Test what happens when a peer is idle for too long.
@@ -161,6 +241,466 @@ fn test_idle_disconnect() {
         .expect("peer disconnects remote");
 }
 
+/// Test that a peer disconnected by command is dropped by all sub-protocols.
+#[test]
+fn test_command_disconnect() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote = ([241, 19, 44, 18], 8333).into();
+
+    peer.connect_addr(&remote, ConnDirection::Outbound);
+    peer.command(Command::Disconnect(remote));
+    peer.outputs()
+        .find(
+            |o| matches!(o, Io::DisconnectPeer(addr, DisconnectReason::Command) if addr == &remote),
+        )
+        .expect("peer disconnects remote");
+
+    // Nothing is sent to the remote anymore, even before the disconnection completes.
+    peer.elapse(pingmgr::PING_INTERVAL);
+    assert!(peer
+        .messages(&remote)
+        .all(|m| !matches!(m, NetworkMessage::Ping(_))));
+}
+
+#[test]
+fn test_headers_only() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+    let genesis = network.genesis_block();
+    let chain = gen::blockchain(genesis, 16, &mut rng);
+    let cfg = Config {
+        network,
+        params: Params::new(network.into()),
+        headers_only: true,
+        ..Config::default()
+    };
+    let mut alice = Peer::config(
+        "alice",
+        [48, 48, 48, 48],
+        chain.tail.iter().map(|b| b.header).collect(),
+        vec![],
+        vec![],
+        cfg,
+        rng,
+    );
+    alice.tick(LocalTime::from_block_time(chain.last().header.time));
+    alice.connect_addr(&remote, ConnDirection::Outbound);
+
+    // Alice is behind on filter headers, but doesn't ask for them.
+    assert!(alice
+        .messages(&remote)
+        .all(|m| !matches!(m, NetworkMessage::GetCFHeaders(_))));
+
+    let (reply, result) = chan::bounded(1);
+    alice.command(Command::GetFilters(0..=16, reply));
+
+    assert_matches!(result.try_recv(), Ok(Err(GetFiltersError::Disabled)));
+}
+
+#[test]
+fn test_deep_reorg() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+    let genesis = network.genesis_block();
+    let chain = gen::blockchain(genesis, 16, &mut rng);
+    let cfg = Config {
+        network,
+        params: Params::new(network.into()),
+        max_reorg_depth: 4,
+        ..Config::default()
+    };
+    let mut alice = Peer::config(
+        "alice",
+        [48, 48, 48, 48],
+        chain.tail.iter().map(|b| b.header).collect(),
+        vec![],
+        vec![],
+        cfg,
+        rng.clone(),
+    );
+    // A longer chain forking off eight blocks below our tip. It is refused even though it
+    // has more work than our chain.
+    let fork = gen::fork(&chain[8].header, 10, &mut rng);
+    let competing_tip = fork.last().unwrap().block_hash();
+
+    alice.tick(LocalTime::from_block_time(fork.last().unwrap().header.time));
+    alice.connect_addr(&remote, ConnDirection::Outbound);
+    alice.drain();
+    alice.received(
+        &remote,
+        NetworkMessage::Headers(fork.iter().map(|b| b.header).collect()),
+    );
+
+    let outputs = alice.outputs().collect::<Vec<_>>();
+    assert!(outputs.iter().any(|o| matches!(
+        o,
+        Io::NotifySubscribers(Event::Chain(syncmgr::Event::DeepReorgDetected { depth: 8, competing_tip: tip, .. }))
+        if *tip == competing_tip
+    )));
+    assert!(outputs.iter().any(
+        |o| matches!(o, Io::DisconnectPeer(addr, DisconnectReason::PeerMisbehaving(_)) if *addr == remote)
+    ));
+    assert_eq!(alice.protocol.tree.height(), 16);
+
+    // A fork within the maximum depth is accepted, if it has more work.
+    let fork = gen::fork(&chain[13].header, 4, &mut rng);
+    let tip = fork.last().unwrap().block_hash();
+
+    alice.connect_addr(&([99, 99, 99, 99], 8333).into(), ConnDirection::Outbound);
+    alice.received(
+        &([99, 99, 99, 99], 8333).into(),
+        NetworkMessage::Headers(fork.iter().map(|b| b.header).collect()),
+    );
+    assert_eq!(alice.protocol.tree.tip().0, tip);
+}
+
+#[test]
+fn test_minimum_chain_work() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let genesis = network.genesis();
+    let headers = gen::headers(genesis, 16, &mut rng);
+    let work = headers
+        .iter()
+        .fold(Work::default(), |work, h| work + h.work());
+    let peer = |addr: PeerId, time| PeerDummy {
+        addr,
+        height: 16,
+        protocol_version: PROTOCOL_VERSION,
+        services: cbfmgr::REQUIRED_SERVICES | syncmgr::REQUIRED_SERVICES,
+        relay: true,
+        time,
+    };
+
+    // A chain without the minimum work is held back, and the peer is disconnected.
+    {
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let cfg = Config {
+            network,
+            params: Params::new(network.into()),
+            minimum_chain_work: Some(Work::from_u64(u64::MAX).unwrap()),
+            ..Config::default()
+        };
+        let mut alice = Peer::config(
+            "alice",
+            [48, 48, 48, 48],
+            vec![],
+            vec![],
+            vec![],
+            cfg,
+            rng.clone(),
+        );
+
+        alice.tick(LocalTime::from_block_time(headers.last().time));
+        alice.connect(&peer(remote, alice.local_time()), ConnDirection::Outbound);
+        alice.drain();
+        alice.received(&remote, NetworkMessage::Headers(headers.tail.clone()));
+
+        let outputs = alice.outputs().collect::<Vec<_>>();
+
+        assert_eq!(alice.protocol.tree.height(), 0);
+        assert!(outputs.iter().any(|o| matches!(
+            o,
+            Io::DisconnectPeer(addr, DisconnectReason::PeerMisbehaving("insufficient chain work"))
+            if *addr == remote
+        )));
+        assert!(!outputs.iter().any(|o| matches!(
+            o,
+            Io::NotifySubscribers(Event::Chain(syncmgr::Event::Synced(..)))
+        )));
+    }
+
+    // Headers are imported once the chain they form has the minimum work.
+    {
+        let remote: PeerId = ([99, 99, 99, 99], 8333).into();
+        let cfg = Config {
+            network,
+            params: Params::new(network.into()),
+            minimum_chain_work: Some(work),
+            ..Config::default()
+        };
+        let mut alice = Peer::config("alice", [48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+
+        alice.tick(LocalTime::from_block_time(headers.last().time));
+        alice.connect(&peer(remote, alice.local_time()), ConnDirection::Outbound);
+        alice.drain();
+        alice.received(&remote, NetworkMessage::Headers(headers.tail[..8].to_vec()));
+
+        assert_eq!(alice.protocol.tree.height(), 0);
+        alice
+            .messages(&remote)
+            .find(|m| matches!(m, NetworkMessage::GetHeaders(msg) if msg.locator_hashes == vec![headers.tail[7].block_hash()]))
+            .expect("The next headers are requested");
+
+        alice.received(&remote, NetworkMessage::Headers(headers.tail[8..].to_vec()));
+        assert_eq!(alice.protocol.tree.height(), 16);
+    }
+}
+
+#[test]
+fn test_minimum_chain_work_held_headers() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let genesis = network.genesis();
+    let headers = gen::headers(genesis, 64, &mut rng);
+    let cfg = Config {
+        network,
+        params: Params::new(network.into()),
+        minimum_chain_work: Some(Work::from_u64(u64::MAX).unwrap()),
+        ..Config::default()
+    };
+    let peer = |addr: PeerId, time| PeerDummy {
+        addr,
+        height: 64,
+        protocol_version: PROTOCOL_VERSION,
+        services: cbfmgr::REQUIRED_SERVICES | syncmgr::REQUIRED_SERVICES,
+        relay: true,
+        time,
+    };
+
+    // Headers with invalid proof-of-work aren't held back, and the peer is disconnected.
+    {
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut alice = Peer::config(
+            "alice",
+            [48, 48, 48, 48],
+            vec![],
+            vec![],
+            vec![],
+            cfg.clone(),
+            rng.clone(),
+        );
+        let mut invalid = headers.tail[..8].to_vec();
+        let header = invalid.last_mut().unwrap();
+
+        while header.validate_pow(&header.target()).is_ok() {
+            header.nonce += 1;
+        }
+        alice.tick(LocalTime::from_block_time(headers.last().time));
+        alice.connect(&peer(remote, alice.local_time()), ConnDirection::Outbound);
+        alice.drain();
+        alice.received(&remote, NetworkMessage::Headers(invalid));
+
+        assert_eq!(alice.protocol.tree.height(), 0);
+        assert!(alice.outputs().any(|o| matches!(
+            o,
+            Io::DisconnectPeer(addr, DisconnectReason::PeerMisbehaving("invalid header proof-of-work"))
+            if addr == remote
+        )));
+    }
+
+    // A peer can't make us hold back more than a few batches of headers.
+    {
+        let remote: PeerId = ([99, 99, 99, 99], 8333).into();
+        let mut alice = Peer::config("alice", [48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+        let batch = 4;
+
+        alice.protocol.syncmgr.config.max_message_headers = batch;
+        alice.tick(LocalTime::from_block_time(headers.last().time));
+        alice.connect(&peer(remote, alice.local_time()), ConnDirection::Outbound);
+        alice.drain();
+
+        headers
+            .tail
+            .chunks(batch)
+            .find(|chunk| {
+                alice.received(&remote, NetworkMessage::Headers(chunk.to_vec()));
+                alice.outputs().any(|o| {
+                    matches!(
+                        o,
+                        Io::DisconnectPeer(addr, DisconnectReason::PeerMisbehaving("too many low-work headers"))
+                        if addr == remote
+                    )
+                })
+            })
+            .expect("The peer is disconnected before sending all its headers");
+
+        assert_eq!(alice.protocol.tree.height(), 0);
+    }
+}
+
+#[test]
+fn test_sync_status() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote: PeerId = ([241, 19, 44, 18], 8333).into();
+    let (reply, status) = chan::bounded(1);
+
+    alice.command(Command::GetSyncStatus(reply.clone()));
+    assert_eq!(
+        status.try_recv(),
+        Ok(SyncStatus::Headers {
+            current: 0,
+            target: 0
+        })
+    );
+
+    alice.connect_addr(&remote, ConnDirection::Outbound);
+    alice.command(Command::GetSyncStatus(reply));
+    assert_eq!(
+        status.try_recv(),
+        Ok(SyncStatus::Headers {
+            current: 0,
+            target: 144
+        })
+    );
+}
+
+#[test]
+fn test_user_agent() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+    let cfg = Config {
+        network,
+        params: Params::new(network.into()),
+        user_agent: "/acme:1.0/".to_owned(),
+        ..Config::default()
+    };
+    let mut alice = Peer::config("alice", [48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+
+    alice.connect_addr(&remote, ConnDirection::Outbound);
+    alice
+        .messages(&remote)
+        .find(|m| matches!(m, NetworkMessage::Version(v) if v.user_agent == "/acme:1.0/"))
+        .expect("Alice sends her configured user agent");
+
+    assert!(super::validate_user_agent(super::USER_AGENT).is_ok());
+    assert_eq!(
+        super::validate_user_agent(&"a".repeat(super::MAX_USER_AGENT_LENGTH + 1)),
+        Err(super::ConfigError::UserAgentTooLong(
+            super::MAX_USER_AGENT_LENGTH + 1
+        ))
+    );
+    assert_eq!(
+        super::validate_user_agent("/acme:1.0/\n"),
+        Err(super::ConfigError::UserAgentInvalid)
+    );
+}
+
+#[test]
+fn test_health() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let genesis = LocalTime::from_block_time(network.genesis().time);
+    let (reply, health) = chan::bounded(1);
+
+    alice.tick(genesis + LocalDuration::from_mins(120));
+    alice.command(Command::GetHealth(reply.clone()));
+
+    let h = health.try_recv().unwrap();
+    assert_eq!(h.peers, 0);
+    assert_eq!(h.tip_age, LocalDuration::from_mins(120));
+    assert!(!h.is_ready(1, LocalDuration::from_mins(180)));
+
+    alice.connect(
+        &PeerDummy {
+            addr: ([241, 19, 44, 18], 8333).into(),
+            height: 0,
+            protocol_version: PROTOCOL_VERSION,
+            services: cbfmgr::REQUIRED_SERVICES | syncmgr::REQUIRED_SERVICES,
+            relay: true,
+            time: alice.local_time(),
+        },
+        ConnDirection::Outbound,
+    );
+    alice.command(Command::GetHealth(reply));
+
+    let h = health.try_recv().unwrap();
+    assert_eq!(h.sync, SyncStatus::Synced { height: 0 });
+    assert_eq!(h.peers, 1);
+    assert!(h.is_ready(1, LocalDuration::from_mins(180)));
+    assert!(!h.is_ready(1, LocalDuration::from_mins(60)));
+    assert!(!h.is_ready(2, LocalDuration::from_mins(180)));
+}
+
+#[test]
+fn test_metrics() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let (reply, metrics) = chan::bounded(1);
+
+    alice.connect(
+        &PeerDummy {
+            addr: ([241, 19, 44, 18], 8333).into(),
+            height: 0,
+            protocol_version: PROTOCOL_VERSION,
+            services: cbfmgr::REQUIRED_SERVICES | syncmgr::REQUIRED_SERVICES,
+            relay: true,
+            time: alice.local_time(),
+        },
+        ConnDirection::Outbound,
+    );
+    alice.protocol.record_bytes_received(42);
+    alice.protocol.record_bytes_sent(24);
+    alice.command(Command::GetMetrics(reply));
+
+    let m = metrics.try_recv().unwrap();
+    assert_eq!(m.outbound_peers, 1);
+    assert_eq!(m.inbound_peers, 0);
+    assert_eq!(m.height, 0);
+    assert_eq!(m.bytes_received, 42);
+    assert_eq!(m.bytes_sent, 24);
+    assert_eq!(m.reorgs, 0);
+    assert_eq!(m.blocks_matched, 0);
+}
+
+#[test]
+fn test_snapshot() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote: PeerId = ([241, 19, 44, 18], 8333).into();
+    let remote2: PeerId = ([241, 19, 44, 19], 8333).into();
+    let (reply, snapshot) = chan::bounded(1);
+
+    alice.init();
+    alice.advance_to(8);
+    alice.connect_addr(&remote, ConnDirection::Outbound);
+    alice.command(Command::GetSnapshot(reply.clone()));
+
+    let s = snapshot.try_recv().unwrap();
+    assert_eq!(s.tip, 8);
+    assert_eq!(s.filter_tip, 0);
+    assert_eq!(s.peers, vec![remote]);
+
+    // Events emitted after the snapshot aren't counted by it.
+    alice.drain();
+    alice.connect_addr(&remote2, ConnDirection::Outbound);
+    let emitted = alice.events().count() as u64;
+    assert!(emitted > 0);
+
+    alice.command(Command::GetSnapshot(reply));
+    let t = snapshot.try_recv().unwrap();
+    assert_eq!(t.events, s.events + emitted);
+}
+
+#[test]
+fn test_advance_to() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+
+    alice.init();
+
+    let headers = alice.advance_to(32);
+    assert_eq!(headers.len(), 32);
+    assert_eq!(headers[0].prev_blockhash, network.genesis_hash());
+    assert_eq!(alice.protocol.tree.tip().0, headers[31].block_hash());
+    assert!(alice.local_time() >= LocalTime::from_block_time(headers[31].time));
+
+    let headers = alice.advance_to(48);
+    assert_eq!(headers.len(), 16);
+    assert_eq!(alice.protocol.tree.height(), 48);
+}
+
 #[test]
 fn test_inv_getheaders() {
     let rng = fastrand::Rng::new();
@@ -248,7 +788,7 @@ fn test_maintain_connections() {
         let addr = alice
             .outputs()
             .find_map(|o| match o {
-                Io::ConnectPeer(addr) => Some(addr),
+                Io::ConnectPeer(addr, _) => Some(addr),
                 _ => None,
             })
             .expect("Alice connects to a new peer");
@@ -338,6 +878,78 @@ fn test_getheaders_retry() {
     }
 }
 
+#[test]
+fn test_serve_headers() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let height = 32;
+    let headers = BITCOIN_HEADERS.tail[0..height].to_vec();
+    let remote: PeerId = ([66, 66, 66, 66], network.port()).into();
+    // The remote peer is at our height, so that we don't sync from it.
+    let dummy = PeerDummy {
+        addr: remote,
+        height: height as Height,
+        protocol_version: PROTOCOL_VERSION,
+        services: syncmgr::REQUIRED_SERVICES,
+        relay: true,
+        time: LocalTime::from_block_time(headers.last().unwrap().time),
+    };
+    let tip = headers.last().unwrap().block_hash();
+    let getheaders =
+        || NetworkMessage::GetHeaders(GetHeadersMessage::new(vec![network.genesis_hash()], tip));
+
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers.clone(),
+        vec![],
+        vec![],
+        rng.clone(),
+    );
+    alice.connect(&dummy, ConnDirection::Inbound);
+    alice.messages(&remote).for_each(drop);
+
+    for _ in 0..super::Config::default().serve_budget {
+        alice.received(&remote, getheaders());
+        assert!(
+            alice
+                .messages(&remote)
+                .any(|m| matches!(m, NetworkMessage::Headers(h) if h.len() == height)),
+            "Alice serves headers within the budget"
+        );
+    }
+    // The peer's budget is spent.
+    alice.received(&remote, getheaders());
+    assert!(alice
+        .messages(&remote)
+        .all(|m| !matches!(m, NetworkMessage::Headers(_))));
+
+    // The budget is replenished over time.
+    alice.elapse(super::SERVE_BUDGET_PERIOD);
+    alice.received(&remote, getheaders());
+    assert!(alice
+        .messages(&remote)
+        .any(|m| matches!(m, NetworkMessage::Headers(_))));
+
+    // Headers aren't served when disabled.
+    let cfg = Config {
+        network,
+        params: Params::new(network.into()),
+        serve_headers: false,
+        ..Config::default()
+    };
+    let mut bob = Peer::config("bob", [97, 97, 97, 97], headers, vec![], vec![], cfg, rng);
+
+    bob.connect(&dummy, ConnDirection::Inbound);
+    bob.messages(&remote).for_each(drop);
+    bob.received(&remote, getheaders());
+
+    assert!(bob
+        .messages(&remote)
+        .all(|m| !matches!(m, NetworkMessage::Headers(_))));
+}
+
 #[test]
 fn test_handshake_version_timeout() {
     let network = Network::Mainnet;
@@ -445,6 +1057,81 @@ fn test_handshake_version_hook() {
         .expect("peer should send a 'verack' message back");
 }
 
+#[test]
+fn test_handshake_required_services() {
+    let network = Network::Mainnet;
+    let rng = fastrand::Rng::new();
+    let required = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS | ServiceFlags::WITNESS;
+    let cfg = Config {
+        required_services: required,
+        ..Config::default()
+    };
+
+    let mut peer = Peer::config("alice", [48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+    let full = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
+    let filters = PeerDummy::new([131, 31, 11, 66], network, 144, required);
+
+    peer.init();
+    peer.protocol.peermgr.connect(&full.addr);
+    peer.protocol
+        .connected(full.addr, &peer.addr, ConnDirection::Outbound);
+    peer.received(
+        &full.addr,
+        NetworkMessage::Version(full.version(peer.addr, 0)),
+    );
+    peer.outputs()
+        .find(|o| {
+            matches!(
+                o,
+                Io::DisconnectPeer(a, DisconnectReason::PeerServices(s))
+                    if a == &full.addr && *s == ServiceFlags::NETWORK
+            )
+        })
+        .expect("peer should disconnect when the required services are missing");
+
+    peer.protocol.peermgr.connect(&filters.addr);
+    peer.protocol
+        .connected(filters.addr, &peer.addr, ConnDirection::Outbound);
+    peer.received(
+        &filters.addr,
+        NetworkMessage::Version(filters.version(peer.addr, 0)),
+    );
+    peer.messages(&filters.addr)
+        .find(|m| matches!(m, NetworkMessage::Verack))
+        .expect("peer should send a 'verack' message back");
+}
+
+#[test]
+fn test_clock_skew() {
+    let network = Network::Mainnet;
+    let rng = fastrand::Rng::new();
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let skew = LocalDuration::from_mins(120);
+
+    // Our clock is behind all of our peers' clocks by two hours.
+    for i in 1..=4 {
+        let mut remote = PeerDummy::new([131, 31, 11, i], network, 0, ServiceFlags::NETWORK);
+        remote.time = peer.local_time() + skew;
+
+        peer.connect(&remote, ConnDirection::Inbound);
+
+        let detected = peer
+            .outputs()
+            .any(|o| matches!(o, Io::NotifySubscribers(Event::ClockSkewDetected { .. })));
+
+        // The skew is only detected once we have enough samples.
+        if i < 4 {
+            assert!(!detected);
+        } else {
+            assert!(detected, "clock skew should be detected");
+        }
+    }
+    assert_eq!(
+        peer.protocol.clock.borrow().skew(),
+        Some(skew.as_secs() as i64)
+    );
+}
+
 #[test]
 fn test_handshake_initial_messages() {
     let rng = fastrand::Rng::new();
@@ -491,7 +1178,7 @@ fn test_connection_error() {
 
     peer.command(Command::Connect(remote.addr));
     peer.outputs()
-        .find(|o| matches!(o, Io::ConnectPeer(addr) if addr == &remote.addr))
+        .find(|o| matches!(o, Io::ConnectPeer(addr, _) if addr == &remote.addr))
         .expect("Alice should try to connect to remote");
     peer.attempted(&remote.addr);
     // Make sure we can handle a disconnection before an established connection.
@@ -548,7 +1235,7 @@ fn test_getaddr() {
 
     alice
         .outputs()
-        .find(|o| matches!(o, Io::ConnectPeer(addr) if addr == &toto))
+        .find(|o| matches!(o, Io::ConnectPeer(addr, _) if addr == &toto))
         .expect("Alice tries to connect to Toto");
 }
 
@@ -603,6 +1290,149 @@ fn test_stale_tip() {
         .expect("Alice emits a `StaleTip` event");
 }
 
+/// Test that header announcements aren't mistaken for responses to our requests, and that
+/// too many announcements that don't connect to our chain get the peer disconnected.
+#[test]
+fn test_header_announcements() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote: PeerId = ([33, 33, 33, 33], network.port()).into();
+    let headers = &BITCOIN_HEADERS.tail;
+
+    alice.connect_addr(&remote, ConnDirection::Outbound);
+    alice
+        .messages(&remote)
+        .find(|msg| matches!(msg, NetworkMessage::GetHeaders(_)))
+        .expect("Alice sends a `getheaders` message");
+
+    // The remote announces a new tip while the request is in flight.
+    alice.received(&remote, NetworkMessage::Headers(vec![headers[150]]));
+    assert!(alice.protocol.syncmgr.is_syncing());
+    assert_eq!(alice.protocol.tree.height(), 0);
+
+    // The response is processed as such, even though it's larger than an announcement.
+    alice.received(&remote, NetworkMessage::Headers(headers[..144].to_vec()));
+    assert_eq!(alice.protocol.tree.height(), 144);
+
+    // Announcements that don't connect to Alice's chain are tolerated, up to a point.
+    for _ in 0..10 {
+        alice.received(&remote, NetworkMessage::Headers(vec![headers[200]]));
+    }
+    assert!(alice.protocol.peermgr.is_connected(&remote));
+
+    alice.received(&remote, NetworkMessage::Headers(vec![headers[200]]));
+    alice
+        .outputs()
+        .find(|o| {
+            matches!(
+                o,
+                Io::DisconnectPeer(
+                    addr,
+                    DisconnectReason::PeerMisbehaving("too many unconnecting headers")
+                ) if addr == &remote
+            )
+        })
+        .expect("Alice disconnects the remote");
+}
+
+/// Test that headers that don't connect to our chain are buffered until their parent
+/// arrives, and discarded if it doesn't arrive in time. Headers that don't form a chain get
+/// the peer disconnected.
+#[test]
+fn test_unconnecting_headers() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote: PeerId = ([33, 33, 33, 33], network.port()).into();
+    let headers = &BITCOIN_HEADERS.tail;
+
+    alice.connect_addr(&remote, ConnDirection::Outbound);
+    alice
+        .messages(&remote)
+        .find(|msg| matches!(msg, NetworkMessage::GetHeaders(_)))
+        .expect("Alice sends a `getheaders` message");
+
+    // The first header doesn't connect to Alice's chain, so the headers are buffered.
+    alice.received(&remote, NetworkMessage::Headers(headers[20..24].to_vec()));
+    assert_eq!(alice.protocol.tree.height(), 0);
+
+    // Once the missing headers arrive, the buffered headers are imported with them.
+    alice.received(&remote, NetworkMessage::Headers(headers[..20].to_vec()));
+    assert_eq!(alice.protocol.tree.height(), 24);
+
+    // Buffered headers whose parent doesn't arrive in time are discarded.
+    alice.received(&remote, NetworkMessage::Headers(headers[40..42].to_vec()));
+    alice.elapse(syncmgr::ORPHAN_HEADERS_TIMEOUT);
+    alice
+        .events()
+        .find(
+            |e| matches!(e, Event::Chain(syncmgr::Event::PeerMisbehaved(addr)) if addr == &remote),
+        )
+        .expect("Alice records the remote as misbehaving");
+    assert_eq!(alice.protocol.tree.height(), 24);
+
+    // Headers that don't form a chain are rejected outright.
+    alice.received(
+        &remote,
+        NetworkMessage::Headers(vec![headers[25], headers[24]]),
+    );
+    alice
+        .outputs()
+        .find(|o| {
+            matches!(
+                o,
+                Io::DisconnectPeer(
+                    addr,
+                    DisconnectReason::PeerMisbehaving("non-continuous headers")
+                ) if addr == &remote
+            )
+        })
+        .expect("Alice disconnects the remote");
+    assert_eq!(alice.protocol.tree.height(), 24);
+}
+
+#[test]
+fn test_get_inflight() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote: PeerId = ([33, 33, 33, 33], network.port()).into();
+    let headers = &BITCOIN_HEADERS.tail;
+
+    alice.connect_addr(&remote, ConnDirection::Outbound);
+    let locators = alice
+        .messages(&remote)
+        .find_map(|msg| match msg {
+            NetworkMessage::GetHeaders(GetHeadersMessage { locator_hashes, .. }) => {
+                Some(locator_hashes)
+            }
+            _ => None,
+        })
+        .expect("Alice sends a `getheaders` message");
+
+    alice.elapse(LocalDuration::from_secs(3));
+
+    let (reply, inflight) = chan::bounded(1);
+    alice.command(Command::GetInFlight(reply.clone()));
+    assert_eq!(
+        inflight.recv().unwrap(),
+        vec![InFlight {
+            peer: remote,
+            request: super::Request::GetHeaders { locators },
+            age: LocalDuration::from_secs(3),
+        }]
+    );
+
+    alice.received(&remote, NetworkMessage::Headers(headers[..144].to_vec()));
+    alice.command(Command::GetInFlight(reply));
+    assert!(inflight
+        .recv()
+        .unwrap()
+        .iter()
+        .all(|r| !matches!(r.request, super::Request::GetHeaders { .. })));
+}
+
 #[quickcheck]
 fn prop_addrs(seed: u64) {
     let rng = fastrand::Rng::with_seed(seed);
@@ -684,7 +1514,7 @@ fn prop_connect_timeout(seed: u64) {
 
     let result = alice
         .outputs()
-        .filter(|o| matches!(o, Io::ConnectPeer(_)))
+        .filter(|o| matches!(o, Io::ConnectPeer(..)))
         .collect::<Vec<_>>();
 
     assert_eq!(
@@ -695,7 +1525,7 @@ fn prop_connect_timeout(seed: u64) {
     let mut attempted: Vec<net::SocketAddr> = result
         .into_iter()
         .map(|r| match r {
-            Io::ConnectPeer(addr) => addr,
+            Io::ConnectPeer(addr, _) => addr,
             _ => panic!(),
         })
         .collect();
@@ -709,7 +1539,7 @@ fn prop_connect_timeout(seed: u64) {
     alice.elapse(peermgr::IDLE_TIMEOUT);
 
     assert!(alice.outputs().all(|o| match o {
-        Io::ConnectPeer(addr) => !attempted.contains(&addr),
+        Io::ConnectPeer(addr, _) => !attempted.contains(&addr),
         _ => true,
     }));
 }
@@ -859,6 +1689,54 @@ fn test_submit_transactions() {
         .expect("Alice responds to `getdata` with a `tx` message");
 }
 
+#[test]
+fn test_reject_tx() {
+    use nakamoto_common::bitcoin::network::message::CommandString;
+    use nakamoto_common::bitcoin::network::message_network::{Reject, RejectReason};
+
+    let network = Network::Mainnet;
+    let mut rng = fastrand::Rng::new();
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng.clone());
+    let remote = ([88, 88, 88, 88], 8333).into();
+    let tx = gen::transaction(&mut rng);
+    let (transmit, _) = chan::unbounded();
+
+    alice.connect_addr(&remote, ConnDirection::Outbound);
+    alice.command(Command::SubmitTransaction(tx.clone(), transmit));
+    alice.drain();
+
+    let reject = |hash| Reject {
+        message: CommandString::try_from_static("tx").unwrap(),
+        ccode: RejectReason::Fee,
+        reason: "insufficient fee".into(),
+        hash,
+    };
+
+    alice.received(&remote, NetworkMessage::Reject(reject(tx.txid().as_hash())));
+    assert_matches!(
+        alice.events().find_map(|e| match e {
+            Event::Inventory(e @ invmgr::Event::MessageRejected { .. }) => Some(e),
+            _ => None,
+        }),
+        Some(invmgr::Event::MessageRejected { peer, txid: Some(txid), code: RejectReason::Fee, .. })
+        if peer == remote && txid == tx.txid()
+    );
+
+    // A reject for a transaction we didn't submit isn't correlated.
+    let other = gen::transaction(&mut rng);
+    alice.received(
+        &remote,
+        NetworkMessage::Reject(reject(other.txid().as_hash())),
+    );
+    assert_matches!(
+        alice.events().find_map(|e| match e {
+            Event::Inventory(e @ invmgr::Event::MessageRejected { .. }) => Some(e),
+            _ => None,
+        }),
+        Some(invmgr::Event::MessageRejected { txid: None, .. })
+    );
+}
+
 /// Should rebroadcast `inv` when no `getdata` is received.
 /// Should rebroadcast when a new peer connects.
 #[test]
@@ -1566,6 +2444,68 @@ fn test_block_events() {
     assert!(events.next().is_none());
 }
 
+#[test]
+fn test_resync() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let genesis = network.genesis();
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng.clone());
+    let (transmit, import) = chan::unbounded();
+    let (reply, resynced) = chan::unbounded();
+
+    let best = 16;
+    let from = 8;
+    let headers = gen::headers(genesis, best, &mut rng);
+
+    alice.tick(LocalTime::from_block_time(headers.last().time));
+    alice.init();
+    alice.command(Command::ImportHeaders(
+        headers.tail.clone(),
+        transmit.clone(),
+    ));
+    import.recv().unwrap().unwrap();
+    alice.events().for_each(drop);
+
+    // Resyncing from above the tip does nothing.
+    alice.command(Command::Resync {
+        from: best + 1,
+        reply: reply.clone(),
+    });
+    assert_eq!(resynced.recv().unwrap().unwrap(), best);
+    assert_eq!(alice.protocol.tree.height(), best);
+
+    alice.command(Command::Resync { from, reply });
+    assert_eq!(resynced.recv().unwrap().unwrap(), from);
+    assert_eq!(alice.protocol.tree.height(), from);
+    assert_eq!(
+        alice.protocol.tree.tip().0,
+        headers[from as usize].block_hash()
+    );
+
+    let mut events = alice.events().filter_map(|e| match e {
+        Event::Chain(event) => Some(event),
+        _ => None,
+    });
+    for height_ in (from + 1..=best).rev() {
+        let hash_ = headers[height_ as usize].block_hash();
+
+        assert_matches!(
+            events.next().unwrap(),
+            syncmgr::Event::BlockDisconnected { height, header }
+            if height == height_ && header.block_hash() == hash_
+        );
+    }
+    assert_matches!(
+        events.next().unwrap(),
+        syncmgr::Event::Synced(_, height) if height == from
+    );
+
+    // The reverted headers can be imported again.
+    alice.command(Command::ImportHeaders(headers.tail.clone(), transmit));
+    import.recv().unwrap().unwrap();
+    assert_eq!(alice.protocol.tree.height(), best);
+}
+
 #[test]
 fn test_transaction_mempool_rebroadcast() {
     // TODO: Should check mempool to rebroadcast.
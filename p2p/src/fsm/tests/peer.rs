@@ -12,6 +12,7 @@ use nakamoto_chain::block::store;
 use nakamoto_common::block::filter::{FilterHash, FilterHeader};
 use nakamoto_common::block::store::Genesis;
 use nakamoto_common::block::time::{Clock, RefClock};
+use nakamoto_common::block::tree::BlockReader as _;
 use nakamoto_common::block::BlockHeader;
 use nakamoto_common::collections::{HashMap, HashSet};
 use nakamoto_common::nonempty::NonEmpty;
@@ -19,6 +20,7 @@ use nakamoto_common::p2p::peer::KnownAddress;
 
 use nakamoto_net::simulator;
 use nakamoto_test::block::cache::model;
+use nakamoto_test::block::gen;
 
 use crate as p2p;
 use crate::fsm::Limits;
@@ -124,6 +126,8 @@ impl Peer<Protocol> {
             // We don't actually have the required services, but we pretend to
             // for testing purposes.
             services: syncmgr::REQUIRED_SERVICES | cbfmgr::REQUIRED_SERVICES,
+            // Test chains don't have the mainnet minimum work.
+            minimum_chain_work: Some(Default::default()),
             ..Config::default()
         };
         Self::config(name, ip, headers, cfheaders, peers, cfg, rng)
@@ -192,6 +196,42 @@ impl Peer<Protocol> {
         self.protocol.clock.local_time()
     }
 
+    /// Extend the active chain of an initialized peer with generated headers up to the given
+    /// height, and import them through the sync manager. The local time is moved forward to
+    /// the time of the new tip if needed, so that the headers aren't rejected as being from
+    /// the future. Nothing else about the peer's state is changed.
+    ///
+    /// Generated headers keep the difficulty of the current tip, so this should be used
+    /// with networks that don't retarget, eg. regtest.
+    pub fn advance_to(&mut self, height: Height) -> Vec<BlockHeader> {
+        let current = self.protocol.tree.height();
+        assert!(self.initialized, "peer must be initialized");
+        assert!(
+            height > current,
+            "target height must be above the current tip"
+        );
+
+        let (_, tip) = self.protocol.tree.tip();
+        let mut rng = self.protocol.rng.clone();
+        let headers = gen::headers(tip, height - current, &mut rng).tail;
+        let time = LocalTime::from_block_time(headers[headers.len() - 1].time);
+
+        if time > self.local_time() {
+            self.tick(time);
+        }
+        let (reply, result) = crossbeam_channel::bounded(1);
+
+        self.command(Command::ImportHeaders(headers.clone(), reply));
+        result
+            .try_recv()
+            .expect("`ImportHeaders` should reply immediately")
+            .expect("generated headers should be valid");
+
+        assert_eq!(self.protocol.tree.height(), height);
+
+        headers
+    }
+
     pub fn connect_addr(&mut self, addr: &PeerId, link: ConnDirection) {
         self.connect(
             &PeerDummy {
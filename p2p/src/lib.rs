@@ -16,6 +16,11 @@
 //! To achieve this, handling of network I/O is cleanly separated into a network
 //! *reactor*. See the `nakamoto-net-poll` crate for an example of a reactor.
 //!
+//! With the `tracing` feature enabled, message handling, connection events and outgoing
+//! sync requests are instrumented with [`tracing`](https://docs.rs/tracing) spans, carrying
+//! structured fields such as the requested heights. Each connection has its own span,
+//! carrying the peer address, under which the peer's messages are handled.
+//!
 #![allow(clippy::type_complexity)]
 #![allow(clippy::new_without_default)]
 #![allow(clippy::collapsible_if)]
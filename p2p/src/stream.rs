@@ -1,11 +1,76 @@
 //! Message stream utilities.
-use std::io;
+use std::convert::TryInto;
 
-use nakamoto_common::bitcoin::consensus::{encode, Decodable};
+use thiserror::Error;
+
+use nakamoto_common::bitcoin::consensus::encode;
+use nakamoto_common::bitcoin::network::message::RawNetworkMessage;
+
+/// Size of a message header: network magic, command, payload length and checksum.
+pub const MESSAGE_HEADER_SIZE: usize = 4 + 12 + 4 + 4;
+/// Maximum size of a message payload. Messages announcing a larger payload are rejected
+/// as soon as their header is received, before the payload is buffered. Known message
+/// types have lower limits, see [`max_payload_size`].
+pub const MAX_PAYLOAD_SIZE: usize = 32 * 1024 * 1024;
+
+/// Maximum serialized size of a block or transaction, in bytes.
+const MAX_BLOCK_SIZE: usize = 4_000_000;
+/// Maximum number of inventory vectors in an `inv`, `getdata` or `notfound` message.
+const MAX_INV_ENTRIES: usize = 50_000;
+/// Maximum number of addresses in an `addr` message.
+const MAX_ADDR_ENTRIES: usize = 1_000;
+/// Maximum number of headers in a `headers` or `cfheaders` message.
+const MAX_HEADERS_ENTRIES: usize = 2_000;
+/// Maximum size of a compact-size length prefix.
+const MAX_LENGTH_PREFIX: usize = 9;
+
+/// A stream decoding error.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The message header announced a payload that is too large for its type.
+    #[error("`{command}` message of {length} bytes exceeds the maximum of {max} bytes")]
+    Oversized {
+        /// Message command.
+        command: String,
+        /// Announced payload length.
+        length: usize,
+        /// Maximum payload length for this message type.
+        max: usize,
+    },
+    /// The message could not be decoded.
+    #[error(transparent)]
+    Decode(#[from] encode::Error),
+}
+
+/// Get the maximum payload size of a message, given its command.
+///
+/// ```
+/// use nakamoto_p2p::stream::{max_payload_size, MAX_PAYLOAD_SIZE};
+///
+/// assert_eq!(max_payload_size("ping"), 8);
+/// assert_eq!(max_payload_size("block"), 4_000_000);
+/// assert_eq!(max_payload_size("unknown"), MAX_PAYLOAD_SIZE);
+/// ```
+pub fn max_payload_size(command: &str) -> usize {
+    match command {
+        "verack" | "getaddr" | "mempool" | "sendheaders" | "wtxidrelay" | "sendaddrv2" => 0,
+        "ping" | "pong" | "feefilter" => 8,
+        "sendcmpct" => 9,
+        "version" => 1024,
+        "addr" => MAX_LENGTH_PREFIX + MAX_ADDR_ENTRIES * 30,
+        "inv" | "getdata" | "notfound" => MAX_LENGTH_PREFIX + MAX_INV_ENTRIES * 36,
+        "headers" => MAX_LENGTH_PREFIX + MAX_HEADERS_ENTRIES * 81,
+        "cfheaders" => 1 + 32 + 32 + MAX_LENGTH_PREFIX + MAX_HEADERS_ENTRIES * 32,
+        "block" | "tx" | "cmpctblock" | "blocktxn" | "merkleblock" | "cfilter" => MAX_BLOCK_SIZE,
+        _ => MAX_PAYLOAD_SIZE,
+    }
+}
 
 /// Message stream decoder.
 ///
-/// Used to for example turn a byte stream into network messages.
+/// Used to turn a byte stream into network messages. Bytes can be input in chunks of any
+/// size: a message is only decoded once all of its bytes were received, and a single chunk
+/// may contain several messages.
 #[derive(Debug)]
 pub struct Decoder {
     unparsed: Vec<u8>,
@@ -24,27 +89,51 @@ impl Decoder {
         self.unparsed.extend_from_slice(bytes);
     }
 
-    /// Decode and return the next message. Returns [`None`] if nothing was decoded.
-    pub fn decode_next<D: Decodable>(&mut self) -> Result<Option<D>, encode::Error> {
-        match encode::deserialize_partial::<D>(&self.unparsed) {
-            Ok((msg, index)) => {
-                // Drain deserialized bytes only.
-                self.unparsed.drain(..index);
-                Ok(Some(msg))
-            }
+    /// Decode and return the next message. Returns [`None`] if a full message wasn't
+    /// received yet.
+    ///
+    /// The message header is checked first, so that incomplete messages are not decoded,
+    /// and messages that are too large are rejected early.
+    pub fn decode_next(&mut self) -> Result<Option<RawNetworkMessage>, Error> {
+        let size = match self.message_size()? {
+            Some(size) if self.unparsed.len() >= size => size,
+            _ => return Ok(None),
+        };
+        let msg = encode::deserialize(&self.unparsed[..size])?;
+        // Drain deserialized bytes only.
+        self.unparsed.drain(..size);
 
-            Err(encode::Error::Io(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
-                Ok(None)
-            }
-            Err(err) => Err(err),
+        Ok(Some(msg))
+    }
+
+    /// Get the size of the next message, including its header, if the header was received.
+    fn message_size(&self) -> Result<Option<usize>, Error> {
+        if self.unparsed.len() < MESSAGE_HEADER_SIZE {
+            return Ok(None);
+        }
+        let command = String::from_utf8_lossy(&self.unparsed[4..16]);
+        let command = command.trim_end_matches('\0');
+        let length = self.unparsed[16..20]
+            .try_into()
+            .map(u32::from_le_bytes)
+            .expect("the payload length field is four bytes long") as usize;
+        let max = max_payload_size(command);
+
+        if length > max {
+            return Err(Error::Oversized {
+                command: command.to_owned(),
+                length,
+                max,
+            });
         }
+        Ok(Some(MESSAGE_HEADER_SIZE + length))
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use nakamoto_common::bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+    use nakamoto_common::bitcoin::network::message::{CommandString, NetworkMessage};
     use quickcheck_macros::quickcheck;
 
     const MSG_VERACK: [u8; 24] = [
@@ -72,7 +161,7 @@ mod test {
         for chunk in bytes.as_slice().chunks(chunk_size) {
             decoder.input(chunk);
 
-            while let Some(msg) = decoder.decode_next::<RawNetworkMessage>().unwrap() {
+            while let Some(msg) = decoder.decode_next().unwrap() {
                 msgs.push(msg);
             }
         }
@@ -94,4 +183,101 @@ mod test {
             }
         );
     }
+
+    #[quickcheck]
+    fn prop_decode_fragmented(nonces: Vec<u64>, payload: Vec<u8>, chunks: Vec<u16>) {
+        let magic = 3652501241;
+        let mut expected = nonces
+            .into_iter()
+            .map(|n| RawNetworkMessage {
+                magic,
+                payload: NetworkMessage::Ping(n),
+            })
+            .collect::<Vec<_>>();
+        expected.push(RawNetworkMessage {
+            magic,
+            payload: NetworkMessage::Unknown {
+                command: CommandString::try_from_static("payload").unwrap(),
+                payload,
+            },
+        });
+        expected.push(RawNetworkMessage {
+            magic,
+            payload: NetworkMessage::Verack,
+        });
+
+        let bytes = expected
+            .iter()
+            .flat_map(encode::serialize)
+            .collect::<Vec<_>>();
+        let mut decoder = Decoder::new(0);
+        let mut decoded = Vec::new();
+        let mut remaining = bytes.as_slice();
+        let mut chunks = chunks.into_iter().map(|c| 1 + c as usize % 512).cycle();
+
+        // Split the stream at arbitrary points.
+        while !remaining.is_empty() {
+            let n = chunks
+                .next()
+                .unwrap_or(remaining.len())
+                .min(remaining.len());
+            let (chunk, rest) = remaining.split_at(n);
+
+            decoder.input(chunk);
+            remaining = rest;
+
+            while let Some(msg) = decoder.decode_next().unwrap() {
+                decoded.push(msg);
+            }
+        }
+        assert_eq!(decoded, expected);
+        assert!(decoder.unparsed.is_empty());
+    }
+
+    #[test]
+    fn test_decode_partial_header() {
+        let mut decoder = Decoder::new(0);
+
+        decoder.input(&MSG_PING[..MESSAGE_HEADER_SIZE - 1]);
+        assert!(decoder.decode_next().unwrap().is_none());
+
+        decoder.input(&MSG_PING[MESSAGE_HEADER_SIZE - 1..MESSAGE_HEADER_SIZE]);
+        assert!(decoder.decode_next().unwrap().is_none());
+
+        decoder.input(&MSG_PING[MESSAGE_HEADER_SIZE..]);
+        assert_eq!(
+            decoder.decode_next().unwrap().map(|m| m.payload),
+            Some(NetworkMessage::Ping(100))
+        );
+    }
+
+    #[test]
+    fn test_decode_oversized() {
+        let mut decoder = Decoder::new(0);
+        let mut header = MSG_PING[..MESSAGE_HEADER_SIZE].to_vec();
+
+        header[16..20].copy_from_slice(&9u32.to_le_bytes());
+        decoder.input(&header);
+
+        assert!(matches!(
+            decoder.decode_next(),
+            Err(Error::Oversized { command, length: 9, max: 8 }) if command == "ping"
+        ));
+
+        let mut decoder = Decoder::new(0);
+        let mut header = MSG_PING[..MESSAGE_HEADER_SIZE].to_vec();
+
+        header[4..16].copy_from_slice(b"unknown\0\0\0\0\0");
+        header[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        decoder.input(&header);
+
+        assert!(matches!(
+            decoder.decode_next(),
+            Err(Error::Oversized {
+                max: MAX_PAYLOAD_SIZE,
+                ..
+            })
+        ));
+        assert!(decoder.unparsed.capacity() < 1024);
+    }
 }
@@ -56,13 +56,6 @@ impl Cache {
         }
     }
 
-    pub fn rollback(&mut self, height: Height) -> Result<(), Error> {
-        for block in self.chain.tail.drain(height as usize..) {
-            self.headers.remove(&block.block_hash());
-        }
-        Ok(())
-    }
-
     fn branch(&self, tip: &BlockHash) -> Option<NonEmpty<BlockHeader>> {
         let mut headers = VecDeque::new();
         let mut tip = *tip;
@@ -170,6 +163,22 @@ impl BlockTree for Cache {
             Ok(ImportResult::TipUnchanged)
         }
     }
+
+    fn rollback(&mut self, height: Height) -> Result<Vec<(Height, BlockHeader)>, Error> {
+        let mut stale = Vec::new();
+
+        if height >= self.height() {
+            return Ok(stale);
+        }
+        for (block, height) in self.chain.tail.drain(height as usize..).zip(height + 1..) {
+            self.headers.remove(&block.block_hash());
+            stale.push((height, block));
+        }
+        self.tip = self.chain.last().block_hash();
+        stale.reverse();
+
+        Ok(stale)
+    }
 }
 
 impl BlockReader for Cache {
@@ -203,7 +212,7 @@ impl BlockReader for Cache {
         BTreeMap::new()
     }
 
-    fn locator_hashes(&self, _from: Height) -> Vec<BlockHash> {
+    fn locator_hashes_with(&self, _from: Height, _density: usize) -> Vec<BlockHash> {
         vec![self.chain.last().block_hash()]
     }
 
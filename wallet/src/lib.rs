@@ -29,12 +29,13 @@ pub fn run(
     birth: Height,
     connect: net::SocketAddr,
     hd_path: DerivationPath,
+    json: bool,
 ) -> Result<(), Error> {
     let network = Network::Mainnet;
     let cfg = Config {
         network,
         listen: vec![], // Don't listen for incoming connections.
-        connect: vec![connect],
+        connect_only: vec![connect],
         limits: Limits {
             max_outbound_peers: 1,
             ..Limits::default()
@@ -59,6 +60,30 @@ pub fn run(
 
     log::info!("Spawning client threads..");
 
+    if json {
+        // Start the signal handler thread.
+        let t2 = thread::spawn(|| input::signals(signals_tx));
+        // Start the network client in the background.
+        let t3 = thread::spawn(|| client.run(cfg));
+
+        // Run the wallet without a UI, writing client events to `stdout`.
+        log::info!("Running main wallet loop in JSON mode..");
+        Wallet::new(handle.clone(), network, db, hw).run_json(
+            birth,
+            signals_rx,
+            client_recv,
+            io::stdout(),
+        )?;
+
+        log::info!("Shutting down client..");
+        handle.shutdown()?;
+
+        t2.join().unwrap()?;
+        t3.join().unwrap()?;
+
+        return Ok(());
+    }
+
     // Start the UI loop in the background.
     let t1 = thread::spawn(|| input::run(inputs_tx, exit_rx));
     // Start the signal handler thread.
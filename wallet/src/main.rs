@@ -30,6 +30,9 @@ pub struct Options {
     /// enable debug logging
     #[argh(switch)]
     pub debug: bool,
+    /// output client events as JSON lines on stdout, instead of running the UI
+    #[argh(switch)]
+    pub json: bool,
 }
 
 impl Options {
@@ -48,9 +51,13 @@ fn main() {
     };
     logger::init(level).expect("initializing logger for the first time");
 
-    if let Err(err) =
-        nakamoto_wallet::run(&opts.wallet, opts.birth_height, opts.connect, opts.hd_path)
-    {
+    if let Err(err) = nakamoto_wallet::run(
+        &opts.wallet,
+        opts.birth_height,
+        opts.connect,
+        opts.hd_path,
+        opts.json,
+    ) {
         log::error!("Fatal: {}", err);
         std::process::exit(1);
     }
@@ -179,6 +179,44 @@ impl<H: Handle> Wallet<H> {
         Ok(())
     }
 
+    /// Run the wallet loop without a UI, until it exits. Client events are written
+    /// to the output as JSON objects, one per line.
+    pub fn run_json<W: io::Write>(
+        &mut self,
+        birth: Height,
+        signals: chan::Receiver<Signal>,
+        events: chan::Receiver<client::Event>,
+        mut out: W,
+    ) -> Result<(), Error> {
+        // Convert our address list into scripts.
+        let watch: Vec<_> = self.watch.iter().map(|a| a.script_pubkey()).collect();
+
+        // Start a re-scan from the birth height, which keeps scanning as new blocks arrive.
+        self.client.rescan(birth.., watch.iter().cloned())?;
+
+        loop {
+            chan::select! {
+                recv(signals) -> signal => {
+                    if let Signal::Interrupted = signal? {
+                        return Ok(());
+                    }
+                }
+                recv(events) -> event => {
+                    let event = event?;
+                    let json = microserde::json::to_string(&event.to_json());
+
+                    writeln!(out, "{}", json)?;
+                    out.flush()?;
+
+                    if let Break(()) = self.handle_client_event(event, &watch)? {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn handle_input(&mut self, input: Event) -> Result<ControlFlow<()>, Error> {
         use termion::event::Key;
 
@@ -214,6 +214,7 @@ impl Ui {
 
     pub fn handle_loading_event(&mut self, event: client::Loading) -> io::Result<ControlFlow<()>> {
         match event {
+            client::Loading::Started { .. } | client::Loading::DefaultDnsSeeds { .. } => {}
             client::Loading::BlockHeaderLoaded { height } => {
                 self.status = Status::LoadingBlockHeaders { height };
             }